@@ -0,0 +1,61 @@
+//! Measures how long creating notes takes, which generates their cards from the fields and
+//! templates of the model, so that slowdowns in card generation show up.
+//!
+//! Run with `cargo bench --bench card_generation`, optionally followed by `-- <number of notes>`.
+
+use genanki_rs::{basic_optional_reversed_card_model, cloze_model, Model, Note};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Creates `count` notes of `model` with `fields` and prints the time per note
+fn bench(name: &str, model: Model, count: usize, fields: impl Fn(usize) -> Vec<String>) {
+    let model = Arc::new(model);
+    let rows: Vec<Vec<String>> = (0..count).map(fields).collect();
+    let start = Instant::now();
+    let cards: usize = rows
+        .iter()
+        .map(|row| {
+            let fields = row.iter().map(String::as_str).collect();
+            let note = Note::new(model.clone(), fields).expect("valid note");
+            note.card_count()
+        })
+        .sum();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<22} {:>8.1?} for {} notes with {} cards, {:>6} ns per note",
+        name,
+        elapsed,
+        count,
+        cards,
+        elapsed.as_nanos() / count.max(1) as u128
+    );
+}
+
+fn main() {
+    let count = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(100_000);
+    bench(
+        "front/back templates",
+        basic_optional_reversed_card_model(),
+        count,
+        |i| {
+            let reverse = if i % 2 == 0 { "y" } else { "" };
+            vec![
+                format!("word {}", i),
+                "meaning".to_string(),
+                reverse.to_string(),
+            ]
+        },
+    );
+    bench("cloze deletions", cloze_model(), count, |i| {
+        vec![
+            format!(
+                "{{{{c1::Berlin}}}} is the capital of {{{{c2::Germany::country}}}} {}",
+                i
+            ),
+            "extra".to_string(),
+        ]
+    });
+}
@@ -0,0 +1,76 @@
+//! Compares building notes with a copy of the model for each note to building them with one
+//! shared `Arc<Model>`, in time and in the peak memory of the notes.
+//!
+//! Run with `cargo bench --bench shared_model`, optionally followed by `-- <number of notes>`.
+
+use genanki_rs::{basic_and_reversed_card_model, Model, Note};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Counts the allocated bytes, to report the peak memory of each way
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Builds `count` notes with `new_note` and returns how long it took and the peak of the
+/// allocated bytes above what was allocated before
+fn measure(count: usize, new_note: impl Fn(&str) -> Note) -> (Duration, usize) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let start = Instant::now();
+    let words: Vec<String> = (0..count).map(|i| format!("word {}", i)).collect();
+    let notes: Vec<Note> = words.iter().map(|word| new_note(word)).collect();
+    let elapsed = start.elapsed();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    drop(notes);
+    (elapsed, peak)
+}
+
+fn main() {
+    let count = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(200_000);
+    // A model with the CSS of a styled deck
+    let css = ".card { font-family: arial; font-size: 20px; }\n".repeat(100);
+    let model: Model = basic_and_reversed_card_model().css(css);
+
+    let (cloned_time, cloned_peak) = measure(count, |word| {
+        Note::new(model.clone(), vec![word, "meaning"]).expect("valid note")
+    });
+    let shared = Arc::new(model);
+    let (shared_time, shared_peak) = measure(count, |word| {
+        Note::new(shared.clone(), vec![word, "meaning"]).expect("valid note")
+    });
+
+    println!("{} notes", count);
+    println!(
+        "one model per note:  {:>8.1?}, {:>6} MiB",
+        cloned_time,
+        cloned_peak >> 20
+    );
+    println!(
+        "shared Arc<Model>:   {:>8.1?}, {:>6} MiB",
+        shared_time,
+        shared_peak >> 20
+    );
+}
@@ -0,0 +1,51 @@
+//! Compares the time it takes to write the notes of a package in SQLite transactions of
+//! different sizes, where one note per transaction is about as slow as writing without explicit
+//! transactions.
+//!
+//! Run with `cargo bench --bench write_package`, optionally followed by `-- <number of notes>`.
+
+use genanki_rs::{basic_model, Deck, Note, PackageWriter};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Writes `count` notes in transactions of `batch_size` notes and prints the time per note
+fn bench(batch_size: usize, count: usize) {
+    let model = Arc::new(basic_model());
+    let notes: Vec<Note> = (0..count)
+        .map(|i| Note::new(model.clone(), vec![&i.to_string(), "a"]).expect("valid note"))
+        .collect();
+    let decks = [Deck::new(1234, "Numbers", "")];
+    let start = Instant::now();
+    let mut writer = PackageWriter::new(Cursor::new(vec![]), vec![], &decks)
+        .expect("valid decks")
+        .batch_size(batch_size);
+    for note in notes {
+        writer.add_note(1234, note).expect("known deck");
+    }
+    let bytes = writer.finish().expect("package written").into_inner().len();
+    let elapsed = start.elapsed();
+    let name = match batch_size {
+        0 => "one transaction".to_string(),
+        1 => "1 note per transaction".to_string(),
+        n => format!("{} notes per transaction", n),
+    };
+    println!(
+        "{:<28} {:>8.1?} for {} notes in {} bytes, {:>6} ns per note",
+        name,
+        elapsed,
+        count,
+        bytes,
+        elapsed.as_nanos() / count.max(1) as u128
+    );
+}
+
+fn main() {
+    let count = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(50_000);
+    for batch_size in [1, 100, 1000, 0] {
+        bench(batch_size, count);
+    }
+}
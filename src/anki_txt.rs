@@ -0,0 +1,372 @@
+//! Reading the notes of a text file exported by Anki 2.1.55 and later with "Notes in Plain Text"
+//!
+//! The file starts with header lines like `#separator:tab`, `#html:true` and
+//! `#guid column:1`, which describe the columns of the rows after them.
+//!
+//! Example:
+//!
+//! ```rust
+//! use genanki_rs::{anki_txt, basic_model, Deck};
+//!
+//! # fn main() -> Result<(), genanki_rs::Error> {
+//! let file = "#separator:tab\n#html:true\n#guid column:1\n#tags column:4\n\
+//!             k~J)wUJLND\tder <b>Hund</b>\tthe dog\tnouns\n";
+//! let export = anki_txt::import(file.as_bytes())?;
+//! let mut deck = Deck::new(1234, "German", "");
+//! for note in export.notes(&basic_model())? {
+//!     deck.add_note(note);
+//! }
+//! assert_eq!(deck.notes().next().unwrap().field(0), Some("der <b>Hund</b>"));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::model::Model;
+use crate::note::Note;
+use crate::Error;
+
+/// The rows of a file read by [`import`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsedExport {
+    /// Whether the fields contain HTML, from `#html:`; fields of files without HTML are escaped
+    /// when they are read, so [`ExportRow::fields`] always contain HTML
+    pub html: bool,
+    /// The names of the field columns, from `#columns:`, if the file has them
+    pub columns: Option<Vec<String>>,
+    pub rows: Vec<ExportRow>,
+}
+
+/// A note in a file read by [`import`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportRow {
+    /// The line of the file the row starts on, starting at `1`
+    pub line: u64,
+    /// The guid of the note, from the `#guid column:`
+    pub guid: Option<String>,
+    /// The name of the model of the note, from the `#notetype column:` or `#notetype:`
+    pub notetype: Option<String>,
+    /// The name of the deck of the note, from the `#deck column:` or `#deck:`
+    pub deck: Option<String>,
+    /// The tags of the note, from the `#tags column:` and `#tags:`
+    pub tags: Vec<String>,
+    /// The values of the other columns, in order
+    pub fields: Vec<String>,
+}
+
+/// The headers of a file, with the columns starting at `0`
+struct Headers {
+    separator: u8,
+    html: bool,
+    columns: Option<Vec<String>>,
+    guid: Option<usize>,
+    notetype: Option<usize>,
+    deck: Option<usize>,
+    tags: Option<usize>,
+    default_notetype: Option<String>,
+    default_deck: Option<String>,
+    default_tags: Vec<String>,
+}
+
+impl Default for Headers {
+    fn default() -> Self {
+        Self {
+            separator: b'\t',
+            html: false,
+            columns: None,
+            guid: None,
+            notetype: None,
+            deck: None,
+            tags: None,
+            default_notetype: None,
+            default_deck: None,
+            default_tags: vec![],
+        }
+    }
+}
+
+/// Reads a text file exported by Anki
+///
+/// Files without a `#separator:` header are read as tab separated. Returns
+/// `Error::InvalidCsvRow` if a header or a row is malformed.
+pub fn import(mut reader: impl Read) -> Result<ParsedExport, Error> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    let mut headers = Headers::default();
+    let mut rest = content.as_str();
+    let mut header_lines = 0;
+    while let Some(header) = rest.strip_prefix('#') {
+        let (line, next) = header.split_once('\n').unwrap_or((header, ""));
+        header_lines += 1;
+        parse_header(&mut headers, line.trim_end_matches('\r'), header_lines)?;
+        rest = next;
+    }
+
+    let invalid = |line: u64, message: String| Error::InvalidCsvRow { line, message };
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .delimiter(headers.separator)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(rest.as_bytes());
+    let mut rows = vec![];
+    for record in csv_reader.records() {
+        let record = record.map_err(|e| {
+            let line = e.position().map_or(0, |position| position.line());
+            invalid(header_lines + line, e.to_string())
+        })?;
+        let line = header_lines + record.position().map_or(0, |position| position.line());
+        let column = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .map(str::to_string)
+        };
+        let mut tags = headers.default_tags.clone();
+        tags.extend(
+            column(headers.tags)
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string),
+        );
+        let special = [headers.guid, headers.notetype, headers.deck, headers.tags];
+        let fields = record
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !special.contains(&Some(*index)))
+            .map(|(_, value)| {
+                if headers.html {
+                    value.to_string()
+                } else {
+                    escape_text(value)
+                }
+            })
+            .collect();
+        rows.push(ExportRow {
+            line,
+            guid: column(headers.guid).filter(|guid| !guid.is_empty()),
+            notetype: column(headers.notetype).or_else(|| headers.default_notetype.clone()),
+            deck: column(headers.deck).or_else(|| headers.default_deck.clone()),
+            tags,
+            fields,
+        });
+    }
+    Ok(ParsedExport {
+        html: headers.html,
+        columns: headers.columns,
+        rows,
+    })
+}
+
+fn parse_header(headers: &mut Headers, header: &str, line: u64) -> Result<(), Error> {
+    let invalid = |message: String| Error::InvalidCsvRow { line, message };
+    let (key, value) = header
+        .split_once(':')
+        .ok_or_else(|| invalid(format!("the header #{} has no value", header)))?;
+    let column = || match value.trim().parse::<usize>() {
+        Ok(column) if column > 0 => Ok(Some(column - 1)),
+        _ => Err(invalid(format!(
+            "{:?} isn't a column, they start at 1",
+            value
+        ))),
+    };
+    match key.trim().to_lowercase().as_str() {
+        "separator" => {
+            headers.separator = match value.to_lowercase().as_str() {
+                "tab" => b'\t',
+                "comma" => b',',
+                "semicolon" => b';',
+                "space" => b' ',
+                "pipe" => b'|',
+                "colon" => b':',
+                _ if value.len() == 1 => value.as_bytes()[0],
+                _ => return Err(invalid(format!("unknown separator {:?}", value))),
+            }
+        }
+        "html" => headers.html = value.trim().eq_ignore_ascii_case("true"),
+        "columns" => {
+            let separator = char::from(headers.separator);
+            headers.columns = Some(value.split(separator).map(str::to_string).collect());
+        }
+        "guid column" => headers.guid = column()?,
+        "notetype column" => headers.notetype = column()?,
+        "deck column" => headers.deck = column()?,
+        "tags column" => headers.tags = column()?,
+        "notetype" => headers.default_notetype = Some(value.to_string()),
+        "deck" => headers.default_deck = Some(value.to_string()),
+        "tags" => headers.default_tags = value.split_whitespace().map(str::to_string).collect(),
+        // Other headers, like `#if matches:`, only change how Anki imports the notes
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Escapes text from a file without HTML like Anki does on import, with line breaks as `<br>`
+fn escape_text(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
+impl ParsedExport {
+    /// Creates notes of `model` from the rows of the file, with the guids and tags of the rows
+    ///
+    /// Rows with a notetype other than the name of `model` are skipped. The fields are mapped by
+    /// name if the file has a `#columns:` header, and by position otherwise. Missing fields are
+    /// left empty. Returns `Error::UnknownNoteField` if a column isn't a field of the model and
+    /// `Error::InvalidCsvRow` if a row has more non-empty fields than the model, and otherwise
+    /// fails like [`Note::new`].
+    pub fn notes(&self, model: &Model) -> Result<Vec<Note>, Error> {
+        // The notes share one copy of the model
+        let model = Arc::new(model.clone());
+        let field_names = model.field_names();
+        let positions = match &self.columns {
+            Some(columns) => columns
+                .iter()
+                .map(|column| {
+                    field_names
+                        .iter()
+                        .position(|name| name == column)
+                        .ok_or_else(|| Error::UnknownNoteField {
+                            key: column.clone(),
+                            known_fields: field_names.iter().map(|n| n.to_string()).collect(),
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => (0..field_names.len()).collect(),
+        };
+
+        let mut notes = vec![];
+        for row in &self.rows {
+            if row
+                .notetype
+                .as_deref()
+                .is_some_and(|name| name != model.name())
+            {
+                continue;
+            }
+            let mut fields = vec![""; field_names.len()];
+            for (index, value) in row.fields.iter().enumerate() {
+                match positions.get(index) {
+                    Some(&position) => fields[position] = value,
+                    None if value.is_empty() => {}
+                    None => {
+                        return Err(Error::InvalidCsvRow {
+                            line: row.line,
+                            message: format!(
+                                "the row has {} fields, but the model has {}",
+                                row.fields.len(),
+                                positions.len()
+                            ),
+                        })
+                    }
+                }
+            }
+            let mut note = Note::new(model.clone(), fields)?.tags(&row.tags)?;
+            if let Some(guid) = &row.guid {
+                note = note.guid(guid);
+            }
+            notes.push(note);
+        }
+        Ok(notes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_model, Field, ModelType, Template};
+
+    /// Notes of Anki's own models exported by Anki with HTML, tags, deck names, notetypes and
+    /// guids, where the field columns are as many as the model with the most fields has
+    const EXPORT: &str = include_str!("../tests/fixtures/anki_export.txt");
+
+    #[test]
+    fn import_export() {
+        let export = import(EXPORT.as_bytes()).unwrap();
+        assert!(export.html);
+        assert_eq!(export.rows.len(), 5);
+        assert_eq!(
+            export.rows[1],
+            ExportRow {
+                line: 8,
+                guid: Some("fIq<ColTd7".to_string()),
+                notetype: Some("Basic".to_string()),
+                deck: Some("Languages::German::Verbs".to_string()),
+                tags: vec!["verbs".to_string()],
+                fields: vec![
+                    "laufen".to_string(),
+                    "to run<br>\nto walk, \"fast\"".to_string(),
+                    "".to_string()
+                ],
+            }
+        );
+        assert_eq!(export.rows[2].line, 10);
+        assert_eq!(export.rows[2].fields[1], "Tom &amp; Jerry");
+        assert_eq!(export.rows[3].fields, vec!["die Katze", "the cat", "y"]);
+        assert_eq!(export.rows[4].tags, Vec::<String>::new());
+
+        let basic = Model::new(
+            1678901234567,
+            "Basic",
+            vec![Field::new("Front"), Field::new("Back")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Front}}")
+                .afmt("{{FrontSide}}<hr id=answer>{{Back}}")],
+        );
+        let notes = export.notes(&basic).unwrap();
+        let guids: Vec<String> = notes.iter().map(Note::get_guid).collect();
+        assert_eq!(guids, vec!["E(%C7Ds!*]", "fIq<ColTd7", "J)l,3bWTJp"]);
+        assert_eq!(notes[0].fields(), &["der <b>Hund</b>", "the dog"]);
+        assert_eq!(notes[0].get_tags(), &["animals", "nouns"]);
+        assert_eq!(notes[2].fields()[1], "the dog&nbsp;/ the cat");
+        let cloze = Model::new(
+            1678901234568,
+            "Cloze",
+            vec![Field::new("Text"), Field::new("Back Extra")],
+            vec![Template::new("Cloze")
+                .qfmt("{{cloze:Text}}")
+                .afmt("{{cloze:Text}}<br>{{Back Extra}}")],
+        )
+        .model_type(ModelType::Cloze);
+        let cloze = export.notes(&cloze).unwrap();
+        assert_eq!(cloze.len(), 1);
+        assert_eq!(
+            cloze[0].fields()[0],
+            "{{c1::Berlin}} is the capital of {{c2::Germany}}"
+        );
+        assert!(export.notes(&basic_model()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn headers() {
+        let file = "#separator:Semicolon\n#html:false\n#columns:Back;Front\n#deck:German\n\
+                    #tags:imported\n#notetype:Basic (genanki)\nthe dog;der <Hund>\n\
+                    \"the cat\nmeow\";die Katze\n";
+        let export = import(file.as_bytes()).unwrap();
+        assert!(!export.html);
+        assert_eq!(export.rows[0].deck.as_deref(), Some("German"));
+        assert_eq!(export.rows[0].tags, vec!["imported"]);
+        let notes = export.notes(&basic_model()).unwrap();
+        assert_eq!(notes[0].fields(), &["der &lt;Hund&gt;", "the dog"]);
+        assert_eq!(notes[1].fields(), &["die Katze", "the cat<br>meow"]);
+
+        assert!(matches!(
+            import("#separator:dots\n".as_bytes()),
+            Err(Error::InvalidCsvRow { line: 1, .. })
+        ));
+        assert!(matches!(
+            import("#html:true\n#guid column:0\n".as_bytes()),
+            Err(Error::InvalidCsvRow { line: 2, .. })
+        ));
+        let export = import("a\tb\tc\n".as_bytes()).unwrap();
+        assert!(matches!(
+            export.notes(&basic_model()),
+            Err(Error::InvalidCsvRow { line: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,478 @@
+//! Adding models, decks and notes to a running Anki with the
+//! [AnkiConnect](https://foosoft.net/projects/anki-connect/) add-on instead of writing a package
+//!
+//! This is e.g. useful while working on the templates of a model: pushing the model again shows
+//! the new templates in Anki right away, without importing a package.
+//!
+//! Example:
+//!
+//! ```rust,no_run
+//! use genanki_rs::ankiconnect::Client;
+//! use genanki_rs::{basic_model, Deck, Note};
+//!
+//! # fn main() -> Result<(), genanki_rs::Error> {
+//! let mut deck = Deck::new(1234, "German", "");
+//! deck.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"])?);
+//! let note_ids = Client::new().push_deck(&deck)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use base64::Engine;
+use serde_json::{json, Map, Value};
+
+use crate::{Deck, Error, Model, ModelType, Note, Package};
+
+/// The address AnkiConnect listens on by default
+const DEFAULT_URL: &str = "http://127.0.0.1:8765";
+
+/// The version of the AnkiConnect API the requests are written for
+const API_VERSION: u32 = 6;
+
+/// The prefix of the tags that [`NoteKey::GuidTag`] adds to notes
+const GUID_TAG_PREFIX: &str = "genanki_guid::";
+
+/// How [`Client::push_note`] finds a note that was pushed before, see [`Client::note_key`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NoteKey {
+    /// Each note is tagged with its guid, e.g. `genanki_guid::6b7e4a...`, and a note with the tag
+    /// of the guid is updated
+    GuidTag,
+    /// A note of the same model with the same value in this field is updated
+    Field(String),
+}
+
+/// A client for the AnkiConnect add-on of a running Anki
+///
+/// Models are matched with the models of the collection by name and notes by their
+/// [`NoteKey`]. The requests are sent one at a time and their errors are returned as
+/// `Error::AnkiConnect`, or as `Error::AnkiConnectNote` with the index of the note for
+/// [`Client::push_deck`].
+#[derive(Clone, Debug)]
+pub struct Client {
+    url: String,
+    note_key: NoteKey,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    /// Creates a client for AnkiConnect on its default address `http://127.0.0.1:8765`
+    pub fn new() -> Self {
+        Self::with_url(DEFAULT_URL)
+    }
+
+    /// Creates a client for AnkiConnect on `url`, e.g. if its `webBindPort` was changed
+    pub fn with_url(url: impl ToString) -> Self {
+        Self {
+            url: url.to_string(),
+            note_key: NoteKey::GuidTag,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// Sets how notes that were pushed before are found, default is `NoteKey::GuidTag`
+    pub fn note_key(mut self, note_key: NoteKey) -> Self {
+        self.note_key = note_key;
+        self
+    }
+
+    /// Adds `model` to the collection, or replaces the templates and the CSS of the model with
+    /// the same name
+    ///
+    /// Anki keeps the fields of an existing model, so fields that were added or renamed aren't
+    /// changed.
+    pub fn push_model(&self, model: &Model) -> Result<(), Error> {
+        let names = self.invoke("modelNames", json!({}))?;
+        let exists = names
+            .as_array()
+            .is_some_and(|names| names.iter().any(|name| name == model.name()));
+        // Anki gives the templates of a new model the ords of their positions
+        let mut templates = model.templates().to_vec();
+        templates.sort_by_key(|template| template.ord);
+        if exists {
+            let templates: Map<String, Value> = templates
+                .iter()
+                .map(|t| (t.name.clone(), json!({ "Front": t.qfmt, "Back": t.afmt })))
+                .collect();
+            self.invoke(
+                "updateModelTemplates",
+                json!({ "model": { "name": model.name(), "templates": templates } }),
+            )?;
+            self.invoke(
+                "updateModelStyling",
+                json!({ "model": { "name": model.name(), "css": model.get_css() } }),
+            )?;
+        } else {
+            let templates: Vec<Value> = templates
+                .iter()
+                .map(|t| json!({ "Name": t.name, "Front": t.qfmt, "Back": t.afmt }))
+                .collect();
+            self.invoke(
+                "createModel",
+                json!({
+                    "modelName": model.name(),
+                    "inOrderFields": model.field_names(),
+                    "css": model.get_css(),
+                    "isCloze": model.get_model_type() == ModelType::Cloze,
+                    "cardTemplates": templates,
+                }),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Creates the deck if it doesn't exist yet, pushes the models of its notes and then its
+    /// notes, and returns the ids of the notes in Anki
+    ///
+    /// Returns `Error::AnkiConnectNote` with the index of the note in the deck if a note can't be
+    /// added or updated, e.g. because it is a duplicate of a note that isn't matched by the
+    /// [`NoteKey`]. The notes before it are pushed already.
+    pub fn push_deck(&self, deck: &Deck) -> Result<Vec<i64>, Error> {
+        self.invoke("createDeck", json!({ "deck": deck.name() }))?;
+        let mut model_ids = vec![];
+        for note in deck.notes() {
+            let model = note.model();
+            if !model_ids.contains(&model.id) {
+                model_ids.push(model.id);
+                self.push_model(model)?;
+            }
+        }
+        deck.notes()
+            .enumerate()
+            .map(|(index, note)| {
+                self.push_note(note, deck.name())
+                    .map_err(|e| Error::AnkiConnectNote {
+                        index,
+                        message: e.to_string(),
+                    })
+            })
+            .collect()
+    }
+
+    /// Adds `note` to the deck `deck_name`, or updates the fields of the note that was pushed
+    /// before, and returns the id of the note in Anki
+    ///
+    /// The deck and the model of the note have to exist already, see [`Client::push_deck`] and
+    /// [`Client::push_model`].
+    pub fn push_note(&self, note: &Note, deck_name: &str) -> Result<i64, Error> {
+        let model = note.model();
+        let fields: Map<String, Value> = model
+            .field_names()
+            .into_iter()
+            .zip(note.fields())
+            .map(|(name, value)| (name.to_string(), value.as_str().into()))
+            .collect();
+        let guid_tag = format!("{}{}", GUID_TAG_PREFIX, hex(&note.get_guid()));
+        let query = match &self.note_key {
+            NoteKey::GuidTag => search_term(&format!("tag:{}", guid_tag)),
+            NoteKey::Field(name) => {
+                let value = note
+                    .field_by_name(name)
+                    .ok_or_else(|| Error::UnknownNoteField {
+                        key: name.clone(),
+                        known_fields: model.field_names().iter().map(|n| n.to_string()).collect(),
+                    })?;
+                format!(
+                    "{} {}",
+                    search_term(&format!("note:{}", model.name())),
+                    search_term(&format!("{}:{}", name, value))
+                )
+            }
+        };
+        let existing = self.invoke("findNotes", json!({ "query": query }))?;
+        if let Some(id) = existing.get(0).and_then(Value::as_i64) {
+            self.invoke(
+                "updateNoteFields",
+                json!({ "note": { "id": id, "fields": fields } }),
+            )?;
+            return Ok(id);
+        }
+
+        let mut tags = note.get_tags().to_vec();
+        if self.note_key == NoteKey::GuidTag {
+            tags.push(guid_tag);
+        }
+        let id = self.invoke(
+            "addNote",
+            json!({
+                "note": {
+                    "deckName": deck_name,
+                    "modelName": model.name(),
+                    "fields": fields,
+                    "tags": tags,
+                }
+            }),
+        )?;
+        id.as_i64().ok_or_else(|| Error::AnkiConnect {
+            action: "addNote".to_string(),
+            message: format!("expected the id of the note, got {}", id),
+        })
+    }
+
+    /// Stores the media files of `package` in the media folder of the collection and pushes its
+    /// decks like [`Client::push_deck`], and returns the ids of the notes in Anki
+    ///
+    /// Media files with the same name as a file in the collection replace it.
+    pub fn push_package(&self, package: &Package) -> Result<Vec<i64>, Error> {
+        for media_file in package.media_files() {
+            let data = base64::engine::general_purpose::STANDARD.encode(media_file.read()?);
+            self.invoke(
+                "storeMediaFile",
+                json!({ "filename": media_file.name(), "data": data }),
+            )?;
+        }
+        let mut note_ids = vec![];
+        for deck in package.decks() {
+            note_ids.extend(self.push_deck(deck)?);
+        }
+        Ok(note_ids)
+    }
+
+    /// Sends the request for `action` and returns its result
+    fn invoke(&self, action: &str, params: Value) -> Result<Value, Error> {
+        let failed = |message: String| Error::AnkiConnect {
+            action: action.to_string(),
+            message,
+        };
+        let request = json!({ "action": action, "version": API_VERSION, "params": params });
+        let response = self
+            .agent
+            .post(&self.url)
+            .send_string(&request.to_string())
+            .map_err(|e| failed(e.to_string()))?
+            .into_string()?;
+        let mut response: Map<String, Value> =
+            serde_json::from_str(&response).map_err(|e| failed(e.to_string()))?;
+        match response.remove("error") {
+            Some(Value::String(message)) => Err(failed(message)),
+            None => Err(failed("the response has no error key".to_string())),
+            Some(_) => Ok(response.remove("result").unwrap_or(Value::Null)),
+        }
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A term of an Anki search that matches `text` literally
+fn search_term(text: &str) -> String {
+    let mut term = String::from("\"");
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_') {
+            term.push('\\');
+        }
+        term.push(c);
+    }
+    term.push('"');
+    term
+}
+
+/// Guids can contain characters that have a meaning in tags and searches, so their tags contain
+/// the guid in hex
+fn hex(text: &str) -> String {
+    text.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_model, Deck};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// A local HTTP server that answers AnkiConnect requests with `respond` and records them
+    struct MockAnki {
+        url: String,
+        requests: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl MockAnki {
+        fn start(respond: impl Fn(&str, &Value) -> Value + Send + 'static) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(vec![]));
+            let recorded = requests.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut len = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == "\r\n" || line.is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                len = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; len];
+                    reader.read_exact(&mut body).unwrap();
+                    let request: Value = serde_json::from_slice(&body).unwrap();
+                    let response = respond(request["action"].as_str().unwrap(), &request);
+                    recorded.lock().unwrap().push(request);
+                    let response = response.to_string();
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.len(),
+                        response
+                    )
+                    .unwrap();
+                }
+            });
+            Self { url, requests }
+        }
+
+        fn actions(&self) -> Vec<String> {
+            let requests = self.requests.lock().unwrap();
+            requests
+                .iter()
+                .map(|r| r["action"].as_str().unwrap().to_string())
+                .collect()
+        }
+
+        fn request(&self, action: &str, nth: usize) -> Value {
+            let requests = self.requests.lock().unwrap();
+            requests
+                .iter()
+                .filter(|r| r["action"] == action)
+                .nth(nth)
+                .unwrap()
+                .clone()
+        }
+    }
+
+    fn german_deck() -> Deck {
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(
+            Note::new(basic_model(), vec!["der Hund", "the dog"])
+                .unwrap()
+                .tags(["nouns"])
+                .unwrap()
+                .guid("a_b"),
+        );
+        deck.add_note(Note::new(basic_model(), vec!["die Katze", "the cat"]).unwrap());
+        deck
+    }
+
+    #[test]
+    fn push_new_deck() {
+        let anki = MockAnki::start(|action, _| match action {
+            "modelNames" => json!({ "result": ["Basic"], "error": null }),
+            "findNotes" => json!({ "result": [], "error": null }),
+            "addNote" => json!({ "result": 1001, "error": null }),
+            _ => json!({ "result": null, "error": null }),
+        });
+        let mut package = Package::new(vec![german_deck()], vec![]).unwrap();
+        package.add_media_bytes("hund.txt", "woof").unwrap();
+        let ids = Client::with_url(&anki.url).push_package(&package).unwrap();
+        assert_eq!(ids, vec![1001, 1001]);
+        assert_eq!(
+            anki.actions(),
+            vec![
+                "storeMediaFile",
+                "createDeck",
+                "modelNames",
+                "createModel",
+                "findNotes",
+                "addNote",
+                "findNotes",
+                "addNote"
+            ]
+        );
+        assert_eq!(
+            anki.request("storeMediaFile", 0)["params"],
+            json!({ "filename": "hund.txt", "data": "d29vZg==" })
+        );
+        let model = &anki.request("createModel", 0)["params"];
+        assert_eq!(model["modelName"], basic_model().name());
+        assert_eq!(model["inOrderFields"], json!(["Front", "Back"]));
+        assert_eq!(model["cardTemplates"][0]["Front"], "{{Front}}");
+        assert_eq!(
+            anki.request("findNotes", 0)["params"]["query"],
+            "\"tag:genanki\\_guid::615f62\""
+        );
+        assert_eq!(
+            anki.request("addNote", 0)["params"]["note"],
+            json!({
+                "deckName": "German",
+                "modelName": basic_model().name(),
+                "fields": { "Front": "der Hund", "Back": "the dog" },
+                "tags": ["nouns", "genanki_guid::615f62"],
+            })
+        );
+    }
+
+    #[test]
+    fn push_existing_notes() {
+        let anki = MockAnki::start(|action, _| match action {
+            "modelNames" => json!({ "result": [basic_model().name()], "error": null }),
+            "findNotes" => json!({ "result": [42], "error": null }),
+            _ => json!({ "result": null, "error": null }),
+        });
+        let client = Client::with_url(&anki.url).note_key(NoteKey::Field("Front".to_string()));
+        assert_eq!(client.push_deck(&german_deck()).unwrap(), vec![42, 42]);
+        assert_eq!(
+            anki.actions(),
+            vec![
+                "createDeck",
+                "modelNames",
+                "updateModelTemplates",
+                "updateModelStyling",
+                "findNotes",
+                "updateNoteFields",
+                "findNotes",
+                "updateNoteFields"
+            ]
+        );
+        assert_eq!(
+            anki.request("findNotes", 1)["params"]["query"],
+            format!("\"note:{}\" \"Front:die Katze\"", basic_model().name())
+        );
+        assert_eq!(
+            anki.request("updateNoteFields", 0)["params"]["note"],
+            json!({ "id": 42, "fields": { "Front": "der Hund", "Back": "the dog" } })
+        );
+    }
+
+    #[test]
+    fn errors_have_the_note_index() {
+        let anki = MockAnki::start(|action, request| match action {
+            "modelNames" => json!({ "result": [basic_model().name()], "error": null }),
+            "findNotes" => json!({ "result": [], "error": null }),
+            "addNote" if request["params"]["note"]["fields"]["Front"] == "die Katze" => {
+                json!({ "result": null, "error": "cannot create note because it is a duplicate" })
+            }
+            "addNote" => json!({ "result": 7, "error": null }),
+            _ => json!({ "result": null, "error": null }),
+        });
+        match Client::with_url(&anki.url).push_deck(&german_deck()) {
+            Err(Error::AnkiConnectNote { index, message }) => {
+                assert_eq!(index, 1);
+                assert!(message.contains("duplicate"), "{}", message);
+            }
+            other => panic!("expected an error of the second note, got {:?}", other),
+        }
+
+        let anki =
+            MockAnki::start(|_, _| json!({ "result": null, "error": "model was not found" }));
+        match Client::with_url(&anki.url).push_model(&basic_model()) {
+            Err(Error::AnkiConnect { action, message }) => {
+                assert_eq!(
+                    (action.as_str(), message.as_str()),
+                    ("modelNames", "model was not found")
+                );
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use serde_json::{Map, Value};
+
+/// The column the card browser of Anki is sorted by, see [`CollectionConf::sort_type`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum SortType {
+    /// The sort field of the note, see [`Model::sort_field_index`](crate::Model::sort_field_index)
+    SortField,
+    /// The time the note was created
+    NoteCreated,
+    /// The time the note was last modified
+    NoteModified,
+    /// The time the card was last modified
+    CardModified,
+    /// The due date of the card
+    Due,
+    /// The interval of the card
+    Interval,
+    /// The ease factor of the card
+    Ease,
+    /// The number of reviews of the card
+    Reviews,
+    /// The number of times the card was forgotten
+    Lapses,
+}
+
+impl SortType {
+    fn key(self) -> &'static str {
+        match self {
+            SortType::SortField => "noteFld",
+            SortType::NoteCreated => "noteCrt",
+            SortType::NoteModified => "noteMod",
+            SortType::CardModified => "cardMod",
+            SortType::Due => "cardDue",
+            SortType::Interval => "cardIvl",
+            SortType::Ease => "cardEase",
+            SortType::Reviews => "cardReps",
+            SortType::Lapses => "cardLapses",
+        }
+    }
+}
+
+/// When new cards are shown in relation to reviews, see [`CollectionConf::new_spread`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum NewSpread {
+    /// Mixed with the reviews
+    Distribute,
+    /// After the reviews
+    Last,
+    /// Before the reviews
+    First,
+}
+
+/// Options of the collection, which Anki stores in the `conf` column of the collection, see
+/// [`Package::collection_conf`](crate::Package::collection_conf)
+///
+/// A `CollectionConf` can be created using the builder pattern. Options that aren't set keep the
+/// values the package has without a `CollectionConf`.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::{CollectionConf, Deck, Package, SortType};
+///
+/// # fn main() -> Result<(), genanki_rs::Error> {
+/// let deck = Deck::new(1234, "Example deck", "");
+/// let conf = CollectionConf::new()
+///     .sort_type(SortType::SortField)
+///     .sort_backwards(true)
+///     .cur_deck(1234);
+/// let package = Package::new(vec![deck], vec![])?.collection_conf(conf);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CollectionConf {
+    sort_type: Option<SortType>,
+    sort_backwards: Option<bool>,
+    cur_deck: Option<i64>,
+    active_decks: Option<Vec<i64>>,
+    day_learn_first: Option<bool>,
+    new_spread: Option<NewSpread>,
+    collapse_time: Option<u32>,
+    est_times: Option<bool>,
+    due_counts: Option<bool>,
+}
+
+impl CollectionConf {
+    /// Creates new options that don't change any value
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the column the card browser is sorted by, default is `SortType::SortField`
+    pub fn sort_type(mut self, value: SortType) -> Self {
+        self.sort_type = Some(value);
+        self
+    }
+
+    /// Sets whether the card browser is sorted in descending order, default is `false`
+    pub fn sort_backwards(mut self, value: bool) -> Self {
+        self.sort_backwards = Some(value);
+        self
+    }
+
+    /// Sets the id of the deck that is selected, default is `1`, the default deck
+    pub fn cur_deck(mut self, deck_id: i64) -> Self {
+        self.cur_deck = Some(deck_id);
+        self
+    }
+
+    /// Sets the ids of the decks that cards are studied from, default is `[1]`
+    pub fn active_decks(mut self, deck_ids: &[i64]) -> Self {
+        self.active_decks = Some(deck_ids.to_vec());
+        self
+    }
+
+    /// Sets whether learning cards that are due on a later day are shown before reviews, default
+    /// is `false`
+    pub fn day_learn_first(mut self, value: bool) -> Self {
+        self.day_learn_first = Some(value);
+        self
+    }
+
+    /// Sets when new cards are shown in relation to reviews, default is `NewSpread::Distribute`
+    pub fn new_spread(mut self, value: NewSpread) -> Self {
+        self.new_spread = Some(value);
+        self
+    }
+
+    /// Sets how many seconds ahead learning cards are shown if there is nothing else to study,
+    /// default is `1200`
+    pub fn collapse_time(mut self, seconds: u32) -> Self {
+        self.collapse_time = Some(seconds);
+        self
+    }
+
+    /// Sets whether the answer buttons show the next intervals, default is `true`
+    pub fn est_times(mut self, value: bool) -> Self {
+        self.est_times = Some(value);
+        self
+    }
+
+    /// Sets whether the number of remaining cards is shown while studying, default is `true`
+    pub fn due_counts(mut self, value: bool) -> Self {
+        self.due_counts = Some(value);
+        self
+    }
+
+    /// The ids of the decks the options refer to
+    pub(crate) fn deck_ids(&self) -> impl Iterator<Item = i64> + '_ {
+        self.cur_deck
+            .iter()
+            .chain(self.active_decks.iter().flatten())
+            .copied()
+    }
+
+    /// Sets the options that were set in the JSON object `conf`
+    pub(crate) fn apply(&self, conf: &mut Map<String, Value>) {
+        let mut set = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                conf.insert(key.to_string(), value);
+            }
+        };
+        set("sortType", self.sort_type.map(|t| t.key().into()));
+        set("sortBackwards", self.sort_backwards.map(Value::from));
+        set("curDeck", self.cur_deck.map(Value::from));
+        set("activeDecks", self.active_decks.clone().map(Value::from));
+        set("dayLearnFirst", self.day_learn_first.map(Value::from));
+        let new_spread = self.new_spread.map(|spread| match spread {
+            NewSpread::Distribute => 0,
+            NewSpread::Last => 1,
+            NewSpread::First => 2,
+        });
+        set("newSpread", new_spread.map(Value::from));
+        set("collapseTime", self.collapse_time.map(Value::from));
+        set("estTimes", self.est_times.map(Value::from));
+        set("dueCounts", self.due_counts.map(Value::from));
+    }
+}
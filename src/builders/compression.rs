@@ -0,0 +1,74 @@
+/// How the files in a `.apkg` are compressed, see
+/// [`Package::compression`](crate::Package::compression)
+///
+/// `CompressionOptions` can be created using the builder pattern.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::{CompressionOptions, Package};
+///
+/// # fn main() -> Result<(), genanki_rs::Error> {
+/// let options = CompressionOptions::new()
+///     .database_level(9)
+///     .stored_extensions(&["mp3", "m4a", "jpg", "jpeg"]);
+/// let package = Package::new(vec![], vec!["sound.mp3"])?.compression(options);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The builder has these default values:
+/// * `database_level` - `None`, the default level of Deflate
+/// * `stored_extensions` - `["mp3", "ogg", "jpg", "png", "mp4"]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompressionOptions {
+    database_level: Option<i32>,
+    stored_extensions: Vec<String>,
+}
+
+impl CompressionOptions {
+    /// Creates new options with the default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the Deflate level, from `0` to `9`, of the `collection.anki2` database
+    pub fn database_level(mut self, level: i32) -> Self {
+        self.database_level = Some(level);
+        self
+    }
+
+    /// Sets the extensions of media files that are stored without compression because they are
+    /// already compressed, compared case-insensitively
+    pub fn stored_extensions(mut self, extensions: &[&str]) -> Self {
+        self.stored_extensions = extensions.iter().map(|e| e.to_lowercase()).collect();
+        self
+    }
+
+    pub(crate) fn get_database_level(&self) -> Option<i32> {
+        self.database_level
+    }
+
+    /// Whether the media file `name` is stored without compression
+    pub(crate) fn is_stored(&self, name: &str) -> bool {
+        match name.rsplit_once('.') {
+            Some((_, extension)) => self
+                .stored_extensions
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(extension)),
+            None => false,
+        }
+    }
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            database_level: None,
+            stored_extensions: ["mp3", "ogg", "jpg", "png", "mp4"]
+                .iter()
+                .map(|e| e.to_string())
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+/// The character that separates the columns of a CSV file, see [`CsvOptions::delimiter`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delimiter {
+    Comma,
+    Tab,
+    Semicolon,
+}
+
+impl Delimiter {
+    pub(crate) fn byte(self) -> u8 {
+        match self {
+            Delimiter::Comma => b',',
+            Delimiter::Tab => b'\t',
+            Delimiter::Semicolon => b';',
+        }
+    }
+}
+
+/// How a CSV file is read by [`Deck::add_notes_from_csv`](crate::Deck::add_notes_from_csv)
+///
+/// `CsvOptions` can be created using the builder pattern.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::{CsvOptions, Delimiter};
+///
+/// let options = CsvOptions::new()
+///     .delimiter(Delimiter::Tab)
+///     .tags_column("Tags")
+///     .escape_html(true);
+/// ```
+///
+/// The builder has these default values:
+/// * `delimiter` - `Delimiter::Comma`
+/// * `has_header` - `true`
+/// * `tags_column` - `None`
+/// * `guid_column` - `None`
+/// * `escape_html` - `false`
+/// * `skip_invalid_rows` - `true`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvOptions {
+    delimiter: Delimiter,
+    has_header: bool,
+    tags_column: Option<String>,
+    guid_column: Option<String>,
+    escape_html: bool,
+    skip_invalid_rows: bool,
+}
+
+impl CsvOptions {
+    /// Creates new options with the default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the character that separates the columns
+    pub fn delimiter(mut self, delimiter: Delimiter) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets whether the first row contains the names of the fields of the columns
+    ///
+    /// Without a header, the columns are the fields in the order of the model, followed by the
+    /// tags if a [tags column](CsvOptions::tags_column) is set and the guid if a
+    /// [guid column](CsvOptions::guid_column) is set.
+    pub fn has_header(mut self, has_header: bool) -> Self {
+        self.has_header = has_header;
+        self
+    }
+
+    /// Sets the name of the column with the tags of the notes, separated by spaces
+    pub fn tags_column(mut self, name: impl ToString) -> Self {
+        self.tags_column = Some(name.to_string());
+        self
+    }
+
+    /// Sets the name of the column with the guids of the notes, e.g. to update the notes of a file
+    /// from [`Deck::write_notes_csv`](crate::Deck::write_notes_csv) on import
+    ///
+    /// Notes with an empty guid get the default guid derived from their fields.
+    pub fn guid_column(mut self, name: impl ToString) -> Self {
+        self.guid_column = Some(name.to_string());
+        self
+    }
+
+    /// Sets whether `&`, `<`, `>` and `"` in the values are escaped, so that they are shown as
+    /// text instead of being interpreted as HTML by Anki
+    pub fn escape_html(mut self, escape_html: bool) -> Self {
+        self.escape_html = escape_html;
+        self
+    }
+
+    /// Sets whether rows that can't be added as notes, e.g. because they have the wrong number of
+    /// columns, are skipped and reported instead of stopping the import with an error
+    pub fn skip_invalid_rows(mut self, skip_invalid_rows: bool) -> Self {
+        self.skip_invalid_rows = skip_invalid_rows;
+        self
+    }
+
+    pub(crate) fn get_delimiter(&self) -> Delimiter {
+        self.delimiter
+    }
+
+    pub(crate) fn get_has_header(&self) -> bool {
+        self.has_header
+    }
+
+    pub(crate) fn get_tags_column(&self) -> Option<&str> {
+        self.tags_column.as_deref()
+    }
+
+    pub(crate) fn get_guid_column(&self) -> Option<&str> {
+        self.guid_column.as_deref()
+    }
+
+    pub(crate) fn get_escape_html(&self) -> bool {
+        self.escape_html
+    }
+
+    pub(crate) fn get_skip_invalid_rows(&self) -> bool {
+        self.skip_invalid_rows
+    }
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: Delimiter::Comma,
+            has_header: true,
+            tags_column: None,
+            guid_column: None,
+            escape_html: false,
+            skip_invalid_rows: true,
+        }
+    }
+}
+
+/// What [`Deck::write_notes_csv`](crate::Deck::write_notes_csv) does with a deck whose notes use
+/// more than one model
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MixedModels {
+    /// Return `Error::MixedModels`
+    Error,
+    /// Write the notes of each model as a section with its own header, in the order the models
+    /// are first used, separated by empty lines
+    Sections,
+}
+
+/// How the notes of a deck are written by
+/// [`Deck::write_notes_csv`](crate::Deck::write_notes_csv)
+///
+/// `CsvExportOptions` can be created using the builder pattern.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::{CsvExportOptions, Delimiter};
+///
+/// let options = CsvExportOptions::new()
+///     .delimiter(Delimiter::Tab)
+///     .guid_column("GUID");
+/// ```
+///
+/// The builder has these default values:
+/// * `delimiter` - `Delimiter::Comma`
+/// * `tags_column` - `"Tags"`
+/// * `guid_column` - `None`
+/// * `mixed_models` - `MixedModels::Error`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvExportOptions {
+    delimiter: Delimiter,
+    tags_column: String,
+    guid_column: Option<String>,
+    mixed_models: MixedModels,
+}
+
+impl CsvExportOptions {
+    /// Creates new options with the default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the character that separates the columns
+    pub fn delimiter(mut self, delimiter: Delimiter) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets the name of the column with the tags of the notes, which is written after the fields
+    pub fn tags_column(mut self, name: impl ToString) -> Self {
+        self.tags_column = name.to_string();
+        self
+    }
+
+    /// Sets the name of a column with the guids of the notes, which is written after the tags,
+    /// so that importing the file again updates the notes instead of adding new ones
+    pub fn guid_column(mut self, name: impl ToString) -> Self {
+        self.guid_column = Some(name.to_string());
+        self
+    }
+
+    /// Sets what is done with the notes of a deck that uses more than one model
+    pub fn mixed_models(mut self, mixed_models: MixedModels) -> Self {
+        self.mixed_models = mixed_models;
+        self
+    }
+
+    pub(crate) fn get_delimiter(&self) -> Delimiter {
+        self.delimiter
+    }
+
+    pub(crate) fn get_tags_column(&self) -> &str {
+        &self.tags_column
+    }
+
+    pub(crate) fn get_guid_column(&self) -> Option<&str> {
+        self.guid_column.as_deref()
+    }
+
+    pub(crate) fn get_mixed_models(&self) -> MixedModels {
+        self.mixed_models
+    }
+}
+
+impl Default for CsvExportOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: Delimiter::Comma,
+            tags_column: "Tags".to_string(),
+            guid_column: None,
+            mixed_models: MixedModels::Error,
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::db_entries::{DeckConfDbEntry, LapseConfDbEntry, NewConfDbEntry, RevConfDbEntry};
+
+/// The order in which new cards of a deck are shown
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum NewCardOrder {
+    /// In the order the notes were added, see [`Note::due`](crate::Note::due)
+    Added,
+    /// In random order
+    Random,
+}
+
+/// Deck options group to be used by a `Deck`, see [`Deck::conf`](crate::Deck::conf)
+///
+/// A `DeckConf` can be created using the builder pattern. Decks with equal `DeckConf`s share
+/// one options group in Anki.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::{Deck, DeckConf};
+///
+/// let conf = DeckConf::new("Vocabulary")
+///     .new_per_day(30)
+///     .learning_steps(&[1.0, 10.0, 1440.0]);
+/// let deck = Deck::new(1234, "Example deck", "").conf(conf);
+/// ```
+///
+/// The builder has Anki's default values:
+/// * `new_per_day` - `20`
+/// * `reviews_per_day` - `100`
+/// * `learning_steps` - `[1.0, 10.0]`
+/// * `relearning_steps` - `[10.0]`
+/// * `new_card_order` - `NewCardOrder::Added`
+/// * `graduating_interval` - `1`
+/// * `easy_interval` - `4`
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeckConf {
+    name: String,
+    new_per_day: u32,
+    reviews_per_day: u32,
+    learning_steps: Vec<f32>,
+    relearning_steps: Vec<f32>,
+    new_card_order: NewCardOrder,
+    graduating_interval: u32,
+    easy_interval: u32,
+}
+
+impl DeckConf {
+    /// Creates a new options group with a `name`, which Anki shows in the deck options
+    pub fn new(name: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            new_per_day: 20,
+            reviews_per_day: 100,
+            learning_steps: vec![1.0, 10.0],
+            relearning_steps: vec![10.0],
+            new_card_order: NewCardOrder::Added,
+            graduating_interval: 1,
+            easy_interval: 4,
+        }
+    }
+
+    /// Sets the maximum number of new cards per day
+    pub fn new_per_day(mut self, value: u32) -> Self {
+        self.new_per_day = value;
+        self
+    }
+
+    /// Sets the maximum number of reviews per day
+    pub fn reviews_per_day(mut self, value: u32) -> Self {
+        self.reviews_per_day = value;
+        self
+    }
+
+    /// Sets the learning steps of new cards in minutes
+    pub fn learning_steps(mut self, minutes: &[f32]) -> Self {
+        self.learning_steps = minutes.to_vec();
+        self
+    }
+
+    /// Sets the relearning steps of forgotten cards in minutes
+    pub fn relearning_steps(mut self, minutes: &[f32]) -> Self {
+        self.relearning_steps = minutes.to_vec();
+        self
+    }
+
+    /// Sets the order in which new cards are shown
+    pub fn new_card_order(mut self, value: NewCardOrder) -> Self {
+        self.new_card_order = value;
+        self
+    }
+
+    /// Sets the interval in days after a new card graduates from its learning steps
+    pub fn graduating_interval(mut self, days: u32) -> Self {
+        self.graduating_interval = days;
+        self
+    }
+
+    /// Sets the interval in days after a new card is answered with "Easy"
+    pub fn easy_interval(mut self, days: u32) -> Self {
+        self.easy_interval = days;
+        self
+    }
+
+    /// The options group as Anki stores it, with the options that can't be set at Anki's
+    /// default values
+    pub(crate) fn to_db_entry(&self, id: i64) -> DeckConfDbEntry {
+        DeckConfDbEntry {
+            autoplay: true,
+            deck_conf_db_entry_dyn: false,
+            id,
+            lapse: LapseConfDbEntry {
+                delays: self.relearning_steps.clone(),
+                leech_action: 0,
+                leech_fails: 8,
+                min_int: 1,
+                mult: 0.0,
+            },
+            max_taken: 60,
+            deck_conf_db_entry_mod: 0,
+            name: self.name.clone(),
+            new: NewConfDbEntry {
+                bury: true,
+                delays: self.learning_steps.clone(),
+                initial_factor: 2500,
+                ints: vec![self.graduating_interval, self.easy_interval, 7],
+                order: match self.new_card_order {
+                    NewCardOrder::Random => 0,
+                    NewCardOrder::Added => 1,
+                },
+                per_day: self.new_per_day,
+                separate: true,
+            },
+            replayq: true,
+            rev: RevConfDbEntry {
+                bury: true,
+                ease4: 1.3,
+                fuzz: 0.05,
+                ivl_fct: 1.0,
+                max_ivl: 36500,
+                min_space: 1,
+                per_day: self.reviews_per_day,
+            },
+            timer: 0,
+            usn: 0,
+        }
+    }
+}
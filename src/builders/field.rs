@@ -18,29 +18,67 @@ use crate::db_entries::Fld;
 /// * `rtl` - `false`
 /// * `font` - `Liberation Sans`
 /// * `size` - `20`
-#[derive(Clone)]
+/// * `description` - empty
+/// * `plain_text` - `false`
+/// * `collapsed` - `false`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Field {
     name: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     sticky: Option<bool>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     rtl: Option<bool>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     font: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     size: Option<i64>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    description: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    plain_text: Option<bool>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    collapsed: Option<bool>,
 }
 
 impl Field {
     /// Creates a new field with a `name`
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: impl ToString) -> Self {
         Self {
             name: name.to_string(),
             sticky: None,
             rtl: None,
             font: None,
             size: None,
+            description: None,
+            plain_text: None,
+            collapsed: None,
         }
     }
 
     /// Sets the font of the `Field` which is currently created
-    pub fn font(mut self, value: &str) -> Self {
+    pub fn font(mut self, value: impl ToString) -> Self {
         self.font = Some(value.to_string());
         self
     }
@@ -62,18 +100,55 @@ impl Field {
         self.size = Some(value);
         self
     }
+
+    /// Sets the description Anki shows in the editor while the currently created `Field` is
+    /// empty
+    pub fn description(mut self, value: impl ToString) -> Self {
+        self.description = Some(value.to_string());
+        self
+    }
+
+    /// Sets whether the currently created `Field` is edited as plain text instead of HTML
+    pub fn plain_text(mut self, value: bool) -> Self {
+        self.plain_text = Some(value);
+        self
+    }
+
+    /// Sets whether the currently created `Field` is collapsed in the editor by default
+    pub fn collapsed(mut self, value: bool) -> Self {
+        self.collapsed = Some(value);
+        self
+    }
 }
 
-impl Into<Fld> for Field {
-    fn into(self) -> Fld {
+impl From<Field> for Fld {
+    fn from(field: Field) -> Self {
         Fld {
-            name: self.name.to_string(),
+            name: field.name,
             media: vec![],
-            sticky: self.sticky.unwrap_or(false),
-            rtl: self.rtl.unwrap_or(false),
+            sticky: field.sticky.unwrap_or(false),
+            rtl: field.rtl.unwrap_or(false),
             ord: 0,
-            font: self.font.unwrap_or("Liberation Sans".to_string()),
-            size: self.size.unwrap_or(20),
+            font: field.font.unwrap_or_else(|| "Liberation Sans".to_string()),
+            size: field.size.unwrap_or(20),
+            description: field.description.unwrap_or_default(),
+            plain_text: field.plain_text.unwrap_or(false),
+            collapsed: field.collapsed.unwrap_or(false),
+        }
+    }
+}
+
+impl From<Fld> for Field {
+    fn from(fld: Fld) -> Self {
+        Field {
+            name: fld.name,
+            sticky: Some(fld.sticky),
+            rtl: Some(fld.rtl),
+            font: Some(fld.font),
+            size: Some(fld.size),
+            description: Some(fld.description),
+            plain_text: Some(fld.plain_text),
+            collapsed: Some(fld.collapsed),
         }
     }
 }
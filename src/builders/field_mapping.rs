@@ -0,0 +1,139 @@
+use crate::{Error, Model};
+
+/// A field of a model, by name or by index
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum FieldRef {
+    Name(String),
+    Index(usize),
+}
+
+impl FieldRef {
+    /// Returns the index of the field in `model`, or `Error::UnknownNoteField` or
+    /// `Error::IndexOutOfRange` if the model has no such field
+    fn resolve(&self, model: &Model) -> Result<usize, Error> {
+        let names = model.field_names();
+        match self {
+            FieldRef::Name(name) => names.iter().position(|field| field == name).ok_or_else(|| {
+                Error::UnknownNoteField {
+                    key: name.clone(),
+                    known_fields: names.iter().map(|name| name.to_string()).collect(),
+                }
+            }),
+            FieldRef::Index(index) if *index < names.len() => Ok(*index),
+            FieldRef::Index(index) => Err(Error::IndexOutOfRange {
+                index: *index,
+                len: names.len(),
+            }),
+        }
+    }
+}
+
+/// Which fields of a note become which fields of another model, see
+/// [`Note::remap_model`](crate::Note::remap_model)
+///
+/// Like "Change Note Type" in Anki, a field of the old model can fill several fields of the new
+/// model. New fields without an old field are empty, and old fields that don't fill any new field
+/// are dropped, unless an overflow field is set that collects them. If a new field is mapped to
+/// twice, the last mapping counts.
+///
+/// `FieldMapping` can be created using the builder pattern.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::FieldMapping;
+///
+/// // "Word" stays "Word", "Meaning" is renamed to "Definition"
+/// let mapping = FieldMapping::by_name()
+///     .map("Meaning", "Definition")
+///     .overflow("Notes");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FieldMapping {
+    by_name: bool,
+    mappings: Vec<(FieldRef, FieldRef)>,
+    overflow: Option<FieldRef>,
+}
+
+impl FieldMapping {
+    /// Creates a mapping without any fields, which empties all fields
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a mapping of every old field to the new field with the same name, if there is one
+    ///
+    /// Fields mapped with [`FieldMapping::map`] or [`FieldMapping::map_index`] take precedence.
+    pub fn by_name() -> Self {
+        Self {
+            by_name: true,
+            ..Self::default()
+        }
+    }
+
+    /// Fills the new field called `new_name` with the old field called `old_name`
+    pub fn map(mut self, old_name: impl ToString, new_name: impl ToString) -> Self {
+        let old = FieldRef::Name(old_name.to_string());
+        self.mappings
+            .push((old, FieldRef::Name(new_name.to_string())));
+        self
+    }
+
+    /// Fills the new field at `new_index` with the old field at `old_index`
+    pub fn map_index(mut self, old_index: usize, new_index: usize) -> Self {
+        let old = FieldRef::Index(old_index);
+        self.mappings.push((old, FieldRef::Index(new_index)));
+        self
+    }
+
+    /// Appends the old fields that would be dropped to the new field called `new_name`, separated
+    /// by `<br>`, so their content isn't lost
+    pub fn overflow(mut self, new_name: impl ToString) -> Self {
+        self.overflow = Some(FieldRef::Name(new_name.to_string()));
+        self
+    }
+
+    /// Returns the fields of a note of `new_model` for the `fields` of a note of `old_model`
+    ///
+    /// Returns `Error::UnknownNoteField` or `Error::IndexOutOfRange` if a field of the mapping
+    /// isn't a field of its model.
+    pub(crate) fn apply(
+        &self,
+        old_model: &Model,
+        new_model: &Model,
+        fields: &[String],
+    ) -> Result<Vec<String>, Error> {
+        let mut sources: Vec<Option<usize>> = vec![None; new_model.field_names().len()];
+        if self.by_name {
+            let old_names = old_model.field_names();
+            for (new_index, new_name) in new_model.field_names().iter().enumerate() {
+                sources[new_index] = old_names.iter().position(|old| old == new_name);
+            }
+        }
+        for (old, new) in &self.mappings {
+            sources[new.resolve(new_model)?] = Some(old.resolve(old_model)?);
+        }
+        let mut new_fields: Vec<String> = sources
+            .iter()
+            .map(|source| {
+                source
+                    .map(|index| fields[index].clone())
+                    .unwrap_or_default()
+            })
+            .collect();
+        if let Some(overflow) = &self.overflow {
+            let overflow = &mut new_fields[overflow.resolve(new_model)?];
+            let dropped = (0..fields.len())
+                .filter(|index| !sources.contains(&Some(*index)))
+                .map(|index| &fields[index])
+                .filter(|field| !field.is_empty());
+            for field in dropped {
+                if !overflow.is_empty() {
+                    overflow.push_str("<br>");
+                }
+                overflow.push_str(field);
+            }
+        }
+        Ok(new_fields)
+    }
+}
@@ -1,5 +1,23 @@
+mod collection_conf;
+mod compression;
+#[cfg(feature = "csv")]
+mod csv_options;
+mod deck_conf;
 mod field;
+mod field_mapping;
+mod model;
+#[cfg(feature = "sanitize")]
+mod sanitize_policy;
 mod template;
 
+pub use collection_conf::{CollectionConf, NewSpread, SortType};
+pub use compression::CompressionOptions;
+#[cfg(feature = "csv")]
+pub use csv_options::{CsvExportOptions, CsvOptions, Delimiter, MixedModels};
+pub use deck_conf::{DeckConf, NewCardOrder};
 pub use field::Field;
+pub use field_mapping::FieldMapping;
+pub use model::ModelBuilder;
+#[cfg(feature = "sanitize")]
+pub use sanitize_policy::SanitizePolicy;
 pub use template::Template;
@@ -0,0 +1,104 @@
+use crate::{Error, Field, Model, ModelType, Template};
+
+/// Builder for a `Model`, created with [`Model::builder`].
+///
+/// Unlike the setters on `Model`, [`ModelBuilder::build`] checks the finished model, so a
+/// mistake in a template shows up where the model is defined instead of when a deck is written.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::{Field, Model, Template};
+///
+/// # fn main() -> Result<(), genanki_rs::Error> {
+/// let model = Model::builder(1607392319, "Simple Model")
+///     .field(Field::new("Question"))
+///     .field(Field::new("Answer"))
+///     .template(
+///         Template::new("Card 1")
+///             .qfmt("{{Question}}")
+///             .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#),
+///     )
+///     .css(".card { font-family: arial; }")
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ModelBuilder {
+    model: Model,
+}
+
+impl ModelBuilder {
+    pub(crate) fn new(id: i64, name: impl ToString) -> Self {
+        Self {
+            model: Model::new(id, name, vec![], vec![]),
+        }
+    }
+
+    /// Adds a field to the model
+    pub fn field(self, field: Field) -> Self {
+        Self {
+            model: self.model.with_field(field),
+        }
+    }
+
+    /// Adds a template to the model
+    pub fn template(self, template: Template) -> Self {
+        Self {
+            model: self.model.with_template(template),
+        }
+    }
+
+    /// Sets the custom CSS of the model
+    pub fn css(self, css: impl ToString) -> Self {
+        Self {
+            model: self.model.css(css),
+        }
+    }
+
+    /// Sets the type of the model, default is `FrontBack`
+    pub fn model_type(self, model_type: ModelType) -> Self {
+        Self {
+            model: self.model.model_type(model_type),
+        }
+    }
+
+    /// Sets the latex declarations at the beginning of a card
+    pub fn latex_pre(self, latex_pre: impl ToString) -> Self {
+        Self {
+            model: self.model.latex_pre(latex_pre),
+        }
+    }
+
+    /// Sets the latex declarations at the end of a card
+    pub fn latex_post(self, latex_post: impl ToString) -> Self {
+        Self {
+            model: self.model.latex_post(latex_post),
+        }
+    }
+
+    /// Sets whether Anki renders the model's LaTeX to SVG, default is `false`
+    pub fn latex_svg(self, latex_svg: bool) -> Self {
+        Self {
+            model: self.model.latex_svg(latex_svg),
+        }
+    }
+
+    /// Sets the index of the field used for sorting, default is `0`
+    pub fn sort_field_index(self, sort_field_index: usize) -> Self {
+        Self {
+            model: self.model.sort_field(sort_field_index),
+        }
+    }
+
+    /// Finishes the model
+    ///
+    /// Returns `Err` if the model does not pass [`Model::validate`] or
+    /// [`Model::validate_sort_field_index`]
+    pub fn build(self) -> Result<Model, Error> {
+        self.model.validate()?;
+        self.model.validate_sort_field_index()?;
+        Ok(self.model)
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeSet;
+
+/// Which HTML [`Note::sanitize_html`](crate::Note::sanitize_html) keeps in the fields of a note
+///
+/// Everything that isn't allowed is removed: other tags, all `on...` attributes and URLs in `src`
+/// with other schemes, like `javascript:`. Relative URLs, i.e. the media files of the package, are
+/// always allowed. `<script>`, `<style>` and `<iframe>` are removed with their content and can't
+/// be allowed.
+///
+/// `SanitizePolicy` can be created using the builder pattern.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::SanitizePolicy;
+///
+/// let policy = SanitizePolicy::new()
+///     .allow_tags(["table", "tr", "td"])
+///     .allow_style_properties(["border"]);
+/// ```
+///
+/// The builder allows these by default:
+/// * tags - `b`, `i`, `u`, `s`, `em`, `strong`, `sub`, `sup`, `br`, `hr`, `div`, `span`, `p`,
+///   `ul`, `ol`, `li` and `img`, with the attributes `class`, `title` and `lang`, `style` on all
+///   but `img`, and `src` and `alt` on `img`; allowed `a` tags keep their `href`
+/// * style properties - `color`, `background-color`, `font-weight`, `font-style`, `font-size`,
+///   `text-decoration` and `text-align`
+/// * URL schemes - `http` and `https`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SanitizePolicy {
+    tags: BTreeSet<String>,
+    style_properties: BTreeSet<String>,
+    url_schemes: BTreeSet<String>,
+}
+
+impl SanitizePolicy {
+    /// Creates a new policy with the default allowlists
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `tags` in addition to the allowed tags
+    pub fn allow_tags(mut self, tags: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.tags.extend(tags.into_iter().map(|t| t.to_string()));
+        self
+    }
+
+    /// Allows the CSS `properties` in `style` attributes in addition to the allowed properties
+    pub fn allow_style_properties(
+        mut self,
+        properties: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        self.style_properties
+            .extend(properties.into_iter().map(|p| p.to_string()));
+        self
+    }
+
+    /// Allows URLs with the `schemes`, e.g. `data`, in addition to the allowed schemes
+    pub fn allow_url_schemes(mut self, schemes: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.url_schemes
+            .extend(schemes.into_iter().map(|s| s.to_string()));
+        self
+    }
+
+    pub(crate) fn get_tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+
+    pub(crate) fn get_style_properties(&self) -> &BTreeSet<String> {
+        &self.style_properties
+    }
+
+    pub(crate) fn get_url_schemes(&self) -> &BTreeSet<String> {
+        &self.url_schemes
+    }
+}
+
+impl Default for SanitizePolicy {
+    fn default() -> Self {
+        let tags = [
+            "b", "i", "u", "s", "em", "strong", "sub", "sup", "br", "hr", "div", "span", "p", "ul",
+            "ol", "li", "img",
+        ];
+        let style_properties = [
+            "color",
+            "background-color",
+            "font-weight",
+            "font-style",
+            "font-size",
+            "text-decoration",
+            "text-align",
+        ];
+        Self {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            style_properties: style_properties.iter().map(|p| p.to_string()).collect(),
+            url_schemes: ["http", "https"].iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
@@ -12,19 +12,54 @@ use crate::db_entries::Tmpl;
 /// let template2 = Template::new("Card 2").qfmt("{{Back}}").afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}");
 /// ```
 ///
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Template {
     name: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     qfmt: Option<String>,
-    did: Option<usize>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "deck_override",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
+    did: Option<i64>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     bafmt: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     afmt: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     bqfmt: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    browser_font: Option<(String, u32)>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    ord: Option<i64>,
 }
 
 impl Template {
     /// Creates a new `Template` with a `name`
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: impl ToString) -> Self {
         Self {
             name: name.to_string(),
             qfmt: None,
@@ -32,50 +67,112 @@ impl Template {
             bafmt: None,
             afmt: None,
             bqfmt: None,
+            browser_font: None,
+            ord: None,
         }
     }
 
     /// Sets the question format of the currently created `Template`
-    pub fn qfmt(mut self, qfmt: &str) -> Self {
+    pub fn qfmt(mut self, qfmt: impl ToString) -> Self {
         self.qfmt = Some(qfmt.to_string());
         self
     }
 
-    /// Sets the deck id of the currently created `Template`
+    /// Sets the deck id of the currently created `Template`, same as `deck_override`
     pub fn did(mut self, did: usize) -> Self {
-        self.did = Some(did);
+        self.did = Some(did as i64);
+        self
+    }
+
+    /// Puts cards generated from the currently created `Template` into the deck with id
+    /// `deck_id` instead of the deck their note is added to.
+    ///
+    /// The deck must be part of the same `Package`, which is checked when the notes are written.
+    pub fn deck_override(mut self, deck_id: i64) -> Self {
+        self.did = Some(deck_id);
         self
     }
 
     /// Sets the browser answer format of the currently created `Template`
-    pub fn bafmt(mut self, bafmt: &str) -> Self {
+    pub fn bafmt(mut self, bafmt: impl ToString) -> Self {
         self.bafmt = Some(bafmt.to_string());
         self
     }
 
     /// Sets the answer format of the currently created `Template`
-    pub fn afmt(mut self, afmt: &str) -> Self {
+    pub fn afmt(mut self, afmt: impl ToString) -> Self {
         self.afmt = Some(afmt.to_string());
         self
     }
 
     /// Sets the browser question format of the currently created template
-    pub fn bqfmt(mut self, bqfmt: &str) -> Self {
+    pub fn bqfmt(mut self, bqfmt: impl ToString) -> Self {
         self.bqfmt = Some(bqfmt.to_string());
         self
     }
+
+    /// Sets the font and font size the card browser uses for the currently created template
+    pub fn browser_font(mut self, name: impl ToString, size: u32) -> Self {
+        self.browser_font = Some((name.to_string(), size));
+        self
+    }
+
+    /// Sets the `ord` of the currently created template, which the cards generated from it are
+    /// stored with.
+    ///
+    /// Without an `ord`, a template gets the lowest ord no other template of the model has, so
+    /// inserting a template before others shifts their ords. Anki matches the cards of an
+    /// imported note by their ord, so shifted ords reset the scheduling of the cards of everyone
+    /// who imports the new version of a deck. To be able to add templates anywhere later, set
+    /// the ords of all templates before a deck is published for the first time, and give new
+    /// templates the next free ord.
+    ///
+    /// The ords of a model have to be `0` up to the number of templates minus one, each used
+    /// once, which [`Model::validate`](crate::Model::validate) checks.
+    pub fn ord(mut self, ord: i64) -> Self {
+        self.ord = Some(ord);
+        self
+    }
 }
 
-impl Into<Tmpl> for Template {
-    fn into(self) -> Tmpl {
+impl From<Template> for Tmpl {
+    fn from(template: Template) -> Self {
+        let (bfont, bsize) = template.browser_font.unwrap_or_default();
         Tmpl {
-            name: self.name,
-            qfmt: self.qfmt.unwrap_or("".to_string()),
-            did: self.did,
-            bafmt: self.bafmt.unwrap_or("".to_string()),
-            afmt: self.afmt.unwrap_or("".to_string()),
-            ord: 0,
-            bqfmt: self.bqfmt.unwrap_or("".to_string()),
+            name: template.name,
+            qfmt: template.qfmt.unwrap_or_default(),
+            did: template.did,
+            bafmt: template.bafmt.unwrap_or_default(),
+            afmt: template.afmt.unwrap_or_default(),
+            ord: template.ord.unwrap_or_default(),
+            sticky_ord: template.ord.is_some(),
+            bqfmt: template.bqfmt.unwrap_or_default(),
+            bfont,
+            bsize: bsize as i64,
+        }
+    }
+}
+
+impl From<Tmpl> for Template {
+    fn from(tmpl: Tmpl) -> Self {
+        let browser_font = if tmpl.bfont.is_empty() && tmpl.bsize == 0 {
+            None
+        } else {
+            Some((tmpl.bfont, tmpl.bsize as u32))
+        };
+        Template {
+            name: tmpl.name,
+            qfmt: Some(tmpl.qfmt),
+            did: tmpl.did,
+            bafmt: Some(tmpl.bafmt),
+            afmt: Some(tmpl.afmt),
+            bqfmt: Some(tmpl.bqfmt),
+            browser_font,
+            ord: if tmpl.sticky_ord {
+                Some(tmpl.ord)
+            } else {
+                None
+            },
         }
     }
 }
@@ -11,27 +11,23 @@ use crate::model::ModelType;
 /// is equivalent to
 /// ```rust
 /// use genanki_rs::{Model, Field, Template};
-/// let my_model = Model::new_with_options(
-///         1559383000,
-///         "Basic (genanki)",
-///         vec![
-///             Field::new("Front").font("Arial"),
-///             Field::new("Back").font("Arial"),
-///         ],
-///         vec![Template::new("Card 1")
-///             .qfmt("{{Front}}")
-///             .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}")],
-///         Some(
-///             ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
-///         ),
-///         None,
-///         None,
-///         None,
-///         None,
-///     );
+/// let my_model = Model::new(
+///     1559383000,
+///     "Basic (genanki)",
+///     vec![
+///         Field::new("Front").font("Arial"),
+///         Field::new("Back").font("Arial"),
+///     ],
+///     vec![Template::new("Card 1")
+///         .qfmt("{{Front}}")
+///         .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}")],
+/// )
+/// .css(
+///     ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
+/// );
 /// ```
 pub fn basic_model() -> Model {
-    Model::new_with_options(
+    Model::new(
         1559383000,
         "Basic (genanki)",
         vec![
@@ -41,13 +37,9 @@ pub fn basic_model() -> Model {
         vec![Template::new("Card 1")
             .qfmt("{{Front}}")
             .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}")],
-        Some(
-            ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
-        ),
-        None,
-        None,
-        None,
-        None,
+    )
+    .css(
+        ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
     )
 }
 
@@ -61,32 +53,28 @@ pub fn basic_model() -> Model {
 /// is equivalent to
 /// ```rust
 /// use genanki_rs::{Model, Field, Template};
-/// let my_model = Model::new_with_options(
-///         1485830179,
-///         "Basic (and reversed card) (genanki)",
-///         vec![
-///             Field::new("Front").font("Arial"),
-///             Field::new("Back").font("Arial"),
-///         ],
-///         vec![
-///             Template::new("Card 1")
-///                 .qfmt("{{Front}}")
-///                 .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"),
-///             Template::new("Card 2")
-///                 .qfmt("{{Back}}")
-///                 .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}"),
-///         ],
-///         Some(
-///             ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
-///         ),
-///         None,
-///         None,
-///         None,
-///         None,
-///     );
+/// let my_model = Model::new(
+///     1485830179,
+///     "Basic (and reversed card) (genanki)",
+///     vec![
+///         Field::new("Front").font("Arial"),
+///         Field::new("Back").font("Arial"),
+///     ],
+///     vec![
+///         Template::new("Card 1")
+///             .qfmt("{{Front}}")
+///             .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"),
+///         Template::new("Card 2")
+///             .qfmt("{{Back}}")
+///             .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}"),
+///     ],
+/// )
+/// .css(
+///     ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
+/// );
 /// ```
 pub fn basic_and_reversed_card_model() -> Model {
-    Model::new_with_options(
+    Model::new(
         1485830179,
         "Basic (and reversed card) (genanki)",
         vec![
@@ -101,13 +89,9 @@ pub fn basic_and_reversed_card_model() -> Model {
                 .qfmt("{{Back}}")
                 .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}"),
         ],
-        Some(
-            ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
-        ),
-        None,
-        None,
-        None,
-        None,
+    )
+    .css(
+        ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
     )
 }
 
@@ -121,33 +105,29 @@ pub fn basic_and_reversed_card_model() -> Model {
 /// is equivalent to
 /// ```rust
 /// use genanki_rs::{Model, Field, Template};
-/// let my_model = Model::new_with_options(
-///         1382232460,
-///         "Basic (optional reversed card) (genanki)",
-///         vec![
-///             Field::new("Front").font("Arial"),
-///             Field::new("Back").font("Arial"),
-///             Field::new("Add Reverse").font("Arial"),
-///         ],
-///         vec![
-///             Template::new("Card 1")
-///                 .qfmt("{{Front}}")
-///                 .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"),
-///             Template::new("Card 2")
-///                 .qfmt("{{#Add Reverse}}{{Back}}{{/Add Reverse}}")
-///                 .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}"),
-///         ],
-///         Some(
-///             ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
-///         ),
-///         None,
-///         None,
-///         None,
-///         None,
-///     );
+/// let my_model = Model::new(
+///     1382232460,
+///     "Basic (optional reversed card) (genanki)",
+///     vec![
+///         Field::new("Front").font("Arial"),
+///         Field::new("Back").font("Arial"),
+///         Field::new("Add Reverse").font("Arial"),
+///     ],
+///     vec![
+///         Template::new("Card 1")
+///             .qfmt("{{Front}}")
+///             .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"),
+///         Template::new("Card 2")
+///             .qfmt("{{#Add Reverse}}{{Back}}{{/Add Reverse}}")
+///             .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}"),
+///     ],
+/// )
+/// .css(
+///     ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
+/// );
 /// ```
 pub fn basic_optional_reversed_card_model() -> Model {
-    Model::new_with_options(
+    Model::new(
         1382232460,
         "Basic (optional reversed card) (genanki)",
         vec![
@@ -163,13 +143,9 @@ pub fn basic_optional_reversed_card_model() -> Model {
                 .qfmt("{{#Add Reverse}}{{Back}}{{/Add Reverse}}")
                 .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}"),
         ],
-        Some(
-            ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
-        ),
-        None,
-        None,
-        None,
-        None,
+    )
+    .css(
+        ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
     )
 }
 
@@ -183,29 +159,25 @@ pub fn basic_optional_reversed_card_model() -> Model {
 /// is equivalent to
 /// ```rust
 /// use genanki_rs::{Model, Field, Template};
-/// let my_model = Model::new_with_options(
-///         1305534440,
-///         "Basic (type in the answer) (genanki)",
-///         vec![
-///             Field::new("Front").font("Arial"),
-///             Field::new("Back").font("Arial"),
-///         ],
-///         vec![
-///             Template::new("Card 1")
-///                 .qfmt("{{Front}}\n\n{{type:Back}}")
-///                 .afmt("{{Front}}\n\n<hr id=answer>\n\n{{type:Back}}"),
-///         ],
-///         Some(
-///             ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
-///         ),
-///         None,
-///         None,
-///         None,
-///         None,
-///     );
+/// let my_model = Model::new(
+///     1305534440,
+///     "Basic (type in the answer) (genanki)",
+///     vec![
+///         Field::new("Front").font("Arial"),
+///         Field::new("Back").font("Arial"),
+///     ],
+///     vec![
+///         Template::new("Card 1")
+///             .qfmt("{{Front}}\n\n{{type:Back}}")
+///             .afmt("{{Front}}\n\n<hr id=answer>\n\n{{type:Back}}"),
+///     ],
+/// )
+/// .css(
+///     ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
+/// );
 /// ```
 pub fn basic_type_in_the_answer_model() -> Model {
-    Model::new_with_options(
+    Model::new(
         1305534440,
         "Basic (type in the answer) (genanki)",
         vec![
@@ -217,18 +189,16 @@ pub fn basic_type_in_the_answer_model() -> Model {
                 .qfmt("{{Front}}\n\n{{type:Back}}")
                 .afmt("{{Front}}\n\n<hr id=answer>\n\n{{type:Back}}"),
         ],
-        Some(
-            ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
-        ),
-        None,
-        None,
-        None,
-        None,
+    )
+    .css(
+        ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n",
     )
 }
 
 /// Returns a basic `Model` for cards with clozes.
 ///
+/// The `Back Extra` field is shown below the cloze on the back of the card.
+///
 /// ```rust
 /// use genanki_rs::cloze_model;
 /// let my_model = cloze_model();
@@ -237,48 +207,44 @@ pub fn basic_type_in_the_answer_model() -> Model {
 /// is equivalent to
 /// ```rust
 /// use genanki_rs::{Model, Field, Template, ModelType};
-/// let my_model = Model::new_with_options(
-///         1122529321,
-///         "Cloze (genanki)",
-///         vec![
-///             Field::new("Text").font("Arial"),
-///         ],
-///         vec![
-///             Template::new("Cloze")
-///                 .qfmt("{{cloze:Text}}")
-///                 .afmt("{{cloze:Text}}"),
-///         ],
-///         Some(
-///             ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n\n \
-///              .cloze {\n font-weight: bold;\n color: blue;\n}\n.nightMode .cloze {\n color: lightblue;\n}",
-///         ),
-///         Some(ModelType::Cloze),
-///         None,
-///         None,
-///         None,
-///     );
+/// let my_model = Model::new(
+///     1550428389,
+///     "Cloze (genanki)",
+///     vec![
+///         Field::new("Text").font("Arial"),
+///         Field::new("Back Extra").font("Arial"),
+///     ],
+///     vec![
+///         Template::new("Cloze")
+///             .qfmt("{{cloze:Text}}")
+///             .afmt("{{cloze:Text}}<br>\n{{Back Extra}}"),
+///     ],
+/// )
+/// .css(
+///     ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n\n\
+///      .cloze {\n font-weight: bold;\n color: blue;\n}\n.nightMode .cloze {\n color: lightblue;\n}",
+/// )
+/// .model_type(ModelType::Cloze);
 /// ```
 pub fn cloze_model() -> Model {
-    Model::new_with_options(
-        1122529321,
+    Model::new(
+        1550428389,
         "Cloze (genanki)",
         vec![
             Field::new("Text").font("Arial"),
+            Field::new("Back Extra").font("Arial"),
         ],
         vec![
             Template::new("Cloze")
                 .qfmt("{{cloze:Text}}")
-                .afmt("{{cloze:Text}}"),
+                .afmt("{{cloze:Text}}<br>\n{{Back Extra}}"),
         ],
-        Some(
-            ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n\n \
-             .cloze {\n font-weight: bold;\n color: blue;\n}\n.nightMode .cloze {\n color: lightblue;\n}",
-        ),
-        Some(ModelType::Cloze),
-        None,
-        None,
-        None,
     )
+    .css(
+        ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n\n\
+         .cloze {\n font-weight: bold;\n color: blue;\n}\n.nightMode .cloze {\n color: lightblue;\n}",
+    )
+    .model_type(ModelType::Cloze)
 }
 
 #[cfg(test)]
@@ -314,7 +280,7 @@ mod tests {
         my_deck.add_note(
             Note::new(
                 cloze_model(),
-                vec!["{{c1::Rome}} is the capital of {{c2::Italy}}"],
+                vec!["{{c1::Rome}} is the capital of {{c2::Italy}}", ""],
             )
             .unwrap(),
         );
@@ -322,4 +288,141 @@ mod tests {
         let out_file = NamedTempFile::new().unwrap().into_temp_path();
         my_deck.write_to_file(out_file.to_str().unwrap()).unwrap();
     }
+
+    const GENANKI_CSS: &str = ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n";
+
+    fn model_json(model: Model) -> serde_json::Value {
+        serde_json::from_str(&model.to_json(0.0, 1).unwrap()).unwrap()
+    }
+
+    fn templates(json: &serde_json::Value) -> Vec<(String, String, String)> {
+        json["tmpls"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| {
+                (
+                    t["name"].as_str().unwrap().to_string(),
+                    t["qfmt"].as_str().unwrap().to_string(),
+                    t["afmt"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn field_names(json: &serde_json::Value) -> Vec<&str> {
+        json["flds"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| {
+                assert_eq!(f["font"], "Arial");
+                f["name"].as_str().unwrap()
+            })
+            .collect()
+    }
+
+    fn template(name: &str, qfmt: &str, afmt: &str) -> (String, String, String) {
+        (name.to_string(), qfmt.to_string(), afmt.to_string())
+    }
+
+    #[test]
+    fn builtin_models_match_genanki() {
+        let json = model_json(basic_model());
+        assert_eq!(json["id"], "1559383000");
+        assert_eq!(json["name"], "Basic (genanki)");
+        assert_eq!(json["css"], GENANKI_CSS);
+        assert_eq!(field_names(&json), vec!["Front", "Back"]);
+        assert_eq!(
+            templates(&json),
+            vec![template(
+                "Card 1",
+                "{{Front}}",
+                "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"
+            )]
+        );
+
+        let json = model_json(basic_and_reversed_card_model());
+        assert_eq!(json["id"], "1485830179");
+        assert_eq!(json["name"], "Basic (and reversed card) (genanki)");
+        assert_eq!(json["css"], GENANKI_CSS);
+        assert_eq!(field_names(&json), vec!["Front", "Back"]);
+        assert_eq!(
+            templates(&json),
+            vec![
+                template(
+                    "Card 1",
+                    "{{Front}}",
+                    "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"
+                ),
+                template(
+                    "Card 2",
+                    "{{Back}}",
+                    "{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}"
+                )
+            ]
+        );
+
+        let json = model_json(basic_optional_reversed_card_model());
+        assert_eq!(json["id"], "1382232460");
+        assert_eq!(json["name"], "Basic (optional reversed card) (genanki)");
+        assert_eq!(json["css"], GENANKI_CSS);
+        assert_eq!(field_names(&json), vec!["Front", "Back", "Add Reverse"]);
+        assert_eq!(
+            templates(&json),
+            vec![
+                template(
+                    "Card 1",
+                    "{{Front}}",
+                    "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"
+                ),
+                template(
+                    "Card 2",
+                    "{{#Add Reverse}}{{Back}}{{/Add Reverse}}",
+                    "{{FrontSide}}\n\n<hr id=answer>\n\n{{Front}}"
+                )
+            ]
+        );
+        assert_eq!(
+            json["req"],
+            serde_json::json!([[0, "all", [0]], [1, "all", [1, 2]]])
+        );
+
+        let json = model_json(basic_type_in_the_answer_model());
+        assert_eq!(json["id"], "1305534440");
+        assert_eq!(json["name"], "Basic (type in the answer) (genanki)");
+        assert_eq!(json["css"], GENANKI_CSS);
+        assert_eq!(field_names(&json), vec!["Front", "Back"]);
+        assert_eq!(
+            templates(&json),
+            vec![template(
+                "Card 1",
+                "{{Front}}\n\n{{type:Back}}",
+                "{{Front}}\n\n<hr id=answer>\n\n{{type:Back}}"
+            )]
+        );
+        // The type-in-the-answer box alone doesn't make the front non-empty
+        assert_eq!(json["req"], serde_json::json!([[0, "all", [0]]]));
+
+        let json = model_json(cloze_model());
+        assert_eq!(json["id"], "1550428389");
+        assert_eq!(json["name"], "Cloze (genanki)");
+        assert_eq!(json["type"], 1);
+        assert_eq!(
+            json["css"],
+            format!(
+                "{}\n.cloze {{\n font-weight: bold;\n color: blue;\n}}\n.nightMode .cloze {{\n color: lightblue;\n}}",
+                GENANKI_CSS
+            )
+        );
+        assert_eq!(field_names(&json), vec!["Text", "Back Extra"]);
+        assert_eq!(
+            templates(&json),
+            vec![template(
+                "Cloze",
+                "{{cloze:Text}}",
+                "{{cloze:Text}}<br>\n{{Back Extra}}"
+            )]
+        );
+    }
 }
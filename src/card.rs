@@ -1,55 +1,194 @@
-use rusqlite::{params, Transaction};
+use rusqlite::{params, Connection};
 use std::ops::RangeFrom;
 
+use crate::timestamp::Timestamp;
 use crate::{error::database_error, Error};
 
-#[derive(Clone)]
+/// The review state of a card that was already studied, see [`Card::set_schedule`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CardSchedule {
+    /// The current interval of the card in days
+    pub interval_days: u32,
+    /// The ease factor in permille, e.g. `2500` for Anki's default of 250%
+    pub ease_factor: u32,
+    /// In how many days the card is due, relative to the day the package is written. Negative
+    /// values make the card overdue.
+    pub due_days_from_today: i64,
+    /// How many times the card was reviewed
+    pub reps: u32,
+    /// How many times the card was forgotten
+    pub lapses: u32,
+}
+
+/// A card that a `Note` generates, see [`Note::cards`]
+///
+/// [`Note::cards`]: crate::Note::cards
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Card {
-    pub ord: i64,
-    pub suspend: bool,
+    pub(crate) ord: i64,
+    pub(crate) suspend: bool,
+    pub(crate) flag: u8,
+    pub(crate) schedule: Option<CardSchedule>,
+    pub(crate) deck_id: Option<i64>,
 }
 
 impl Card {
-    pub fn new(ord: i64, suspend: bool) -> Self {
-        Self { ord, suspend }
+    pub(crate) fn new(ord: i64, suspend: bool) -> Self {
+        Self {
+            ord,
+            suspend,
+            flag: 0,
+            schedule: None,
+            deck_id: None,
+        }
     }
-    #[allow(dead_code)]
+
+    /// Returns the ord of the card
+    ///
+    /// For a `FrontBack` model, this is the index of the template the card is generated from.
+    /// For a `Cloze` model, it is the number of the cloze deletion minus one, so `{{c1::...}}`
+    /// generates the card with ord `0`.
     pub fn ord(&self) -> i64 {
         self.ord
     }
-    pub fn write_to_db(
+
+    /// Returns whether the card is suspended when it is imported
+    pub fn suspended(&self) -> bool {
+        self.suspend
+    }
+
+    /// Sets whether the card is suspended when it is imported
+    ///
+    /// A suspended card is still a new card, so it is studied like one once it is unsuspended.
+    pub fn set_suspended(&mut self, suspended: bool) {
+        self.suspend = suspended;
+    }
+
+    /// Returns the id of the deck the card is written to instead of the deck of its note, see
+    /// [`Card::set_deck_id`]
+    pub fn deck_id(&self) -> Option<i64> {
+        self.deck_id
+    }
+
+    /// Writes the card to the deck with the id `deck_id` instead of the deck its note is added
+    /// to, or its template's deck override (see [`Template::deck_override`]). `None` writes it
+    /// to the deck of the note again.
+    ///
+    /// The deck has to be part of the same package, which is checked when the note is written.
+    ///
+    /// [`Template::deck_override`]: crate::Template::deck_override
+    pub fn set_deck_id(&mut self, deck_id: Option<i64>) {
+        self.deck_id = deck_id;
+    }
+
+    /// Returns the flag of the card, `0` if it has none
+    pub fn flag(&self) -> u8 {
+        self.flag
+    }
+
+    /// Sets the flag of the card, with Anki's numbers: `0` for no flag, then `1` red, `2`
+    /// orange, `3` green, `4` blue, `5` pink, `6` turquoise and `7` purple
+    ///
+    /// Returns `Error::InvalidCardFlag` if `flag` is larger than `7`.
+    pub fn set_flag(&mut self, flag: u8) -> Result<(), Error> {
+        if flag > 7 {
+            return Err(Error::InvalidCardFlag(flag));
+        }
+        self.flag = flag;
+        Ok(())
+    }
+
+    /// Returns the review state of the card, `None` for a new card
+    pub fn schedule(&self) -> Option<CardSchedule> {
+        self.schedule
+    }
+
+    /// Sets the review state of the card, or makes it a new card again with `None`
+    ///
+    /// A scheduled card is written as a review card. Returns `Error::InvalidEaseFactor` if the
+    /// ease factor is lower than `1300`. Cards in learning can't be created.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{CardSchedule, Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let schedule = CardSchedule {
+    ///     interval_days: 12,
+    ///     ease_factor: 2350,
+    ///     due_days_from_today: 3,
+    ///     reps: 5,
+    ///     lapses: 1,
+    /// };
+    /// let note = Note::new(basic_model(), vec!["der Hund", "the dog"])?
+    ///     .configure_cards(|card| card.set_schedule(Some(schedule)).expect("valid ease factor"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_schedule(&mut self, schedule: Option<CardSchedule>) -> Result<(), Error> {
+        if let Some(CardSchedule { ease_factor, .. }) = schedule {
+            if ease_factor < 1300 {
+                return Err(Error::InvalidEaseFactor(ease_factor));
+            }
+        }
+        self.schedule = schedule;
+        Ok(())
+    }
+
+    pub(crate) fn write_to_db(
         &self,
-        transaction: &Transaction,
-        timestamp: f64,
+        transaction: &Connection,
+        timestamp: Timestamp,
         deck_id: i64,
         note_id: usize,
+        position: i64,
         id_gen: &mut RangeFrom<usize>,
     ) -> Result<(), Error> {
-        let queue = if self.suspend { -1 } else { 0 };
+        // New cards are due by their position, review cards by the day since the collection
+        // was created, which Anki converts to its own collection on import
+        let (card_type, queue, due) = match &self.schedule {
+            None => (0, 0, position),
+            Some(schedule) => {
+                let created: i64 = transaction
+                    .query_row("SELECT crt FROM col", [], |row| row.get(0))
+                    .map_err(database_error)?;
+                let today = (timestamp.as_secs() - created).div_euclid(86400);
+                (2, 2, today + schedule.due_days_from_today)
+            }
+        };
+        let queue = if self.suspend { -1 } else { queue };
+        let schedule = self.schedule.unwrap_or(CardSchedule {
+            interval_days: 0,
+            ease_factor: 0,
+            due_days_from_today: 0,
+            reps: 0,
+            lapses: 0,
+        });
         transaction
-            .execute(
-                "INSERT INTO cards VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?);",
-                params![
-                    id_gen.next(),    // id
-                    note_id,          // nid
-                    deck_id,          // did
-                    self.ord,         // ord
-                    timestamp as i64, // mod
-                    -1,               // usn
-                    0,                // type (=0 for non-Cloze)
-                    queue,            // queue
-                    0,                // due
-                    0,                // ivl
-                    0,                // factor
-                    0,                // reps
-                    0,                // lapses
-                    0,                // left
-                    0,                // odue
-                    0,                // odid
-                    0,                // flags
-                    "",               // data
-                ],
-            )
+            .prepare_cached("INSERT INTO cards VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?);")
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    id_gen.next(),          // id
+                    note_id,                // nid
+                    deck_id,                // did
+                    self.ord,               // ord
+                    timestamp.as_secs(),    // mod
+                    -1,                     // usn
+                    card_type,              // type
+                    queue,                  // queue
+                    due,                    // due
+                    schedule.interval_days, // ivl
+                    schedule.ease_factor,   // factor
+                    schedule.reps,          // reps
+                    schedule.lapses,        // lapses
+                    0,                      // left
+                    0,                      // odue
+                    0,                      // odid
+                    self.flag,              // flags
+                    "",                     // data
+                ])
+            })
             .map_err(database_error)?;
         Ok(())
     }
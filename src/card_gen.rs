@@ -0,0 +1,273 @@
+use std::collections::BTreeSet;
+use std::sync::OnceLock;
+
+use crate::card::Card;
+use crate::model::{Model, ModelType};
+use crate::note::cloze_numbers;
+use crate::Error;
+
+/// Whether any or all of the required fields of a template have to be non-empty for a card
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Requirement {
+    Any,
+    All,
+}
+
+/// When a template of a `FrontBack` model generates a card, from the `req` of the model
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TemplatePlan {
+    pub(crate) ord: i64,
+    pub(crate) requirement: Requirement,
+    pub(crate) fields: Vec<usize>,
+}
+
+/// Which cards the notes of a model get, depending on their fields
+///
+/// It only depends on the model, so a model computes it once for all its notes, see
+/// [`Model::card_gen_plan`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CardGenPlan {
+    /// The requirements of each template, see [`Model::req`]
+    FrontBack(Vec<TemplatePlan>),
+    /// The indices of the fields the template uses as clozes, which get a card for each cloze
+    /// number in them
+    Cloze { field_indices: Vec<usize> },
+}
+
+impl CardGenPlan {
+    /// Computes the plan of `model`
+    ///
+    /// Returns `Err` like [`Model::req`] if a template of a `FrontBack` model can't generate
+    /// cards.
+    pub(crate) fn new(model: &Model) -> Result<Self, Error> {
+        match model.get_model_type() {
+            ModelType::FrontBack => {
+                let templates = model
+                    .req()?
+                    .into_iter()
+                    .map(|(ord, any_or_all, fields)| {
+                        let requirement = match any_or_all.as_str() {
+                            "any" => Requirement::Any,
+                            "all" => Requirement::All,
+                            // Invariant: `Model::req` only computes "any" and "all" requirements
+                            _ => unreachable!("only any or all"),
+                        };
+                        let ord = ord as i64;
+                        TemplatePlan {
+                            ord,
+                            requirement,
+                            fields,
+                        }
+                    })
+                    .collect();
+                Ok(CardGenPlan::FrontBack(templates))
+            }
+            ModelType::Cloze => {
+                let field_names = model.field_names();
+                let field_indices = model
+                    .cloze_field_names()
+                    .iter()
+                    .filter_map(|name| field_names.iter().position(|field| field == name))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect();
+                Ok(CardGenPlan::Cloze { field_indices })
+            }
+        }
+    }
+
+    /// The cards of a note with `fields`, which has the number of fields of the model
+    ///
+    /// A note of a `Cloze` model without cloze deletions gets no cards.
+    pub(crate) fn cards(&self, fields: &[String]) -> Vec<Card> {
+        match self {
+            CardGenPlan::FrontBack(templates) => templates
+                .iter()
+                .filter(|template| {
+                    let mut required = template.fields.iter().map(|&index| &fields[index]);
+                    match template.requirement {
+                        Requirement::Any => required.any(|field| !field.is_empty()),
+                        Requirement::All => required.all(|field| !field.is_empty()),
+                    }
+                })
+                .map(|template| Card::new(template.ord, false))
+                .collect(),
+            CardGenPlan::Cloze { field_indices } => field_indices
+                .iter()
+                .flat_map(|&index| cloze_numbers(&fields[index]))
+                .filter(|&number| number > 0)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .map(|number| Card::new(i64::from(number) - 1, false))
+                .collect(),
+        }
+    }
+}
+
+/// The `CardGenPlan` of a model once it was computed
+///
+/// The cache doesn't count when models are compared, so a model equals its clone before the plan
+/// of either was computed.
+#[derive(Clone, Default)]
+pub(crate) struct PlanCache(OnceLock<CardGenPlan>);
+
+impl PlanCache {
+    /// Returns the cached plan, or computes it with `compute` and caches it if it succeeds
+    pub(crate) fn get_or_try_init(
+        &self,
+        compute: impl FnOnce() -> Result<CardGenPlan, Error>,
+    ) -> Result<&CardGenPlan, Error> {
+        if let Some(plan) = self.0.get() {
+            return Ok(plan);
+        }
+        let plan = compute()?;
+        Ok(self.0.get_or_init(|| plan))
+    }
+}
+
+impl PartialEq for PlanCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        basic_and_reversed_card_model, basic_model, basic_optional_reversed_card_model,
+        cloze_model, Field, Note, Template,
+    };
+
+    /// Every combination of empty and non-empty values for `field_count` fields
+    fn field_combinations(field_count: usize, value: &str) -> Vec<Vec<String>> {
+        (0..1 << field_count)
+            .map(|mask: usize| {
+                (0..field_count)
+                    .map(|i| if mask & (1 << i) != 0 { value } else { "" })
+                    .map(str::to_string)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The ords of `cards`
+    fn ords(cards: &[Card]) -> Vec<i64> {
+        cards.iter().map(Card::ord).collect()
+    }
+
+    #[test]
+    fn front_back_cards_like_anki() {
+        let conditional = Model::builder(1, "Conditional")
+            .field(Field::new("Word"))
+            .field(Field::new("Meaning"))
+            .field(Field::new("Audio"))
+            .template(Template::new("Recognition").qfmt("{{Word}}"))
+            .template(Template::new("Listening").qfmt("{{#Audio}}{{Audio}}{{Word}}{{/Audio}}"))
+            .template(Template::new("Either").qfmt("{{Meaning}}{{Audio}}").ord(0))
+            .build()
+            .unwrap();
+        // The ords of the cards Anki generates for each combination of non-empty fields
+        type Expected = fn(&[bool]) -> Vec<i64>;
+        let models: Vec<(Model, Expected)> = vec![
+            (basic_and_reversed_card_model(), |filled| {
+                (0..2).filter(|&ord| filled[ord as usize]).collect()
+            }),
+            (basic_optional_reversed_card_model(), |filled| {
+                let back = filled[1] && filled[2];
+                [(0, filled[0]), (1, back)]
+                    .iter()
+                    .filter(|(_, card)| *card)
+                    .map(|(ord, _)| *ord)
+                    .collect()
+            }),
+            (conditional, |filled| {
+                let either = filled[1] || filled[2];
+                [(0, either), (1, filled[0]), (2, filled[2])]
+                    .iter()
+                    .filter(|(_, card)| *card)
+                    .map(|(ord, _)| *ord)
+                    .collect()
+            }),
+        ];
+        for (model, expected) in models {
+            let field_count = model.field_names().len();
+            for fields in field_combinations(field_count, "value") {
+                let filled: Vec<bool> = fields.iter().map(|field| !field.is_empty()).collect();
+                let expected = expected(&filled);
+                let uncached = CardGenPlan::new(&model).unwrap().cards(&fields);
+                assert_eq!(ords(&uncached), expected, "{:?}", fields);
+                let cached = model.card_gen_plan().unwrap().cards(&fields);
+                assert_eq!(cached, uncached, "{:?}", fields);
+                let values = fields.iter().map(String::as_str).collect();
+                let note = Note::new_lenient(model.clone(), values).unwrap();
+                assert_eq!(ords(note.cards()), expected, "{:?}", fields);
+            }
+        }
+    }
+
+    #[test]
+    fn cloze_cards_like_anki() {
+        let model = cloze_model();
+        let cases: [(&str, &str, Vec<i64>); 6] = [
+            ("{{c1::a}} {{c3::b::hint}}", "", vec![0, 2]),
+            ("{{c2::{{c1::nested}}}}", "", vec![0, 1]),
+            ("{{c0::zero}} {{c1::unclosed", "", vec![]),
+            ("{{c12::a}} {{c12::b}}", "", vec![11]),
+            // Only the field of the template with the cloze filter counts
+            ("", "{{c2::x}}", vec![]),
+            ("{{c1::a}}", "{{c2::x}}", vec![0]),
+        ];
+        for (text, extra, expected) in cases.iter() {
+            let fields = vec![text.to_string(), extra.to_string()];
+            let uncached = CardGenPlan::new(&model).unwrap().cards(&fields);
+            assert_eq!(&ords(&uncached), expected, "{:?}", fields);
+            let cached = model.card_gen_plan().unwrap().cards(&fields);
+            assert_eq!(cached, uncached, "{:?}", fields);
+        }
+        assert_eq!(
+            CardGenPlan::new(&model).unwrap(),
+            CardGenPlan::Cloze {
+                field_indices: vec![0]
+            }
+        );
+    }
+
+    #[test]
+    fn changed_model_gets_new_plan() {
+        let model = basic_and_reversed_card_model();
+        let fields = vec!["der Hund".to_string(), "".to_string()];
+        assert_eq!(model.card_gen_plan().unwrap().cards(&fields).len(), 1);
+        // The plan of the clone is cached as well, but adding a template computes it again
+        let extended = model
+            .clone()
+            .with_template(Template::new("Card 3").qfmt("{{Front}}!"));
+        assert_eq!(extended, extended.clone());
+        assert_eq!(extended.card_gen_plan().unwrap().cards(&fields).len(), 2);
+        let extended = model.clone().with_field(Field::new("Extra"));
+        let fields = vec!["".to_string(), "the dog".to_string(), "".to_string()];
+        assert_eq!(extended.card_gen_plan().unwrap().cards(&fields).len(), 1);
+        // Cloze models have a single template
+        let cloze = basic_model().map_templates(|t| t.qfmt("{{cloze:Front}}"));
+        let cloze = cloze.model_type(ModelType::Cloze);
+        assert!(matches!(
+            cloze.card_gen_plan().unwrap(),
+            CardGenPlan::Cloze { .. }
+        ));
+    }
+
+    #[test]
+    fn plan_is_only_cached_for_valid_models() {
+        let model = basic_and_reversed_card_model();
+        assert!(model.card_gen_plan().is_ok());
+        // Notes of a model that became invalid fail, even though the plan of the original model
+        // was cached, and they keep failing as nothing is cached for the invalid model
+        let invalid = model.with_template(Template::new("Card 1").qfmt("{{Front}}"));
+        for _ in 0..2 {
+            assert!(matches!(
+                Note::new(invalid.clone(), vec!["der Hund", "the dog"]),
+                Err(Error::DuplicateTemplateName { .. })
+            ));
+        }
+    }
+}
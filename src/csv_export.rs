@@ -0,0 +1,150 @@
+use std::io::Write;
+
+use crate::builders::{CsvExportOptions, MixedModels};
+use crate::model::Model;
+use crate::note::Note;
+use crate::Error;
+
+/// Writes `notes` for [`Deck::write_notes_csv`](crate::Deck::write_notes_csv)
+pub(crate) fn write_csv_notes<'a>(
+    notes: impl Iterator<Item = &'a Note>,
+    mut writer: impl Write,
+    options: &CsvExportOptions,
+) -> Result<(), Error> {
+    // The notes grouped by model, in the order the models are first used
+    let mut sections: Vec<(&Model, Vec<&Note>)> = vec![];
+    for note in notes {
+        let model = note.model();
+        match sections.iter_mut().find(|(known, _)| known.id == model.id) {
+            Some((_, notes)) => notes.push(note),
+            None => sections.push((model, vec![note])),
+        }
+    }
+    if let (MixedModels::Error, [(first, _), (second, _), ..]) =
+        (options.get_mixed_models(), sections.as_slice())
+    {
+        return Err(Error::MixedModels {
+            first: first.name().to_string(),
+            second: second.name().to_string(),
+        });
+    }
+
+    let mut builder = csv::WriterBuilder::new();
+    builder.delimiter(options.get_delimiter().byte());
+    for (index, (model, notes)) in sections.iter().enumerate() {
+        if index > 0 {
+            writer.write_all(b"\n")?;
+        }
+        let mut csv_writer = builder.from_writer(&mut writer);
+        let mut header = model.field_names();
+        header.push(options.get_tags_column());
+        header.extend(options.get_guid_column());
+        csv_writer.write_record(&header).map_err(csv_error)?;
+        for note in notes {
+            let tags = note.get_tags().join(" ");
+            let guid = note.get_guid();
+            let mut row: Vec<&str> = note.fields().iter().map(String::as_str).collect();
+            row.push(&tags);
+            if options.get_guid_column().is_some() {
+                row.push(&guid);
+            }
+            csv_writer.write_record(&row).map_err(csv_error)?;
+        }
+        csv_writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Writing only fails if `writer` does, which `csv` wraps in its own error
+fn csv_error(e: csv::Error) -> Error {
+    Error::Io(e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_model, cloze_model, CsvOptions, Deck, Delimiter};
+
+    fn export(deck: &Deck, options: CsvExportOptions) -> String {
+        let mut out = vec![];
+        deck.write_notes_csv(&mut out, options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut deck = Deck::new(1234, "German", "");
+        let fields = [
+            ["der Hund", "the dog"],
+            ["die \"Katze\"", "the cat\nmeow"],
+            ["laufen", "to\trun, to walk"],
+        ];
+        for (i, [front, back]) in fields.iter().enumerate() {
+            let note = Note::new(basic_model(), vec![front, back])
+                .unwrap()
+                .tags(if i == 0 {
+                    vec!["nouns", "animals"]
+                } else {
+                    vec![]
+                })
+                .unwrap()
+                .guid(format!("guid{}", i));
+            deck.add_note(note);
+        }
+
+        for delimiter in [Delimiter::Comma, Delimiter::Tab, Delimiter::Semicolon] {
+            let options = CsvExportOptions::new()
+                .delimiter(delimiter)
+                .guid_column("GUID");
+            let csv = export(&deck, options);
+            let mut imported = Deck::new(1234, "German", "");
+            let options = CsvOptions::new()
+                .delimiter(delimiter)
+                .tags_column("Tags")
+                .guid_column("GUID");
+            let report = imported
+                .add_notes_from_csv(&basic_model(), csv.as_bytes(), options)
+                .unwrap();
+            assert_eq!((report.added, report.skipped), (3, 0));
+            for (note, imported) in deck.notes().zip(imported.notes()) {
+                assert_eq!(imported.fields(), note.fields());
+                assert_eq!(imported.get_tags(), note.get_tags());
+                assert_eq!(imported.get_guid(), note.get_guid());
+            }
+        }
+
+        assert_eq!(
+            export(&deck, CsvExportOptions::new().delimiter(Delimiter::Tab)),
+            "Front\tBack\tTags\nder Hund\tthe dog\tnouns animals\n\
+             \"die \"\"Katze\"\"\"\t\"the cat\nmeow\"\t\nlaufen\t\"to\trun, to walk\"\t\n"
+        );
+    }
+
+    #[test]
+    fn mixed_models() {
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"]).unwrap());
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::die Katze}}", ""]).unwrap());
+        deck.add_note(Note::new(basic_model(), vec!["laufen", "to run"]).unwrap());
+        match deck.write_notes_csv(vec![], CsvExportOptions::new()) {
+            Err(Error::MixedModels { first, second }) => {
+                assert_eq!(
+                    (first.as_str(), second.as_str()),
+                    ("Basic (genanki)", "Cloze (genanki)")
+                )
+            }
+            other => panic!("expected mixed models, got {:?}", other),
+        }
+
+        let options = CsvExportOptions::new().mixed_models(MixedModels::Sections);
+        assert_eq!(
+            export(&deck, options),
+            "Front,Back,Tags\nder Hund,the dog,\nlaufen,to run,\n\n\
+             Text,Back Extra,Tags\n{{c1::die Katze}},,\n"
+        );
+        assert_eq!(
+            export(&Deck::new(1234, "German", ""), CsvExportOptions::new()),
+            ""
+        );
+    }
+}
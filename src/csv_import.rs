@@ -0,0 +1,276 @@
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::builders::CsvOptions;
+use crate::model::Model;
+use crate::note::Note;
+use crate::util::escape_html;
+use crate::Error;
+
+/// What [`Deck::add_notes_from_csv`](crate::Deck::add_notes_from_csv) imported
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// The number of notes that were added to the deck
+    pub added: usize,
+    /// The number of rows that were skipped because they are invalid
+    pub skipped: usize,
+    /// Why the rows were skipped, in the order of the rows
+    pub errors: Vec<RowError>,
+}
+
+/// A row of a CSV file that couldn't be added as a note, see [`ImportReport`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RowError {
+    /// The line of the file the row starts on, starting at `1`
+    pub line: u64,
+    pub message: String,
+}
+
+/// Where the values of the fields, the tags and the guid are in a row
+struct Columns {
+    /// The column of each field of the model, if the file has one
+    fields: Vec<Option<usize>>,
+    tags: Option<usize>,
+    guid: Option<usize>,
+    len: usize,
+}
+
+/// Reads the notes of a CSV file for [`Deck::add_notes_from_csv`](crate::Deck::add_notes_from_csv)
+pub(crate) fn read_csv_notes(
+    model: &Model,
+    reader: impl Read,
+    options: &CsvOptions,
+) -> Result<(Vec<Note>, ImportReport), Error> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .delimiter(options.get_delimiter().byte())
+        .has_headers(options.get_has_header())
+        .flexible(true)
+        .from_reader(reader);
+    let field_names = model.field_names();
+    let columns = if options.get_has_header() {
+        let header = csv_reader.headers().map_err(csv_error)?;
+        let mut columns = Columns {
+            fields: vec![None; field_names.len()],
+            tags: None,
+            guid: None,
+            len: header.len(),
+        };
+        for (index, name) in header.iter().enumerate() {
+            if Some(name) == options.get_tags_column() {
+                columns.tags = Some(index);
+            } else if Some(name) == options.get_guid_column() {
+                columns.guid = Some(index);
+            } else if let Some(field) = field_names.iter().position(|&field| field == name) {
+                columns.fields[field] = Some(index);
+            } else {
+                return Err(Error::UnknownNoteField {
+                    key: name.to_string(),
+                    known_fields: field_names.iter().map(|name| name.to_string()).collect(),
+                });
+            }
+        }
+        columns
+    } else {
+        let tags = options.get_tags_column().map(|_| field_names.len());
+        let guid_index = field_names.len() + usize::from(tags.is_some());
+        let guid = options.get_guid_column().map(|_| guid_index);
+        Columns {
+            fields: (0..field_names.len()).map(Some).collect(),
+            tags,
+            guid,
+            len: guid_index + usize::from(guid.is_some()),
+        }
+    };
+
+    // The notes share one copy of the model
+    let model = Arc::new(model.clone());
+    let mut notes = vec![];
+    let mut report = ImportReport::default();
+    for record in csv_reader.records() {
+        let result = match record {
+            Ok(record) => {
+                let line = record.position().map_or(0, |position| position.line());
+                (line, note_from_record(&model, &record, &columns, options))
+            }
+            Err(e) if e.is_io_error() => return Err(csv_error(e)),
+            Err(e) => {
+                let line = e.position().map_or(0, |position| position.line());
+                (line, Err(e.to_string()))
+            }
+        };
+        match result {
+            (_, Ok(note)) => notes.push(note),
+            (line, Err(message)) if options.get_skip_invalid_rows() => {
+                report.skipped += 1;
+                report.errors.push(RowError { line, message });
+            }
+            (line, Err(message)) => return Err(Error::InvalidCsvRow { line, message }),
+        }
+    }
+    report.added = notes.len();
+    Ok((notes, report))
+}
+
+fn note_from_record(
+    model: &Arc<Model>,
+    record: &csv::StringRecord,
+    columns: &Columns,
+    options: &CsvOptions,
+) -> Result<Note, String> {
+    if record.len() != columns.len {
+        return Err(format!(
+            "the row has {} columns instead of {}",
+            record.len(),
+            columns.len
+        ));
+    }
+    let fields: Vec<String> = columns
+        .fields
+        .iter()
+        .map(|column| {
+            let value = column.and_then(|index| record.get(index)).unwrap_or("");
+            if options.get_escape_html() {
+                escape_html(value)
+            } else {
+                value.to_string()
+            }
+        })
+        .collect();
+    let tags = columns
+        .tags
+        .and_then(|index| record.get(index))
+        .unwrap_or("")
+        .split_whitespace();
+    let note = Note::new(model.clone(), fields.iter().map(String::as_str).collect())
+        .and_then(|note| note.tags(tags))
+        .map_err(|e| e.to_string())?;
+    match columns.guid.and_then(|index| record.get(index)) {
+        Some(guid) if !guid.is_empty() => Ok(note.guid(guid)),
+        _ => Ok(note),
+    }
+}
+
+fn csv_error(e: csv::Error) -> Error {
+    if e.is_io_error() {
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => Error::Io(e),
+            // Invariant: `is_io_error` is true only for `ErrorKind::Io`
+            _ => unreachable!(),
+        }
+    } else {
+        let line = e.position().map_or(0, |position| position.line());
+        Error::InvalidCsvRow {
+            line,
+            message: e.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_model, cloze_model, Deck, Delimiter};
+
+    fn fields(deck: &Deck) -> Vec<Vec<String>> {
+        deck.notes().map(|note| note.fields().to_vec()).collect()
+    }
+
+    #[test]
+    fn header_mapped_file() {
+        let csv = "Back,Front\nthe dog,der Hund\n\"the cat, small\",die Katze\n";
+        let mut deck = Deck::new(1234, "German", "");
+        let report = deck
+            .add_notes_from_csv(&basic_model(), csv.as_bytes(), CsvOptions::new())
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                added: 2,
+                skipped: 0,
+                errors: vec![]
+            }
+        );
+        assert_eq!(
+            fields(&deck),
+            vec![
+                vec!["der Hund", "the dog"],
+                vec!["die Katze", "the cat, small"]
+            ]
+        );
+
+        let csv = "Front,Meaning\nder Hund,the dog\n";
+        assert!(matches!(
+            deck.add_notes_from_csv(&basic_model(), csv.as_bytes(), CsvOptions::new()),
+            Err(Error::UnknownNoteField { key, .. }) if key == "Meaning"
+        ));
+        assert_eq!(deck.note_count(), 2);
+    }
+
+    #[test]
+    fn positional_file_with_tags() {
+        let tsv = "der Hund\tthe dog\tnouns animals\ndie <b>Katze</b>\tthe cat\t\n";
+        let mut deck = Deck::new(1234, "German", "");
+        let options = CsvOptions::new()
+            .delimiter(Delimiter::Tab)
+            .has_header(false)
+            .tags_column("tags")
+            .escape_html(true);
+        let report = deck
+            .add_notes_from_csv(&basic_model(), tsv.as_bytes(), options)
+            .unwrap();
+        assert_eq!(report.added, 2);
+        assert_eq!(
+            fields(&deck),
+            vec![
+                vec!["der Hund", "the dog"],
+                vec!["die &lt;b&gt;Katze&lt;/b&gt;", "the cat"]
+            ]
+        );
+        let tags: Vec<&[String]> = deck.notes().map(|note| note.get_tags()).collect();
+        assert_eq!(
+            tags,
+            vec![&["nouns".to_string(), "animals".to_string()][..], &[]]
+        );
+    }
+
+    #[test]
+    fn tags_column_in_header() {
+        let csv = "Front;Tags;Back\nder Hund;nouns;the dog\n";
+        let mut deck = Deck::new(1234, "German", "");
+        let options = CsvOptions::new()
+            .delimiter(Delimiter::Semicolon)
+            .tags_column("Tags");
+        deck.add_notes_from_csv(&basic_model(), csv.as_bytes(), options)
+            .unwrap();
+        let note = deck.notes().next().unwrap();
+        assert_eq!(note.fields(), &["der Hund", "the dog"]);
+        assert_eq!(note.get_tags(), &["nouns"]);
+    }
+
+    #[test]
+    fn malformed_rows() {
+        let csv = "Text,Back Extra\n{{c1::Berlin}} is the capital,\nno cloze,\ntoo,many,columns\n";
+        let mut deck = Deck::new(1234, "German", "");
+        let report = deck
+            .add_notes_from_csv(&cloze_model(), csv.as_bytes(), CsvOptions::new())
+            .unwrap();
+        assert_eq!((report.added, report.skipped), (1, 2));
+        assert_eq!(deck.note_count(), 1);
+        assert_eq!(
+            report.errors.iter().map(|e| e.line).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(
+            report.errors[1].message,
+            "the row has 3 columns instead of 2"
+        );
+
+        let mut deck = Deck::new(1234, "German", "");
+        let options = CsvOptions::new().skip_invalid_rows(false);
+        match deck.add_notes_from_csv(&cloze_model(), csv.as_bytes(), options) {
+            Err(Error::InvalidCsvRow { line, .. }) => assert_eq!(line, 3),
+            other => panic!("expected an invalid row, got {:?}", other),
+        }
+        assert_eq!(deck.note_count(), 0);
+    }
+}
@@ -1,9 +1,11 @@
 use crate::deck::Deck;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeckDbEntry {
     pub collapsed: bool,
+    #[serde(rename = "browserCollapsed", default, skip_serializing_if = "is_false")]
+    pub browser_collapsed: bool,
     pub conf: i64,
     pub desc: String,
     #[serde(rename = "dyn")]
@@ -29,51 +31,179 @@ pub struct DeckDbEntry {
 
 impl From<Deck> for DeckDbEntry {
     fn from(deck: Deck) -> Self {
-        deck.into()
+        deck.to_deck_db_entry()
     }
 }
 
+/// A deck options group as stored in the `dconf` column of an Anki collection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeckConfDbEntry {
+    pub autoplay: bool,
+    #[serde(default, rename = "dyn")]
+    pub deck_conf_db_entry_dyn: bool,
+    pub id: i64,
+    pub lapse: LapseConfDbEntry,
+    #[serde(rename = "maxTaken")]
+    pub max_taken: i64,
+    #[serde(rename = "mod")]
+    pub deck_conf_db_entry_mod: i64,
+    pub name: String,
+    pub new: NewConfDbEntry,
+    pub replayq: bool,
+    pub rev: RevConfDbEntry,
+    pub timer: i64,
+    pub usn: i64,
+}
+
+/// The `new` part of a `DeckConfDbEntry`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NewConfDbEntry {
+    pub bury: bool,
+    pub delays: Vec<f32>,
+    #[serde(rename = "initialFactor")]
+    pub initial_factor: i64,
+    pub ints: Vec<u32>,
+    pub order: i64,
+    #[serde(rename = "perDay")]
+    pub per_day: u32,
+    pub separate: bool,
+}
+
+/// The `rev` part of a `DeckConfDbEntry`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RevConfDbEntry {
+    pub bury: bool,
+    pub ease4: f64,
+    pub fuzz: f64,
+    #[serde(rename = "ivlFct")]
+    pub ivl_fct: f64,
+    #[serde(rename = "maxIvl")]
+    pub max_ivl: i64,
+    #[serde(rename = "minSpace")]
+    pub min_space: i64,
+    #[serde(rename = "perDay")]
+    pub per_day: u32,
+}
+
+/// The `lapse` part of a `DeckConfDbEntry`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LapseConfDbEntry {
+    pub delays: Vec<f32>,
+    #[serde(rename = "leechAction")]
+    pub leech_action: i64,
+    #[serde(rename = "leechFails")]
+    pub leech_fails: i64,
+    #[serde(rename = "minInt")]
+    pub min_int: i64,
+    pub mult: f64,
+}
+
+/// A model (note type) as stored in the `models` column of an Anki collection
 #[derive(Serialize, Deserialize)]
 pub struct ModelDbEntry {
+    #[serde(default)]
     pub vers: Vec<Option<serde_json::Value>>,
     pub name: String,
+    #[serde(default)]
     pub tags: Vec<Option<serde_json::Value>>,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub did: i64,
+    #[serde(default)]
     pub usn: i64,
+    #[serde(default)]
     pub req: Vec<(usize, String, Vec<usize>)>,
     pub flds: Vec<Fld>,
+    #[serde(default)]
     pub sortf: i64,
     pub tmpls: Vec<Tmpl>,
-    #[serde(rename = "mod")]
+    #[serde(rename = "mod", default)]
     pub model_db_entry_mod: i64,
-    #[serde(rename = "latexPost")]
+    #[serde(rename = "latexPost", default)]
     pub latex_post: String,
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default)]
     pub model_db_entry_type: i64,
+    #[serde(deserialize_with = "string_or_number")]
     pub id: String,
+    #[serde(default)]
     pub css: String,
-    #[serde(rename = "latexPre")]
+    #[serde(rename = "latexPre", default)]
     pub latex_pre: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub latexsvg: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// A field of a `ModelDbEntry`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Fld {
     pub name: String,
+    #[serde(default)]
     pub media: Vec<Option<serde_json::Value>>,
+    #[serde(default)]
     pub sticky: bool,
+    #[serde(default)]
     pub rtl: bool,
+    #[serde(default)]
     pub ord: i64,
+    #[serde(default)]
     pub font: String,
+    #[serde(default)]
     pub size: i64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(rename = "plainText", default, skip_serializing_if = "is_false")]
+    pub plain_text: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub collapsed: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// A template of a `ModelDbEntry`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Tmpl {
     pub name: String,
     pub qfmt: String,
-    pub did: Option<usize>,
+    #[serde(default)]
+    pub did: Option<i64>,
+    #[serde(default)]
     pub bafmt: String,
     pub afmt: String,
+    #[serde(default)]
     pub ord: i64,
+    #[serde(default)]
     pub bqfmt: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub bfont: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub bsize: i64,
+    /// Whether `ord` was set with `Template::ord` and is kept when the model is written, which
+    /// isn't stored by Anki
+    #[serde(skip)]
+    pub sticky_ord: bool,
+}
+
+fn is_zero(value: &i64) -> bool {
+    *value == 0
+}
+
+pub(crate) fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Anki writes some ids as numbers and others as strings, so accept both
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        other => Err(serde::de::Error::custom(format!(
+            "expected a string or a number, found {}",
+            other
+        ))),
+    }
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
 }
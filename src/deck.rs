@@ -1,38 +1,304 @@
 use super::Package;
-use crate::db_entries::{DeckDbEntry, ModelDbEntry};
+use crate::builders::DeckConf;
+#[cfg(feature = "csv")]
+use crate::builders::{CsvExportOptions, CsvOptions};
+#[cfg(feature = "csv")]
+use crate::csv_export::write_csv_notes;
+#[cfg(feature = "csv")]
+use crate::csv_import::{read_csv_notes, ImportReport};
+#[cfg(feature = "serde")]
+use crate::db_entries::is_false;
+use crate::db_entries::{DeckConfDbEntry, DeckDbEntry};
 use crate::error::{database_error, json_error};
-use crate::model::Model;
-use crate::note::Note;
+use crate::media::MediaRef;
+use crate::model::{same_model, Model};
+use crate::note::{normalize_tags, Note};
+use crate::stats::DeckStats;
 use crate::Error;
-use rusqlite::{params, Transaction};
-use std::collections::HashMap;
+use rusqlite::{params, Connection};
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[cfg(feature = "csv")]
+use std::io::{Read, Write};
 use std::ops::RangeFrom;
 use std::path::Path;
+use std::sync::Arc;
+
+/// What makes two notes duplicates of each other, see [`Deck::add_note_dedup_by`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupBy {
+    /// The notes have the same GUID, so Anki would only import one of them
+    Guid,
+    /// The notes have the same model and the same first field once HTML is stripped, which is
+    /// what Anki's "Find Duplicates" reports
+    FirstField,
+}
+
+impl DedupBy {
+    fn duplicates(self, a: &Note, b: &Note) -> bool {
+        match self {
+            DedupBy::Guid => a.get_guid() == b.get_guid(),
+            DedupBy::FirstField => {
+                a.model().id == b.model().id && a.stripped_first_field() == b.stripped_first_field()
+            }
+        }
+    }
+}
+
+/// What [`Deck::extend`] does with an incoming note that has the same GUID as a note of the deck
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Keep the note of the deck and drop the incoming one
+    KeepFirst,
+    /// Replace the note of the deck with the incoming one, at the position of the old note
+    KeepLast,
+    /// Return `Error::DuplicateNoteGuid`
+    Error,
+}
 
 /// A flashcard deck which can be written into an .apkg file.
-#[derive(Clone)]
+///
+/// With the `serde` feature, a deck is (de)serialized as a map with the keys `id`, `name`,
+/// `description`, `notes`, `conf`, `default_tags`, `collapsed`, `browser_collapsed`,
+/// `extend_new` and `extend_rev`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Deck {
     id: i64,
     name: String,
+    #[cfg_attr(feature = "serde", serde(default))]
     description: String,
+    #[cfg_attr(feature = "serde", serde(default))]
     notes: Vec<Note>,
-    models: HashMap<i64, Model>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    conf: Option<DeckConf>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    default_tags: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_false"))]
+    collapsed: bool,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_false"))]
+    browser_collapsed: bool,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    extend_new: Option<u32>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    extend_rev: Option<u32>,
 }
 
 impl Deck {
     /// Creates a new deck with an `id`, `name` and `description`.
     ///
     /// `id` should always be unique when creating multiple decks.
-    pub fn new(id: i64, name: &str, description: &str) -> Self {
+    pub fn new(id: i64, name: impl ToString, description: impl ToString) -> Self {
         Self {
             id,
             name: name.to_string(),
             description: description.to_string(),
             notes: vec![],
-            models: HashMap::new(),
+            conf: None,
+            default_tags: vec![],
+            collapsed: false,
+            browser_collapsed: false,
+            extend_new: None,
+            extend_rev: None,
+        }
+    }
+
+    /// Creates a new deck with an id derived from its `name`, see [`Deck::id_from_name`]
+    ///
+    /// Decks generated again with the same name keep updating the same deck in Anki.
+    pub fn new_with_id_from_name(name: &str, description: impl ToString) -> Self {
+        Self::new(Self::id_from_name(name), name, description)
+    }
+
+    /// Derives a deterministic deck id from a deck `name`, the same way as
+    /// [`Model::id_from_name`]
+    ///
+    /// The id is never `1`, the id of Anki's default deck, or `0`; those two are replaced by `2`.
+    /// In Python: `max(int.from_bytes(hashlib.sha256(name.encode()).digest()[:8], "big") &
+    /// (2**53 - 1), 2)`
+    pub fn id_from_name(name: &str) -> i64 {
+        Model::id_from_name(name).max(2)
+    }
+
+    /// Creates a new deck with an `id` below this one in the deck hierarchy, named
+    /// `Parent::suffix`
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::Deck;
+    ///
+    /// let japanese = Deck::new(1234, "Japanese", "");
+    /// let vocab = japanese.subdeck("Vocab", 1235);
+    /// ```
+    pub fn subdeck(&self, suffix: &str, id: i64) -> Deck {
+        Deck::new(id, format!("{}::{}", self.name, suffix), "")
+    }
+
+    /// Returns `Error::InvalidDeckName` if a part of the name between `::` is empty
+    pub(super) fn validate_name(&self) -> Result<(), Error> {
+        if self.name.split("::").any(str::is_empty) {
+            return Err(Error::InvalidDeckName {
+                name: self.name.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the decks above this one in the deck hierarchy, from the top
+    pub(super) fn ancestor_names(&self) -> Vec<String> {
+        let parts: Vec<&str> = self.name.split("::").collect();
+        (1..parts.len()).map(|i| parts[..i].join("::")).collect()
+    }
+
+    /// Returns the id of the deck
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// Returns the name of the deck
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the notes of the deck, in the order they were added
+    pub fn notes(&self) -> impl Iterator<Item = &Note> {
+        self.notes.iter()
+    }
+
+    /// Returns the number of notes in the deck
+    pub fn note_count(&self) -> usize {
+        self.notes.len()
+    }
+
+    /// Returns the number of cards the notes of the deck generate, see [`Note::cards`]
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, Note, basic_and_reversed_card_model, cloze_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut my_deck = Deck::new(1234, "Example deck", "This is an example deck");
+    /// my_deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["der Hund", "the dog"])?);
+    /// my_deck.add_note(Note::new(cloze_model(), vec!["{{c1::Paris}} is in {{c2::France}}", ""])?);
+    /// assert_eq!(my_deck.note_count(), 2);
+    /// assert_eq!(my_deck.card_count(), 4);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn card_count(&self) -> usize {
+        self.notes.iter().map(Note::card_count).sum()
+    }
+
+    /// Sets the description shown on the overview screen of the deck, which may contain HTML
+    pub fn description(self, html: impl ToString) -> Self {
+        Self {
+            description: html.to_string(),
+            ..self
+        }
+    }
+
+    /// Sets the description shown on the overview screen of the deck from Markdown
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::Deck;
+    ///
+    /// let deck = Deck::new(1234, "Example deck", "")
+    ///     .description_markdown("Nouns from *chapter 1*, see [the book](https://example.com)");
+    /// ```
+    #[cfg(feature = "markdown")]
+    pub fn description_markdown(self, markdown: &str) -> Self {
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(markdown));
+        self.description(html)
+    }
+
+    /// Sets the options group of the deck, Anki's default options are used otherwise
+    pub fn conf(self, conf: DeckConf) -> Self {
+        Self {
+            conf: Some(conf),
+            ..self
+        }
+    }
+
+    /// Sets whether the subdecks of the deck are hidden in the deck list of Anki, default is
+    /// `false`
+    pub fn collapsed(self, collapsed: bool) -> Self {
+        Self { collapsed, ..self }
+    }
+
+    /// Sets whether the subdecks of the deck are hidden in the sidebar of the card browser,
+    /// default is `false`
+    pub fn browser_collapsed(self, browser_collapsed: bool) -> Self {
+        Self {
+            browser_collapsed,
+            ..self
+        }
+    }
+
+    /// Sets how many new cards custom study offers to add to today's limit of the deck, default
+    /// is `0`
+    pub fn extend_new(self, cards: u32) -> Self {
+        Self {
+            extend_new: Some(cards),
+            ..self
         }
     }
 
+    /// Sets how many reviews custom study offers to add to today's limit of the deck, default is
+    /// `50`
+    pub fn extend_rev(self, cards: u32) -> Self {
+        Self {
+            extend_rev: Some(cards),
+            ..self
+        }
+    }
+
+    /// Sets tags that every note of the deck gets in addition to its own tags when the deck is
+    /// written, e.g. the tags of a vocabulary list
+    ///
+    /// The notes keep their own tags, so the same note can be added to decks with different
+    /// default tags. The default tags come after the tags of a note, without those that it
+    /// already has. Returns `Error::TagContainsWhitespace` like [`Note::tags`].
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::Deck;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let deck = Deck::new(1234, "JLPT N3", "").default_tags(["jlpt::n3", "vocab"])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn default_tags(
+        self,
+        tags: impl IntoIterator<Item = impl ToString>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            default_tags: normalize_tags(tags)?,
+            ..self
+        })
+    }
+
+    pub(super) fn get_default_tags(&self) -> &[String] {
+        &self.default_tags
+    }
+
     /// Adds a `note` (Flashcard) to the deck.
     ///
     /// Example:
@@ -40,25 +306,347 @@ impl Deck {
     /// ```rust
     /// use genanki_rs::{Deck, Note, basic_model};
     ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
     /// let mut my_deck = Deck::new(1234, "Example deck", "This is an example deck");
     /// my_deck.add_note(Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?);
+    /// # Ok(())
+    /// # }
     /// ```
     pub fn add_note(&mut self, note: Note) {
         self.notes.push(note);
     }
 
-    fn add_model(&mut self, model: Model) {
-        self.models.insert(model.id, model);
+    /// Adds a `note` whose cards are written to the decks in `routing`, one deck id for each
+    /// card in the order of [`Note::cards`], see [`Card::set_deck_id`]. The note itself is in
+    /// this deck.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{basic_and_reversed_card_model, Deck, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut recognition = Deck::new(1234, "German::Recognition", "");
+    /// let recall = Deck::new(5678, "German::Recall", "");
+    /// let note = Note::new(basic_and_reversed_card_model(), vec!["der Hund", "the dog"])?;
+    /// recognition.add_note_with_card_routing(note, &[1234, 5678])?;
+    /// let package = Package::new(vec![recognition, recall], vec![])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Returns `Error::CardRoutingMismatch` if the note doesn't have as many cards as `routing`
+    /// has deck ids. The decks have to be part of the same package, otherwise writing the
+    /// package returns `Error::UnknownDeckId`.
+    ///
+    /// [`Card::set_deck_id`]: crate::Card::set_deck_id
+    pub fn add_note_with_card_routing(&mut self, note: Note, routing: &[i64]) -> Result<(), Error> {
+        if note.card_count() != routing.len() {
+            return Err(Error::CardRoutingMismatch {
+                cards: note.card_count(),
+                deck_ids: routing.len(),
+            });
+        }
+        let mut routing = routing.iter();
+        let note = note.configure_cards(|card| card.set_deck_id(routing.next().copied()));
+        self.notes.push(note);
+        Ok(())
+    }
+
+    /// Removes the HTML that `policy` doesn't allow from the fields of all notes, see
+    /// [`Note::sanitize_html`]
+    ///
+    /// Returns the error of the first note whose sanitized fields are invalid, the notes before
+    /// it are sanitized already.
+    #[cfg(feature = "sanitize")]
+    pub fn sanitize_fields(&mut self, policy: &crate::SanitizePolicy) -> Result<(), Error> {
+        for note in &mut self.notes {
+            *note = note.clone().sanitize_html(policy)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a note of `model` for each row of the CSV file `reader` and returns what was added
+    ///
+    /// With a header, which is the default, the columns are mapped to the fields of `model` by
+    /// name and fields without a column are left empty. Rows with the wrong number of columns or
+    /// values that don't make a valid note are skipped and reported with their line numbers,
+    /// unless [`CsvOptions::skip_invalid_rows`] is `false`.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{basic_model, CsvOptions, Deck};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let csv = "Front,Back,Tags\nder Hund,the dog,nouns\ndie Katze,the cat,nouns\n";
+    /// let mut deck = Deck::new(1234, "German", "");
+    /// let options = CsvOptions::new().tags_column("Tags");
+    /// let report = deck.add_notes_from_csv(&basic_model(), csv.as_bytes(), options)?;
+    /// assert_eq!(report.added, 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Returns `Error::UnknownNoteField` if a column of the header isn't a field of `model` or
+    /// the tags column, `Error::InvalidCsvRow` for an invalid row if they aren't skipped, and
+    /// `Err` if `reader` fails. The deck is unchanged if an error is returned.
+    #[cfg(feature = "csv")]
+    pub fn add_notes_from_csv(
+        &mut self,
+        model: &Model,
+        reader: impl Read,
+        options: CsvOptions,
+    ) -> Result<ImportReport, Error> {
+        let (notes, report) = read_csv_notes(model, reader, &options)?;
+        self.notes.extend(notes);
+        Ok(report)
+    }
+
+    /// Writes the notes of the deck to `writer` as a CSV file, e.g. to edit them in a spreadsheet
+    /// and import them again with [`Deck::add_notes_from_csv`]
+    ///
+    /// The file has a header with the field names of the model, followed by the tags column and
+    /// the optional guid column, and a row for each note. Values with the delimiter, quotes or
+    /// line breaks are quoted as described in RFC 4180. Nothing is written for a deck without
+    /// notes.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{basic_model, CsvExportOptions, Deck, Delimiter, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "German", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"])?.tags(["nouns"])?);
+    /// let mut tsv = vec![];
+    /// deck.write_notes_csv(&mut tsv, CsvExportOptions::new().delimiter(Delimiter::Tab))?;
+    /// assert_eq!(tsv, b"Front\tBack\tTags\nder Hund\tthe dog\tnouns\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Returns `Error::MixedModels` if the notes use more than one model, unless
+    /// [`CsvExportOptions::mixed_models`] is `MixedModels::Sections`, and `Err` if writing to
+    /// `writer` fails
+    #[cfg(feature = "csv")]
+    pub fn write_notes_csv(
+        &self,
+        writer: impl Write,
+        options: CsvExportOptions,
+    ) -> Result<(), Error> {
+        write_csv_notes(self.notes.iter(), writer, &options)
     }
 
-    fn to_deck_db_entry(&self) -> DeckDbEntry {
+    /// Moves all notes of `other` into this deck, after the notes of this deck
+    ///
+    /// Notes of `other` with the GUID of a note of this deck or of an earlier note of `other` are
+    /// handled according to `policy`, and get the [default tags](Deck::default_tags) of `other`
+    /// as their own tags. Returns `Error::ModelIdConflict` if the notes of the decks use different
+    /// models with the same id, as Anki would replace one with the other on import. The deck is
+    /// unchanged if an error is returned.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, MergePolicy, Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut my_deck = Deck::new(1234, "German", "");
+    /// my_deck.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"])?);
+    /// let mut chapter = Deck::new(1235, "German chapter 2", "");
+    /// chapter.add_note(Note::new(basic_model(), vec!["die Katze", "the cat"])?);
+    /// my_deck.extend(chapter, MergePolicy::Error)?;
+    /// assert_eq!(my_deck.note_count(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extend(&mut self, other: Deck, policy: MergePolicy) -> Result<(), Error> {
+        let mut models: HashMap<i64, &Arc<Model>> = HashMap::new();
+        for note in self.notes.iter().chain(&other.notes) {
+            let model = note.model();
+            match models.get(&model.id) {
+                Some(known) if !same_model(known, model) => {
+                    return Err(Error::ModelIdConflict {
+                        id: model.id,
+                        name: known.name().to_string(),
+                        other_name: model.name().to_string(),
+                    })
+                }
+                Some(_) => {}
+                None => {
+                    models.insert(model.id, model);
+                }
+            }
+        }
+        // The incoming notes are added to the map as well, so that duplicates among them are
+        // handled like duplicates of notes of this deck
+        let mut guids: HashMap<String, usize> = self
+            .notes
+            .iter()
+            .enumerate()
+            .map(|(index, note)| (note.get_guid(), index))
+            .collect();
+        if policy == MergePolicy::Error {
+            let mut seen: HashSet<String> = guids.keys().cloned().collect();
+            if let Some(note) = other
+                .notes
+                .iter()
+                .find(|note| !seen.insert(note.get_guid()))
+            {
+                return Err(Error::DuplicateNoteGuid {
+                    guid: note.get_guid(),
+                });
+            }
+        }
+        for note in other.notes {
+            let note = note.with_default_tags(&other.default_tags);
+            match guids.get(&note.get_guid()) {
+                None => {
+                    guids.insert(note.get_guid(), self.notes.len());
+                    self.notes.push(note);
+                }
+                Some(&index) => {
+                    if policy == MergePolicy::KeepLast {
+                        self.notes[index] = note;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps only the notes for which `keep` returns `true`, in their order
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut my_deck = Deck::new(1234, "Example deck", "This is an example deck");
+    /// my_deck.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"])?);
+    /// my_deck.add_note(Note::new(basic_model(), vec!["die Katze", ""])?);
+    /// my_deck.retain_notes(|note| note.fields().iter().all(|field| !field.is_empty()));
+    /// assert_eq!(my_deck.note_count(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn retain_notes(&mut self, keep: impl FnMut(&Note) -> bool) {
+        self.notes.retain(keep);
+    }
+
+    /// Removes the first note with the GUID `guid` and returns it, or `None` if the deck has no
+    /// such note
+    pub fn remove_note_by_guid(&mut self, guid: &str) -> Option<Note> {
+        let index = self.notes.iter().position(|note| note.get_guid() == guid)?;
+        Some(self.notes.remove(index))
+    }
+
+    /// Adds a `note` like [`Deck::add_note`], unless the deck already has a note with the same
+    /// GUID
+    ///
+    /// Returns whether the note was added.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut my_deck = Deck::new(1234, "Example deck", "This is an example deck");
+    /// let note = Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?;
+    /// assert!(my_deck.add_note_dedup(note.clone())?);
+    /// assert!(!my_deck.add_note_dedup(note)?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_note_dedup(&mut self, note: Note) -> Result<bool, Error> {
+        self.add_note_dedup_by(note, DedupBy::Guid)
+    }
+
+    /// Adds a `note` like [`Deck::add_note`], unless the deck already has a duplicate of it
+    /// according to `by`
+    ///
+    /// Returns whether the note was added.
+    pub fn add_note_dedup_by(&mut self, note: Note, by: DedupBy) -> Result<bool, Error> {
+        if self
+            .notes
+            .iter()
+            .any(|existing| by.duplicates(existing, &note))
+        {
+            return Ok(false);
+        }
+        self.add_note(note);
+        Ok(true)
+    }
+
+    /// Returns the indices of all pairs of notes, in the order they were added, that have the
+    /// same GUID or are duplicates by [`DedupBy::FirstField`]
+    pub fn find_duplicates(&self) -> Vec<(usize, usize)> {
+        let mut duplicates = vec![];
+        for (i, a) in self.notes.iter().enumerate() {
+            for (j, b) in self.notes.iter().enumerate().skip(i + 1) {
+                if DedupBy::Guid.duplicates(a, b) || DedupBy::FirstField.duplicates(a, b) {
+                    duplicates.push((i, j));
+                }
+            }
+        }
+        duplicates
+    }
+
+    /// Returns the numbers of notes and cards of the deck, grouped by model, with the cards of
+    /// each template, the empty fields and the tags of the notes, e.g. to check a deck before
+    /// it is written
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, Note, basic_optional_reversed_card_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut my_deck = Deck::new(1234, "Example deck", "");
+    /// let model = basic_optional_reversed_card_model();
+    /// my_deck.add_note(Note::new(model.clone(), vec!["der Hund", "the dog", "y"])?);
+    /// my_deck.add_note(Note::new(model, vec!["die Katze", "", ""])?.tags(["cats"])?);
+    ///
+    /// let stats = my_deck.stats();
+    /// assert_eq!((stats.notes, stats.cards), (2, 3));
+    /// assert_eq!(stats.models[0].templates[&1].cards, 1);
+    /// assert_eq!(stats.models[0].empty_fields[1], ("Back".to_string(), 1));
+    /// println!("{}", stats);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stats(&self) -> DeckStats {
+        DeckStats::new(&self.notes, &self.default_tags)
+    }
+
+    /// Returns the local media files referenced by the notes of the deck, see
+    /// [`Note::media_references`]
+    pub fn media_references(&self) -> Vec<MediaRef> {
+        self.notes.iter().flat_map(Note::media_references).collect()
+    }
+
+    pub(super) fn notes_mut(&mut self) -> std::slice::IterMut<'_, Note> {
+        self.notes.iter_mut()
+    }
+
+    pub(super) fn rename_media(&mut self, from: &str, to: &str) {
+        for note in &mut self.notes {
+            note.rename_media(from, to);
+        }
+    }
+
+    pub(super) fn to_deck_db_entry(&self) -> DeckDbEntry {
         DeckDbEntry {
-            collapsed: false,
+            collapsed: self.collapsed,
+            browser_collapsed: self.browser_collapsed,
             conf: 1,
             desc: self.description.clone(),
             deck_db_entry_dyn: 0,
-            extend_new: 0,
-            extend_rev: 50,
+            extend_new: self.extend_new.map_or(0, i64::from),
+            extend_rev: self.extend_rev.map_or(50, i64::from),
             id: self.id,
             lrn_today: vec![163, 2],
             deck_db_entry_mod: 1425278051,
@@ -73,48 +661,33 @@ impl Deck {
     #[allow(dead_code)]
     fn to_json(&self) -> String {
         let db_entry: DeckDbEntry = self.to_deck_db_entry();
+        // Invariant: the entry has only string keys and no values that can fail to serialize
         serde_json::to_string(&db_entry).expect("Should always serialize")
     }
 
+    /// Adds the deck and its options group to the collection, but not its notes, which
+    /// `PackageWriter` writes
     pub(super) fn write_to_db(
-        &mut self,
-        transaction: &Transaction,
-        timestamp: f64,
+        &self,
+        transaction: &Connection,
         id_gen: &mut RangeFrom<usize>,
     ) -> Result<(), Error> {
         let decks_json_str: String = transaction
             .query_row("SELECT decks FROM col", [], |row| row.get(0))
             .map_err(database_error)?;
-        let mut decks: HashMap<i64, DeckDbEntry> =
+        let mut decks: BTreeMap<i64, DeckDbEntry> =
             serde_json::from_str(&decks_json_str).map_err(json_error)?;
-        decks.insert(self.id, self.to_deck_db_entry());
+        let mut deck_db_entry = self.to_deck_db_entry();
+        if let Some(conf) = &self.conf {
+            deck_db_entry.conf = write_conf_to_db(transaction, conf, id_gen)?;
+        }
+        decks.insert(self.id, deck_db_entry);
         transaction
             .execute(
                 "UPDATE col SET decks = ?",
                 params![serde_json::to_string(&decks).map_err(json_error)?],
             )
             .map_err(database_error)?;
-
-        let models_json_str: String = transaction
-            .query_row("SELECT models FROM col", [], |row| row.get(0))
-            .map_err(database_error)?;
-        let mut models: HashMap<i64, ModelDbEntry> =
-            serde_json::from_str(&models_json_str).map_err(json_error)?;
-        for note in self.notes.clone().iter() {
-            self.add_model(note.model());
-        }
-        for (i, model) in &mut self.models {
-            models.insert(*i, model.to_model_db_entry(timestamp, self.id)?);
-        }
-        transaction
-            .execute(
-                "UPDATE col SET models = ?",
-                [serde_json::to_string(&models).map_err(json_error)?],
-            )
-            .map_err(database_error)?;
-        for note in &mut self.notes {
-            note.write_to_db(transaction, timestamp, self.id, id_gen)?;
-        }
         Ok(())
     }
 
@@ -123,26 +696,405 @@ impl Deck {
     /// Returns `Err` if the file can not be created.
     ///
     /// Example:
-    /// ```rust
+    /// ```rust,no_run
     /// use genanki_rs::{Deck, Note, basic_model};
     ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
     /// let mut my_deck = Deck::new(1234, "Example deck", "This is an example deck");
     /// my_deck.add_note(Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?);
     ///
     /// my_deck.write_to_file("output.apkg")?;
+    /// # Ok(())
+    /// # }
     /// ```
     ///
     /// This is equivalent to:
-    /// ```rust
+    /// ```rust,no_run
     /// use genanki_rs::{Deck, Note, basic_model, Package};
     ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
     /// let mut my_deck = Deck::new(1234, "Example deck", "This is an example deck");
     /// my_deck.add_note(Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?);
     ///
     /// Package::new(vec![my_deck], vec![])?.write_to_file("output.apkg")?;
+    /// # Ok(())
+    /// # }
     /// ```
     pub fn write_to_file<P: AsRef<Path>>(&self, file: P) -> Result<(), Error> {
         Package::new(vec![self.clone()], Vec::default())?.write_to_file(file)?;
         Ok(())
     }
 }
+
+/// Adds `conf` to the options groups of the collection, unless an equal one was already added
+///
+/// Returns the id of the options group.
+fn write_conf_to_db(
+    transaction: &Connection,
+    conf: &DeckConf,
+    id_gen: &mut RangeFrom<usize>,
+) -> Result<i64, Error> {
+    let dconf_json_str: String = transaction
+        .query_row("SELECT dconf FROM col", [], |row| row.get(0))
+        .map_err(database_error)?;
+    let mut dconf: BTreeMap<i64, DeckConfDbEntry> =
+        serde_json::from_str(&dconf_json_str).map_err(json_error)?;
+    let unassigned = conf.to_db_entry(0);
+    let existing = dconf.values().find(|entry| {
+        let mut entry = (*entry).clone();
+        entry.id = 0;
+        entry == unassigned
+    });
+    if let Some(entry) = existing {
+        return Ok(entry.id);
+    }
+    // Invariant: `id_gen` is an unbounded range
+    let id = id_gen.next().expect("Should always have a next id") as i64;
+    dconf.insert(id, conf.to_db_entry(id));
+    transaction
+        .execute(
+            "UPDATE col SET dconf = ?",
+            [serde_json::to_string(&dconf).map_err(json_error)?],
+        )
+        .map_err(database_error)?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apkg_col::APKG_COL;
+    use crate::apkg_schema::APKG_SCHEMA;
+    use crate::timestamp::Timestamp;
+    use crate::{basic_and_reversed_card_model, basic_model, NewCardOrder};
+    use tempfile::NamedTempFile;
+
+    fn note(front: &str, back: &str) -> Note {
+        Note::new(basic_model(), vec![front, back]).unwrap()
+    }
+
+    /// Writes `deck` and its notes, with increasing positions like `PackageWriter`
+    fn write_deck(deck: &Deck, conn: &mut Connection) {
+        let transaction = conn.transaction().unwrap();
+        let mut id_gen = 1000..;
+        deck.write_to_db(&transaction, &mut id_gen).unwrap();
+        for (position, note) in deck.notes().enumerate() {
+            note.write_to_db(
+                &transaction,
+                Timestamp::from_secs(1),
+                deck.id,
+                &deck.default_tags,
+                position as i64,
+                &mut id_gen,
+            )
+            .unwrap();
+        }
+        transaction.commit().unwrap();
+    }
+
+    #[test]
+    fn add_note_dedup_identical_fields() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        assert!(deck
+            .add_note_dedup(note("Capital of France?", "Paris"))
+            .unwrap());
+        assert!(!deck
+            .add_note_dedup(note("Capital of France?", "Paris"))
+            .unwrap());
+        assert!(deck
+            .add_note_dedup(note("Capital of France?", "paris"))
+            .unwrap());
+        assert_eq!(deck.notes.len(), 2);
+    }
+
+    #[test]
+    fn add_note_dedup_explicit_guid() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        assert!(deck.add_note_dedup(note("a", "b").guid("id-1")).unwrap());
+        assert!(!deck.add_note_dedup(note("c", "d").guid("id-1")).unwrap());
+        assert!(deck.add_note_dedup(note("a", "b")).unwrap());
+        assert_eq!(deck.notes.len(), 2);
+    }
+
+    #[test]
+    fn add_note_dedup_by_first_field() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(note("<b>Hund</b>", "dog"));
+        assert!(!deck
+            .add_note_dedup_by(note("Hund", "hound"), DedupBy::FirstField)
+            .unwrap());
+        assert!(deck
+            .add_note_dedup_by(note("Hund", "hound"), DedupBy::Guid)
+            .unwrap());
+    }
+
+    #[test]
+    fn add_note_keeps_duplicates() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(note("a", "b"));
+        deck.add_note(note("x", "y").guid("id-1"));
+        deck.add_note(note("a", "b"));
+        deck.add_note(note("a&nbsp;", "c"));
+        deck.add_note(note("z", "y").guid("id-1"));
+        assert_eq!(deck.notes.len(), 5);
+        assert_eq!(deck.find_duplicates(), vec![(0, 2), (1, 4)]);
+    }
+
+    #[test]
+    fn cards_due_positions() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(
+            Note::new(basic_and_reversed_card_model(), vec!["der Hund", "the dog"]).unwrap(),
+        );
+        deck.add_note(note("die Katze", "the cat").due(10));
+        deck.add_note(note("die Maus", "the mouse"));
+
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let mut conn = Connection::open(&db_file).unwrap();
+        conn.execute_batch(APKG_SCHEMA).unwrap();
+        conn.execute_batch(APKG_COL).unwrap();
+        write_deck(&deck, &mut conn);
+        let dues: Vec<(i64, i64)> = conn
+            .prepare("SELECT due, ord FROM cards ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(dues, vec![(0, 0), (0, 1), (10, 0), (2, 0)]);
+    }
+
+    fn written_descriptions(deck: &mut Deck) -> HashMap<i64, String> {
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let mut conn = Connection::open(&db_file).unwrap();
+        conn.execute_batch(APKG_SCHEMA).unwrap();
+        conn.execute_batch(APKG_COL).unwrap();
+        write_deck(deck, &mut conn);
+        let decks: String = conn
+            .query_row("SELECT decks FROM col", [], |row| row.get(0))
+            .unwrap();
+        let decks: HashMap<i64, DeckDbEntry> = serde_json::from_str(&decks).unwrap();
+        decks
+            .into_iter()
+            .map(|(id, deck)| (id, deck.desc))
+            .collect()
+    }
+
+    #[test]
+    fn description_html() {
+        let html = format!(
+            "<p class=\"intro\">Nouns from 'chapter 1'</p>\n\t{}\\",
+            "<b>long</b> ".repeat(2000)
+        );
+        let mut deck = Deck::new(1234, "Example deck", "plain").description(&html);
+        assert_eq!(written_descriptions(&mut deck)[&1234], html);
+    }
+
+    #[test]
+    #[cfg(feature = "markdown")]
+    fn description_markdown() {
+        let mut deck = Deck::new(1234, "Example deck", "")
+            .description_markdown("Nouns from *chapter 1*\n\n- der Hund");
+        assert_eq!(
+            written_descriptions(&mut deck)[&1234],
+            "<p>Nouns from <em>chapter 1</em></p>\n<ul>\n<li>der Hund</li>\n</ul>\n"
+        );
+    }
+
+    #[test]
+    fn shared_conf() {
+        let conf = DeckConf::new("Vocabulary")
+            .new_per_day(30)
+            .reviews_per_day(300)
+            .learning_steps(&[1.0, 10.0, 1440.0])
+            .relearning_steps(&[5.0])
+            .new_card_order(NewCardOrder::Random)
+            .graduating_interval(2)
+            .easy_interval(5);
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let mut conn = Connection::open(&db_file).unwrap();
+        conn.execute_batch(APKG_SCHEMA).unwrap();
+        conn.execute_batch(APKG_COL).unwrap();
+        let transaction = conn.transaction().unwrap();
+        let mut id_gen = 1000..;
+        for (id, conf) in &[
+            (1, Some(conf.clone())),
+            (2, Some(conf)),
+            (3, None),
+            (4, Some(DeckConf::new("Default"))),
+        ] {
+            let mut deck = Deck::new(*id, format!("Deck {}", id), "");
+            deck.conf = conf.clone();
+            deck.write_to_db(&transaction, &mut id_gen).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        let (decks, dconf): (String, String) = conn
+            .query_row("SELECT decks, dconf FROM col", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        let decks: HashMap<i64, serde_json::Value> = serde_json::from_str(&decks).unwrap();
+        let conf_ids: Vec<i64> = (1..=4)
+            .map(|id| decks[&id]["conf"].as_i64().unwrap())
+            .collect();
+        assert_eq!(conf_ids, vec![1000, 1000, 1, 1]);
+
+        let dconf: HashMap<String, serde_json::Value> = serde_json::from_str(&dconf).unwrap();
+        assert_eq!(dconf.len(), 2);
+        let conf = &dconf["1000"];
+        assert_eq!(conf["id"], 1000);
+        assert_eq!(conf["name"], "Vocabulary");
+        assert_eq!(conf["new"]["perDay"], 30);
+        assert_eq!(conf["rev"]["perDay"], 300);
+        assert_eq!(
+            conf["new"]["delays"],
+            serde_json::json!([1.0, 10.0, 1440.0])
+        );
+        assert_eq!(conf["lapse"]["delays"], serde_json::json!([5.0]));
+        assert_eq!(conf["new"]["order"], 0);
+        assert_eq!(conf["new"]["ints"], serde_json::json!([2, 5, 7]));
+        assert_eq!(conf["rev"]["maxIvl"], 36500);
+    }
+
+    #[test]
+    fn counts() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        assert_eq!((deck.note_count(), deck.card_count()), (0, 0));
+        deck.add_note(note("a", "b"));
+        deck.add_note(
+            Note::new(
+                crate::cloze_model(),
+                vec!["{{c1::a}} {{c3::b}} {{c3::c}} {{c4::d}}", ""],
+            )
+            .unwrap(),
+        );
+        assert_eq!((deck.note_count(), deck.card_count()), (2, 4));
+        assert_eq!(
+            deck.notes()
+                .map(|note| note.fields()[0].as_str())
+                .collect::<Vec<_>>(),
+            vec!["a", "{{c1::a}} {{c3::b}} {{c3::c}} {{c4::d}}"]
+        );
+        assert_eq!((deck.id(), deck.name()), (1234, "Example deck"));
+    }
+
+    #[test]
+    fn retain_and_remove_notes() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(note("der Hund", "the dog"));
+        deck.add_note(note("die Katze", ""));
+        deck.add_note(note("die Maus", "the mouse").guid("maus"));
+        deck.add_note(note("das Pferd", "the horse"));
+        deck.retain_notes(|note| !note.fields()[1].is_empty());
+        assert_eq!(deck.card_count(), 3);
+        let removed = deck.remove_note_by_guid("maus").unwrap();
+        assert_eq!(removed.fields()[0], "die Maus");
+        assert!(deck.remove_note_by_guid("maus").is_none());
+        assert_eq!(deck.card_count(), 2);
+
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let mut conn = Connection::open(&db_file).unwrap();
+        conn.execute_batch(APKG_SCHEMA).unwrap();
+        conn.execute_batch(APKG_COL).unwrap();
+        write_deck(&deck, &mut conn);
+        let fields: Vec<String> = conn
+            .prepare("SELECT flds FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            fields,
+            vec!["der Hund\x1fthe dog", "das Pferd\x1fthe horse"]
+        );
+    }
+
+    fn first_fields(deck: &Deck) -> Vec<&str> {
+        deck.notes().map(|note| note.fields()[0].as_str()).collect()
+    }
+
+    #[test]
+    fn extend_disjoint() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(note("a", "b"));
+        let mut other = Deck::new(1235, "Other deck", "");
+        other.add_note(note("c", "d"));
+        other.add_note(Note::new(crate::cloze_model(), vec!["{{c1::e}}", ""]).unwrap());
+        deck.extend(other, MergePolicy::Error).unwrap();
+        assert_eq!(first_fields(&deck), vec!["a", "c", "{{c1::e}}"]);
+    }
+
+    #[test]
+    fn extend_guid_conflicts() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(note("a", "b").guid("x"));
+        deck.add_note(note("c", "d"));
+        let mut other = Deck::new(1235, "Other deck", "");
+        other.add_note(note("e", "f").guid("x"));
+        other.add_note(note("c", "d"));
+        other.add_note(note("g", "h"));
+
+        let mut keep_first = deck.clone();
+        keep_first
+            .extend(other.clone(), MergePolicy::KeepFirst)
+            .unwrap();
+        assert_eq!(first_fields(&keep_first), vec!["a", "c", "g"]);
+
+        let mut keep_last = deck.clone();
+        keep_last
+            .extend(other.clone(), MergePolicy::KeepLast)
+            .unwrap();
+        assert_eq!(first_fields(&keep_last), vec!["e", "c", "g"]);
+
+        assert!(matches!(
+            deck.extend(other, MergePolicy::Error),
+            Err(Error::DuplicateNoteGuid { guid }) if guid == "x"
+        ));
+        assert_eq!(first_fields(&deck), vec!["a", "c"]);
+
+        let mut other = Deck::new(1235, "Other deck", "");
+        other.add_note(note("e", "f").guid("y"));
+        other.add_note(note("g", "h"));
+        other.add_note(note("i", "j").guid("y"));
+        let mut keep_first = deck.clone();
+        keep_first
+            .extend(other.clone(), MergePolicy::KeepFirst)
+            .unwrap();
+        assert_eq!(first_fields(&keep_first), vec!["a", "c", "e", "g"]);
+        let mut keep_last = deck.clone();
+        keep_last
+            .extend(other.clone(), MergePolicy::KeepLast)
+            .unwrap();
+        assert_eq!(first_fields(&keep_last), vec!["a", "c", "i", "g"]);
+        assert!(matches!(
+            deck.extend(other, MergePolicy::Error),
+            Err(Error::DuplicateNoteGuid { guid }) if guid == "y"
+        ));
+        assert_eq!(first_fields(&deck), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn extend_model_id_conflict() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(note("a", "b"));
+        let mut other = Deck::new(1235, "Other deck", "");
+        let renamed = basic_model().rename("Basic (renamed)");
+        other.add_note(Note::new(renamed, vec!["c", "d"]).unwrap());
+        assert!(matches!(
+            deck.extend(other, MergePolicy::KeepFirst),
+            Err(Error::ModelIdConflict { name, other_name, .. })
+                if name == "Basic (genanki)" && other_name == "Basic (renamed)"
+        ));
+        assert_eq!(deck.note_count(), 1);
+    }
+
+    #[test]
+    fn id_from_name() {
+        assert_eq!(Deck::id_from_name("German"), 7237041979772557);
+        assert_eq!(Deck::id_from_name("Japanese::Vocab"), 4434699623503261);
+        let deck = Deck::new_with_id_from_name("German", "");
+        assert_eq!((deck.id(), deck.name()), (7237041979772557, "German"));
+    }
+}
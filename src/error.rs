@@ -1,8 +1,10 @@
-use std::{convert::Infallible, time::SystemTimeError};
+use std::{convert::Infallible, path::PathBuf, time::SystemTimeError};
 
 use zip::result::ZipError;
 
 use crate::db_entries::Tmpl;
+use crate::media::MediaIssue;
+use crate::validation::IdKind;
 
 // Make sure `Error` is `Send` and `Sync`
 const fn _assert_send<T: Send>() {}
@@ -10,6 +12,11 @@ const fn _assert_sync<T: Sync>() {}
 const _: () = _assert_send::<Error>();
 const _: () = _assert_sync::<Error>();
 
+/// The errors of this crate
+///
+/// Each variant describes one kind of failure, so that e.g. an invalid CSV row
+/// (`Error::InvalidCsvRow`) can be told apart from a full disk (`Error::Io`). Errors of the
+/// underlying libraries are available through [`std::error::Error::source`].
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 pub enum Error {
@@ -19,23 +26,166 @@ pub enum Error {
     /// cast to a Box<dyn std::error::Error> so that we can change
     /// the underlying library in the future if needed without breaking
     /// client code.
-    #[error(transparent)]
-    Database(Box<dyn std::error::Error + Send + Sync>),
+    #[error("collection database error: {0}")]
+    Database(#[source] Box<dyn std::error::Error + Send + Sync>),
     /// Indicates an error happened with the JSON parser
     ///
     /// Currently the argument is a `serde_json::Error`, but it is
     /// cast to a Box<dyn std::error::Error> so that we can change
     /// the underlying library in the future if needed without breaking
     /// client code.
-    #[error(transparent)]
-    JsonParser(Box<dyn std::error::Error + Send + Sync>),
+    #[error("JSON error: {0}")]
+    JsonParser(#[source] Box<dyn std::error::Error + Send + Sync>),
     #[error("Could not compute required fields for this template; please check the formatting of \"qfmt\": {0:?}")]
-    TemplateFormat(Tmpl),
-    #[error("number of model field ({0}) does not match number of fields ({1})")]
-    ModelFieldCountMismatch(usize, usize),
+    TemplateFormat(Box<Tmpl>),
+    #[error("the question format of template \"{template_name}\" does not reference any field")]
+    TemplateHasNoFields { template_name: String },
+    #[error("cloze models must have exactly one template, found {0}")]
+    ClozeTemplateCount(usize),
+    #[error("the question format of cloze template \"{template_name}\" contains no {{{{cloze:...}}}} replacement")]
+    ClozeTemplateWithoutCloze { template_name: String },
+    #[error("syntax error in template \"{template_name}\" at offset {offset} ({tag}): {message}")]
+    TemplateSyntax {
+        template_name: String,
+        message: String,
+        tag: String,
+        offset: usize,
+    },
+    #[error("template \"{template_name}\" references unknown field \"{field_name}\"; known fields are {known_fields:?}")]
+    UnknownTemplateField {
+        template_name: String,
+        field_name: String,
+        known_fields: Vec<String>,
+    },
+    #[error("duplicate field name \"{name}\" at index {index}")]
+    DuplicateFieldName { name: String, index: usize },
+    #[error("duplicate template name \"{name}\" at index {index}")]
+    DuplicateTemplateName { name: String, index: usize },
+    #[error("templates \"{first}\" and \"{second}\" both have the ord {ord}")]
+    DuplicateTemplateOrd {
+        ord: i64,
+        first: String,
+        second: String,
+    },
+    #[error(
+        "ord {ord} of template \"{template_name}\" is out of range for a model with {template_count} templates"
+    )]
+    TemplateOrdOutOfRange {
+        template_name: String,
+        ord: i64,
+        template_count: usize,
+    },
+    #[error("index {index} is out of range for a list of length {len}")]
+    IndexOutOfRange { index: usize, len: usize },
+    #[error("sort field index {index} is out of range for a model with {field_count} fields")]
+    SortFieldOutOfRange { index: i64, field_count: usize },
+    #[error("field {field_index} contains a cloze deletion, but the model is not a cloze model")]
+    ClozeInNonClozeModel { field_index: usize },
+    #[error("none of the cloze fields of the note contain a cloze deletion")]
+    NoClozeDeletionsFound,
+    #[error("note field \"{key}\" does not exist on the model; known fields are {known_fields:?}")]
+    UnknownNoteField {
+        key: String,
+        known_fields: Vec<String>,
+    },
+    #[error("no value for the note field \"{name}\"")]
+    MissingNoteField { name: String },
+    #[error("model \"{model_name}\" ({model_id}) has {expected} fields, but the note has {got}; first field of the note: \"{first_field}\"")]
+    FieldCountMismatch {
+        model_name: String,
+        model_id: i64,
+        expected: usize,
+        got: usize,
+        first_field: String,
+    },
     #[error("One of the tags contains whitespace, this is not allowed!")]
     TagContainsWhitespace,
-    #[error(transparent)]
+    #[error("card flag {0} is invalid, flags are numbers from 0 (no flag) to 7")]
+    InvalidCardFlag(u8),
+    #[error("ease factor {0} is invalid, Anki's lowest ease factor is 1300 (130%)")]
+    InvalidEaseFactor(u32),
+    #[error("two different models named {name:?} and {other_name:?} have the same id {id}")]
+    ModelIdConflict {
+        id: i64,
+        name: String,
+        other_name: String,
+    },
+    #[error("both decks contain a note with the GUID {guid:?}")]
+    DuplicateNoteGuid { guid: String },
+    #[error("the decks {:?} and {:?} have the same id {id}", names.0, names.1)]
+    DuplicateDeckId { id: i64, names: (String, String) },
+    /// The id of a model or deck isn't in `1..=MAX_ID`, see [`MAX_ID`](crate::MAX_ID)
+    #[error("the {kind} id {id} isn't between 1 and 2^53 - 1")]
+    IdOutOfRange { kind: IdKind, id: i64 },
+    /// Two different media files have the same name; the sources are `None` for files added
+    /// from bytes or readers
+    #[error("different media files are named {name:?}: {first_source:?} and {second_source:?}")]
+    MediaNameCollision {
+        name: String,
+        first_source: Option<PathBuf>,
+        second_source: Option<PathBuf>,
+    },
+    #[error("media files referenced by notes are missing: {filenames:?}")]
+    MissingMediaFiles { filenames: Vec<String> },
+    #[error("media files are invalid: {issues:?}")]
+    InvalidMedia { issues: Vec<MediaIssue> },
+    #[error("media file path {path:?} doesn't end in a file name")]
+    InvalidMediaPath { path: PathBuf },
+    /// The file name of a media file isn't valid UTF-8, which the names in a package have to be
+    #[error("the file name of the media file {path:?} isn't valid UTF-8")]
+    NonUtf8MediaFilename { path: PathBuf },
+    /// A media file name contains `/`, `\` or NUL, which Anki's media database can't hold
+    #[error("the media file name {name:?} contains a path separator or NUL")]
+    InvalidMediaFilename { name: String },
+    #[error("media file {path:?} doesn't exist")]
+    MediaNotFound {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("the package doesn't contain a deck with the id {0}")]
+    UnknownDeckId(i64),
+    #[error("the note has {cards} cards, but {deck_ids} deck ids were given for them")]
+    CardRoutingMismatch { cards: usize, deck_ids: usize },
+    #[error("deck name {name:?} is invalid, the names of parent decks can't be empty")]
+    InvalidDeckName { name: String },
+    #[error("the package has no decks")]
+    NoDecks,
+    #[error(
+        "field {field_index} of the note {guid:?} is {len} bytes long, the limit is {limit} bytes"
+    )]
+    FieldTooLarge {
+        guid: String,
+        field_index: usize,
+        len: usize,
+        limit: usize,
+    },
+    #[error("the package can't be read: {0}")]
+    InvalidPackage(String),
+    #[error("the written package is invalid: {detail}")]
+    VerificationFailed { detail: String },
+    #[error("the spec is invalid at {path}: {message}")]
+    InvalidSpec { path: String, message: String },
+    #[error("row on line {line} of the CSV file is invalid: {message}")]
+    InvalidCsvRow { line: u64, message: String },
+    #[error("the notes use more than one model: {first:?} and {second:?}")]
+    MixedModels { first: String, second: String },
+    /// The size of an image of [`ImageOcclusion`](crate::image_occlusion::ImageOcclusion) can't
+    /// be read, because it isn't a PNG, JPEG or GIF image
+    #[error("the size of the image {name:?} can't be read, it has to be set")]
+    UnknownImageSize { name: String },
+    #[error("the LaTeX in field {field} of note {index} of deck {deck_id} couldn't be rendered: {output}")]
+    LatexRenderFailed {
+        deck_id: i64,
+        index: usize,
+        field: String,
+        output: String,
+    },
+    #[error("the AnkiConnect action {action} failed: {message}")]
+    AnkiConnect { action: String, message: String },
+    #[error("note {index} couldn't be pushed to Anki: {message}")]
+    AnkiConnectNote { index: usize, message: String },
+    #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
     /// Indicates an error with the underlying template system
     ///
@@ -43,9 +193,9 @@ pub enum Error {
     /// cast to a Box<dyn std::error::Error> so that we can change
     /// the underlying library in the future if needed without breaking
     /// client code.
-    #[error(transparent)]
+    #[error("template error: {0}")]
     Template(#[from] Box<dyn std::error::Error + Send + Sync>),
-    #[error(transparent)]
+    #[error("the system clock is set before 1970: {0}")]
     SystemTime(#[from] SystemTimeError),
     /// Indicates an error with zip file handling
     ///
@@ -53,8 +203,8 @@ pub enum Error {
     /// cast to a Box<dyn std::error::Error> so that we can change
     /// the underlying library in the future if needed without breaking
     /// client code.
-    #[error(transparent)]
-    Zip(Box<dyn std::error::Error + Send + Sync>),
+    #[error("zip archive error: {0}")]
+    Zip(#[source] Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl From<Infallible> for Error {
@@ -65,13 +215,115 @@ impl From<Infallible> for Error {
 }
 
 pub(crate) fn database_error(e: rusqlite::Error) -> Error {
-    Error::Template(Box::new(e))
+    Error::Database(Box::new(e))
 }
 
 pub(crate) fn json_error(e: serde_json::Error) -> Error {
-    Error::Template(Box::new(e))
+    Error::JsonParser(Box::new(e))
 }
 
 pub(crate) fn zip_error(e: ZipError) -> Error {
     Error::Zip(Box::new(e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_model, Deck, Note, Package};
+    use std::error::Error as _;
+
+    #[test]
+    fn display() {
+        let messages = [
+            (
+                Error::FieldCountMismatch {
+                    model_name: "Basic".to_string(),
+                    model_id: 1234,
+                    expected: 2,
+                    got: 3,
+                    first_field: "der Hund".to_string(),
+                },
+                "model \"Basic\" (1234) has 2 fields, but the note has 3; first field of the note: \"der Hund\"",
+            ),
+            (
+                Error::DuplicateFieldName {
+                    name: "Front".to_string(),
+                    index: 1,
+                },
+                "duplicate field name \"Front\" at index 1",
+            ),
+            (
+                Error::TemplateSyntax {
+                    template_name: "Card 1".to_string(),
+                    message: "unclosed section".to_string(),
+                    tag: "{{#Front}}".to_string(),
+                    offset: 4,
+                },
+                "syntax error in template \"Card 1\" at offset 4 ({{#Front}}): unclosed section",
+            ),
+            (
+                Error::InvalidCsvRow {
+                    line: 3,
+                    message: "the row has 3 columns instead of 2".to_string(),
+                },
+                "row on line 3 of the CSV file is invalid: the row has 3 columns instead of 2",
+            ),
+            (
+                Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "No space left on device",
+                )),
+                "I/O error: No space left on device",
+            ),
+            (
+                database_error(rusqlite::Error::InvalidQuery),
+                "collection database error: Query is not read-only",
+            ),
+        ];
+        for (error, message) in &messages {
+            assert_eq!(&error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn source() {
+        let error = Error::Io(std::io::Error::other("disk full"));
+        assert_eq!(error.source().unwrap().to_string(), "disk full");
+        let error = database_error(rusqlite::Error::InvalidQuery);
+        assert!(error
+            .source()
+            .unwrap()
+            .downcast_ref::<rusqlite::Error>()
+            .is_some());
+        assert!(Error::NoDecks.source().is_none());
+
+        fn with_anyhow() -> anyhow::Result<()> {
+            Err(Error::NoDecks)?
+        }
+        assert_eq!(
+            with_anyhow().unwrap_err().to_string(),
+            "the package has no decks"
+        );
+    }
+
+    #[test]
+    fn media_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("dog.jpg");
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(Note::new(basic_model(), vec!["<img src=\"dog.jpg\">", "dog"]).unwrap());
+        let mut package = Package::new(vec![deck], vec![missing.to_str().unwrap()]).unwrap();
+        match package.write_to_file(dir.path().join("output.apkg")) {
+            Err(error @ Error::MediaNotFound { .. }) => {
+                assert_eq!(
+                    error.to_string(),
+                    format!("media file {:?} doesn't exist", missing)
+                );
+                let source = error.source().unwrap();
+                let source = source.downcast_ref::<std::io::Error>().unwrap();
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("expected a missing media file, got {:?}", other),
+        }
+    }
+}
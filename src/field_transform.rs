@@ -0,0 +1,63 @@
+use std::fmt;
+
+use crate::util::escape_html;
+
+/// A cleanup of the text of a field, see [`Note::with_transforms`](crate::Note::with_transforms)
+///
+/// Transforms are applied in the order they are given, so `[HtmlEscape, NewlinesToBr]` escapes
+/// the text and then adds `<br>` tags, while `[NewlinesToBr, HtmlEscape]` would escape the tags
+/// too.
+pub enum FieldTransform {
+    /// Removes whitespace at the start and the end
+    Trim,
+    /// Replaces line breaks with `<br>`, which Anki shows as line breaks
+    NewlinesToBr,
+    /// Escapes `&`, `<`, `>` and `"`, so that raw text shows up literally instead of as HTML
+    HtmlEscape,
+    /// Replaces the field with what the function returns for it
+    Custom(Box<dyn Fn(&str) -> String>),
+}
+
+impl FieldTransform {
+    pub(crate) fn apply(&self, field: &str) -> String {
+        match self {
+            FieldTransform::Trim => field.trim().to_string(),
+            FieldTransform::NewlinesToBr => field.replace("\r\n", "<br>").replace('\n', "<br>"),
+            FieldTransform::HtmlEscape => escape_html(field),
+            FieldTransform::Custom(transform) => transform(field),
+        }
+    }
+}
+
+impl fmt::Debug for FieldTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldTransform::Trim => f.write_str("Trim"),
+            FieldTransform::NewlinesToBr => f.write_str("NewlinesToBr"),
+            FieldTransform::HtmlEscape => f.write_str("HtmlEscape"),
+            FieldTransform::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let field = "  Tom & <Jerry>\r\nmeow \n";
+        assert_eq!(FieldTransform::Trim.apply(field), "Tom & <Jerry>\r\nmeow");
+        assert_eq!(
+            FieldTransform::NewlinesToBr.apply(field),
+            "  Tom & <Jerry><br>meow <br>"
+        );
+        assert_eq!(
+            FieldTransform::HtmlEscape.apply(field),
+            "  Tom &amp; &lt;Jerry&gt;\r\nmeow \n"
+        );
+        let upper = FieldTransform::Custom(Box::new(|field| field.to_uppercase()));
+        assert_eq!(upper.apply("meow"), "MEOW");
+        assert_eq!(format!("{:?}", upper), "Custom(..)");
+    }
+}
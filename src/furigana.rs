@@ -0,0 +1,132 @@
+//! Readings of Japanese text in the `word[reading]` convention of the Japanese Support add-on
+//!
+//! Anki shows the readings of a field with the template filters `{{furigana:Field}}`,
+//! `{{kana:Field}}` and `{{kanji:Field}}`. These functions do the same to the text of a field,
+//! e.g. for fields that should show the readings without the filters.
+//!
+//! A reading belongs to the word right before it, which starts after the last space or `>`. The
+//! space before the word is removed, so `日本語 の 漢字[かんじ]` reads `日本語 の` and then
+//! `漢字` with its reading. `[[` and `]]` are literal brackets, and `[sound:...]` stays as it
+//! is.
+//!
+//! Example:
+//!
+//! ```rust
+//! use genanki_rs::furigana;
+//!
+//! let text = "日本語[にほんご]を 勉強[べんきょう]する";
+//! assert_eq!(
+//!     furigana::to_ruby(text),
+//!     "<ruby><rb>日本語</rb><rt>にほんご</rt></ruby>を<ruby><rb>勉強</rb><rt>べんきょう</rt></ruby>する"
+//! );
+//! assert_eq!(furigana::strip_readings(text), "日本語を勉強する");
+//! assert_eq!(furigana::readings_only(text), "にほんごをべんきょうする");
+//! ```
+
+/// A part of a text with readings
+#[derive(Debug, PartialEq, Eq)]
+enum Segment<'a> {
+    Text(String),
+    Reading { word: String, reading: &'a str },
+}
+
+/// Converts the readings in `text` to `<ruby>` HTML, like `{{furigana:Field}}`
+pub fn to_ruby(text: &str) -> String {
+    render(text, |word, reading| {
+        format!("<ruby><rb>{}</rb><rt>{}</rt></ruby>", word, reading)
+    })
+}
+
+/// Removes the readings from `text` and keeps the words, like `{{kanji:Field}}`
+pub fn strip_readings(text: &str) -> String {
+    render(text, |word, _| word.to_string())
+}
+
+/// Replaces the words with readings in `text` with their readings, like `{{kana:Field}}`
+pub fn readings_only(text: &str) -> String {
+    render(text, |_, reading| reading.to_string())
+}
+
+fn render(text: &str, reading: impl Fn(&str, &str) -> String) -> String {
+    let text = text.replace("&nbsp;", " ");
+    parse(&text)
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => text,
+            Segment::Reading { word, reading: r } => reading(&word, r),
+        })
+        .collect()
+}
+
+fn parse(text: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    let mut pending = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("[[").or_else(|| rest.strip_prefix("]]")) {
+            pending.push(c);
+            rest = after;
+            continue;
+        }
+        let reading = rest
+            .strip_prefix('[')
+            .and_then(|after| after.split_once(']'))
+            .filter(|(reading, _)| !reading.is_empty() && !reading.starts_with("sound:"));
+        let word_start = pending.rfind([' ', '>']).map_or(0, |index| index + 1);
+        match reading {
+            Some((reading, after)) if word_start < pending.len() => {
+                let word = pending.split_off(word_start);
+                if pending.ends_with(' ') {
+                    pending.pop();
+                }
+                segments.push(Segment::Text(std::mem::take(&mut pending)));
+                segments.push(Segment::Reading { word, reading });
+                rest = after;
+            }
+            _ => {
+                pending.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    segments.push(Segment::Text(pending));
+    segments.retain(|segment| segment != &Segment::Text(String::new()));
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentences() {
+        let text = "今日[きょう]は いい 天気[てんき]ですね。";
+        assert_eq!(
+            to_ruby(text),
+            "<ruby><rb>今日</rb><rt>きょう</rt></ruby>は いい<ruby><rb>天気</rb><rt>てんき</rt></ruby>ですね。"
+        );
+        assert_eq!(strip_readings(text), "今日は いい天気ですね。");
+        assert_eq!(readings_only(text), "きょうは いいてんきですね。");
+
+        let text = "<b>漢[かん]字[じ]</b>&nbsp;と ひらがな";
+        assert_eq!(
+            to_ruby(text),
+            "<b><ruby><rb>漢</rb><rt>かん</rt></ruby><ruby><rb>字</rb><rt>じ</rt></ruby></b> と ひらがな"
+        );
+        assert_eq!(strip_readings(text), "<b>漢字</b> と ひらがな");
+        assert_eq!(readings_only(text), "<b>かんじ</b> と ひらがな");
+    }
+
+    #[test]
+    fn brackets() {
+        assert_eq!(
+            to_ruby("配列[[0]] と 東京[とうきょう]"),
+            "配列[0] と<ruby><rb>東京</rb><rt>とうきょう</rt></ruby>"
+        );
+        assert_eq!(strip_readings("[[注]] 猫[ねこ]"), "[注]猫");
+        assert_eq!(to_ruby("猫 [sound:neko.mp3]"), "猫 [sound:neko.mp3]");
+        assert_eq!(to_ruby(" [よみ] 空[]"), " [よみ] 空[]");
+        assert_eq!(to_ruby("未完[みかん"), "未完[みかん");
+        assert_eq!(readings_only(""), "");
+    }
+}
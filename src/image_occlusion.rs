@@ -0,0 +1,541 @@
+//! Notes that hide parts of an image, in the layout of the "Image Occlusion Enhanced" add-on
+//!
+//! Each mask of an [`ImageOcclusion`] becomes a note with one card, which shows the image with
+//! the mask in red and asks what is under it. The masks are SVG files that are laid over the
+//! image, so the notes use the same fields and HTML ids as the notes of the add-on and its CSS
+//! applies to them.
+//!
+//! Example:
+//!
+//! ```rust,no_run
+//! use genanki_rs::image_occlusion::{ImageOcclusion, Shape};
+//! use genanki_rs::{Deck, Package};
+//!
+//! # fn main() -> Result<(), genanki_rs::Error> {
+//! let occlusion = ImageOcclusion::new("heart.png", std::fs::read("heart.png")?)
+//!     .header("The heart")
+//!     .mask(Shape::rect(10.0, 10.0, 40.0, 20.0), "Aorta")
+//!     .mask(Shape::polygon(vec![(60.0, 50.0), (90.0, 50.0), (75.0, 80.0)]), "Left ventricle")
+//!     .build()?;
+//!
+//! let mut deck = Deck::new(1234, "Anatomy", "");
+//! for note in occlusion.notes {
+//!     deck.add_note(note);
+//! }
+//! let mut package = Package::new(vec![deck], vec![])?;
+//! for (name, bytes) in occlusion.media {
+//!     package.add_media_bytes(&name, bytes)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use sha1::{Digest, Sha1};
+
+use crate::media::check_media_filename;
+use crate::util::{escape_html, guid_for};
+use crate::{Error, Field, Model, Note, Template};
+
+/// The fill of the masks that are hidden, but not asked for
+const MASK_FILL: &str = "#FFEBA2";
+/// The fill of the mask that is asked for
+const QUESTION_FILL: &str = "#FF7E7E";
+const STROKE: &str = "#2D2D2D";
+
+/// The area of the image a mask covers, in pixels from the top left corner of the image
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    Rect {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    },
+    /// The corners of the polygon, as `(x, y)`
+    Polygon(Vec<(f64, f64)>),
+}
+
+impl Shape {
+    pub fn rect(x: f64, y: f64, width: f64, height: f64) -> Self {
+        Self::Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn polygon(points: Vec<(f64, f64)>) -> Self {
+        Self::Polygon(points)
+    }
+
+    /// The middle of the bounding box, where the label is shown
+    fn center(&self) -> (f64, f64) {
+        match self {
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => (x + width / 2.0, y + height / 2.0),
+            Shape::Polygon(points) => {
+                let middle = |coordinate: fn(&(f64, f64)) -> f64| {
+                    let values = points.iter().map(coordinate);
+                    let min = values.clone().fold(f64::INFINITY, f64::min);
+                    let max = values.fold(f64::NEG_INFINITY, f64::max);
+                    (min + max) / 2.0
+                };
+                (middle(|point| point.0), middle(|point| point.1))
+            }
+        }
+    }
+
+    fn to_svg(&self, fill: &str, class: Option<&str>) -> String {
+        let class = class.map_or(String::new(), |class| format!(" class=\"{}\"", class));
+        let style = format!("fill=\"{}\" stroke=\"{}\"{}", fill, STROKE, class);
+        match self {
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" {}/>",
+                x, y, width, height, style
+            ),
+            Shape::Polygon(points) => {
+                let points: Vec<String> =
+                    points.iter().map(|(x, y)| format!("{},{}", x, y)).collect();
+                format!("<polygon points=\"{}\" {}/>", points.join(" "), style)
+            }
+        }
+    }
+}
+
+/// Which masks the cards hide, named like the modes of the add-on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OcclusionMode {
+    /// The question hides all masks, the answer shows the asked one and hides the others
+    #[default]
+    HideAllGuessOne,
+    /// The question only hides the asked mask, the answer shows the whole image
+    HideOneGuessOne,
+}
+
+impl OcclusionMode {
+    /// The abbreviation of the mode in the ids and file names of the add-on
+    fn abbreviation(self) -> &'static str {
+        match self {
+            OcclusionMode::HideAllGuessOne => "ao",
+            OcclusionMode::HideOneGuessOne => "oa",
+        }
+    }
+}
+
+/// An image with masks, which [`ImageOcclusion::build`] turns into notes and media files
+///
+/// `ImageOcclusion` can be created using the builder pattern.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageOcclusion {
+    image_name: String,
+    image: Vec<u8>,
+    size: Option<(u32, u32)>,
+    masks: Vec<(Shape, String)>,
+    header: String,
+    footer: String,
+    mode: OcclusionMode,
+}
+
+/// The notes and media files of an [`ImageOcclusion`]
+#[derive(Clone, Debug)]
+pub struct Occlusion {
+    /// A note of [`image_occlusion_model`] for each mask, with one card each
+    pub notes: Vec<Note>,
+    /// The image and the SVG masks as `(name, content)`, which have to be added to the package
+    pub media: Vec<(String, Vec<u8>)>,
+}
+
+impl ImageOcclusion {
+    /// Starts an occlusion of the image `image_name` with the content `image`
+    ///
+    /// The size of PNG, JPEG and GIF images is read from `image`, set it with
+    /// [`ImageOcclusion::size`] for other images.
+    pub fn new(image_name: impl ToString, image: Vec<u8>) -> Self {
+        Self {
+            image_name: image_name.to_string(),
+            image,
+            size: None,
+            masks: vec![],
+            header: String::new(),
+            footer: String::new(),
+            mode: OcclusionMode::default(),
+        }
+    }
+
+    /// Sets the size of the image in pixels instead of reading it from the image
+    pub fn size(mut self, width: u32, height: u32) -> Self {
+        self.size = Some((width, height));
+        self
+    }
+
+    /// Adds a mask over `shape`, whose `label` is shown on it in the answer
+    pub fn mask(mut self, shape: Shape, label: impl ToString) -> Self {
+        self.masks.push((shape, label.to_string()));
+        self
+    }
+
+    /// Sets the HTML above the image on both sides of the cards
+    pub fn header(mut self, header: impl ToString) -> Self {
+        self.header = header.to_string();
+        self
+    }
+
+    /// Sets the HTML below the image on both sides of the cards
+    pub fn footer(mut self, footer: impl ToString) -> Self {
+        self.footer = footer.to_string();
+        self
+    }
+
+    /// Sets which masks the cards hide, by default all of them
+    pub fn mode(mut self, mode: OcclusionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Creates a note for each mask and the SVG files of the masks
+    ///
+    /// The notes have the ids of the add-on, which are taken from a hash of the image, and
+    /// guids from these ids, so building the same image again gives notes that update the notes
+    /// of the last build.
+    ///
+    /// Returns `Error::InvalidMediaFilename` if the image name isn't a valid media file name and
+    /// `Error::UnknownImageSize` if the size of the image can't be read and isn't set.
+    pub fn build(&self) -> Result<Occlusion, Error> {
+        check_media_filename(&self.image_name)?;
+        let (width, height) = match self.size.or_else(|| image_size(&self.image)) {
+            Some(size) => size,
+            None => {
+                return Err(Error::UnknownImageSize {
+                    name: self.image_name.clone(),
+                })
+            }
+        };
+        let hash = Sha1::new()
+            .chain_update(self.image_name.as_bytes())
+            .chain_update(&self.image)
+            .finalize();
+        let mut unique_id = String::new();
+        for byte in &hash[..16] {
+            // Invariant: writing to a `String` can't fail
+            write!(unique_id, "{:02x}", byte).expect("Should always write to a String");
+        }
+        let prefix = format!("{}-{}", unique_id, self.mode.abbreviation());
+
+        let svg = |shapes: String| {
+            format!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+                 viewBox=\"0 0 {w} {h}\"><g><title>Masks</title>{}</g></svg>",
+                shapes,
+                w = width,
+                h = height,
+            )
+            .into_bytes()
+        };
+        let all_masks: String = self
+            .masks
+            .iter()
+            .map(|(shape, _)| shape.to_svg(MASK_FILL, None))
+            .collect();
+        let original_name = format!("{}-O.svg", prefix);
+        let mut media = vec![
+            (self.image_name.clone(), self.image.clone()),
+            (original_name.clone(), svg(all_masks)),
+        ];
+
+        let model = Arc::new(image_occlusion_model());
+        let mut notes = vec![];
+        for (index, (shape, label)) in self.masks.iter().enumerate() {
+            let id = format!("{}-{}", prefix, index + 1);
+            let (mut question, mut answer) = (String::new(), String::new());
+            for (other_index, (other, _)) in self.masks.iter().enumerate() {
+                if other_index == index {
+                    question.push_str(&shape.to_svg(QUESTION_FILL, Some("qshape")));
+                } else if self.mode == OcclusionMode::HideAllGuessOne {
+                    question.push_str(&other.to_svg(MASK_FILL, Some("shape")));
+                    answer.push_str(&other.to_svg(MASK_FILL, Some("shape")));
+                }
+            }
+            let (x, y) = shape.center();
+            write!(
+                answer,
+                "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" dominant-baseline=\"middle\">{}</text>",
+                x,
+                y,
+                escape_html(label)
+            )
+            .expect("Should always write to a String");
+            let question_name = format!("{}-Q.svg", id);
+            let answer_name = format!("{}-A.svg", id);
+            let fields: HashMap<&str, String> = [
+                ("ID (hidden)", id.clone()),
+                ("Header", self.header.clone()),
+                ("Image", image_tag(&self.image_name)),
+                ("Question Mask", image_tag(&question_name)),
+                ("Footer", self.footer.clone()),
+                ("Answer Mask", image_tag(&answer_name)),
+                ("Original Mask", image_tag(&original_name)),
+            ]
+            .iter()
+            .cloned()
+            .collect();
+            notes.push(Note::new_with_field_map(model.clone(), &fields)?.guid(guid_for(&[&id])));
+            media.push((question_name, svg(question)));
+            media.push((answer_name, svg(answer)));
+        }
+        Ok(Occlusion { notes, media })
+    }
+}
+
+fn image_tag(name: &str) -> String {
+    format!("<img src=\"{}\" />", escape_html(name))
+}
+
+/// Returns the width and height of a PNG, JPEG or GIF image in pixels
+fn image_size(image: &[u8]) -> Option<(u32, u32)> {
+    let be_u32 = |at: usize| Some(u32::from_be_bytes(image.get(at..at + 4)?.try_into().ok()?));
+    let be_u16 = |at: usize| Some(u16::from_be_bytes(image.get(at..at + 2)?.try_into().ok()?));
+    let le_u16 = |at: usize| Some(u16::from_le_bytes(image.get(at..at + 2)?.try_into().ok()?));
+    if image.starts_with(b"\x89PNG\r\n\x1a\n") {
+        // The IHDR chunk comes first and starts with the width and height
+        return Some((be_u32(16)?, be_u32(20)?));
+    }
+    if image.starts_with(b"GIF87a") || image.starts_with(b"GIF89a") {
+        return Some((u32::from(le_u16(6)?), u32::from(le_u16(8)?)));
+    }
+    if image.starts_with(&[0xff, 0xd8]) {
+        // The segments before the start of the frame each start with a marker and their length
+        let mut at = 2;
+        while let (Some(&0xff), Some(&marker)) = (image.get(at), image.get(at + 1)) {
+            let is_frame = (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker);
+            if is_frame {
+                return Some((u32::from(be_u16(at + 7)?), u32::from(be_u16(at + 5)?)));
+            }
+            at += 2 + usize::from(be_u16(at + 2)?);
+        }
+    }
+    None
+}
+
+/// Returns the model of the notes of [`ImageOcclusion::build`], with the fields, template and
+/// CSS of the "Image Occlusion Enhanced" note type
+///
+/// The notes only have one card, which is shown if the field `Image` isn't empty.
+pub fn image_occlusion_model() -> Model {
+    let extra = ["Remarks", "Sources", "Extra 1", "Extra 2"]
+        .iter()
+        .map(|name| {
+            format!(
+                "    {{{{#{name}}}}}\n    <div class=\"io-extra-entry\">\n      \
+                 <div class=\"io-field-descr\">{name}</div>{{{{{name}}}}}\n    </div>\n    \
+                 {{{{/{name}}}}}\n",
+                name = name
+            )
+        })
+        .collect::<String>();
+    let image = |mask: &str| {
+        format!(
+            "<div id=\"io-header\">{{{{Header}}}}</div>\n<div id=\"io-wrapper\">\n  \
+             <div id=\"io-overlay\">{{{{{}}}}}</div>\n  \
+             <div id=\"io-original\">{{{{Image}}}}</div>\n</div>\n",
+            mask
+        )
+    };
+    let qfmt = format!(
+        "{{{{#Image}}}}\n{}<div id=\"io-footer\">{{{{Footer}}}}</div>\n{{{{/Image}}}}",
+        image("Question Mask")
+    );
+    let afmt = format!(
+        "{{{{#Image}}}}\n{}{{{{#Footer}}}}<div id=\"io-footer\">{{{{Footer}}}}</div>{{{{/Footer}}}}\n\
+         <div id=\"io-extra-wrapper\">\n  <div id=\"io-extra\">\n{}  </div>\n</div>\n{{{{/Image}}}}",
+        image("Answer Mask"),
+        extra
+    );
+    let fields = [
+        "ID (hidden)",
+        "Header",
+        "Image",
+        "Question Mask",
+        "Footer",
+        "Remarks",
+        "Sources",
+        "Extra 1",
+        "Extra 2",
+        "Answer Mask",
+        "Original Mask",
+    ];
+    Model::new(
+        1608316831,
+        "Image Occlusion Enhanced",
+        fields.iter().map(Field::new).collect(),
+        vec![Template::new("IO Card").qfmt(&qfmt).afmt(&afmt)],
+    )
+    .css(
+        ".card {\n font-family: \"Helvetica LT Std\", Helvetica, Arial, Sans;\n font-size: 150%;\n \
+         text-align: center;\n color: black;\n background-color: white;\n}\n\n\
+         #io-overlay {\n position: absolute;\n top: 0;\n width: 100%;\n z-index: 3;\n}\n\n\
+         #io-original {\n position: relative;\n top: 0;\n width: 100%;\n z-index: 2;\n}\n\n\
+         #io-wrapper {\n position: relative;\n width: 100%;\n}\n\n\
+         #io-header {\n font-size: 1.1em;\n margin-bottom: 0.2em;\n}\n\n\
+         #io-footer {\n max-width: 80%;\n margin-left: auto;\n margin-right: auto;\n \
+         margin-top: 0.8em;\n font-style: italic;\n}\n\n\
+         #io-extra-wrapper {\n width: 80%;\n margin-left: auto;\n margin-right: auto;\n \
+         margin-top: 0.5em;\n}\n\n\
+         #io-extra {\n text-align: center;\n display: inline-block;\n}\n\n\
+         .io-extra-entry {\n margin-top: 0.8em;\n font-size: 0.9em;\n text-align: left;\n}\n\n\
+         .io-field-descr {\n margin-bottom: 0.2em;\n font-weight: bold;\n font-size: 1em;\n}\n",
+    )
+    .sort_field(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Deck, Package};
+
+    /// The first bytes of a PNG image that is `width` by `height` pixels
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&width.to_be_bytes());
+        png.extend_from_slice(&height.to_be_bytes());
+        png.extend_from_slice(&[8, 6, 0, 0, 0]);
+        png
+    }
+
+    #[test]
+    fn notes_and_media() {
+        let occlusion = ImageOcclusion::new("heart.png", png(640, 480))
+            .header("The heart")
+            .mask(Shape::rect(10.0, 20.0, 30.0, 40.5), "Aorta")
+            .mask(
+                Shape::polygon(vec![(0.0, 0.0), (10.0, 0.0), (5.0, 8.0)]),
+                "A & B",
+            )
+            .mask(Shape::rect(100.0, 100.0, 10.0, 10.0), "Vena cava")
+            .build()
+            .unwrap();
+        assert_eq!(occlusion.notes.len(), 3);
+        assert!(occlusion.notes.iter().all(|note| note.card_count() == 1));
+        let names: Vec<&str> = occlusion.media.iter().map(|m| m.0.as_str()).collect();
+        assert_eq!(names.len(), 2 + 2 * 3);
+        assert_eq!(names[0], "heart.png");
+        let prefix = names[1].strip_suffix("-O.svg").unwrap();
+        assert!(prefix.ends_with("-ao"));
+        assert_eq!(names[2], format!("{}-1-Q.svg", prefix));
+        assert_eq!(names[7], format!("{}-3-A.svg", prefix));
+
+        let note = &occlusion.notes[1];
+        assert_eq!(
+            note.field_by_name("ID (hidden)"),
+            Some(&*format!("{}-2", prefix))
+        );
+        assert_eq!(note.field_by_name("Header"), Some("The heart"));
+        assert_eq!(
+            note.field_by_name("Image"),
+            Some("<img src=\"heart.png\" />")
+        );
+        assert_eq!(
+            note.field_by_name("Question Mask"),
+            Some(&*format!("<img src=\"{}-2-Q.svg\" />", prefix))
+        );
+        let referenced: Vec<String> = occlusion
+            .notes
+            .iter()
+            .flat_map(Note::media_references)
+            .map(|media| media.filename)
+            .collect();
+        assert!(names
+            .iter()
+            .all(|name| referenced.iter().any(|r| r == name)));
+
+        let svg = |index: usize| String::from_utf8(occlusion.media[index].1.clone()).unwrap();
+        for index in 1..names.len() {
+            assert!(svg(index).starts_with(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"640\" height=\"480\" \
+                 viewBox=\"0 0 640 480\">"
+            ));
+        }
+        let question = svg(4);
+        assert!(question.contains(
+            "<polygon points=\"0,0 10,0 5,8\" fill=\"#FF7E7E\" stroke=\"#2D2D2D\" class=\"qshape\"/>"
+        ));
+        assert_eq!(question.matches("fill=\"#FFEBA2\"").count(), 2);
+        let answer = svg(5);
+        assert_eq!(answer.matches("<rect").count(), 2);
+        assert!(!answer.contains("<polygon"));
+        assert!(answer.contains("<text x=\"5\" y=\"4\" text-anchor=\"middle\" dominant-baseline=\"middle\">A &amp; B</text>"));
+    }
+
+    #[test]
+    fn modes_and_sizes() {
+        let occlusion = ImageOcclusion::new("heart.svg", b"<svg/>".to_vec())
+            .mask(Shape::rect(1.0, 1.0, 2.0, 2.0), "a")
+            .mask(Shape::rect(5.0, 5.0, 2.0, 2.0), "b");
+        assert!(matches!(
+            occlusion.build(),
+            Err(Error::UnknownImageSize { name }) if name == "heart.svg"
+        ));
+        let hide_one = occlusion
+            .clone()
+            .size(20, 10)
+            .mode(OcclusionMode::HideOneGuessOne)
+            .build()
+            .unwrap();
+        let question = String::from_utf8(hide_one.media[2].1.clone()).unwrap();
+        assert!(question.contains("width=\"20\" height=\"10\""));
+        assert_eq!(question.matches("<rect").count(), 1);
+        assert!(hide_one.media[1].0.ends_with("-oa-O.svg"));
+        let first = occlusion.clone().size(20, 10).build().unwrap();
+        let changed = occlusion.size(20, 10).header("changed").build().unwrap();
+        assert_eq!(first.notes[0].get_guid(), changed.notes[0].get_guid());
+        assert_ne!(first.notes[0].get_guid(), hide_one.notes[0].get_guid());
+
+        assert!(matches!(
+            ImageOcclusion::new("a/b.png", png(1, 1)).build(),
+            Err(Error::InvalidMediaFilename { .. })
+        ));
+
+        let gif = b"GIF89a\x20\x03\x58\x02".to_vec();
+        assert_eq!(image_size(&gif), Some((800, 600)));
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0, 4, 0, 0];
+        jpeg.extend_from_slice(&[0xff, 0xc0, 0, 17, 8, 0x01, 0xe0, 0x02, 0x80]);
+        assert_eq!(image_size(&jpeg), Some((640, 480)));
+        assert_eq!(image_size(&png(3, 2)[..20]), None);
+    }
+
+    #[test]
+    fn package() {
+        let occlusion = ImageOcclusion::new("heart.png", png(64, 48))
+            .mask(Shape::rect(1.0, 1.0, 2.0, 2.0), "a")
+            .build()
+            .unwrap();
+        let mut deck = Deck::new(1234, "Anatomy", "");
+        for note in occlusion.notes {
+            deck.add_note(note);
+        }
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        for (name, bytes) in occlusion.media {
+            package.add_media_bytes(&name, bytes).unwrap();
+        }
+        assert!(package.check_media().missing.is_empty());
+        let collection = package.build_collection().unwrap();
+        assert_eq!((collection.notes, collection.cards), (1, 1));
+        let model = &collection.models[image_occlusion_model().id.to_string()];
+        assert_eq!(model["name"], "Image Occlusion Enhanced");
+        assert_eq!(model["flds"][3]["name"], "Question Mask");
+    }
+}
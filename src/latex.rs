@@ -0,0 +1,250 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use fancy_regex::{Captures, Regex};
+use sha1::{Digest, Sha1};
+
+use crate::note::Note;
+use crate::util::decode_entities;
+use crate::Error;
+
+/// How [`Package::prerender_latex`](crate::Package::prerender_latex) renders LaTeX to images
+///
+/// The LaTeX of a note is rendered as a document that starts with the `latex_pre` and ends with
+/// the `latex_post` of its model, to SVG if the model has [`Model::latex_svg`] set and to PNG
+/// otherwise.
+///
+/// Example:
+///
+/// ```rust,no_run
+/// use genanki_rs::LatexRenderer;
+///
+/// let renderer = LatexRenderer::commands().latex_command("/usr/local/texlive/bin/latex");
+/// ```
+///
+/// [`Model::latex_svg`]: crate::Model::latex_svg
+pub struct LatexRenderer {
+    render: Render,
+}
+
+/// Renders a document and returns the content of the image file
+type RenderFn = dyn Fn(&str) -> Result<Vec<u8>, Error>;
+
+enum Render {
+    /// The commands Anki runs to render LaTeX
+    Commands {
+        latex: PathBuf,
+        dvisvgm: PathBuf,
+        dvipng: PathBuf,
+    },
+    Custom(Box<RenderFn>),
+}
+
+impl LatexRenderer {
+    /// Renders documents like Anki does, with `latex` and then `dvisvgm` or `dvipng`, which are
+    /// looked up in the `PATH` unless they are set with the methods below
+    pub fn commands() -> Self {
+        Self {
+            render: Render::Commands {
+                latex: PathBuf::from("latex"),
+                dvisvgm: PathBuf::from("dvisvgm"),
+                dvipng: PathBuf::from("dvipng"),
+            },
+        }
+    }
+
+    /// Renders documents with `render`, which returns the content of the SVG or PNG file, e.g.
+    /// to render them with a library instead of running commands
+    pub fn custom(render: impl Fn(&str) -> Result<Vec<u8>, Error> + 'static) -> Self {
+        Self {
+            render: Render::Custom(Box::new(render)),
+        }
+    }
+
+    /// Sets the path of the `latex` command, this has no effect on a [`LatexRenderer::custom`]
+    pub fn latex_command(mut self, path: impl Into<PathBuf>) -> Self {
+        if let Render::Commands { latex, .. } = &mut self.render {
+            *latex = path.into();
+        }
+        self
+    }
+
+    /// Sets the path of the `dvisvgm` command, this has no effect on a [`LatexRenderer::custom`]
+    pub fn dvisvgm_command(mut self, path: impl Into<PathBuf>) -> Self {
+        if let Render::Commands { dvisvgm, .. } = &mut self.render {
+            *dvisvgm = path.into();
+        }
+        self
+    }
+
+    /// Sets the path of the `dvipng` command, this has no effect on a [`LatexRenderer::custom`]
+    pub fn dvipng_command(mut self, path: impl Into<PathBuf>) -> Self {
+        if let Render::Commands { dvipng, .. } = &mut self.render {
+            *dvipng = path.into();
+        }
+        self
+    }
+
+    /// Renders `document` and returns the image, or the output of the failed command
+    fn render(&self, document: &str, svg: bool) -> Result<Vec<u8>, String> {
+        match &self.render {
+            Render::Commands {
+                latex,
+                dvisvgm,
+                dvipng,
+            } => {
+                let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+                std::fs::write(dir.path().join("tmp.tex"), document).map_err(|e| e.to_string())?;
+                run(dir.path(), latex, &["-interaction=nonstopmode", "tmp.tex"])?;
+                let out = if svg {
+                    let args = [
+                        "--no-fonts",
+                        "--exact",
+                        "-Z",
+                        "2",
+                        "tmp.dvi",
+                        "-o",
+                        "tmp.svg",
+                    ];
+                    run(dir.path(), dvisvgm, &args)?;
+                    "tmp.svg"
+                } else {
+                    let args = ["-D", "200", "-T", "tight", "tmp.dvi", "-o", "tmp.png"];
+                    run(dir.path(), dvipng, &args)?;
+                    "tmp.png"
+                };
+                std::fs::read(dir.path().join(out)).map_err(|e| e.to_string())
+            }
+            Render::Custom(render) => render(document).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+fn run(dir: &Path, program: &Path, args: &[&str]) -> Result<(), String> {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("{} couldn't be run: {}", program.display(), e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(format!(
+        "{} failed with {}:\n{}{}",
+        program.display(),
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+/// Replaces the `[latex]`, `[$]` and `[$$]` blocks in the fields of `note` with `<img>` tags like
+/// Anki does when it shows the note, and returns the images that aren't in `rendered` yet
+///
+/// `index` is the index of the note in the deck `deck_id` for the errors.
+pub(crate) fn prerender_note_latex(
+    note: &mut Note,
+    renderer: &LatexRenderer,
+    rendered: impl Fn(&str) -> bool,
+    deck_id: i64,
+    index: usize,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let model = note.model().clone();
+    let field_names = model.field_names();
+    let mut images: Vec<(String, Vec<u8>)> = vec![];
+    for (field_index, name) in field_names.iter().enumerate() {
+        let field = note.fields()[field_index].clone();
+        let mut error = None;
+        let replaced = latex_regex().replace_all(&field, |captures: &Captures| {
+            let latex = match (captures.get(1), captures.get(2), captures.get(3)) {
+                (Some(latex), _, _) => latex.as_str().to_string(),
+                (_, Some(expression), _) => format!("${}$", expression.as_str()),
+                (_, _, Some(math)) => {
+                    format!(r"\begin{{displaymath}}{}\end{{displaymath}}", math.as_str())
+                }
+                _ => unreachable!("one of the groups matches"),
+            };
+            let latex = strip_html_for_latex(&latex);
+            let filename = latex_filename(&latex, model.get_latex_svg());
+            if error.is_none()
+                && !rendered(&filename)
+                && !images.iter().any(|(name, _)| *name == filename)
+            {
+                let document = format!(
+                    "{}\n{}\n{}",
+                    model.get_latex_pre(),
+                    latex,
+                    model.get_latex_post()
+                );
+                match renderer.render(&document, model.get_latex_svg()) {
+                    Ok(image) => {
+                        images.push((filename.clone(), image));
+                    }
+                    Err(output) => error = Some(output),
+                }
+            }
+            format!(
+                r#"<img class=latex alt="{}" src="{}">"#,
+                encode_attribute(&latex),
+                filename
+            )
+        });
+        if let Some(output) = error {
+            return Err(Error::LatexRenderFailed {
+                deck_id,
+                index,
+                field: name.to_string(),
+                output,
+            });
+        }
+        if replaced != field {
+            let replaced = replaced.into_owned();
+            note.set_field(field_index, replaced)?;
+        }
+    }
+    Ok(images)
+}
+
+fn latex_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(r"(?si)\[latex\](.+?)\[/latex\]|\[\$\](.+?)\[/\$\]|\[\$\$\](.+?)\[/\$\$\]")
+            .expect("static regex")
+    })
+}
+
+/// Line breaks of the editor become new lines, the rest of the HTML is removed
+fn strip_html_for_latex(latex: &str) -> String {
+    static REGEXES: OnceLock<(Regex, Regex)> = OnceLock::new();
+    let (newlines, markup) = REGEXES.get_or_init(|| {
+        let newlines = Regex::new(r"(?i)<br( /)?>|<div>").expect("static regex");
+        let markup = Regex::new(r"(?s)<!--.*?-->|<.*?>").expect("static regex");
+        (newlines, markup)
+    });
+    let latex = newlines.replace_all(latex, "\n");
+    decode_entities(&markup.replace_all(&latex, ""))
+}
+
+/// The name Anki gives the image of `latex`, the hex SHA-1 hash of the LaTeX
+fn latex_filename(latex: &str, svg: bool) -> String {
+    let hash: String = Sha1::digest(latex.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("latex-{}.{}", hash, if svg { "svg" } else { "png" })
+}
+
+/// Escapes the characters below 256 except for ASCII letters and digits as `&#xHH;`, like the
+/// `htmlescape` crate Anki uses for the `alt` attribute
+fn encode_attribute(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if (c as u32) < 256 && !c.is_ascii_alphanumeric() {
+                format!("&#x{:02X};", c as u32)
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
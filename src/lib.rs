@@ -183,26 +183,84 @@
 //! the Note, `1` means the second, etc.
 //!
 
+#[cfg(feature = "csv")]
+pub mod anki_txt;
+#[cfg(feature = "ankiconnect")]
+pub mod ankiconnect;
 mod apkg_col;
 mod apkg_schema;
 mod builders;
 mod builtin_models;
 mod card;
+mod card_gen;
+#[cfg(feature = "csv")]
+mod csv_export;
+#[cfg(feature = "csv")]
+mod csv_import;
 mod db_entries;
 mod deck;
 mod error;
+mod field_transform;
+pub mod furigana;
+pub mod image_occlusion;
+mod latex;
+mod media;
 mod model;
+#[cfg(feature = "modern-format")]
+mod modern_format;
 mod note;
 mod package;
+mod package_appender;
+mod package_reader;
+mod package_writer;
+mod render;
+#[cfg(feature = "sanitize")]
+mod sanitize;
+#[cfg(feature = "spec")]
+mod spec;
+mod stats;
+mod template_parser;
+mod timestamp;
+mod tts;
 mod util;
-
-pub use builders::{Field, Template};
+mod validation;
+mod verification;
+
+#[cfg(feature = "sanitize")]
+pub use builders::SanitizePolicy;
+pub use builders::{
+    CollectionConf, CompressionOptions, DeckConf, Field, FieldMapping, ModelBuilder, NewCardOrder,
+    NewSpread, SortType, Template,
+};
+#[cfg(feature = "csv")]
+pub use builders::{CsvExportOptions, CsvOptions, Delimiter, MixedModels};
 pub use builtin_models::*;
-pub use deck::Deck;
+pub use card::{Card, CardSchedule};
+#[cfg(feature = "csv")]
+pub use csv_import::{ImportReport, RowError};
+pub use db_entries::{Fld, ModelDbEntry, Tmpl};
+pub use deck::{Deck, DedupBy, MergePolicy};
 pub use error::Error;
+pub use field_transform::FieldTransform;
+pub use latex::LatexRenderer;
+pub use media::{
+    MediaCheck, MediaIssue, MediaIssueKind, MediaKind, MediaRef, MediaReport, Strictness,
+};
 pub use model::{Model, ModelType};
+#[cfg(feature = "modern-format")]
+pub use modern_format::PackageFormat;
 pub use note::Note;
 pub use package::Package;
+pub use package_reader::LoadedPackage;
+pub use package_writer::{CollectionDump, PackageWriter, Progress, SchedulerVersion, WriteSummary};
+pub use render::RenderedCard;
+#[cfg(feature = "spec")]
+pub use spec::{DeckSpec, ModelSpec, Record, TemplateSpec};
+pub use stats::{DeckStats, ModelStats, TemplateStats};
+pub use timestamp::Timestamp;
+pub use tts::{TtsField, TtsProvider};
+pub use util::guid_for;
+pub use validation::{IdKind, ValidationReport, ValidationWarning, MAX_ID};
 
 #[cfg(test)]
 mod tests {
@@ -274,37 +332,34 @@ mod tests {
     const CUSTOM_LATEX_POST: &str = "% here is a great comment\n\\end{document}";
 
     fn model_with_latex() -> Model {
-        Model::new_with_options(
-            567890,
-            "with latex",
-            vec![Field::new("AField"), Field::new("Bfield")],
-            vec![Template::new("card1")
-                .qfmt("{{AField}}")
-                .afmt(r#"{{FrontSide}}<hr id="answer">{{BField}}"#)],
-            None,
-            None,
-            Some(CUSTOM_LATEX_PRE),
-            Some(CUSTOM_LATEX_POST),
-            None,
-        )
+        Model::builder(567890, "with latex")
+            .field(Field::new("AField"))
+            .field(Field::new("BField"))
+            .template(
+                Template::new("card1")
+                    .qfmt("{{AField}}")
+                    .afmt(r#"{{FrontSide}}<hr id="answer">{{BField}}"#),
+            )
+            .latex_pre(CUSTOM_LATEX_PRE)
+            .latex_post(CUSTOM_LATEX_POST)
+            .build()
+            .unwrap()
     }
 
-    const CUSTOM_SORT_FIELD_INDEX: i64 = 1;
+    const CUSTOM_SORT_FIELD_INDEX: usize = 1;
 
     fn model_with_sort_field_index() -> Model {
-        Model::new_with_options(
-            567890,
-            "with latex",
-            vec![Field::new("AField"), Field::new("Bfield")],
-            vec![Template::new("card1")
-                .qfmt("{{AField}}")
-                .afmt(r#"{{FrontSide}}<hr id="answer">{{BField}}"#)],
-            None,
-            None,
-            None,
-            None,
-            Some(CUSTOM_SORT_FIELD_INDEX),
-        )
+        Model::builder(567890, "with latex")
+            .field(Field::new("AField"))
+            .field(Field::new("BField"))
+            .template(
+                Template::new("card1")
+                    .qfmt("{{AField}}")
+                    .afmt(r#"{{FrontSide}}<hr id="answer">{{BField}}"#),
+            )
+            .sort_field_index(CUSTOM_SORT_FIELD_INDEX)
+            .build()
+            .unwrap()
     }
 
     const VALID_MP3: &[u8] =
@@ -429,6 +484,31 @@ res = col
             self.col = col;
         }
 
+        /// Imports `package` in the latest format with the importer of Anki 2.1.50 and later
+        #[cfg(feature = "modern-format")]
+        pub fn import_latest_package(&mut self, package: Package) {
+            self.tmp_files
+                .push(NamedTempFile::new().unwrap().into_temp_path());
+            let out_file = self.tmp_files.last().unwrap();
+            package
+                .format(PackageFormat::Latest)
+                .write_to_file(out_file)
+                .unwrap();
+            let locals = PyDict::new(*self.py);
+            locals.set_item("col", self.col).unwrap();
+            locals
+                .set_item(
+                    "outfile",
+                    PyString::new(*self.py, out_file.to_str().unwrap()),
+                )
+                .unwrap();
+            let code = r#"
+from anki.collection import ImportAnkiPackageRequest
+col.import_anki_package(ImportAnkiPackageRequest(package_path=outfile))
+        "#;
+            self.py.run(code, None, Some(locals)).unwrap();
+        }
+
         fn check_col(&mut self, condition_str: &str) -> bool {
             let code = format!(
                 r#"
@@ -468,11 +548,111 @@ def check_media(col):
                 .unwrap()
         }
 
+        /// Exports the collection with the `.apkg` exporter of Anki, without scheduling
+        pub fn export_package(&mut self) -> &TempPath {
+            self.tmp_files
+                .push(NamedTempFile::new().unwrap().into_temp_path());
+            let out_file = self.tmp_files.last().unwrap();
+            let locals = PyDict::new(*self.py);
+            locals.set_item("col", self.col).unwrap();
+            locals
+                .set_item(
+                    "outfile",
+                    PyString::new(*self.py, out_file.to_str().unwrap()),
+                )
+                .unwrap();
+            let code = r#"
+from anki.exporting import AnkiPackageExporter
+exporter = AnkiPackageExporter(col)
+exporter.includeSched = False
+exporter.exportInto(outfile)
+        "#;
+            self.py.run(code, None, Some(locals)).unwrap();
+            out_file
+        }
+
         fn col(&self) -> &PyAny {
             self.col
         }
     }
 
+    #[test]
+    fn card_is_generated_if_hint_is_empty() {
+        let note = Note::new(model_with_hint(), vec!["Capital of France", "", "Paris"]).unwrap();
+        assert_eq!(note.cards().len(), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let mut deck = Deck::new(123456, "foodeck", "a description");
+        deck.add_note(
+            Note::new(model_with_hint(), vec!["Capital of France", "", "Paris"])
+                .unwrap()
+                .tags(vec!["geography"])
+                .unwrap(),
+        );
+        deck.add_note(
+            Note::new(
+                cloze_model().latex_svg(true),
+                vec!["{{c1::Paris}} is in {{c2::France}}", ""],
+            )
+            .unwrap(),
+        );
+        deck.add_note(
+            Note::new(basic_and_reversed_card_model(), vec!["der Hund", "the dog"])
+                .unwrap()
+                .configure_cards(|card| {
+                    if card.ord() == 1 {
+                        card.set_suspended(true);
+                        card.set_flag(3).unwrap();
+                        card.set_deck_id(Some(654321));
+                        card.set_schedule(Some(CardSchedule {
+                            interval_days: 12,
+                            ease_factor: 2350,
+                            due_days_from_today: 3,
+                            reps: 5,
+                            lapses: 1,
+                        }))
+                        .unwrap();
+                    }
+                }),
+        );
+        deck.add_note(Note::new_lenient(cloze_model(), vec!["no cloze deletions", ""]).unwrap());
+        let json = serde_json::to_string(&deck).unwrap();
+        let deserialized = serde_json::from_str::<Deck>(&json).unwrap();
+        assert_eq!(deserialized, deck);
+        let card = &deserialized.notes().nth(2).unwrap().cards()[1];
+        assert!(card.suspended());
+        assert_eq!(card.flag(), 3);
+        assert_eq!(card.deck_id(), Some(654321));
+        assert_eq!(card.schedule().map(|schedule| schedule.reps), Some(5));
+        assert_eq!(deserialized.notes().nth(3).unwrap().card_count(), 1);
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let model = &value["notes"][1]["model"];
+        assert_eq!(model["id"], 1550428389);
+        assert_eq!(model["model_type"], "cloze");
+        assert_eq!(model["fields"][1]["name"], "Back Extra");
+        assert_eq!(model["templates"][0]["qfmt"], "{{cloze:Text}}");
+        assert_eq!(value["notes"][0]["tags"], serde_json::json!(["geography"]));
+
+        let model: Model = serde_json::from_str(
+            r#"{"id": 1, "name": "Minimal", "fields": [{"name": "Front"}],
+                "templates": [{"name": "Card 1", "qfmt": "{{Front}}"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            model,
+            Model::new(
+                1,
+                "Minimal",
+                vec![Field::new("Front")],
+                vec![Template::new("Card 1").qfmt("{{Front}}")]
+            )
+        );
+    }
+
     #[test]
     #[serial]
     fn import_anki() {
@@ -524,6 +704,49 @@ def check_media(col):
         });
     }
 
+    #[test]
+    #[serial]
+    fn exported_package_can_be_opened() {
+        Python::with_gil(|py| {
+            let mut setup = TestSetup::new(&py);
+            let mut deck = Deck::new(123456, "foodeck", "");
+            deck.add_note(Note::new(model(), vec!["a", "b"]).unwrap().guid("first"));
+            deck.add_note(Note::new(cn_model(), vec!["c", "d", "e"]).unwrap());
+            setup.import_package(Package::new(vec![deck], vec![]).unwrap(), None);
+            let loaded = Package::open(setup.export_package()).unwrap();
+            assert_eq!(loaded.decks.len(), 1);
+            assert_eq!(loaded.decks[0].name(), "foodeck");
+            let notes: Vec<&Note> = loaded.decks[0].notes().collect();
+            assert_eq!(notes.len(), 2);
+            assert_eq!(notes[0].fields(), ["a", "b"]);
+            assert_eq!(notes[1].card_count(), 2);
+            let model_ids: Vec<i64> = loaded.models.iter().map(|model| model.id).collect();
+            assert!(model_ids.contains(&model().id) && model_ids.contains(&cn_model().id));
+
+            setup.import_package(loaded.into_package().unwrap(), None);
+            assert!(setup.check_col("len(col.find_notes('')) == 2"));
+        });
+    }
+
+    #[cfg(feature = "modern-format")]
+    #[test]
+    #[serial]
+    fn latest_format_can_be_imported() {
+        Python::with_gil(|py| {
+            let mut setup = TestSetup::new(&py);
+            let mut deck = Deck::new(123456, "foodeck", "");
+            deck.add_note(Note::new(cn_model(), vec!["a", "b", "c [sound:a.mp3]"]).unwrap());
+            let mut package = Package::new(vec![deck], vec![]).unwrap();
+            package.add_media_bytes("a.mp3", VALID_MP3).unwrap();
+            setup.import_latest_package(package);
+            assert!(setup.check_col(
+                "{i['name'] for i in col.decks.all()} == {'Default', 'foodeck'} and len(col.find_cards('')) == 2"
+            ));
+            let (missing, _, unused) = setup.check_media();
+            assert!(missing.is_empty() && unused.is_empty());
+        });
+    }
+
     #[test]
     #[serial]
     fn media_files() {
@@ -542,11 +765,11 @@ def check_media(col):
         deck.add_note(note);
         std::fs::File::create("present.mp3")
             .unwrap()
-            .write(VALID_MP3)
+            .write_all(VALID_MP3)
             .unwrap();
         std::fs::File::create("present.jpg")
             .unwrap()
-            .write(VALID_JPG)
+            .write_all(VALID_JPG)
             .unwrap();
         Python::with_gil(|py| {
             let mut setup = TestSetup::new(&py);
@@ -585,11 +808,11 @@ def check_media(col):
         let present_jpg_path = tmp_dir.path().join("present.jpg");
         std::fs::File::create(present_mp3_path.clone())
             .unwrap()
-            .write(VALID_MP3)
+            .write_all(VALID_MP3)
             .unwrap();
         std::fs::File::create(present_jpg_path.clone())
             .unwrap()
-            .write(VALID_JPG)
+            .write_all(VALID_JPG)
             .unwrap();
         Python::with_gil(|py| {
             let mut setup = TestSetup::new(&py);
@@ -0,0 +1,647 @@
+use crate::util::decode_entities;
+use crate::Error;
+use fancy_regex::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// How a media file is referenced in a field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MediaKind {
+    /// `<img src="...">`
+    Image,
+    /// `<audio src="...">`
+    Audio,
+    /// `[sound:...]`, which Anki plays with its own player
+    Sound,
+    /// `<object data="...">`
+    Object,
+    /// The `src` attribute of any other tag, e.g. `<video>` or `<source>`
+    Other,
+}
+
+/// A file name referenced in the field of a note, see [`Note::media_references`]
+///
+/// [`Note::media_references`]: crate::Note::media_references
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MediaRef {
+    /// How the file is referenced
+    pub kind: MediaKind,
+    /// The file name, with entities and URL escapes like `%20` decoded
+    pub filename: String,
+}
+
+/// The result of [`Package::collect_media_from_dir`]
+///
+/// [`Package::collect_media_from_dir`]: crate::Package::collect_media_from_dir
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MediaReport {
+    /// The referenced file names that were found and added to the package, in the order they
+    /// are first referenced
+    pub found: Vec<String>,
+    /// The referenced file names that weren't found
+    pub missing: Vec<String>,
+}
+
+/// The result of [`Package::check_media`]
+///
+/// [`Package::check_media`]: crate::Package::check_media
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MediaCheck {
+    /// The media files of the package that no note references, in the order they were added
+    pub unreferenced: Vec<String>,
+    /// The referenced file names that aren't media files of the package, in the order they are
+    /// first referenced
+    pub missing: Vec<String>,
+}
+
+/// How [`Package::validate_media`] treats problems with media files
+///
+/// [`Package::validate_media`]: crate::Package::validate_media
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Strictness {
+    /// Problems are returned as a list of [`MediaIssue`]s
+    Lenient,
+    /// Problems are returned as `Error::InvalidMedia`
+    Strict,
+}
+
+/// A problem with a media file found by [`Package::validate_media`]
+///
+/// [`Package::validate_media`]: crate::Package::validate_media
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MediaIssue {
+    /// The name of the media file in the package
+    pub filename: String,
+    /// What is wrong with the file
+    pub kind: MediaIssueKind,
+}
+
+/// What is wrong with a media file, see [`MediaIssue`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MediaIssueKind {
+    /// The file is empty
+    Empty,
+    /// Anki can't show or play files with this extension, or the file has no extension
+    UnsupportedExtension,
+    /// The content doesn't look like the format of the extension, e.g. a `.mp3` that is an HTML
+    /// page; `detected` is the format the content looks like, if known, e.g. `"html"`
+    ContentMismatch { detected: Option<&'static str> },
+}
+
+/// The number of bytes at the start of a file that [`check_media_content`] needs
+pub(crate) const MEDIA_HEAD_LEN: usize = 64;
+
+/// A media file of a `Package` and the name it has in the collection
+#[derive(Clone)]
+pub(crate) enum MediaFile {
+    Path { name: String, path: PathBuf },
+    Bytes { name: String, data: Vec<u8> },
+}
+
+impl MediaFile {
+    /// Creates the file at `path`, which is named after the last component of the path
+    pub(crate) fn from_path(path: &Path) -> Result<Self, Error> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| Error::InvalidMediaPath {
+                path: path.to_path_buf(),
+            })?
+            .to_str()
+            .ok_or_else(|| Error::NonUtf8MediaFilename {
+                path: path.to_path_buf(),
+            })?
+            .to_string();
+        check_media_filename(&name)?;
+        let path = path.to_path_buf();
+        Ok(MediaFile::Path { name, path })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            MediaFile::Path { name, .. } | MediaFile::Bytes { name, .. } => name,
+        }
+    }
+
+    pub(crate) fn source(&self) -> Option<PathBuf> {
+        match self {
+            MediaFile::Path { path, .. } => Some(path.clone()),
+            MediaFile::Bytes { .. } => None,
+        }
+    }
+
+    pub(crate) fn len(&self) -> Result<u64, Error> {
+        match self {
+            MediaFile::Path { path, .. } => Ok(std::fs::metadata(path)
+                .map_err(|e| media_error(path, e))?
+                .len()),
+            MediaFile::Bytes { data, .. } => Ok(data.len() as u64),
+        }
+    }
+
+    /// The length and the first `MEDIA_HEAD_LEN` bytes of the content
+    pub(crate) fn head(&self) -> Result<(u64, Vec<u8>), Error> {
+        match self {
+            MediaFile::Path { path, .. } => {
+                let file = open_media(path)?;
+                let len = file.metadata()?.len();
+                let mut head = vec![];
+                file.take(MEDIA_HEAD_LEN as u64).read_to_end(&mut head)?;
+                Ok((len, head))
+            }
+            MediaFile::Bytes { data, .. } => {
+                let head = data[..data.len().min(MEDIA_HEAD_LEN)].to_vec();
+                Ok((data.len() as u64, head))
+            }
+        }
+    }
+
+    /// The SHA-256 hash of the content, files are read in chunks instead of loading them into
+    /// memory at once
+    pub(crate) fn content_hash(&self) -> Result<[u8; 32], Error> {
+        let mut hasher = Sha256::new();
+        match self {
+            MediaFile::Path { path, .. } => {
+                std::io::copy(&mut open_media(path)?, &mut hasher)?;
+            }
+            MediaFile::Bytes { data, .. } => hasher.update(data),
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// The whole content
+    #[cfg(feature = "ankiconnect")]
+    pub(crate) fn read(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            MediaFile::Path { path, .. } => std::fs::read(path).map_err(|e| media_error(path, e)),
+            MediaFile::Bytes { data, .. } => Ok(data.clone()),
+        }
+    }
+
+    /// The SHA-1 hash of the content, which the latest package format lists for each file
+    #[cfg(feature = "modern-format")]
+    pub(crate) fn sha1(&self) -> Result<[u8; 20], Error> {
+        let mut hasher = sha1::Sha1::new();
+        match self {
+            MediaFile::Path { path, .. } => {
+                std::io::copy(&mut open_media(path)?, &mut hasher)?;
+            }
+            MediaFile::Bytes { data, .. } => hasher.update(data),
+        }
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// Returns `Error::InvalidMediaFilename` if `name` can't be the name of a file in Anki's media
+/// folder
+///
+/// A `\` is a file name character on Unix, but it would become a path separator on Windows.
+pub(crate) fn check_media_filename(name: &str) -> Result<(), Error> {
+    if name.contains(['/', '\\', '\0']) {
+        return Err(Error::InvalidMediaFilename {
+            name: name.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Opens the file of a `MediaFile::Path`
+pub(crate) fn open_media(path: &Path) -> Result<File, Error> {
+    File::open(path).map_err(|e| media_error(path, e))
+}
+
+/// `Error::MediaNotFound` if the media file at `path` doesn't exist, so that the error names it
+fn media_error(path: &Path, e: std::io::Error) -> Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        Error::MediaNotFound {
+            path: path.to_path_buf(),
+            source: e,
+        }
+    } else {
+        Error::Io(e)
+    }
+}
+
+/// The media files of a `Package`, which have different names
+#[derive(Clone, Default)]
+pub(crate) struct MediaFiles {
+    files: Vec<MediaFile>,
+    /// The index of each file in `files` by name
+    index: HashMap<String, usize>,
+}
+
+impl MediaFiles {
+    pub(crate) fn iter(&self) -> std::slice::Iter<'_, MediaFile> {
+        self.files.iter()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    /// Adds `media_file` unless there is a file with the same name and content and returns the
+    /// name of the file
+    ///
+    /// If there is a file with the same name but different content, a `MediaFile::Path` is
+    /// renamed with [`hashed_name`] if `auto_rename` is set.
+    pub(crate) fn push(
+        &mut self,
+        media_file: MediaFile,
+        auto_rename: bool,
+    ) -> Result<String, Error> {
+        let existing = match self.index.get(media_file.name()) {
+            Some(&idx) => &self.files[idx],
+            None => {
+                let name = media_file.name().to_string();
+                self.index.insert(name.clone(), self.files.len());
+                self.files.push(media_file);
+                return Ok(name);
+            }
+        };
+        let hash = media_file.content_hash()?;
+        if existing.content_hash()? == hash {
+            return Ok(media_file.name().to_string());
+        }
+        match media_file {
+            MediaFile::Path { name, path } if auto_rename => {
+                let name = hashed_name(&name, &hash);
+                self.push(MediaFile::Path { name, path }, auto_rename)
+            }
+            _ => Err(Error::MediaNameCollision {
+                name: media_file.name().to_string(),
+                first_source: existing.source(),
+                second_source: media_file.source(),
+            }),
+        }
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.files.truncate(len);
+        self.reindex();
+    }
+
+    pub(crate) fn retain(&mut self, f: impl FnMut(&MediaFile) -> bool) {
+        self.files.retain(f);
+        self.reindex();
+    }
+
+    fn reindex(&mut self) {
+        self.index = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(idx, f)| (f.name().to_string(), idx))
+            .collect();
+    }
+}
+
+/// Appends the first 4 bytes of `hash` in hex to the stem of `name`
+fn hashed_name(name: &str, hash: &[u8]) -> String {
+    let suffix: String = hash[..4].iter().map(|b| format!("{:02x}", b)).collect();
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some(extension) => {
+            let stem = &name[..name.len() - extension.len() - 1];
+            format!("{}-{}.{}", stem, suffix, extension)
+        }
+        None => format!("{}-{}", name, suffix),
+    }
+}
+
+/// Whether the first bytes of a file match a format
+type Sniffer = fn(&[u8]) -> bool;
+
+/// Formats that can be recognized by their first bytes, with their extensions
+const SNIFFED_FORMATS: &[(&str, &[&str], Sniffer)] = &[
+    ("mp3", &["mp3"], |b| {
+        b.starts_with(b"ID3") || (b.len() >= 2 && b[0] == 0xff && b[1] & 0xe0 == 0xe0)
+    }),
+    ("ogg", &["ogg", "oga", "ogv", "opus"], |b| {
+        b.starts_with(b"OggS")
+    }),
+    ("wav", &["wav"], |b| riff(b, b"WAVE")),
+    ("mp4", &["m4a", "mp4"], |b| b.get(4..8) == Some(b"ftyp")),
+    ("jpg", &["jpg", "jpeg"], |b| {
+        b.starts_with(&[0xff, 0xd8, 0xff])
+    }),
+    ("png", &["png"], |b| b.starts_with(b"\x89PNG\r\n\x1a\n")),
+    ("gif", &["gif"], |b| {
+        b.starts_with(b"GIF87a") || b.starts_with(b"GIF89a")
+    }),
+    ("webp", &["webp"], |b| riff(b, b"WEBP")),
+    ("webm", &["webm", "mkv"], |b| {
+        b.starts_with(&[0x1a, 0x45, 0xdf, 0xa3])
+    }),
+    ("html", &[], html),
+    ("svg", &["svg"], |b| {
+        let text = String::from_utf8_lossy(b);
+        text.trim_start_matches('\u{feff}')
+            .trim_start()
+            .starts_with('<')
+            && !html(b)
+    }),
+];
+
+/// Extensions of other files that Anki supports, but whose content isn't checked
+const OTHER_EXTENSIONS: &[&str] = &[
+    "aac", "avi", "avif", "bmp", "css", "flac", "ico", "js", "mov", "mpeg", "mpg", "otf", "spx",
+    "tif", "tiff", "ttf", "woff", "woff2", "3gp",
+];
+
+fn html(bytes: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(bytes).trim_start().to_lowercase();
+    text.starts_with("<!doctype html") || text.starts_with("<html")
+}
+
+fn riff(bytes: &[u8], format: &[u8]) -> bool {
+    bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(format)
+}
+
+/// Checks the content of the media file `filename` of `len` bytes, which starts with `head`,
+/// against its extension
+pub(crate) fn check_media_content(filename: &str, len: u64, head: &[u8]) -> Option<MediaIssueKind> {
+    let extension = match filename.rsplit_once('.') {
+        Some((_, extension)) => extension.to_lowercase(),
+        None => return Some(MediaIssueKind::UnsupportedExtension),
+    };
+    let detected = SNIFFED_FORMATS
+        .iter()
+        .find(|(_, _, matches)| matches(head))
+        .map(|(format, _, _)| *format);
+    match SNIFFED_FORMATS
+        .iter()
+        .find(|(_, extensions, _)| extensions.contains(&extension.as_str()))
+    {
+        _ if len == 0 => Some(MediaIssueKind::Empty),
+        Some((_, _, matches)) if !matches(head) => {
+            Some(MediaIssueKind::ContentMismatch { detected })
+        }
+        Some(_) => None,
+        None if OTHER_EXTENSIONS.contains(&extension.as_str()) => None,
+        None => Some(MediaIssueKind::UnsupportedExtension),
+    }
+}
+
+/// Whether Anki keeps the media file `filename` even if no note references it
+///
+/// Files starting with `_` are meant to be used by templates and styling, e.g.
+/// `_background.png` or a font, and Anki's "Check Media" never removes them.
+pub(crate) fn is_always_used(filename: &str) -> bool {
+    filename.starts_with('_')
+}
+
+/// Finds the local media files referenced in `field`, in the order they appear
+///
+/// Remote files, i.e. URLs with a scheme like `https://` and `data:` URLs, are skipped.
+pub(crate) fn media_references(field: &str) -> Vec<MediaRef> {
+    find_media_references(field)
+        .into_iter()
+        .map(|(_, media)| media)
+        .collect()
+}
+
+/// Replaces the references to the media file `from` in `field` with references to `to`
+pub(crate) fn rename_media_references(field: &str, from: &str, to: &str) -> String {
+    let escaped = to
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    let mut renamed = field.to_string();
+    for (span, media) in find_media_references(field).into_iter().rev() {
+        if media.filename == from {
+            renamed.replace_range(span, &escaped);
+        }
+    }
+    renamed
+}
+
+/// Finds the local media files referenced in `field` with the spans of their names in `field`
+fn find_media_references(field: &str) -> Vec<(Range<usize>, MediaRef)> {
+    static REGEXES: OnceLock<(Regex, Regex, Regex)> = OnceLock::new();
+    let (tag, attribute, sound) = REGEXES.get_or_init(|| {
+        let tag = Regex::new(r"(?i)<\s*([a-z][a-z0-9]*)\b([^>]*)>").expect("static regex");
+        let attribute =
+            Regex::new(r#"(?i)(?:^|\s)(src|data)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#)
+                .expect("static regex");
+        let sound = Regex::new(r"\[sound:(.+?)\]").expect("static regex");
+        (tag, attribute, sound)
+    });
+
+    let mut found = vec![];
+    for captures in tag.captures_iter(field).map(|c| c.expect("static regex")) {
+        let name = captures[1].to_lowercase();
+        for attr in attribute
+            .captures_iter(&captures[2])
+            .map(|c| c.expect("static regex"))
+        {
+            let kind = match (name.as_str(), attr[1].to_lowercase().as_str()) {
+                ("img", "src") => MediaKind::Image,
+                ("audio", "src") => MediaKind::Audio,
+                ("object", "data") => MediaKind::Object,
+                (_, "src") => MediaKind::Other,
+                _ => continue,
+            };
+            let value = match attr.get(2).or_else(|| attr.get(3)).or_else(|| attr.get(4)) {
+                Some(value) => value,
+                None => continue,
+            };
+            let filename = percent_decode(&decode_entities(value.as_str()));
+            if is_local(&filename) {
+                let offset = captures.get(2).map_or(0, |m| m.start());
+                let span = offset + value.start()..offset + value.end();
+                found.push((span, MediaRef { kind, filename }));
+            }
+        }
+    }
+    for captures in sound.captures_iter(field).map(|c| c.expect("static regex")) {
+        let filename = decode_entities(&captures[1]);
+        if is_local(&filename) {
+            let span = captures.get(1).map_or(0..0, |m| m.range());
+            let kind = MediaKind::Sound;
+            found.push((span, MediaRef { kind, filename }));
+        }
+    }
+    found.sort_by_key(|(span, _)| span.start);
+    found
+}
+
+fn is_local(filename: &str) -> bool {
+    static SCHEME: OnceLock<Regex> = OnceLock::new();
+    let scheme = SCHEME
+        .get_or_init(|| Regex::new(r"(?i)^(?:[a-z][a-z0-9+.-]*://|data:)").expect("static regex"));
+    !filename.is_empty() && !scheme.is_match(filename).expect("static regex")
+}
+
+/// Decodes `%XX` escapes, leaving invalid ones as they are
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media(kind: MediaKind, filename: &str) -> MediaRef {
+        MediaRef {
+            kind,
+            filename: filename.to_string(),
+        }
+    }
+
+    #[test]
+    fn multiple_images() {
+        assert_eq!(
+            media_references(
+                r#"<img src="a.jpg"><br><IMG class=big SRC='b.png'/> <img src=c.gif>"#
+            ),
+            vec![
+                media(MediaKind::Image, "a.jpg"),
+                media(MediaKind::Image, "b.png"),
+                media(MediaKind::Image, "c.gif"),
+            ]
+        );
+    }
+
+    #[test]
+    fn sound_inside_anchor() {
+        assert_eq!(
+            media_references(r#"<a href="x.mp3">[sound:hund.mp3]</a><audio src="katze.ogg">"#),
+            vec![
+                media(MediaKind::Sound, "hund.mp3"),
+                media(MediaKind::Audio, "katze.ogg"),
+            ]
+        );
+    }
+
+    #[test]
+    fn filename_with_spaces() {
+        assert_eq!(
+            media_references(
+                r#"<img src="my%20dog.jpg"><img src='my cat.jpg'>[sound:big &amp; loud.mp3]"#
+            ),
+            vec![
+                media(MediaKind::Image, "my dog.jpg"),
+                media(MediaKind::Image, "my cat.jpg"),
+                media(MediaKind::Sound, "big & loud.mp3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_tags() {
+        assert_eq!(
+            media_references(
+                r#"<object data="a.svg"></object><video><source src="b.webm"></video><p data-src="c">"#
+            ),
+            vec![
+                media(MediaKind::Object, "a.svg"),
+                media(MediaKind::Other, "b.webm"),
+            ]
+        );
+    }
+
+    #[test]
+    fn remote_files_are_skipped() {
+        assert_eq!(
+            media_references(
+                r#"<img src="https://example.com/a.jpg"><img src="HTTP://example.com/b.jpg"><img src="data:image/png;base64,AAAA"><img src="c.jpg">"#
+            ),
+            vec![media(MediaKind::Image, "c.jpg")]
+        );
+    }
+
+    #[test]
+    fn rename_references() {
+        assert_eq!(
+            rename_media_references(
+                r#"<img src="a/dog.jpg"><img src='a%2Fdog.jpg'><img src="dog.jpg">[sound:a/dog.jpg]"#,
+                "a/dog.jpg",
+                "dog-1234abcd.jpg"
+            ),
+            r#"<img src="dog-1234abcd.jpg"><img src='dog-1234abcd.jpg'><img src="dog.jpg">[sound:dog-1234abcd.jpg]"#
+        );
+    }
+
+    #[test]
+    fn media_content() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(check_media_content("a.png", 16, png), None);
+        assert_eq!(check_media_content("a.PNG", 16, png), None);
+        assert_eq!(check_media_content("a.mp3", 3, b"ID3"), None);
+        assert_eq!(check_media_content("a.mp3", 2, &[0xff, 0xfb]), None);
+        assert_eq!(check_media_content("a.wav", 12, b"RIFF\0\0\0\0WAVE"), None);
+        assert_eq!(check_media_content("a.m4a", 8, b"\0\0\0\x20ftyp"), None);
+        assert_eq!(check_media_content("a.svg", 5, b"<svg>"), None);
+        assert_eq!(check_media_content("a.ttf", 1, b"\0"), None);
+
+        assert_eq!(
+            check_media_content("a.png", 0, b""),
+            Some(MediaIssueKind::Empty)
+        );
+        assert_eq!(
+            check_media_content("a.mp3", 20, b"<!DOCTYPE html><html>"),
+            Some(MediaIssueKind::ContentMismatch {
+                detected: Some("html")
+            })
+        );
+        assert_eq!(
+            check_media_content("a.svg", 6, b"<html>"),
+            Some(MediaIssueKind::ContentMismatch {
+                detected: Some("html")
+            })
+        );
+        assert_eq!(
+            check_media_content("a.jpg", 16, png),
+            Some(MediaIssueKind::ContentMismatch {
+                detected: Some("png")
+            })
+        );
+        assert_eq!(
+            check_media_content("a.gif", 3, b"abc"),
+            Some(MediaIssueKind::ContentMismatch { detected: None })
+        );
+        assert_eq!(
+            check_media_content("a.exe", 2, b"MZ"),
+            Some(MediaIssueKind::UnsupportedExtension)
+        );
+        assert_eq!(
+            check_media_content("README", 2, b"hi"),
+            Some(MediaIssueKind::UnsupportedExtension)
+        );
+    }
+
+    #[test]
+    fn percent_decode_invalid() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%C3%BCber"), "%zzüber");
+    }
+}
@@ -1,6 +1,20 @@
-use crate::builders::Template;
+use crate::builders::{ModelBuilder, Template};
+use crate::card_gen::{CardGenPlan, PlanCache};
 use crate::db_entries::{Fld, ModelDbEntry, Tmpl};
+use crate::error::json_error;
+use crate::note::Note;
+use crate::render::{render, Context, RenderedCard};
+use crate::template_parser;
+use crate::timestamp::Timestamp;
+use crate::util::truncate;
+use crate::validation::MAX_ID;
 use crate::{Error, Field};
+use fancy_regex::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
 
 const DEFAULT_LATEX_PRE: &str = r#"
 \documentclass[12pt]{article}
@@ -17,14 +31,29 @@ const DEFAULT_LATEX_POST: &str = r"\end{document}";
 /// `FrontBack` or `Cloze` to determine the type of a Model.
 ///
 /// When creating a Model, the default is `FrontBack`
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum ModelType {
     FrontBack,
     Cloze,
 }
 
 /// `Model` to determine the structure of a `Note`
-#[derive(Clone)]
+///
+/// With the `serde` feature, a model is (de)serialized as a map with the keys `id`, `name`,
+/// `fields`, `templates`, `css`, `model_type` (`"front_back"` or `"cloze"`), `latex_pre`,
+/// `latex_post`, `latex_svg` and `sort_field_index`. Fields and templates use the names of
+/// their builder methods as keys.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "ModelDef", from = "ModelDef")
+)]
 pub struct Model {
     pub id: i64,
     name: String,
@@ -34,7 +63,10 @@ pub struct Model {
     model_type: ModelType,
     latex_pre: String,
     latex_post: String,
+    latex_svg: bool,
     sort_field_index: i64,
+    /// Computed the first time a note of the model generates its cards
+    card_gen_plan: PlanCache,
 }
 
 impl Model {
@@ -53,7 +85,7 @@ impl Model {
     ///         .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
     /// );
     /// ```
-    pub fn new(id: i64, name: &str, fields: Vec<Field>, templates: Vec<Template>) -> Self {
+    pub fn new(id: i64, name: impl ToString, fields: Vec<Field>, templates: Vec<Template>) -> Self {
         Self {
             id,
             name: name.to_string(),
@@ -63,8 +95,50 @@ impl Model {
             model_type: ModelType::FrontBack,
             latex_pre: DEFAULT_LATEX_PRE.to_string(),
             latex_post: DEFAULT_LATEX_POST.to_string(),
+            latex_svg: false,
             sort_field_index: 0,
+            card_gen_plan: PlanCache::default(),
         }
+        .assign_template_ords()
+    }
+
+    /// Creates a new model like [`Model::new`], but derives the `id` from the `name` with
+    /// [`Model::id_from_name`], so the same name always results in the same id.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use genanki_rs::{Model, Field, Template};
+    /// let model = Model::new_with_id_from_name(
+    ///     "Simple Model",
+    ///     vec![Field::new("Question"), Field::new("Answer")],
+    ///     vec![Template::new("Card 1")
+    ///         .qfmt("{{Question}}")
+    ///         .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
+    /// );
+    /// assert_eq!(model.id, Model::id_from_name("Simple Model"));
+    /// ```
+    pub fn new_with_id_from_name(
+        name: impl ToString,
+        fields: Vec<Field>,
+        templates: Vec<Template>,
+    ) -> Self {
+        let name = name.to_string();
+        Self::new(Self::id_from_name(&name), name, fields, templates)
+    }
+
+    /// Derives a deterministic model id from a model `name`.
+    ///
+    /// The id is the first 8 bytes of the SHA-256 digest of the UTF-8 encoded name, read as a
+    /// big-endian `u64`, of which the lowest 53 bits are kept so it is at most
+    /// [`MAX_ID`](crate::MAX_ID). The id is never `0`, which is replaced by `1`.
+    /// In Python: `max(int.from_bytes(hashlib.sha256(name.encode()).digest()[:8], "big") &
+    /// (2**53 - 1), 1)`
+    pub fn id_from_name(name: &str) -> i64 {
+        let digest = Sha256::digest(name.as_bytes());
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        ((u64::from_be_bytes(bytes) & MAX_ID as u64) as i64).max(1)
     }
 
     /// Creates a new model with a unique(!) `ìd`, a `name`, `fields` and  `templates` and custom parameters:
@@ -73,10 +147,27 @@ impl Model {
     /// * `latex_pre`: Custom latex declarations at the beginning of a card.
     /// * `latex_post`: Custom latex declarations at the end of a card.
     /// * `sort_field_index`: Custom sort field index
+    ///
+    /// Deprecated in favor of [`Model::builder`], which sets each option with a method of the
+    /// same name:
+    ///
+    /// ```
+    /// use genanki_rs::{Field, Model, ModelType};
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let model = Model::builder(1607392319, "Simple Model")
+    ///     .field(Field::new("Question"))
+    ///     .css(format!(".card {{ font-size: {}px; }}", 20))
+    ///     .model_type(ModelType::FrontBack)
+    ///     .sort_field_index(0)
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[deprecated(note = "use `Model::builder` instead")]
     #[allow(clippy::too_many_arguments)]
     pub fn new_with_options(
         id: i64,
-        name: &str,
+        name: impl ToString,
         fields: Vec<Field>,
         templates: Vec<Template>,
         css: Option<&str>,
@@ -94,22 +185,68 @@ impl Model {
             model_type: model_type.unwrap_or(ModelType::FrontBack),
             latex_pre: latex_pre.unwrap_or(DEFAULT_LATEX_PRE).to_string(),
             latex_post: latex_post.unwrap_or(DEFAULT_LATEX_POST).to_string(),
+            latex_svg: false,
             sort_field_index: sort_field_index.unwrap_or(0),
+            card_gen_plan: PlanCache::default(),
         }
+        .assign_template_ords()
+    }
+
+    /// Starts building a model with a unique(!) `id` and a `name`, see [`ModelBuilder`]
+    pub fn builder(id: i64, name: impl ToString) -> ModelBuilder {
+        ModelBuilder::new(id, name)
     }
 
     /// Adds an additional field to the model
     pub fn with_field(mut self, field: Field) -> Self {
         self.fields.push(field.into());
+        self.card_gen_plan = PlanCache::default();
         self
     }
 
     /// Adds an additional template to the model
     pub fn with_template(mut self, template: Template) -> Self {
         self.templates.push(template.into());
+        self.assign_template_ords()
+    }
+
+    /// Adds several additional fields to the model, in order
+    pub fn with_fields(mut self, fields: impl IntoIterator<Item = Field>) -> Self {
+        self.fields.extend(fields.into_iter().map(Fld::from));
+        self.card_gen_plan = PlanCache::default();
         self
     }
 
+    /// Adds several additional templates to the model, in order
+    pub fn with_templates(mut self, templates: impl IntoIterator<Item = Template>) -> Self {
+        self.templates.extend(templates.into_iter().map(Tmpl::from));
+        self.assign_template_ords()
+    }
+
+    /// Inserts a field at position `index`, shifting all fields after it to the right.
+    ///
+    /// If the field is inserted at or before the sort field, `sort_field_index` is adjusted so
+    /// it keeps pointing at the same field. Returns `Error::IndexOutOfRange` if `index` is
+    /// greater than the number of fields.
+    pub fn insert_field(mut self, index: usize, field: Field) -> Result<Self, Error> {
+        check_insert_index(index, self.fields.len())?;
+        self.fields.insert(index, field.into());
+        self.card_gen_plan = PlanCache::default();
+        if index as i64 <= self.sort_field_index {
+            self.sort_field_index += 1;
+        }
+        Ok(self)
+    }
+
+    /// Inserts a template at position `index`, shifting all templates after it to the right.
+    ///
+    /// Returns `Error::IndexOutOfRange` if `index` is greater than the number of templates.
+    pub fn insert_template(mut self, index: usize, template: Template) -> Result<Self, Error> {
+        check_insert_index(index, self.templates.len())?;
+        self.templates.insert(index, template.into());
+        Ok(self.assign_template_ords())
+    }
+
     /// Sets the custom CSS for this model
     pub fn css(self, css: impl ToString) -> Self {
         Self {
@@ -120,7 +257,11 @@ impl Model {
 
     /// Change the type of the model
     pub fn model_type(self, model_type: ModelType) -> Self {
-        Self { model_type, ..self }
+        Self {
+            model_type,
+            card_gen_plan: PlanCache::default(),
+            ..self
+        }
     }
 
     /// Sets the model's latex_pre field
@@ -139,6 +280,11 @@ impl Model {
         }
     }
 
+    /// Sets whether Anki renders the model's LaTeX to SVG instead of PNG, default is `false`
+    pub fn latex_svg(self, latex_svg: bool) -> Self {
+        Self { latex_svg, ..self }
+    }
+
     /// Sets the index of the field used for sorting with this model
     pub fn sort_field_index(self, sort_field_index: i64) -> Self {
         Self {
@@ -147,44 +293,490 @@ impl Model {
         }
     }
 
-    pub(super) fn req(&self) -> Result<Vec<(usize, String, Vec<usize>)>, Error> {
-        let sentinel = "SeNtInEl".to_string();
-        let field_names: Vec<String> = self.fields.iter().map(|field| field.name.clone()).collect();
-        let field_values = field_names
+    /// Sets the index of the field used for sorting with this model, like `sort_field_index`
+    pub fn sort_field(self, index: usize) -> Self {
+        self.sort_field_index(index as i64)
+    }
+
+    /// Returns a copy of the model with a different `id`
+    pub fn clone_with_id(&self, new_id: i64) -> Self {
+        Self {
+            id: new_id,
+            ..self.clone()
+        }
+    }
+
+    /// Changes the name of the model
+    pub fn rename(self, name: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            ..self
+        }
+    }
+
+    /// Replaces every template of the model with the result of calling `f` on it
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use genanki_rs::basic_and_reversed_card_model;
+    /// let model = basic_and_reversed_card_model()
+    ///     .clone_with_id(1607392320)
+    ///     .rename("Bold reversed")
+    ///     .map_templates(|template| template.afmt("<b>{{FrontSide}}</b>"));
+    /// ```
+    pub fn map_templates(self, f: impl FnMut(Template) -> Template) -> Self {
+        let templates = self
+            .templates
+            .into_iter()
+            .map(Template::from)
+            .map(f)
+            .map(Tmpl::from)
+            .collect();
+        Self { templates, ..self }.assign_template_ords()
+    }
+
+    /// Sets the ords of the templates without an ord set with [`Template::ord`] to the lowest
+    /// ords no other template has, which is their position if no template has an ord set
+    ///
+    /// As the templates changed, this also drops the cached `CardGenPlan`.
+    fn assign_template_ords(mut self) -> Self {
+        let mut taken: HashSet<i64> = self
+            .templates
+            .iter()
+            .filter(|template| template.sticky_ord)
+            .map(|template| template.ord)
+            .collect();
+        let mut next_ord = 0;
+        for template in self.templates.iter_mut().filter(|t| !t.sticky_ord) {
+            while taken.contains(&next_ord) {
+                next_ord += 1;
+            }
+            template.ord = next_ord;
+            taken.insert(next_ord);
+        }
+        self.card_gen_plan = PlanCache::default();
+        self
+    }
+
+    /// Checks that `sort_field_index` points at one of the model's fields.
+    ///
+    /// Returns `Error::SortFieldOutOfRange` if it is negative or not less than the number of
+    /// fields. This is checked when the model is written.
+    pub fn validate_sort_field_index(&self) -> Result<(), Error> {
+        if self.sort_field_index < 0 || self.sort_field_index as usize >= self.fields.len() {
+            Err(Error::SortFieldOutOfRange {
+                index: self.sort_field_index,
+                field_count: self.fields.len(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the name of the model
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the custom CSS of the model
+    pub fn get_css(&self) -> &str {
+        &self.css
+    }
+
+    /// Returns the names of the model's fields, in the order note values are expected
+    pub fn field_names(&self) -> Vec<&str> {
+        self.fields
+            .iter()
+            .map(|field| field.name.as_str())
+            .collect()
+    }
+
+    /// Returns the names of the model's templates
+    pub fn template_names(&self) -> Vec<&str> {
+        self.templates
             .iter()
-            .map(|field| (field.as_str(), format!("{}{}", &field, &sentinel)));
+            .map(|template| template.name.as_str())
+            .collect()
+    }
+
+    /// Returns the type of the model
+    pub fn get_model_type(&self) -> ModelType {
+        self.model_type
+    }
+
+    /// Returns the model's latex_pre field
+    pub fn get_latex_pre(&self) -> &str {
+        &self.latex_pre
+    }
+
+    /// Returns the model's latex_post field
+    pub fn get_latex_post(&self) -> &str {
+        &self.latex_post
+    }
+
+    /// Returns whether the model's LaTeX is rendered to SVG
+    pub fn get_latex_svg(&self) -> bool {
+        self.latex_svg
+    }
+
+    /// Returns the index of the field used for sorting with this model
+    pub fn get_sort_field_index(&self) -> i64 {
+        self.sort_field_index
+    }
+
+    /// Checks that the model is well-formed
+    ///
+    /// Returns `Err` if two fields or two templates share the same name, if the `{{#X}}`,
+    /// `{{^X}}` and `{{/X}}` sections of a template are unbalanced, or if a template references
+    /// a field that does not exist on the model. Names are compared case-sensitively,
+    /// like Anki does. Special fields like `{{FrontSide}}` or `{{Tags}}` and filters like
+    /// `{{cloze:Text}}` or `{{furigana:Reading}}` are understood.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use genanki_rs::{Field, Model, Template};
+    ///
+    /// let model = Model::new(
+    ///     1607392319,
+    ///     "Simple Model",
+    ///     vec![Field::new("Question"), Field::new("Answer")],
+    ///     vec![Template::new("Card 1")
+    ///         .qfmt("{{Qestion}}")
+    ///         .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
+    /// );
+    /// assert!(model.validate().is_err());
+    /// ```
+    pub fn validate(&self) -> Result<(), Error> {
+        let field_names = self.field_names();
+        if let Some((index, name)) = find_duplicate(field_names.iter().copied()) {
+            return Err(Error::DuplicateFieldName {
+                name: name.to_string(),
+                index,
+            });
+        }
+        if let Some((index, name)) = find_duplicate(self.template_names().into_iter()) {
+            return Err(Error::DuplicateTemplateName {
+                name: name.to_string(),
+                index,
+            });
+        }
+        self.validate_template_ords()?;
+        for template in &self.templates {
+            for format in [&template.qfmt, &template.afmt].iter() {
+                template_parser::check(format, &field_names).map_err(|e| {
+                    Error::TemplateSyntax {
+                        template_name: template.name.clone(),
+                        message: e.to_string(),
+                        tag: e.tag(format).to_string(),
+                        offset: e.offset(),
+                    }
+                })?;
+                let nodes = template_parser::parse(format)
+                    .map_err(|_| Error::TemplateFormat(Box::new(template.clone())))?;
+                if let Some(unknown) = template_parser::referenced_fields(&nodes)
+                    .into_iter()
+                    .find(|name| !field_names.contains(name))
+                {
+                    return Err(Error::UnknownTemplateField {
+                        template_name: template.name.clone(),
+                        field_name: unknown.to_string(),
+                        known_fields: field_names.iter().map(|name| name.to_string()).collect(),
+                    });
+                }
+            }
+        }
+        if self.model_type == ModelType::Cloze {
+            self.validate_cloze_template()?;
+        }
+        Ok(())
+    }
+
+    /// Renders the card of `note` from the template at `template_index` like Anki shows it, e.g.
+    /// to try changes to the templates without importing the deck into Anki
+    ///
+    /// The fields of `note` are taken by position, so the note can have an older version of the
+    /// model. Replacements, `{{FrontSide}}`, `{{#Field}}` and `{{^Field}}` sections and the
+    /// filters `cloze:`, `hint:`, `text:`, `furigana:`, `kana:` and `kanji:` are rendered, while
+    /// `type:` renders nothing and other filters leave the field unchanged. Like in Anki, a
+    /// section treats a field that only contains whitespace, `<br>` and `<div>` tags as empty.
+    /// `{{Deck}}`, `{{Subdeck}}`, `{{CardFlag}}` and `{{CardID}}` are empty, since the note isn't
+    /// in a collection.
+    ///
+    /// For a `FrontBack` model, `template_index` is the ord of the template, see
+    /// [`Template::ord`]. For a `Cloze` model, it is the ord of the card, i.e. the cloze number
+    /// minus one, and its only template is rendered. Cards are rendered even if the note doesn't
+    /// generate them.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{cloze_model, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let text = "{{c1::Berlin}} is the capital of {{c2::Germany::country}}";
+    /// let note = Note::new(cloze_model(), vec![text, ""])?;
+    /// let card = cloze_model().render_preview(&note, 1)?;
+    /// assert_eq!(
+    ///     card.question_html,
+    ///     "Berlin is the capital of <span class=cloze>[country]</span>"
+    /// );
+    /// assert!(card
+    ///     .answer_html
+    ///     .starts_with("Berlin is the capital of <span class=cloze>Germany</span>"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Returns `Error::IndexOutOfRange` if a `FrontBack` model has no template at
+    /// `template_index` or if `template_index` is `usize::MAX`, which has no cloze number, or `Err`
+    /// if the model isn't valid, see [`Model::validate`].
+    pub fn render_preview(
+        &self,
+        note: &Note,
+        template_index: usize,
+    ) -> Result<RenderedCard, Error> {
+        self.validate()?;
+        let template = match self.model_type {
+            ModelType::FrontBack => self.template_by_ord(template_index as i64),
+            ModelType::Cloze => self.templates.first(),
+        }
+        .ok_or(Error::IndexOutOfRange {
+            index: template_index,
+            len: self.templates.len(),
+        })?;
+        let format_error = |_| Error::TemplateFormat(Box::new(template.clone()));
+        let tags = note.get_tags().join(" ");
+        let mut fields: HashMap<&str, &str> = self
+            .field_names()
+            .into_iter()
+            .zip(note.fields().iter().map(String::as_str))
+            .collect();
+        fields.insert("Tags", &tags);
+        fields.insert("Type", &self.name);
+        fields.insert("Card", &template.name);
+        let cloze_number = template_index
+            .checked_add(1)
+            .ok_or(Error::IndexOutOfRange {
+                index: template_index,
+                len: self.templates.len(),
+            })?;
+        let cloze_number = u32::try_from(cloze_number).unwrap_or(u32::MAX);
+
+        let question = Context {
+            fields: fields.clone(),
+            cloze_number,
+            question: true,
+        };
+        let question_html = render(
+            &template_parser::parse(&template.qfmt).map_err(format_error)?,
+            &question,
+        );
+        fields.insert("FrontSide", &question_html);
+        let answer = Context {
+            fields,
+            cloze_number,
+            question: false,
+        };
+        let answer_html = render(
+            &template_parser::parse(&template.afmt).map_err(format_error)?,
+            &answer,
+        );
+        Ok(RenderedCard {
+            question_html,
+            answer_html,
+            css: self.css.clone(),
+        })
+    }
+
+    /// Checks that the ords set with [`Template::ord`] are used once and less than the number
+    /// of templates, so the ords of the model are `0` up to the number of templates minus one
+    fn validate_template_ords(&self) -> Result<(), Error> {
+        let mut names = HashMap::new();
+        for template in self.templates.iter().filter(|template| template.sticky_ord) {
+            if template.ord < 0 || template.ord as usize >= self.templates.len() {
+                return Err(Error::TemplateOrdOutOfRange {
+                    template_name: template.name.clone(),
+                    ord: template.ord,
+                    template_count: self.templates.len(),
+                });
+            }
+            if let Some(first) = names.insert(template.ord, &template.name) {
+                return Err(Error::DuplicateTemplateOrd {
+                    ord: template.ord,
+                    first: first.clone(),
+                    second: template.name.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Anki only supports cloze models with exactly one template, which has to contain a cloze
+    fn validate_cloze_template(&self) -> Result<(), Error> {
+        let template = match self.templates.as_slice() {
+            [template] => template,
+            templates => return Err(Error::ClozeTemplateCount(templates.len())),
+        };
+        let nodes = template_parser::parse(&template.qfmt)
+            .map_err(|_| Error::TemplateFormat(Box::new(template.clone())))?;
+        if template_parser::has_cloze(&nodes) {
+            Ok(())
+        } else {
+            Err(Error::ClozeTemplateWithoutCloze {
+                template_name: template.name.clone(),
+            })
+        }
+    }
+
+    /// Computes which fields are required for each template to produce a non-empty front side.
+    ///
+    /// For every template, this first checks which fields the question is empty without
+    /// (`"all"` of them are required). If there are none, it checks which fields can produce a
+    /// non-empty question on their own (`"any"` of them is required). Templates whose question
+    /// stays empty even when every field is filled in are rejected.
+    ///
+    /// Like Anki, this skips the analysis for cloze models, whose cards are generated from the
+    /// cloze deletions in a note instead.
+    pub(super) fn req(&self) -> Result<Vec<(usize, String, Vec<usize>)>, Error> {
+        if self.model_type == ModelType::Cloze {
+            return Ok(vec![]);
+        }
+        let field_names = self.field_names();
         let mut req = Vec::new();
-        for template_ord in 0..self.templates.len() {
-            req.push((
-                template_ord,
-                "all".to_string(),
-                (0..field_values.len()).collect(),
-            ));
+        for template in self.templates() {
+            let template_ord = template.ord as usize;
+            let nodes = template_parser::parse(&template.qfmt)
+                .map_err(|_| Error::TemplateFormat(Box::new(template.clone())))?;
+            if template_parser::referenced_fields(&nodes).is_empty() {
+                return Err(Error::TemplateHasNoFields {
+                    template_name: template.name.clone(),
+                });
+            }
+            let all_fields = field_names.iter().copied().collect::<HashSet<_>>();
+            if !template_parser::renders_with_fields(&nodes, &all_fields) {
+                return Err(Error::TemplateFormat(Box::new(template.clone())));
+            }
+
+            let required_fields: Vec<usize> = (0..field_names.len())
+                .filter(|&field_ord| {
+                    let nonempty = field_names
+                        .iter()
+                        .enumerate()
+                        .filter(|&(ord, _)| ord != field_ord)
+                        .map(|(_, &name)| name)
+                        .collect::<HashSet<_>>();
+                    !template_parser::renders_with_fields(&nodes, &nonempty)
+                })
+                .collect();
+            if !required_fields.is_empty() {
+                req.push((template_ord, "all".to_string(), required_fields));
+                continue;
+            }
+
+            let required_fields: Vec<usize> = (0..field_names.len())
+                .filter(|&field_ord| {
+                    let nonempty = std::iter::once(field_names[field_ord]).collect::<HashSet<_>>();
+                    template_parser::renders_with_fields(&nodes, &nonempty)
+                })
+                .collect();
+            req.push((template_ord, "any".to_string(), required_fields));
         }
+        req.sort_by_key(|(ord, _, _)| *ord);
         Ok(req)
     }
 
-    pub(super) fn fields(&self) -> Vec<Fld> {
-        self.fields.clone()
+    /// Returns the `CardGenPlan` of the model, which is only computed once
+    ///
+    /// The model is checked with [`Model::validate`] before the plan is computed, so a cached
+    /// plan also means that the model is valid and notes don't validate it again.
+    pub(super) fn card_gen_plan(&self) -> Result<&CardGenPlan, Error> {
+        self.card_gen_plan.get_or_try_init(|| {
+            self.validate()?;
+            CardGenPlan::new(self)
+        })
+    }
+
+    /// Returns the names of the fields the first template uses with the `cloze` filter
+    pub(super) fn cloze_field_names(&self) -> Vec<String> {
+        static REGEXES: OnceLock<[Regex; 2]> = OnceLock::new();
+        let regexes = REGEXES.get_or_init(|| {
+            [
+                Regex::new(r"{{[^}]*?cloze:(?:[^}]?:)*(.+?)}}").expect("static regex"),
+                Regex::new("<%cloze:(.+?)%>").expect("static regex"),
+            ]
+        });
+        let qfmt = match self.templates.first() {
+            Some(template) => &template.qfmt,
+            None => return vec![],
+        };
+        regexes
+            .iter()
+            .flat_map(|regex| {
+                regex
+                    .captures_iter(qfmt)
+                    // The iterator doesn't advance past an error, so the matches stop at the
+                    // first one
+                    .map_while(Result::ok)
+                    .filter_map(|cap| cap.get(1).map(|m| m.as_str().to_string()))
+            })
+            .collect()
+    }
+
+    /// Returns the templates in the order they were added, with their ords set, see
+    /// [`Template::ord`]
+    pub(super) fn templates(&self) -> &[Tmpl] {
+        &self.templates
     }
-    pub(super) fn templates(&self) -> Vec<Tmpl> {
-        self.templates.clone()
+    pub(super) fn template_by_ord(&self, ord: i64) -> Option<&Tmpl> {
+        self.templates.iter().find(|template| template.ord == ord)
     }
-    pub(super) fn get_model_type(&self) -> ModelType {
-        self.model_type.clone()
+    /// Serializes the model into the JSON structure Anki stores in the `models` column of its
+    /// collection, as if it was last modified at `timestamp` and belongs to `deck_id`.
+    ///
+    /// Returns `Err` if the model does not pass [`Model::validate`]
+    pub fn to_json(&self, timestamp: impl Into<Timestamp>, deck_id: i64) -> Result<String, Error> {
+        let db_entry = self.to_db_entry(timestamp, deck_id)?;
+        serde_json::to_string(&db_entry).map_err(json_error)
+    }
+
+    /// Parses a model from the JSON structure Anki stores in the `models` column of its
+    /// collection, e.g. a note type copied out of an existing `collection.anki2`.
+    ///
+    /// Unknown keys are ignored and fields and templates are ordered by their `ord`.
+    ///
+    /// Returns `Err` if the JSON is malformed or does not describe a model.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let db_entry: ModelDbEntry = serde_json::from_str(json).map_err(json_error)?;
+        Self::try_from(db_entry)
     }
+
     pub(super) fn to_model_db_entry(
-        &mut self,
-        timestamp: f64,
+        &self,
+        timestamp: Timestamp,
         deck_id: i64,
     ) -> Result<ModelDbEntry, Error> {
-        self.templates
-            .iter_mut()
-            .enumerate()
-            .for_each(|(i, template)| {
-                template.ord = i as i64;
-            });
-        self.fields.iter_mut().enumerate().for_each(|(i, field)| {
+        self.to_db_entry(timestamp, deck_id)
+    }
+
+    /// Converts the model into the structure Anki stores in its collection, as if it was last
+    /// modified at `timestamp` and belongs to `deck_id`. The `ord`s of the fields are set to
+    /// their positions in the model, and the templates are ordered by their `ord`s, see
+    /// [`Template::ord`].
+    ///
+    /// Returns `Err` if the model does not pass [`Model::validate`] or
+    /// [`Model::validate_sort_field_index`]
+    pub fn to_db_entry(
+        &self,
+        timestamp: impl Into<Timestamp>,
+        deck_id: i64,
+    ) -> Result<ModelDbEntry, Error> {
+        self.validate()?;
+        self.validate_sort_field_index()?;
+        let mut templates = self.templates.clone();
+        templates.sort_by_key(|template| template.ord);
+        let mut fields = self.fields.clone();
+        fields.iter_mut().enumerate().for_each(|(i, field)| {
             field.ord = i as i64;
         });
         let model_type = match self.model_type {
@@ -198,11 +790,12 @@ impl Model {
             did: deck_id,
             usn: -1,
             req: self.req()?,
-            flds: self.fields.clone(),
+            flds: fields,
             sortf: self.sort_field_index,
-            tmpls: self.templates.clone(),
-            model_db_entry_mod: timestamp as i64,
+            tmpls: templates,
+            model_db_entry_mod: timestamp.into().as_secs(),
             latex_post: self.latex_post.clone(),
+            latexsvg: self.latex_svg,
             model_db_entry_type: model_type,
             id: self.id.to_string(),
             css: self.css.clone(),
@@ -211,12 +804,161 @@ impl Model {
     }
 }
 
+/// Whether the models of two notes are the same, which is cheap if the notes share the model
+pub(crate) fn same_model(a: &Arc<Model>, b: &Arc<Model>) -> bool {
+    Arc::ptr_eq(a, b) || a == b
+}
+
+/// Reads a model from the structure Anki stores in its collection. Fields and templates are
+/// ordered by their `ord`.
+impl TryFrom<ModelDbEntry> for Model {
+    type Error = Error;
+
+    fn try_from(mut db_entry: ModelDbEntry) -> Result<Self, Error> {
+        let id = db_entry
+            .id
+            .parse()
+            .map_err(|e| Error::JsonParser(Box::new(e)))?;
+        let model_type = match db_entry.model_db_entry_type {
+            0 => ModelType::FrontBack,
+            1 => ModelType::Cloze,
+            other => {
+                return Err(json_error(serde::de::Error::custom(format!(
+                    "unknown model type {}",
+                    other
+                ))))
+            }
+        };
+        db_entry.flds.sort_by_key(|field| field.ord);
+        db_entry.tmpls.sort_by_key(|template| template.ord);
+        Ok(Self {
+            id,
+            name: db_entry.name,
+            fields: db_entry.flds,
+            templates: db_entry.tmpls,
+            css: db_entry.css,
+            model_type,
+            latex_pre: db_entry.latex_pre,
+            latex_post: db_entry.latex_post,
+            latex_svg: db_entry.latexsvg,
+            sort_field_index: db_entry.sortf,
+            card_gen_plan: PlanCache::default(),
+        }
+        .assign_template_ords())
+    }
+}
+
+/// The (de)serialized layout of a `Model`
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ModelDef {
+    id: i64,
+    name: String,
+    #[serde(default)]
+    fields: Vec<Field>,
+    #[serde(default)]
+    templates: Vec<Template>,
+    #[serde(default)]
+    css: String,
+    #[serde(default = "default_model_type")]
+    model_type: ModelType,
+    #[serde(default = "default_latex_pre")]
+    latex_pre: String,
+    #[serde(default = "default_latex_post")]
+    latex_post: String,
+    #[serde(default)]
+    latex_svg: bool,
+    #[serde(default)]
+    sort_field_index: i64,
+}
+
+#[cfg(feature = "serde")]
+fn default_model_type() -> ModelType {
+    ModelType::FrontBack
+}
+
+#[cfg(feature = "serde")]
+fn default_latex_pre() -> String {
+    DEFAULT_LATEX_PRE.to_string()
+}
+
+#[cfg(feature = "serde")]
+fn default_latex_post() -> String {
+    DEFAULT_LATEX_POST.to_string()
+}
+
+#[cfg(feature = "serde")]
+impl From<Model> for ModelDef {
+    fn from(model: Model) -> Self {
+        ModelDef {
+            id: model.id,
+            name: model.name,
+            fields: model.fields.into_iter().map(Field::from).collect(),
+            templates: model.templates.into_iter().map(Template::from).collect(),
+            css: model.css,
+            model_type: model.model_type,
+            latex_pre: model.latex_pre,
+            latex_post: model.latex_post,
+            latex_svg: model.latex_svg,
+            sort_field_index: model.sort_field_index,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<ModelDef> for Model {
+    fn from(def: ModelDef) -> Self {
+        Model::new(def.id, def.name, def.fields, def.templates)
+            .css(def.css)
+            .model_type(def.model_type)
+            .latex_pre(def.latex_pre)
+            .latex_post(def.latex_post)
+            .latex_svg(def.latex_svg)
+            .sort_field_index(def.sort_field_index)
+    }
+}
+
+/// Returns the index and value of the first item that already appeared before
+fn find_duplicate<'a>(names: impl Iterator<Item = &'a str>) -> Option<(usize, &'a str)> {
+    let mut seen = HashSet::new();
+    names.enumerate().find(|(_, name)| !seen.insert(*name))
+}
+
+/// Format strings and CSS longer than this are truncated in the `Debug` output of a `Model`
+const DEBUG_TRUNCATE_LEN: usize = 40;
+
+impl fmt::Debug for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Model")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("fields", &self.field_names())
+            .field("templates", &self.template_names())
+            .field("css", &truncate(&self.css, DEBUG_TRUNCATE_LEN))
+            .field("model_type", &self.model_type)
+            .field("latex_pre", &truncate(&self.latex_pre, DEBUG_TRUNCATE_LEN))
+            .field(
+                "latex_post",
+                &truncate(&self.latex_post, DEBUG_TRUNCATE_LEN),
+            )
+            .field("latex_svg", &self.latex_svg)
+            .field("sort_field_index", &self.sort_field_index)
+            .finish()
+    }
+}
+
+fn check_insert_index(index: usize, len: usize) -> Result<(), Error> {
+    if index > len {
+        Err(Error::IndexOutOfRange { index, len })
+    } else {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Deck, Note};
-    use std::collections::HashSet;
-    use tempfile::NamedTempFile;
+    use crate::Note;
 
     fn css() -> String {
         r#".card {
@@ -239,35 +981,33 @@ mod tests {
     }
 
     fn cloze_model() -> Model {
-        Model::new_with_options(
-            998877661,
-            "Cloze Model",
-            vec![Field::new("Text"), Field::new("Extra")],
-            vec![Template::new("My Cloze Card")
-                .qfmt("{{Text}}")
-                .afmt("{{Text}}<br>{{Extra}}")],
-            Some(&css()),
-            Some(ModelType::Cloze),
-            None,
-            None,
-            None,
-        )
+        Model::builder(998877661, "Cloze Model")
+            .field(Field::new("Text"))
+            .field(Field::new("Extra"))
+            .template(
+                Template::new("My Cloze Card")
+                    .qfmt("{{cloze:Text}}")
+                    .afmt("{{cloze:Text}}<br>{{Extra}}"),
+            )
+            .css(css())
+            .model_type(ModelType::Cloze)
+            .build()
+            .unwrap()
     }
 
     fn multi_field_cloze_model() -> Model {
-        Model::new_with_options(
-            1047194615,
-            "Multi Field Cloze Model",
-            vec![Field::new("Text1"), Field::new("Text2")],
-            vec![Template::new("Cloze")
-                .qfmt("{{cloze:Text1}} and {{cloze:Text2}}")
-                .afmt("{{cloze:Text1}} and {{cloze:Text2}}")],
-            Some(&css()),
-            Some(ModelType::Cloze),
-            None,
-            None,
-            None,
-        )
+        Model::builder(1047194615, "Multi Field Cloze Model")
+            .field(Field::new("Text1"))
+            .field(Field::new("Text2"))
+            .template(
+                Template::new("Cloze")
+                    .qfmt("{{cloze:Text1}} and {{cloze:Text2}}")
+                    .afmt("{{cloze:Text1}} and {{cloze:Text2}}"),
+            )
+            .css(css())
+            .model_type(ModelType::Cloze)
+            .build()
+            .unwrap()
     }
 
     #[test]
@@ -286,6 +1026,712 @@ mod tests {
         assert_eq!(sorted, vec![0, 1, 2, 3]);
     }
 
+    fn optional_reversed_model() -> Model {
+        Model::new(
+            1382232460,
+            "Optional Reversed Model",
+            vec![
+                Field::new("Front"),
+                Field::new("Back"),
+                Field::new("Add Reverse"),
+            ],
+            vec![
+                Template::new("Card 1")
+                    .qfmt("{{Front}}")
+                    .afmt("{{FrontSide}}<hr id=answer>{{Back}}"),
+                Template::new("Card 2")
+                    .qfmt("{{#Add Reverse}}{{Back}}{{/Add Reverse}}")
+                    .afmt("{{FrontSide}}<hr id=answer>{{Front}}"),
+            ],
+        )
+    }
+
+    #[test]
+    fn req_all() {
+        assert_eq!(
+            optional_reversed_model().req().unwrap(),
+            vec![
+                (0, "all".to_string(), vec![0]),
+                (1, "all".to_string(), vec![1, 2])
+            ]
+        );
+        // Anki doesn't use `req` for cloze models
+        assert_eq!(cloze_model().req().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn req_any() {
+        let model = Model::new(
+            1382232461,
+            "Any Model",
+            vec![Field::new("A"), Field::new("B"), Field::new("C")],
+            vec![Template::new("Card 1")
+                .qfmt("{{A}}{{^A}}{{B}}{{/A}}")
+                .afmt("{{C}}")],
+        );
+        assert_eq!(
+            model.req().unwrap(),
+            vec![(0, "any".to_string(), vec![0, 1])]
+        );
+    }
+
+    #[test]
+    fn req_without_fields() {
+        let model = Model::new(
+            1382232462,
+            "Static Model",
+            vec![Field::new("A")],
+            vec![Template::new("Bad").qfmt("static text only").afmt("{{A}}")],
+        );
+        match model.req() {
+            Err(Error::TemplateHasNoFields { template_name }) => assert_eq!(template_name, "Bad"),
+            _ => panic!("expected TemplateHasNoFields"),
+        }
+        assert!(matches!(
+            Note::new(model, vec!["a"]),
+            Err(Error::TemplateHasNoFields { .. })
+        ));
+
+        let model = Model::new(
+            1382232463,
+            "Conditional Static Model",
+            vec![Field::new("Cond")],
+            vec![Template::new("Bad").qfmt("{{#Cond}}static{{/Cond}}")],
+        );
+        assert!(matches!(
+            model.req(),
+            Err(Error::TemplateHasNoFields { .. })
+        ));
+
+        let model = Model::new(
+            1382232464,
+            "Never Rendered Model",
+            vec![Field::new("A")],
+            vec![Template::new("Card 1").qfmt("{{^A}}{{A}}{{/A}}")],
+        );
+        assert!(matches!(model.req(), Err(Error::TemplateFormat(_))));
+    }
+
+    #[test]
+    fn conditional_front_side_generates_cards_only_when_filled() {
+        let note = Note::new(optional_reversed_model(), vec!["a", "b", ""]).unwrap();
+        assert_eq!(
+            note.cards()
+                .iter()
+                .map(|card| card.ord())
+                .collect::<Vec<_>>(),
+            vec![0]
+        );
+        let note = Note::new(optional_reversed_model(), vec!["a", "b", "y"]).unwrap();
+        assert_eq!(
+            note.cards()
+                .iter()
+                .map(|card| card.ord())
+                .collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn validate_unknown_field() {
+        let model = Model::new(
+            1382232463,
+            "Typo Model",
+            vec![Field::new("Question"), Field::new("Answer")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Question}}")
+                .afmt("{{FrontSide}}<hr id=answer>{{Answr}}")],
+        );
+        match model.validate() {
+            Err(Error::UnknownTemplateField {
+                template_name,
+                field_name,
+                known_fields,
+            }) => {
+                assert_eq!(template_name, "Card 1");
+                assert_eq!(field_name, "Answr");
+                assert_eq!(known_fields, vec!["Question", "Answer"]);
+            }
+            _ => panic!("expected UnknownTemplateField"),
+        }
+        assert!(Note::new(model, vec!["a", "b"]).is_err());
+    }
+
+    #[test]
+    fn validate_special_fields_and_filters() {
+        let model = Model::new(
+            1382232464,
+            "Filter Model",
+            vec![Field::new("Text"), Field::new("Hint")],
+            vec![Template::new("Card 1")
+                .qfmt("{{cloze:Text}}{{hint:Hint}}{{Deck}}{{Card}}{{Type}}")
+                .afmt("{{FrontSide}}<hr id=answer>{{Tags}}{{type:Text}}")],
+        );
+        model.validate().unwrap();
+
+        let japanese = Model::new(
+            1382232465,
+            "Japanese Model",
+            vec![Field::new("Expression"), Field::new("Reading")],
+            vec![Template::new("Recognition")
+                .qfmt("{{kanji:Reading}}")
+                .afmt("{{furigana:Reading}}<br>{{kana:Reading}}{{ kanji : Expression }}")],
+        );
+        japanese.validate().unwrap();
+        let unknown = japanese.map_templates(|template| template.qfmt("{{furigana:Meaning}}"));
+        assert!(matches!(
+            unknown.validate(),
+            Err(Error::UnknownTemplateField { field_name, .. }) if field_name == "Meaning"
+        ));
+    }
+
+    #[test]
+    fn validate_sections() {
+        let model = optional_reversed_model()
+            .map_templates(|template| template.qfmt("{{#Add Reverse}}{{Back}}"));
+        match model.validate() {
+            Err(Error::TemplateSyntax {
+                template_name,
+                tag,
+                offset,
+                ..
+            }) => {
+                assert_eq!(template_name, "Card 1");
+                assert_eq!(tag, "{{#Add Reverse}}");
+                assert_eq!(offset, 0);
+            }
+            _ => panic!("expected TemplateSyntax"),
+        }
+
+        let model = optional_reversed_model()
+            .map_templates(|template| template.qfmt("{{Front}}{{^Hint}}no hint{{/Hint}}"));
+        match model.validate() {
+            Err(error @ Error::TemplateSyntax { .. }) => assert_eq!(
+                error.to_string(),
+                r#"syntax error in template "Card 1" at offset 9 ({{^Hint}}): section "Hint" is not a field"#
+            ),
+            _ => panic!("expected TemplateSyntax"),
+        }
+    }
+
+    #[test]
+    fn validate_type_in_the_answer_field() {
+        let model = Model::new(
+            1,
+            "Type",
+            vec![Field::new("Front"), Field::new("Back")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Front}} {{type:Answer}}")
+                .afmt("{{FrontSide}}")],
+        );
+        match model.validate() {
+            Err(Error::UnknownTemplateField { field_name, .. }) => assert_eq!(field_name, "Answer"),
+            _ => panic!("expected UnknownTemplateField"),
+        }
+    }
+
+    #[test]
+    fn validate_duplicate_field_names() {
+        let model = Model::new(1382232465, "Duplicates", vec![], vec![])
+            .with_field(Field::new("Front"))
+            .with_field(Field::new("front"))
+            .with_field(Field::new("Front"));
+        match model.validate() {
+            Err(Error::DuplicateFieldName { name, index }) => {
+                assert_eq!(name, "Front");
+                assert_eq!(index, 2);
+            }
+            _ => panic!("expected DuplicateFieldName"),
+        }
+    }
+
+    #[test]
+    fn validate_duplicate_template_names() {
+        let model = Model::new(1382232466, "Duplicates", vec![Field::new("Front")], vec![])
+            .with_template(Template::new("Card 1").qfmt("{{Front}}"))
+            .with_template(Template::new("Card 1").qfmt("{{Front}}"));
+        match model.to_model_db_entry(Timestamp::from_secs(0), 0) {
+            Err(Error::DuplicateTemplateName { name, index }) => {
+                assert_eq!(name, "Card 1");
+                assert_eq!(index, 1);
+            }
+            _ => panic!("expected DuplicateTemplateName"),
+        }
+    }
+
+    #[test]
+    fn id_from_name_is_pinned() {
+        assert_eq!(Model::id_from_name("Simple Model"), 8765803422469661);
+        assert_eq!(Model::id_from_name("Basic (genanki)"), 4754480568469925);
+        assert_eq!(Model::id_from_name("Modèle français"), 7233979248851589);
+        assert_eq!(Model::id_from_name(""), 4719389940915220);
+        let model = Model::new_with_id_from_name("Simple Model", vec![], vec![]);
+        assert_eq!(model.id, 8765803422469661);
+        assert_eq!(model.name(), "Simple Model");
+    }
+
+    #[test]
+    fn getters() {
+        let model = cloze_model();
+        assert_eq!(model.name(), "Cloze Model");
+        assert_eq!(model.get_css(), css());
+        assert_eq!(model.field_names(), vec!["Text", "Extra"]);
+        assert_eq!(model.template_names(), vec!["My Cloze Card"]);
+        assert!(model.get_model_type() == ModelType::Cloze);
+        assert_eq!(model.get_latex_pre(), DEFAULT_LATEX_PRE);
+        assert_eq!(model.get_latex_post(), DEFAULT_LATEX_POST);
+        assert_eq!(model.get_sort_field_index(), 0);
+    }
+
+    const ANKI_BASIC_MODEL_JSON: &str = r#"{
+        "id": 1637085264469,
+        "name": "Basic",
+        "type": 0,
+        "mod": 1637085297,
+        "usn": -1,
+        "sortf": 0,
+        "did": null,
+        "tmpls": [
+            {
+                "name": "Card 1",
+                "ord": 0,
+                "qfmt": "{{Front}}",
+                "afmt": "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}",
+                "bqfmt": "",
+                "bafmt": "",
+                "did": null,
+                "bfont": "",
+                "bsize": 0
+            }
+        ],
+        "flds": [
+            {
+                "name": "Back",
+                "ord": 1,
+                "sticky": false,
+                "rtl": false,
+                "font": "Arial",
+                "size": 20,
+                "description": ""
+            },
+            {
+                "name": "Front",
+                "ord": 0,
+                "sticky": false,
+                "rtl": false,
+                "font": "Arial",
+                "size": 20,
+                "description": ""
+            }
+        ],
+        "css": ".card {\n    font-family: arial;\n    font-size: 20px;\n    text-align: center;\n    color: black;\n    background-color: white;\n}\n",
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "latexsvg": false,
+        "req": [[0, "any", [0]]],
+        "originalStockKind": 1
+    }"#;
+
+    #[test]
+    fn from_json_anki_model() {
+        let model = Model::from_json(ANKI_BASIC_MODEL_JSON).unwrap();
+        assert_eq!(model.id, 1637085264469);
+        assert_eq!(model.name(), "Basic");
+        assert_eq!(model.field_names(), vec!["Front", "Back"]);
+        assert_eq!(model.template_names(), vec!["Card 1"]);
+        assert!(model.get_model_type() == ModelType::FrontBack);
+        assert_eq!(model.get_latex_post(), r"\end{document}");
+        assert!(model.get_css().starts_with(".card {"));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let model = Model::from_json(ANKI_BASIC_MODEL_JSON).unwrap();
+        let json = model.to_json(1637085297.0, 1).unwrap();
+        let written: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let original: serde_json::Value = serde_json::from_str(ANKI_BASIC_MODEL_JSON).unwrap();
+        for key in &["name", "type", "sortf", "css", "latexPre", "latexPost"] {
+            assert_eq!(written[key], original[key], "{}", key);
+        }
+        // `req` is recomputed on write; with a single required field, "all" and "any" are
+        // equivalent.
+        assert_eq!(written["req"], serde_json::json!([[0, "all", [0]]]));
+        assert_eq!(written["flds"][0]["name"], "Front");
+        assert_eq!(written["flds"][1]["ord"], 1);
+        assert_eq!(written["tmpls"][0]["afmt"], original["tmpls"][0]["afmt"]);
+
+        let reparsed = Model::from_json(&json).unwrap();
+        assert_eq!(reparsed.to_json(1637085297.0, 1).unwrap(), json);
+
+        let cloze = Model::from_json(&cloze_model().to_json(0.0, 1).unwrap()).unwrap();
+        assert!(cloze.get_model_type() == ModelType::Cloze);
+        assert_eq!(cloze.get_css(), css());
+    }
+
+    #[test]
+    fn browser_formats_match_anki_tmpl() {
+        let model = Model::new(
+            1637085264469,
+            "Basic",
+            vec![Field::new("Front"), Field::new("Back")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Front}}")
+                .afmt("{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}")
+                .bqfmt("{{Front}} (browser)")
+                .bafmt("{{Back}}")
+                .browser_font("Arial", 12)],
+        );
+        let written: serde_json::Value =
+            serde_json::from_str(&model.to_json(0.0, 1).unwrap()).unwrap();
+        // Exported from Anki after setting the browser appearance of "Card 1"
+        let anki_tmpl: serde_json::Value = serde_json::from_str(
+            r#"{
+                "name": "Card 1",
+                "ord": 0,
+                "qfmt": "{{Front}}",
+                "afmt": "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}",
+                "bqfmt": "{{Front}} (browser)",
+                "bafmt": "{{Back}}",
+                "did": null,
+                "bfont": "Arial",
+                "bsize": 12
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(written["tmpls"][0], anki_tmpl);
+
+        let default_tmpl = Model::from_json(ANKI_BASIC_MODEL_JSON)
+            .unwrap()
+            .to_json(0.0, 1)
+            .unwrap();
+        let default_tmpl: serde_json::Value = serde_json::from_str(&default_tmpl).unwrap();
+        assert_eq!(default_tmpl["tmpls"][0]["bqfmt"], "");
+        assert_eq!(default_tmpl["tmpls"][0]["bafmt"], "");
+        assert!(default_tmpl["tmpls"][0].get("bfont").is_none());
+        assert!(default_tmpl["tmpls"][0].get("bsize").is_none());
+    }
+
+    #[test]
+    fn field_options_match_anki_fld() {
+        let model = Model::new(
+            1637085264469,
+            "Basic",
+            vec![Field::new("Front")
+                .font("Noto Naskh Arabic")
+                .size(24)
+                .rtl(true)
+                .sticky(true)
+                .description("The word in Arabic")
+                .plain_text(true)
+                .collapsed(true)],
+            vec![Template::new("Card 1").qfmt("{{Front}}")],
+        );
+        let written: serde_json::Value =
+            serde_json::from_str(&model.to_json(0.0, 1).unwrap()).unwrap();
+        // Exported from Anki after changing the options of the "Front" field
+        let anki_fld: serde_json::Value = serde_json::from_str(
+            r#"{
+                "name": "Front",
+                "ord": 0,
+                "sticky": true,
+                "rtl": true,
+                "font": "Noto Naskh Arabic",
+                "size": 24,
+                "description": "The word in Arabic",
+                "plainText": true,
+                "collapsed": true
+            }"#,
+        )
+        .unwrap();
+        for (key, value) in anki_fld.as_object().unwrap() {
+            assert_eq!(&written["flds"][0][key], value, "{}", key);
+        }
+
+        let default_fld = serde_json::to_value(Fld::from(Field::new("Front"))).unwrap();
+        assert_eq!(
+            default_fld,
+            serde_json::json!({
+                "name": "Front",
+                "media": [],
+                "sticky": false,
+                "rtl": false,
+                "ord": 0,
+                "font": "Liberation Sans",
+                "size": 20
+            })
+        );
+    }
+
+    #[test]
+    fn from_json_invalid() {
+        assert!(Model::from_json("{}").is_err());
+        assert!(
+            Model::from_json(r#"{"id": "abc", "name": "x", "flds": [], "tmpls": []}"#).is_err()
+        );
+        assert!(
+            Model::from_json(r#"{"id": 1, "name": "x", "type": 7, "flds": [], "tmpls": []}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn bulk_and_positional_builders() {
+        let model = Model::new(1, "test", vec![], vec![])
+            .with_fields(vec![Field::new("a"), Field::new("c")])
+            .with_templates(vec![
+                Template::new("t1").qfmt("{{a}}"),
+                Template::new("t3").qfmt("{{c}}"),
+            ])
+            .sort_field_index(1)
+            .insert_field(1, Field::new("b"))
+            .unwrap()
+            .insert_template(1, Template::new("t2").qfmt("{{b}}"))
+            .unwrap();
+        assert_eq!(model.field_names(), vec!["a", "b", "c"]);
+        assert_eq!(model.template_names(), vec!["t1", "t2", "t3"]);
+        assert_eq!(model.get_sort_field_index(), 2);
+
+        let model = model.insert_field(3, Field::new("d")).unwrap();
+        assert_eq!(model.field_names(), vec!["a", "b", "c", "d"]);
+        assert_eq!(model.get_sort_field_index(), 2);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&model.to_json(0.0, 1).unwrap()).unwrap();
+        assert_eq!(json["flds"][1]["name"], "b");
+        assert_eq!(json["flds"][1]["ord"], 1);
+        assert_eq!(json["tmpls"][2]["ord"], 2);
+        assert_eq!(json["sortf"], 2);
+    }
+
+    #[test]
+    fn insert_out_of_range() {
+        let model = Model::new(1, "test", vec![Field::new("a")], vec![]);
+        match model.clone().insert_field(2, Field::new("b")) {
+            Err(Error::IndexOutOfRange { index, len }) => assert_eq!((index, len), (2, 1)),
+            _ => panic!("expected IndexOutOfRange"),
+        }
+        match model.insert_template(1, Template::new("t")) {
+            Err(Error::IndexOutOfRange { index, len }) => assert_eq!((index, len), (1, 0)),
+            _ => panic!("expected IndexOutOfRange"),
+        }
+    }
+
+    #[test]
+    fn partial_eq_and_debug() {
+        assert_eq!(cloze_model(), cloze_model());
+        assert_ne!(cloze_model(), cloze_model().sort_field_index(1));
+        assert_ne!(
+            cloze_model(),
+            cloze_model().with_field(Field::new("Another"))
+        );
+
+        let debug = format!("{:?}", cloze_model().latex_post("short"));
+        assert!(debug.starts_with(
+            r#"Model { id: 998877661, name: "Cloze Model", fields: ["Text", "Extra"], templates: ["My Cloze Card"], css: ".card {"#
+        ));
+        assert!(debug.contains("…"));
+        assert!(debug.contains(r#"latex_post: "short""#));
+        assert!(!debug.contains("qfmt"));
+    }
+
+    #[test]
+    fn sort_field_out_of_range() {
+        let model = Model::new(
+            1,
+            "test",
+            vec![Field::new("a"), Field::new("b")],
+            vec![Template::new("t").qfmt("{{a}}")],
+        );
+        assert!(model.clone().sort_field(1).to_json(0.0, 1).is_ok());
+        for index in &[2, -1] {
+            match model.clone().sort_field_index(*index).to_json(0.0, 1) {
+                Err(Error::SortFieldOutOfRange {
+                    index: found,
+                    field_count,
+                }) => assert_eq!((found, field_count), (*index, 2)),
+                _ => panic!("expected SortFieldOutOfRange"),
+            }
+        }
+
+        let empty = Model::new(2, "empty", vec![], vec![]);
+        match empty.validate_sort_field_index() {
+            Err(Error::SortFieldOutOfRange { index, field_count }) => {
+                assert_eq!((index, field_count), (0, 0))
+            }
+            _ => panic!("expected SortFieldOutOfRange"),
+        }
+        assert!(empty.to_json(0.0, 1).is_err());
+    }
+
+    #[test]
+    fn latex_svg() {
+        let json: serde_json::Value =
+            serde_json::from_str(&cloze_model().latex_svg(true).to_json(0.0, 1).unwrap()).unwrap();
+        assert_eq!(json["latexsvg"], true);
+        let model = Model::from_json(&json.to_string()).unwrap();
+        assert!(model.get_latex_svg());
+
+        let json: serde_json::Value =
+            serde_json::from_str(&cloze_model().to_json(0.0, 1).unwrap()).unwrap();
+        assert!(json.get("latexsvg").is_none());
+    }
+
+    #[test]
+    fn derive_variant() {
+        let original = Model::new(
+            1,
+            "Vocab DE→EN",
+            vec![Field::new("DE"), Field::new("EN")],
+            vec![Template::new("Card 1")
+                .qfmt("{{DE}}")
+                .afmt("{{FrontSide}}<hr id=answer>{{EN}}")
+                .browser_font("Arial", 12)],
+        );
+        let variant = original
+            .clone_with_id(2)
+            .rename("Vocab EN→DE")
+            .map_templates(|template| {
+                template
+                    .qfmt("{{EN}}")
+                    .afmt("{{FrontSide}}<hr id=answer>{{DE}}")
+            });
+        assert_eq!(original.id, 1);
+        assert_eq!(original.name(), "Vocab DE→EN");
+        assert_eq!(original.templates()[0].qfmt, "{{DE}}");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&variant.to_json(0.0, 1).unwrap()).unwrap();
+        assert_eq!(json["id"], "2");
+        assert_eq!(json["name"], "Vocab EN→DE");
+        assert_eq!(json["tmpls"][0]["name"], "Card 1");
+        assert_eq!(json["tmpls"][0]["qfmt"], "{{EN}}");
+        assert_eq!(
+            json["tmpls"][0]["afmt"],
+            "{{FrontSide}}<hr id=answer>{{DE}}"
+        );
+        assert_eq!(json["tmpls"][0]["bfont"], "Arial");
+        assert_eq!(
+            original.clone_with_id(2).map_templates(|t| t),
+            original.clone_with_id(2)
+        );
+    }
+
+    #[test]
+    fn db_entry_conversion() {
+        let model = Model::new(
+            1,
+            "test",
+            vec![Field::new("a"), Field::new("b")],
+            vec![
+                Template::new("t1").qfmt("{{a}}"),
+                Template::new("t2").qfmt("{{b}}"),
+            ],
+        );
+        let db_entry = model.to_db_entry(0.0, 1).unwrap();
+        assert_eq!(db_entry.flds[1].ord, 1);
+        assert_eq!(db_entry.tmpls[1].ord, 1);
+        // The model keeps the templates in the order they were added, with their ords
+        assert_eq!(model.templates()[1].name, "t2");
+        assert_eq!(model.templates()[1].ord, 1);
+
+        let converted = Model::try_from(db_entry).unwrap();
+        assert_eq!(converted.id, 1);
+        assert_eq!(converted.field_names(), vec!["a", "b"]);
+        assert_eq!(
+            converted.to_json(0.0, 1).unwrap(),
+            model.to_json(0.0, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn cloze_models_need_one_template_with_a_cloze() {
+        let no_templates =
+            Model::new(1, "test", vec![Field::new("Text")], vec![]).model_type(ModelType::Cloze);
+        assert!(matches!(
+            no_templates.validate(),
+            Err(Error::ClozeTemplateCount(0))
+        ));
+
+        let two_templates =
+            cloze_model().with_template(Template::new("Second").qfmt("{{cloze:Text}}"));
+        assert!(matches!(
+            two_templates.to_json(0.0, 1),
+            Err(Error::ClozeTemplateCount(2))
+        ));
+
+        let without_cloze = Model::new(
+            1,
+            "test",
+            vec![Field::new("Text")],
+            vec![Template::new("Not a cloze").qfmt("{{Text}}")],
+        )
+        .model_type(ModelType::Cloze);
+        match without_cloze.validate() {
+            Err(Error::ClozeTemplateWithoutCloze { template_name }) => {
+                assert_eq!(template_name, "Not a cloze")
+            }
+            _ => panic!("expected ClozeTemplateWithoutCloze"),
+        }
+
+        assert!(cloze_model().validate().is_ok());
+    }
+
+    #[test]
+    fn owned_strings() {
+        let header = "Question,Answer".to_string();
+        let fields: Vec<Field> = header.split(',').map(Field::new).collect();
+        let name = format!("{} Model", "CSV");
+        let model = Model::new(1, &name, fields, vec![]).with_template(
+            Template::new(String::from("Card 1")).qfmt(format!("{{{{{}}}}}", "Question")),
+        );
+        assert_eq!(model.name(), "CSV Model");
+        assert_eq!(model.field_names(), vec!["Question", "Answer"]);
+        assert_eq!(model.templates()[0].qfmt, "{{Question}}");
+        assert_eq!(Model::new(1, name.clone(), vec![], vec![]).name(), name);
+    }
+
+    #[test]
+    fn builder_validates() {
+        let missing_field = Model::builder(1, "test")
+            .field(Field::new("Front"))
+            .template(Template::new("Card 1").qfmt("{{Back}}"))
+            .build();
+        assert!(matches!(
+            missing_field,
+            Err(Error::UnknownTemplateField { .. })
+        ));
+        let sort_field = Model::builder(1, "test")
+            .field(Field::new("Front"))
+            .template(Template::new("Card 1").qfmt("{{Front}}"))
+            .sort_field_index(1)
+            .build();
+        assert!(matches!(sort_field, Err(Error::SortFieldOutOfRange { .. })));
+        let cloze = Model::builder(1, "test")
+            .field(Field::new("Text"))
+            .model_type(ModelType::Cloze)
+            .build();
+        assert!(matches!(cloze, Err(Error::ClozeTemplateCount(0))));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn new_with_options_matches_builder() {
+        let model = Model::new_with_options(
+            998877661,
+            "Cloze Model",
+            vec![Field::new("Text"), Field::new("Extra")],
+            vec![Template::new("My Cloze Card")
+                .qfmt("{{cloze:Text}}")
+                .afmt("{{cloze:Text}}<br>{{Extra}}")],
+            Some(&css()),
+            Some(ModelType::Cloze),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(model, cloze_model());
+    }
+
     #[test]
     fn build_all_fields() {
         // A simple test to make sure we can call all the setters on the builder.
@@ -299,4 +1745,214 @@ mod tests {
             .sort_field_index(1)
             .model_type(ModelType::FrontBack);
     }
+
+    #[test]
+    fn render_preview_front_back() {
+        let model = Model::builder(1, "Vocab")
+            .field(Field::new("Word"))
+            .field(Field::new("Meaning"))
+            .field(Field::new("Example"))
+            .template(
+                Template::new("Recognition")
+                    .qfmt("{{Word}}{{#Example}}<i>{{text:Example}}</i>{{/Example}}{{^Example}}-{{/Example}}")
+                    .afmt("{{FrontSide}}<hr id=answer>{{Meaning}} ({{Card}}, {{Type}}, {{Tags}}){{type:Word}}"),
+            )
+            .css(".card {}")
+            .build()
+            .unwrap();
+        let note = Note::new(model.clone(), vec!["Hund", "dog", "<b>Der</b> Hund"])
+            .unwrap()
+            .tags(["nouns", "animals"])
+            .unwrap();
+        let card = model.render_preview(&note, 0).unwrap();
+        assert_eq!(card.question_html, "Hund<i>Der Hund</i>");
+        assert_eq!(
+            card.answer_html,
+            "Hund<i>Der Hund</i><hr id=answer>dog (Recognition, Vocab, nouns animals)"
+        );
+        assert_eq!(card.css, ".card {}");
+
+        // Fields with only whitespace and line breaks are empty for sections
+        let note = Note::new(model.clone(), vec!["Katze", "cat", " <br> <div></div>"]).unwrap();
+        let card = model.render_preview(&note, 0).unwrap();
+        assert_eq!(card.question_html, "Katze-");
+        assert!(matches!(
+            model.render_preview(&note, 1),
+            Err(Error::IndexOutOfRange { index: 1, len: 1 })
+        ));
+        assert!(matches!(
+            model.render_preview(&note, usize::MAX),
+            Err(Error::IndexOutOfRange {
+                index: usize::MAX,
+                len: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn render_preview_cloze() {
+        let model = crate::cloze_model();
+        let note = Note::new(
+            model.clone(),
+            vec![
+                "{{c1::Berlin::city}} is in {{c2::{{c1::Ger}}many}}, {{c3::open",
+                "Extra",
+            ],
+        )
+        .unwrap();
+        let card = model.render_preview(&note, 0).unwrap();
+        assert_eq!(
+            card.question_html,
+            "<span class=cloze>[city]</span> is in <span class=cloze>[...]</span>many, {{c3::open"
+        );
+        assert_eq!(
+            card.answer_html,
+            "<span class=cloze>Berlin</span> is in <span class=cloze>Ger</span>many, {{c3::open<br>\nExtra"
+        );
+        let card = model.render_preview(&note, 1).unwrap();
+        assert_eq!(
+            card.question_html,
+            "Berlin is in <span class=cloze>[...]</span>, {{c3::open"
+        );
+        assert!(card
+            .answer_html
+            .starts_with("Berlin is in <span class=cloze>Germany</span>, "));
+        // Cloze numbers that aren't in the note render like the other clozes are revealed
+        let last = model.render_preview(&note, u32::MAX as usize).unwrap();
+        assert_eq!(last.question_html, "Berlin is in Germany, {{c3::open");
+        assert!(matches!(
+            model.render_preview(&note, usize::MAX),
+            Err(Error::IndexOutOfRange {
+                index: usize::MAX,
+                len: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn render_preview_hint() {
+        let model = Model::builder(1, "Hints")
+            .field(Field::new("Front"))
+            .field(Field::new("Hint"))
+            .template(
+                Template::new("Card 1")
+                    .qfmt("{{Front}}{{hint:Hint}}")
+                    .afmt("{{Front}}"),
+            )
+            .build()
+            .unwrap();
+        let note = Note::new(model.clone(), vec!["Front", "a hint"]).unwrap();
+        let id = crate::util::field_checksum("a hint");
+        assert_eq!(
+            model.render_preview(&note, 0).unwrap().question_html,
+            format!(
+                "Front<a class=hint href=\"#\" onclick=\"this.style.display='none';\
+                 document.getElementById('hint{id}').style.display='block';return false;\" \
+                 draggable=false>Hint</a><div id=\"hint{id}\" class=hint style=\"display: none\">a hint</div>",
+                id = id
+            )
+        );
+        let note = Note::new(model.clone(), vec!["Front", ""]).unwrap();
+        assert_eq!(
+            model.render_preview(&note, 0).unwrap().question_html,
+            "Front"
+        );
+    }
+
+    #[test]
+    fn sticky_template_ords() {
+        let templates = vec![
+            Template::new("Recognition").qfmt("{{Word}}").ord(0),
+            Template::new("Recall").qfmt("{{Meaning}}").ord(1),
+            Template::new("Listening").qfmt("{{Audio}}").ord(2),
+        ];
+        let fields = vec![
+            Field::new("Word"),
+            Field::new("Meaning"),
+            Field::new("Audio"),
+        ];
+        let model = Model::new(1, "Vocab", fields.clone(), templates.clone());
+        let mut reordered = templates;
+        reordered.swap(0, 2);
+        let reordered = Model::new(1, "Vocab", fields, reordered);
+
+        let ords = |model: &Model| -> Vec<(String, i64)> {
+            let db_entry = model.to_db_entry(0.0, 1).unwrap();
+            db_entry
+                .tmpls
+                .into_iter()
+                .map(|t| (t.name, t.ord))
+                .collect()
+        };
+        assert_eq!(ords(&reordered), ords(&model));
+        assert_eq!(ords(&model)[2], ("Listening".to_string(), 2));
+        let card_ords = |model: &Model| -> Vec<(i64, String)> {
+            let note = Note::new(model.clone(), vec!["Hund", "", "hund.mp3"]).unwrap();
+            note.cards()
+                .iter()
+                .map(|card| {
+                    let template = model.template_by_ord(card.ord()).unwrap();
+                    (card.ord(), template.name.clone())
+                })
+                .collect()
+        };
+        assert_eq!(
+            card_ords(&reordered),
+            vec![(0, "Recognition".to_string()), (2, "Listening".to_string())]
+        );
+        assert_eq!(card_ords(&reordered), card_ords(&model));
+        assert_eq!(
+            reordered
+                .render_preview(
+                    &Note::new(reordered.clone(), vec!["a", "b", "c"]).unwrap(),
+                    2
+                )
+                .unwrap()
+                .question_html,
+            "c"
+        );
+    }
+
+    #[test]
+    fn automatic_template_ords() {
+        let model = Model::new(
+            1,
+            "Vocab",
+            vec![Field::new("Word"), Field::new("Meaning")],
+            vec![
+                Template::new("New").qfmt("{{Word}}{{Meaning}}"),
+                Template::new("Recognition").qfmt("{{Word}}").ord(0),
+                Template::new("Recall").qfmt("{{Meaning}}"),
+            ],
+        );
+        let db_entry = model.to_db_entry(0.0, 1).unwrap();
+        let tmpls: Vec<(&str, i64)> = db_entry
+            .tmpls
+            .iter()
+            .map(|t| (t.name.as_str(), t.ord))
+            .collect();
+        assert_eq!(tmpls, vec![("Recognition", 0), ("New", 1), ("Recall", 2)]);
+        let req: Vec<usize> = db_entry.req.iter().map(|(ord, _, _)| *ord).collect();
+        assert_eq!(req, vec![0, 1, 2]);
+
+        let model = model.with_template(Template::new("Extra").qfmt("{{Word}}").ord(4));
+        assert!(matches!(
+            model.validate(),
+            Err(Error::TemplateOrdOutOfRange {
+                ord: 4,
+                template_count: 4,
+                ..
+            })
+        ));
+        let model = model.map_templates(|t| t.ord(1));
+        match model.validate() {
+            Err(Error::DuplicateTemplateOrd { ord, first, second }) => {
+                assert_eq!(
+                    (ord, first.as_str(), second.as_str()),
+                    (1, "New", "Recognition")
+                )
+            }
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
 }
@@ -0,0 +1,382 @@
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::apkg_col::APKG_COL;
+use crate::apkg_schema::APKG_SCHEMA;
+use crate::db_entries::{Fld, ModelDbEntry, Tmpl};
+use crate::deck::Deck;
+use crate::error::database_error;
+use crate::media::MediaFile;
+use crate::model::Model;
+use crate::note::Note;
+use crate::package_writer::{with_serialized, write_model_to_col};
+use crate::timestamp::Timestamp;
+use crate::{basic_model, Error};
+
+/// The note of the `collection.anki2` in the latest format, which Anki versions that can't
+/// import the format show instead of the notes of the package
+const UPDATE_NOTE: &str =
+    "Please update to the latest Anki version, then import the .colpkg/.apkg file again.";
+
+/// The format of a `.apkg` file, see [`Package::format`](crate::Package::format)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PackageFormat {
+    /// A `collection.anki2`, which all Anki versions can import
+    #[default]
+    Legacy,
+    /// The format of Anki 2.1.50 and later: a `collection.anki21b` and media files compressed
+    /// with zstd, and a `collection.anki2` for older versions that only contains a note asking
+    /// to update Anki
+    Latest,
+}
+
+/// The `meta` file of the latest format, a `PackageMetadata` protobuf message with the version
+/// `LATEST`
+pub(crate) fn meta() -> Vec<u8> {
+    let mut message = vec![];
+    encode_uint(1, 3, &mut message);
+    message
+}
+
+/// The `media` file of the latest format before it is compressed, a `MediaEntries` protobuf
+/// message with the name, size and SHA-1 hash of each file
+pub(crate) fn media_entries<'a>(
+    files: impl Iterator<Item = (String, &'a MediaFile)>,
+) -> Result<Vec<u8>, Error> {
+    let mut message = vec![];
+    for (name, media_file) in files {
+        let size = u32::try_from(media_file.len()?).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("the media file {} is larger than 4 GiB", name),
+            )
+        })?;
+        let mut entry = vec![];
+        encode_bytes(1, name.as_bytes(), &mut entry);
+        encode_uint(2, u64::from(size), &mut entry);
+        encode_bytes(3, &media_file.sha1()?, &mut entry);
+        encode_bytes(1, &entry, &mut message);
+    }
+    Ok(message)
+}
+
+/// The `collection.anki2` of the latest format, with one note in the default deck that asks to
+/// update Anki
+pub(crate) fn update_collection(timestamp: Timestamp) -> Result<Vec<u8>, Error> {
+    let conn = Connection::open_in_memory().map_err(database_error)?;
+    conn.execute_batch(APKG_SCHEMA).map_err(database_error)?;
+    conn.execute_batch(APKG_COL).map_err(database_error)?;
+    let mut id_gen = timestamp.ids();
+    let note = Note::new(basic_model(), vec![UPDATE_NOTE, ""])?;
+    write_model_to_col(&conn, note.model(), timestamp, 1)?;
+    note.write_to_db(&conn, timestamp, 1, &[], 0, &mut id_gen)?;
+    with_serialized(&conn, 1, |db| Ok(db.to_vec()))
+}
+
+/// The names of the media files in the `media` file of the latest format after it is
+/// decompressed, in the order of their entries in the archive, which are named by their index
+pub(crate) fn media_names(entries: &[u8]) -> Result<Vec<String>, Error> {
+    Message::decode(entries)?
+        .repeated(1)
+        .map(|entry| Message::decode(entry)?.string(1))
+        .collect()
+}
+
+/// Whether the collection in `conn` is one of Anki 2.1.50 and later, see [`read_notetypes`]
+pub(crate) fn has_notetypes(conn: &Connection) -> Result<bool, Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'notetypes')",
+        [],
+        |row| row.get(0),
+    )
+    .map_err(database_error)
+}
+
+/// Reads the models of a collection of Anki 2.1.50 and later, which stores them in the
+/// `notetypes`, `fields` and `templates` tables instead of the `models` column of `col`
+pub(crate) fn read_notetypes(conn: &Connection) -> Result<Vec<Model>, Error> {
+    let mut fields: HashMap<i64, Vec<Fld>> = HashMap::new();
+    for_each_row(
+        conn,
+        "SELECT ntid, ord, name, config FROM fields",
+        |row| {
+            let config: Vec<u8> = row.get(3)?;
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, config))
+        },
+        |(model_id, ord, name, config): (i64, i64, String, Vec<u8>)| {
+            let config = Message::decode(&config)?;
+            fields.entry(model_id).or_default().push(Fld {
+                name,
+                media: vec![],
+                sticky: config.uint(1) != 0,
+                rtl: config.uint(2) != 0,
+                ord,
+                font: config.string(3)?,
+                size: config.uint(4) as i64,
+                description: config.string(5)?,
+                plain_text: config.uint(6) != 0,
+                collapsed: config.uint(7) != 0,
+            });
+            Ok(())
+        },
+    )?;
+    let mut templates: HashMap<i64, Vec<Tmpl>> = HashMap::new();
+    for_each_row(
+        conn,
+        "SELECT ntid, ord, name, config FROM templates",
+        |row| {
+            let config: Vec<u8> = row.get(3)?;
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, config))
+        },
+        |(model_id, ord, name, config): (i64, i64, String, Vec<u8>)| {
+            let config = Message::decode(&config)?;
+            let deck_id = config.uint(5) as i64;
+            templates.entry(model_id).or_default().push(Tmpl {
+                name,
+                qfmt: config.string(1)?,
+                did: Some(deck_id).filter(|&id| id != 0),
+                bafmt: config.string(4)?,
+                afmt: config.string(2)?,
+                ord,
+                bqfmt: config.string(3)?,
+                bfont: config.string(6)?,
+                bsize: config.uint(7) as i64,
+                sticky_ord: false,
+            });
+            Ok(())
+        },
+    )?;
+    let mut models = vec![];
+    for_each_row(
+        conn,
+        "SELECT id, name, config FROM notetypes",
+        |row| {
+            let config: Vec<u8> = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, config))
+        },
+        |(id, name, config): (i64, String, Vec<u8>)| {
+            let config = Message::decode(&config)?;
+            let entry = ModelDbEntry {
+                vers: vec![],
+                name,
+                tags: vec![],
+                did: 0,
+                usn: 0,
+                req: vec![],
+                flds: fields.remove(&id).unwrap_or_default(),
+                sortf: config.uint(2) as i64,
+                tmpls: templates.remove(&id).unwrap_or_default(),
+                model_db_entry_mod: 0,
+                latex_post: config.string(6)?,
+                model_db_entry_type: config.uint(1) as i64,
+                id: id.to_string(),
+                css: config.string(3)?,
+                latex_pre: config.string(5)?,
+                latexsvg: config.uint(7) != 0,
+            };
+            models.push(Model::try_from(entry)?);
+            Ok(())
+        },
+    )?;
+    Ok(models)
+}
+
+/// Reads the decks of a collection of Anki 2.1.50 and later except filtered decks, which it
+/// stores in the `decks` table instead of the `decks` column of `col`
+pub(crate) fn read_decks(conn: &Connection) -> Result<Vec<Deck>, Error> {
+    let mut decks = vec![];
+    for_each_row(
+        conn,
+        "SELECT id, name, kind FROM decks",
+        |row| {
+            let kind: Vec<u8> = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, kind))
+        },
+        |(id, name, kind): (i64, String, Vec<u8>)| {
+            // The kind is either a normal deck in field 1 or a filtered deck in field 2
+            if let Some(normal) = Message::decode(&kind)?.bytes(1) {
+                let description = Message::decode(normal)?.string(4)?;
+                // The levels of the name are separated by \x1f instead of ::
+                decks.push(Deck::new(id, name.replace('\x1f', "::"), description));
+            }
+            Ok(())
+        },
+    )?;
+    Ok(decks)
+}
+
+/// Calls `f` with each row of the result of `sql`, as `read` returns it
+fn for_each_row<T>(
+    conn: &Connection,
+    sql: &str,
+    read: impl Fn(&rusqlite::Row) -> rusqlite::Result<T>,
+    mut f: impl FnMut(T) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut statement = conn.prepare(sql).map_err(database_error)?;
+    let rows = statement.query_map([], read).map_err(database_error)?;
+    for row in rows {
+        f(row.map_err(database_error)?)?;
+    }
+    Ok(())
+}
+
+/// The value of a field of a protobuf message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// The fields of a protobuf message, without the fixed-size ones, which none of the read
+/// messages use
+#[derive(Debug)]
+struct Message<'a>(Vec<(u32, FieldValue<'a>)>);
+
+impl<'a> Message<'a> {
+    fn decode(mut bytes: &'a [u8]) -> Result<Self, Error> {
+        let mut fields = vec![];
+        while !bytes.is_empty() {
+            let key = decode_varint(&mut bytes)?;
+            let field = u32::try_from(key >> 3).map_err(|_| invalid_message())?;
+            let len = match key & 7 {
+                0 => {
+                    fields.push((field, FieldValue::Varint(decode_varint(&mut bytes)?)));
+                    continue;
+                }
+                1 => 8,
+                2 => usize::try_from(decode_varint(&mut bytes)?).map_err(|_| invalid_message())?,
+                5 => 4,
+                _ => return Err(invalid_message()),
+            };
+            if len > bytes.len() {
+                return Err(invalid_message());
+            }
+            let (value, rest) = bytes.split_at(len);
+            if key & 7 == 2 {
+                fields.push((field, FieldValue::Bytes(value)));
+            }
+            bytes = rest;
+        }
+        Ok(Self(fields))
+    }
+
+    /// The varint `field`, or `0` if the message doesn't have it
+    ///
+    /// Like for all fields that aren't repeated, the last value counts if the field is there more
+    /// than once.
+    fn uint(&self, field: u32) -> u64 {
+        self.0
+            .iter()
+            .rev()
+            .find_map(|&(f, value)| match value {
+                FieldValue::Varint(value) if f == field => Some(value),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    /// The length-delimited `field`, or `None` if the message doesn't have it
+    fn bytes(&self, field: u32) -> Option<&'a [u8]> {
+        self.repeated(field).last()
+    }
+
+    /// The string `field`, or an empty string if the message doesn't have it
+    fn string(&self, field: u32) -> Result<String, Error> {
+        let bytes = self.bytes(field).unwrap_or_default();
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid_message())
+    }
+
+    /// The values of the repeated length-delimited `field`
+    fn repeated(&self, field: u32) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.0.iter().filter_map(move |&(f, value)| match value {
+            FieldValue::Bytes(bytes) if f == field => Some(bytes),
+            _ => None,
+        })
+    }
+}
+
+fn decode_varint(bytes: &mut &[u8]) -> Result<u64, Error> {
+    let mut value = 0;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err(invalid_message())
+}
+
+fn invalid_message() -> Error {
+    Error::InvalidPackage("the package contains an invalid protobuf message".to_string())
+}
+
+/// Appends a varint field of a protobuf message to `message`, except for `0`, which is the
+/// default value
+fn encode_uint(field: u32, value: u64, message: &mut Vec<u8>) {
+    if value != 0 {
+        encode_varint(u64::from(field << 3), message);
+        encode_varint(value, message);
+    }
+}
+
+/// Appends a length-delimited field of a protobuf message to `message`
+fn encode_bytes(field: u32, bytes: &[u8], message: &mut Vec<u8>) {
+    encode_varint(u64::from(field << 3 | 2), message);
+    encode_varint(bytes.len() as u64, message);
+    message.extend_from_slice(bytes);
+}
+
+fn encode_varint(mut value: u64, message: &mut Vec<u8>) {
+    while value >= 0x80 {
+        message.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    message.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protobuf_messages() {
+        assert_eq!(meta(), vec![0x08, 0x03]);
+
+        let files = [
+            MediaFile::Bytes {
+                name: "a.txt".to_string(),
+                data: b"abc".to_vec(),
+            },
+            MediaFile::Bytes {
+                name: "empty".to_string(),
+                data: vec![],
+            },
+        ];
+        let entries = media_entries(files.iter().map(|f| (f.name().to_string(), f))).unwrap();
+        let mut expected = vec![0x0a, 31, 0x0a, 5];
+        expected.extend_from_slice(b"a.txt");
+        expected.extend_from_slice(&[0x10, 3, 0x1a, 20]);
+        expected.extend_from_slice(&files[0].sha1().unwrap());
+        expected.extend_from_slice(&[0x0a, 29, 0x0a, 5]);
+        expected.extend_from_slice(b"empty");
+        expected.extend_from_slice(&[0x1a, 20]);
+        expected.extend_from_slice(&files[1].sha1().unwrap());
+        assert_eq!(entries, expected);
+        assert_eq!(media_names(&entries).unwrap(), ["a.txt", "empty"]);
+        assert!(matches!(
+            media_names(&entries[..entries.len() - 1]),
+            Err(Error::InvalidPackage(_))
+        ));
+        assert_eq!(
+            files[0].sha1().unwrap()[..4],
+            [0xa9, 0x99, 0x3e, 0x36],
+            "SHA-1 of abc"
+        );
+
+        let mut varint = vec![];
+        encode_varint(300, &mut varint);
+        assert_eq!(varint, vec![0xac, 0x02]);
+    }
+}
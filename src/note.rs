@@ -1,138 +1,678 @@
+use crate::builders::FieldMapping;
 use crate::card::Card;
+#[cfg(feature = "serde")]
+use crate::card::CardSchedule;
 use crate::error::database_error;
+use crate::field_transform::FieldTransform;
+use crate::media::{media_references, rename_media_references, MediaRef};
 use crate::model::{Model, ModelType};
-use crate::util::guid_for;
+use crate::timestamp::Timestamp;
+#[cfg(feature = "markdown")]
+use crate::util::markdown_to_html;
+use crate::util::{field_checksum, guid_for, strip_html_media, truncate};
 use crate::Error;
 use fancy_regex::Regex;
-use rusqlite::{params, Transaction};
-use std::collections::HashSet;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection};
+use std::collections::{BTreeSet, HashSet};
 use std::ops::RangeFrom;
-use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
 
 /// Note (Flashcard) to be added to a `Deck`
-#[derive(Clone)]
+///
+/// With the `serde` feature, a note is (de)serialized as a map with the keys `model` (the
+/// whole `Model`, inline), `fields`, `sort_field`, `tags`, `guid`, `guid_fields`, `due`, `strict`
+/// and `cards`, the settings of the cards by their ord. The cards are generated again when
+/// deserializing, with the same checks as [`Note::new`], or as [`Note::new_lenient`] if `strict`
+/// is `false`, and then get their settings back.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "NoteDef", try_from = "NoteDef")
+)]
 pub struct Note {
-    model: Model,
+    model: Arc<Model>,
     fields: Vec<String>,
     sort_field: bool,
     tags: Vec<String>,
-    guid: String,
+    guid: Option<String>,
+    guid_fields: Option<Vec<usize>>,
     cards: Vec<Card>,
+    strict: bool,
+    due: Option<i64>,
 }
 
 impl Note {
     /// Creates a new Note with a new `model` and `fields`
     ///
-    /// Returns `Err` if the fields are not matching the model or if the fields are invalid
+    /// Returns `Err` if the fields are not matching the model, if the fields are invalid or if
+    /// the model does not pass [`Model::validate`]. Fields with cloze deletions like
+    /// `{{c1::...}}` are rejected with `Error::ClozeInNonClozeModel` unless the model is a
+    /// `Cloze` model, and notes of a `Cloze` model without any cloze deletions are rejected with
+    /// `Error::NoClozeDeletionsFound`. See [`Note::new_lenient`] to allow them anyway.
+    ///
+    /// `model` is a `Model` or an `Arc<Model>`. Notes created with clones of the same
+    /// `Arc<Model>` share the model instead of each having a copy of it, which saves time and
+    /// memory for large decks.
     ///
     /// Example:
     /// ```
     /// use genanki_rs::{Note, basic_model};
+    /// use std::sync::Arc;
     ///
     /// let note = Note::new(basic_model(), vec!["What is the capital of France?", "Paris"]);
+    ///
+    /// let model = Arc::new(basic_model());
+    /// let notes: Vec<Note> = ["France", "Germany"]
+    ///     .iter()
+    ///     .map(|country| Note::new(model.clone(), vec![country, ""]).unwrap())
+    ///     .collect();
     /// ```
-    pub fn new(model: Model, fields: Vec<&str>) -> Result<Self, Error> {
-        let fields = fields.iter().map(|&s| s.to_string()).collect();
-        let cards = match model.get_model_type() {
-            ModelType::FrontBack => front_back_cards(&model, &fields)?,
-            ModelType::Cloze => cloze_cards(&model, &fields),
-        };
-        let guid = guid_for(&fields);
+    pub fn new(model: impl Into<Arc<Model>>, fields: Vec<&str>) -> Result<Self, Error> {
+        Self::new_checked(model, fields, true)
+    }
+
+    /// Creates a new Note like [`Note::new`], but skips the checks of the field contents
+    /// against the model type, e.g. to show cloze markers literally on a `FrontBack` card.
+    /// A note of a `Cloze` model without cloze deletions gets a single card.
+    pub fn new_lenient(model: impl Into<Arc<Model>>, fields: Vec<&str>) -> Result<Self, Error> {
+        Self::new_checked(model, fields, false)
+    }
+
+    /// Creates a new Note with a new `model` and field values looked up by field name
+    ///
+    /// Fields of the model without a value are left empty. Returns
+    /// `Error::UnknownNoteField` if a name doesn't match any field of the model exactly, and
+    /// otherwise fails like [`Note::new`].
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{Note, basic_model};
+    ///
+    /// let note = Note::new_with_field_map(
+    ///     basic_model(),
+    ///     vec![("Back", "Paris"), ("Front", "What is the capital of France?")],
+    /// );
+    /// assert!(note.is_ok());
+    /// ```
+    pub fn new_with_field_map<K: AsRef<str>, V: AsRef<str>>(
+        model: impl Into<Arc<Model>>,
+        fields: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self, Error> {
+        let model = model.into();
+        let fields = fields_from_map(&model, fields, false)?;
+        Self::new(model, fields.iter().map(String::as_str).collect())
+    }
+
+    /// Like [`Note::new_with_field_map`], but returns `Error::MissingNoteField` if a field of
+    /// the model has no value
+    pub fn new_with_field_map_strict<K: AsRef<str>, V: AsRef<str>>(
+        model: impl Into<Arc<Model>>,
+        fields: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self, Error> {
+        let model = model.into();
+        let fields = fields_from_map(&model, fields, true)?;
+        Self::new(model, fields.iter().map(String::as_str).collect())
+    }
+
+    /// Creates a new Note like [`Note::new`], but fills up missing values at the end of
+    /// `fields` with empty strings
+    pub fn new_padded(model: impl Into<Arc<Model>>, mut fields: Vec<&str>) -> Result<Self, Error> {
+        let model = model.into();
+        let field_count = model.field_names().len();
+        if fields.len() < field_count {
+            fields.resize(field_count, "");
+        }
+        Self::new(model, fields)
+    }
+
+    /// Creates a new Note like [`Note::new`], but with `fields` written in Markdown, which is
+    /// rendered as HTML
+    ///
+    /// Fenced code blocks become `<pre><code>` with a `language-...` class, and images like
+    /// `![a dog](dog.jpg)` become `<img>` tags, which are returned by
+    /// [`Note::media_references`]; file names with spaces are written in angle brackets, like
+    /// `![a dog](<my dog.jpg>)`. Raw HTML in the Markdown is escaped, so `<`, `&` and e.g. a
+    /// pasted `<div>` are shown literally instead of breaking the card. A field that is a single
+    /// paragraph isn't wrapped in `<p>`.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new_markdown(basic_model(), vec!["der **Hund**", "the dog"])?;
+    /// assert_eq!(note.field(0), Some("der <strong>Hund</strong>"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "markdown")]
+    pub fn new_markdown(model: impl Into<Arc<Model>>, fields: Vec<&str>) -> Result<Self, Error> {
+        let fields: Vec<String> = fields.into_iter().map(markdown_to_html).collect();
+        Self::new(model, fields.iter().map(String::as_str).collect())
+    }
+
+    fn new_checked(
+        model: impl Into<Arc<Model>>,
+        fields: Vec<&str>,
+        strict: bool,
+    ) -> Result<Self, Error> {
+        let model = model.into();
+        // Validates the model, unless its plan was already computed for an earlier note
+        model.card_gen_plan()?;
+        let fields: Vec<String> = fields.iter().map(|&s| s.to_string()).collect();
+        let cards = generate_cards(&model, &fields, strict)?;
         Ok(Self {
             model,
             fields,
             sort_field: false,
             tags: vec![],
-            guid,
+            guid: None,
+            guid_fields: None,
             cards,
+            strict,
+            due: None,
         })
     }
 
     /// Creates a new Note with a new `model`, `fields` and custom parameters:
     /// * `sort_field` - whether to sort field, default is `false`
     /// * `tags` - List of tags
-    /// * `guid` - Custom unique note id, default is hash of all fields
+    /// * `guid` - Custom unique note id, default is [`guid_for`] of all fields
     ///
-    /// Returns `Err` if tags or fields are invalid
+    /// Returns `Err` if tags or fields are invalid or if the model does not pass
+    /// [`Model::validate`], with the same checks of the fields as [`Note::new`]
     pub fn new_with_options(
-        model: Model,
+        model: impl Into<Arc<Model>>,
         fields: Vec<&str>,
         sort_field: Option<bool>,
         tags: Option<Vec<&str>>,
         guid: Option<&str>,
     ) -> Result<Self, Error> {
-        let tags = tags
-            .unwrap_or_default()
-            .iter()
-            .map(|s| s.to_string())
-            .collect();
-        validate_tags(&tags)?;
-        let fields = fields.iter().map(|s| s.to_string()).collect();
-        let cards = match model.get_model_type() {
-            ModelType::FrontBack => front_back_cards(&model, &fields)?,
-            ModelType::Cloze => cloze_cards(&model, &fields),
-        };
-        let guid = guid.unwrap_or(&guid_for(&fields)).to_string();
+        let tags = normalize_tags(tags.unwrap_or_default())?;
+        let note = Self::new_checked(model, fields, true)?;
         Ok(Self {
-            model,
-            fields,
             sort_field: sort_field.unwrap_or(false),
             tags,
-            guid,
-            cards,
+            guid: guid.map(str::to_string),
+            ..note
         })
     }
 
     /// Returns a new Note with the sort field replace with the new one
+    ///
+    /// This doesn't change which field Anki sorts notes by, that is the sort field of the model,
+    /// see [`Model::sort_field`].
     pub fn sort_field(self, sort_field: bool) -> Self {
         Self { sort_field, ..self }
     }
 
     /// Sets or replaces tags with the provided ones
-    pub fn tags(self, tags: impl IntoIterator<Item = impl ToString>) -> Self {
-        Self {
-            tags: tags.into_iter().map(|tag| tag.to_string()).collect(),
+    ///
+    /// Hierarchical tags like `lang::de::nouns` are kept as they are, and tags that only differ
+    /// in case from an earlier one are dropped, like Anki does. Returns
+    /// `Error::TagContainsWhitespace` if a tag contains whitespace.
+    pub fn tags(self, tags: impl IntoIterator<Item = impl ToString>) -> Result<Self, Error> {
+        Ok(Self {
+            tags: normalize_tags(tags)?,
             ..self
-        }
+        })
     }
 
-    /// Adds an additional tag
-    pub fn with_tag(mut self, tag: impl ToString) -> Self {
-        self.tags.push(tag.to_string());
-        self
+    /// Adds an additional tag, with the same checks as [`Note::tags`]
+    pub fn with_tag(mut self, tag: impl ToString) -> Result<Self, Error> {
+        let mut tags = std::mem::take(&mut self.tags);
+        tags.push(tag.to_string());
+        self.tags(tags)
     }
 
     /// Sets the GUID for this note
     ///
-    /// The GUID is auto-generated if this option is not provided.
+    /// Anki updates a note with the same GUID on import instead of adding a duplicate. If this is
+    /// not set, the GUID is [`guid_for`] of all fields.
     pub fn guid(self, guid: impl ToString) -> Self {
         Self {
-            guid: guid.to_string(),
+            guid: Some(guid.to_string()),
             ..self
         }
     }
 
-    pub(super) fn model(&self) -> Model {
-        self.model.clone()
+    /// Sets the position of the cards of this note among the new cards
+    ///
+    /// Anki shows new cards with a lower position first, and cards of the same note by their
+    /// ord. By default, the notes of a `Package` get increasing positions in the order they were
+    /// added to their decks, starting at `0`.
+    pub fn due(self, position: i64) -> Self {
+        Self {
+            due: Some(position),
+            ..self
+        }
     }
 
-    #[allow(dead_code)]
-    pub(super) fn cards(&self) -> Vec<Card> {
-        self.cards.clone()
+    /// Derives the GUID from only the fields at `field_indices`, in that order, instead of all
+    /// fields
+    ///
+    /// This keeps the GUID of a note with a stable key field the same when its other fields
+    /// change, so Anki updates the note on import. The GUID is computed with [`guid_for`] when
+    /// the note is written, and a GUID set with [`Note::guid`] takes precedence over it.
+    ///
+    /// Returns `Error::IndexOutOfRange` if an index is not a field of the model.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{Field, Model, Note, Template};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let model = Model::new(
+    ///     1607392319,
+    ///     "Vocabulary",
+    ///     vec![Field::new("ID"), Field::new("Word"), Field::new("Meaning")],
+    ///     vec![Template::new("Card 1").qfmt("{{Word}}").afmt("{{Meaning}}")],
+    /// );
+    /// let note = Note::new(model, vec!["42", "der Hund", "the dog"])?.guid_from_fields(&[0])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn guid_from_fields(self, field_indices: &[usize]) -> Result<Self, Error> {
+        let len = self.fields.len();
+        if let Some(&index) = field_indices.iter().find(|&&index| index >= len) {
+            return Err(Error::IndexOutOfRange { index, len });
+        }
+        Ok(Self {
+            guid_fields: Some(field_indices.to_vec()),
+            ..self
+        })
+    }
+
+    /// Derives the GUID from only the fields named `field_names`, like
+    /// [`Note::guid_from_fields`]
+    ///
+    /// Returns `Error::UnknownNoteField` if a name doesn't match any field of the model exactly.
+    pub fn guid_from_field_names(self, field_names: &[&str]) -> Result<Self, Error> {
+        let field_indices = field_names
+            .iter()
+            .map(|name| self.field_index(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.guid_from_fields(&field_indices)
     }
 
-    fn get_guid(&self) -> String {
-        self.guid.clone()
+    /// Returns the values of the fields, in the order of the fields of the model
+    pub fn fields(&self) -> &[String] {
+        &self.fields
     }
 
-    fn check_number_model_fields_matches_num_fields(&self) -> Result<(), Error> {
-        if self.model.fields().len() != self.fields.len() {
-            Err(Error::ModelFieldCountMismatch(
-                self.model.fields().len(),
-                self.fields.len(),
-            ))
-        } else {
-            Ok(())
+    /// Returns the value of the field at `index`, or `None` if the model has no such field
+    pub fn field(&self, index: usize) -> Option<&str> {
+        self.fields.get(index).map(String::as_str)
+    }
+
+    /// Returns the value of the field called `name`, or `None` if the model has no such field
+    pub fn field_by_name(&self, name: &str) -> Option<&str> {
+        self.field_index(name)
+            .ok()
+            .and_then(|index| self.field(index))
+    }
+
+    /// Replaces the value of the field at `index`
+    ///
+    /// A note can be edited until it is moved into a `Deck` with [`Deck::add_note`]. The cards
+    /// are generated again with the same checks as when the note was created, and a GUID derived
+    /// from the fields changes with them. Returns `Error::IndexOutOfRange` if the model has no
+    /// field at `index`, and leaves the note unchanged if the new value is rejected.
+    ///
+    /// [`Deck::add_note`]: crate::Deck::add_note
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut note = Note::new(basic_model(), vec!["der Hund", "the dog"])?;
+    /// let with_audio = format!("{}[sound:hund.mp3]", note.fields()[0]);
+    /// note.set_field(0, with_audio)?;
+    /// assert_eq!(note.field(0), Some("der Hund[sound:hund.mp3]"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_field(&mut self, index: usize, value: impl ToString) -> Result<(), Error> {
+        let len = self.fields.len();
+        if index >= len {
+            return Err(Error::IndexOutOfRange { index, len });
+        }
+        let mut fields = self.fields.clone();
+        fields[index] = value.to_string();
+        let mut cards = generate_cards(&self.model, &fields, self.strict)?;
+        for card in &mut cards {
+            if let Some(old) = self.cards.iter().find(|old| old.ord == card.ord) {
+                *card = old.clone();
+            }
+        }
+        self.cards = cards;
+        self.fields = fields;
+        Ok(())
+    }
+
+    /// Replaces the value of the field called `name`, like [`Note::set_field`]
+    ///
+    /// Returns `Error::UnknownNoteField` if a name doesn't match any field of the model exactly.
+    pub fn set_field_by_name(&mut self, name: &str, value: impl ToString) -> Result<(), Error> {
+        let index = self.field_index(name)?;
+        self.set_field(index, value)
+    }
+
+    /// Converts the note to a note of `new_model`, with the fields moved by `mapping`, like
+    /// "Change Note Type" in Anki
+    ///
+    /// The note keeps its GUID, so Anki updates the note it imported before instead of adding a
+    /// new one, as well as its tags and the settings of the cards with the same ords. The cards
+    /// are generated again with the same checks as when the note was created. Returns
+    /// `Error::UnknownNoteField` or `Error::IndexOutOfRange` if a field of `mapping` isn't a
+    /// field of its model, and fails like [`Note::new`] if the new fields are rejected.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{Field, FieldMapping, Model, Note, Template};
+    /// use std::sync::Arc;
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let old_model = Model::builder(1607392319, "Vocabulary")
+    ///     .field(Field::new("Word"))
+    ///     .field(Field::new("Meaning"))
+    ///     .template(Template::new("Card 1").qfmt("{{Word}}").afmt("{{Meaning}}"))
+    ///     .build()?;
+    /// let new_model = Arc::new(
+    ///     Model::builder(1607392320, "Vocabulary 2")
+    ///         .field(Field::new("Word"))
+    ///         .field(Field::new("Definition"))
+    ///         .field(Field::new("Example"))
+    ///         .template(Template::new("Card 1").qfmt("{{Word}}").afmt("{{Definition}}"))
+    ///         .build()?,
+    /// );
+    /// let mapping = FieldMapping::by_name().map("Meaning", "Definition");
+    ///
+    /// let note = Note::new(old_model, vec!["der Hund", "the dog"])?;
+    /// let remapped = note.remap_model(new_model.clone(), &mapping)?;
+    /// assert_eq!(remapped.fields(), ["der Hund", "the dog", ""]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remap_model(
+        &self,
+        new_model: impl Into<Arc<Model>>,
+        mapping: &FieldMapping,
+    ) -> Result<Note, Error> {
+        let new_model = new_model.into();
+        let fields = mapping.apply(&self.model, &new_model, &self.fields)?;
+        let mut note = Self::new_checked(
+            new_model,
+            fields.iter().map(String::as_str).collect(),
+            self.strict,
+        )?;
+        for card in &mut note.cards {
+            if let Some(old) = self.cards.iter().find(|old| old.ord == card.ord) {
+                *card = old.clone();
+            }
+        }
+        Ok(Self {
+            sort_field: self.sort_field,
+            tags: self.tags.clone(),
+            guid: Some(self.get_guid()),
+            due: self.due,
+            ..note
+        })
+    }
+
+    /// Removes the HTML in the fields that `policy` doesn't allow, e.g. `<script>` tags and
+    /// `onerror` attributes in content from untrusted sources, which Anki would run when it shows
+    /// the card
+    ///
+    /// Formatting like `<b>` and `<span style="color: red">`, images of media files and
+    /// `[sound:...]` tags are kept with the default [`SanitizePolicy`]. Fields are never
+    /// sanitized unless this is called.
+    ///
+    /// Returns `Err` if the sanitized fields are invalid, like [`Note::set_field`].
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{basic_model, Note, SanitizePolicy};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new(basic_model(), vec!["<b>der Hund</b><script>alert(1)</script>", "the dog"])?
+    ///     .sanitize_html(&SanitizePolicy::new())?;
+    /// assert_eq!(note.field(0), Some("<b>der Hund</b>"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`SanitizePolicy`]: crate::SanitizePolicy
+    #[cfg(feature = "sanitize")]
+    pub fn sanitize_html(mut self, policy: &crate::SanitizePolicy) -> Result<Self, Error> {
+        for index in 0..self.fields.len() {
+            let sanitized = crate::sanitize::sanitize_html(&self.fields[index], policy);
+            if sanitized != self.fields[index] {
+                self.set_field(index, sanitized)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Applies the `transforms` to all fields, in the order they are given
+    ///
+    /// The transformed fields are the fields of the note, so they are also what the sort field,
+    /// the checksum for duplicates and [`Note::media_references`] are taken from. Returns `Err` if
+    /// the transformed fields are invalid, like [`Note::set_field`].
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{basic_model, FieldTransform, Note};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new(basic_model(), vec!["  a < b\nb < c\n", "yes"])?.with_transforms(&[
+    ///     FieldTransform::Trim,
+    ///     FieldTransform::HtmlEscape,
+    ///     FieldTransform::NewlinesToBr,
+    /// ])?;
+    /// assert_eq!(note.field(0), Some("a &lt; b<br>b &lt; c"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_transforms(mut self, transforms: &[FieldTransform]) -> Result<Self, Error> {
+        for index in 0..self.fields.len() {
+            self.transform_field(index, transforms)?;
+        }
+        Ok(self)
+    }
+
+    /// Applies the `transforms` to the field called `name`, like [`Note::with_transforms`], e.g.
+    /// to escape a column of raw text but keep the HTML of the other fields
+    ///
+    /// Returns `Error::UnknownNoteField` if a name doesn't match any field of the model exactly.
+    pub fn with_field_transforms(
+        mut self,
+        name: &str,
+        transforms: &[FieldTransform],
+    ) -> Result<Self, Error> {
+        let index = self.field_index(name)?;
+        self.transform_field(index, transforms)?;
+        Ok(self)
+    }
+
+    fn transform_field(
+        &mut self,
+        index: usize,
+        transforms: &[FieldTransform],
+    ) -> Result<(), Error> {
+        let transformed = transforms
+            .iter()
+            .fold(self.fields[index].clone(), |field, transform| {
+                transform.apply(&field)
+            });
+        if transformed != self.fields[index] {
+            self.set_field(index, transformed)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the local media files referenced in the fields, in the order they appear
+    ///
+    /// This finds `<img src>`, `<audio src>`, `<object data>` and other `src` attributes as well
+    /// as `[sound:...]` tags. Remote URLs and `data:` URLs are skipped. Images Anki renders from
+    /// LaTeX are not included, Anki generates those itself.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{MediaKind, MediaRef, Note, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new(basic_model(), vec![r#"<img src="dog.jpg">"#, "[sound:dog.mp3]"])?;
+    /// assert_eq!(
+    ///     note.media_references(),
+    ///     vec![
+    ///         MediaRef { kind: MediaKind::Image, filename: "dog.jpg".to_string() },
+    ///         MediaRef { kind: MediaKind::Sound, filename: "dog.mp3".to_string() },
+    ///     ]
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn media_references(&self) -> Vec<MediaRef> {
+        self.fields
+            .iter()
+            .flat_map(|field| media_references(field))
+            .collect()
+    }
+
+    /// Replaces the references to the media file `from` in the fields with references to `to`
+    pub(super) fn rename_media(&mut self, from: &str, to: &str) {
+        for field in &mut self.fields {
+            *field = rename_media_references(field, from, to);
+        }
+    }
+
+    /// Converts the fields to Unicode NFC, like Anki does with text that is pasted into it
+    #[cfg(feature = "nfc")]
+    pub(super) fn normalize_unicode(&mut self) {
+        use unicode_normalization::UnicodeNormalization;
+        for field in &mut self.fields {
+            *field = field.nfc().collect();
+        }
+    }
+
+    fn field_index(&self, key: &str) -> Result<usize, Error> {
+        let names = self.model.field_names();
+        names
+            .iter()
+            .position(|&name| name == key)
+            .ok_or_else(|| Error::UnknownNoteField {
+                key: key.to_string(),
+                known_fields: names.iter().map(|name| name.to_string()).collect(),
+            })
+    }
+
+    pub(super) fn model_id(&self) -> i64 {
+        self.model.id
+    }
+
+    pub(super) fn model(&self) -> &Arc<Model> {
+        &self.model
+    }
+
+    /// Returns the cards the note generates, ordered by their ord
+    ///
+    /// A `FrontBack` note gets a card for each template whose front side isn't empty for the
+    /// fields of the note. A `Cloze` note gets a card for each cloze number in its fields, with
+    /// the ord being the cloze number minus one.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{Note, Card, basic_and_reversed_card_model, cloze_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new(basic_and_reversed_card_model(), vec!["der Hund", "the dog"])?;
+    /// assert_eq!(note.card_count(), 2);
+    ///
+    /// let text = "{{c1::Berlin}} is the capital of {{c3::Germany}}";
+    /// let note = Note::new(cloze_model(), vec![text, ""])?;
+    /// assert_eq!(note.card_count(), 2);
+    /// assert_eq!(note.cards().iter().map(Card::ord).collect::<Vec<_>>(), vec![0, 2]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cards(&self) -> &[Card] {
+        &self.cards
+    }
+
+    /// Sets whether all cards of the note are suspended when they are imported
+    pub fn suspend_cards(self, suspended: bool) -> Self {
+        self.configure_cards(|card| card.set_suspended(suspended))
+    }
+
+    /// Calls `configure` with each card of the note, e.g. to suspend or flag some of them
+    ///
+    /// The settings of a card are kept if the fields are changed with [`Note::set_field`], as
+    /// long as the card is still generated.
+    ///
+    /// Example:
+    /// ```
+    /// use genanki_rs::{Note, basic_and_reversed_card_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let note = Note::new(basic_and_reversed_card_model(), vec!["der Hund", "the dog"])?
+    ///     .configure_cards(|card| {
+    ///         if card.ord() == 1 {
+    ///             card.set_suspended(true);
+    ///             card.set_flag(1).expect("1 is a valid flag");
+    ///         }
+    ///     });
+    /// assert!(!note.cards()[0].suspended());
+    /// assert!(note.cards()[1].suspended());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn configure_cards(mut self, configure: impl FnMut(&mut Card)) -> Self {
+        self.cards.iter_mut().for_each(configure);
+        self
+    }
+
+    /// The ids of the decks the cards of the note are written to if the note is added to the
+    /// deck with the id `deck_id`: the deck set with [`Card::set_deck_id`], or else the deck
+    /// override of the card's template, or else `deck_id`
+    pub(crate) fn card_deck_ids(&self, deck_id: i64) -> Vec<i64> {
+        self.cards
+            .iter()
+            .map(|card| {
+                // Cloze models only have one template, which all of their cards are generated from
+                let template = match self.model.get_model_type() {
+                    ModelType::FrontBack => self.model.template_by_ord(card.ord),
+                    ModelType::Cloze => self.model.templates().first(),
+                };
+                let template_deck_id = template.and_then(|template| template.did);
+                card.deck_id.or(template_deck_id).unwrap_or(deck_id)
+            })
+            .collect()
+    }
+
+    /// Returns the number of cards the note generates, see [`Note::cards`]
+    pub fn card_count(&self) -> usize {
+        self.cards.len()
+    }
+
+    /// Returns the tags of the note, see [`Note::tags`]
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub(super) fn get_guid(&self) -> String {
+        match (&self.guid, &self.guid_fields) {
+            (Some(guid), _) => guid.clone(),
+            (None, Some(indices)) => guid_for(
+                &indices
+                    .iter()
+                    .map(|&index| self.fields[index].as_str())
+                    .collect::<Vec<_>>(),
+            ),
+            (None, None) => guid_for(&self.fields),
         }
     }
 
@@ -153,125 +693,349 @@ impl Note {
         self.fields.clone().join("\x1f")
     }
 
-    fn format_tags(&self) -> String {
-        format!(" {} ", self.tags.join(" "))
+    /// The first field without HTML, which Anki uses to find duplicate notes
+    pub(super) fn stripped_first_field(&self) -> String {
+        self.fields
+            .first()
+            .map_or_else(String::new, |field| strip_html_media(field))
+    }
+
+    /// The stripped sort field of the model, which Anki stores as a number if it is one so
+    /// that numeric fields sort by value
+    fn sort_field_value(&self) -> Result<Value, Error> {
+        self.model.validate_sort_field_index()?;
+        let index = self.model.get_sort_field_index() as usize;
+        let stripped = strip_html_media(&self.fields[index]);
+        Ok(match stripped.parse::<i64>() {
+            Ok(number) => Value::Integer(number),
+            Err(_) => Value::Text(stripped),
+        })
+    }
+
+    /// The tags of the note followed by the `default_tags` of its deck that it doesn't have yet
+    pub(crate) fn tags_with(&self, default_tags: &[String]) -> Vec<String> {
+        let mut seen: HashSet<String> = self.tags.iter().map(|tag| tag.to_lowercase()).collect();
+        let mut tags = self.tags.clone();
+        tags.extend(
+            default_tags
+                .iter()
+                .filter(|tag| seen.insert(tag.to_lowercase()))
+                .cloned(),
+        );
+        tags
+    }
+
+    /// Adds the `default_tags` of a deck to the tags of the note
+    pub(super) fn with_default_tags(self, default_tags: &[String]) -> Self {
+        Self {
+            tags: self.tags_with(default_tags),
+            ..self
+        }
+    }
+
+    fn format_tags(&self, default_tags: &[String]) -> String {
+        let tags = self.tags_with(default_tags);
+        if tags.is_empty() {
+            String::new()
+        } else {
+            format!(" {} ", tags.join(" "))
+        }
     }
+
+    /// Writes the note and its cards, with the `default_tags` of the deck in addition to its tags
     pub(super) fn write_to_db(
         &self,
-        transaction: &Transaction,
-        timestamp: f64,
+        transaction: &Connection,
+        timestamp: Timestamp,
         deck_id: i64,
-        mut id_gen: &mut RangeFrom<usize>,
+        default_tags: &[String],
+        position: i64,
+        id_gen: &mut RangeFrom<usize>,
     ) -> Result<(), Error> {
-        self.check_number_model_fields_matches_num_fields()?;
+        check_field_count(&self.model, &self.fields)?;
         self.check_invalid_html_tags_in_fields()?;
+        let sort_field = self.sort_field_value()?;
+        let checksum = self.fields.first().map_or(0, |field| field_checksum(field));
         transaction
-            .execute(
-                "INSERT INTO notes VALUES(?,?,?,?,?,?,?,?,?,?,?);",
-                params![
-                    id_gen.next(),        // id
-                    self.get_guid(),      // guid
-                    self.model.id,        // mid
-                    timestamp as i64,     // mod
-                    -1,                   // usn
-                    self.format_tags(),   // TODO tags
-                    self.format_fields(), // flds
-                    self.sort_field,      // sfld
-                    0,                    // csum, can be ignored
-                    0,                    // flags
-                    "",                   // data
-                ],
-            )
+            .prepare_cached("INSERT INTO notes VALUES(?,?,?,?,?,?,?,?,?,?,?);")
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    id_gen.next(),                  // id
+                    self.get_guid(),                // guid
+                    self.model.id,                  // mid
+                    timestamp.as_secs(),            // mod
+                    -1,                             // usn
+                    self.format_tags(default_tags), // tags
+                    self.format_fields(),           // flds
+                    sort_field,                     // sfld
+                    checksum,                       // csum
+                    0,                              // flags
+                    "",                             // data
+                ])
+            })
             .map_err(database_error)?;
         let note_id = transaction.last_insert_rowid() as usize;
-        for card in &self.cards {
-            card.write_to_db(transaction, timestamp, deck_id, note_id, &mut id_gen)?
+        for (card, card_deck_id) in self.cards.iter().zip(self.card_deck_ids(deck_id)) {
+            let due = self.due.unwrap_or(position);
+            card.write_to_db(transaction, timestamp, card_deck_id, note_id, due, id_gen)?
         }
         Ok(())
     }
 }
 
-fn cloze_cards(model: &Model, self_fields: &Vec<String>) -> Vec<Card> {
-    let mut card_ords: HashSet<i64> = HashSet::new();
-    let mut cloze_replacements: HashSet<String> = HashSet::new();
-    cloze_replacements.extend(re_findall(
-        r"{{[^}]*?cloze:(?:[^}]?:)*(.+?)}}",
-        &model.templates()[0].qfmt,
-    ));
-    cloze_replacements.extend(re_findall("<%cloze:(.+?)%>", &model.templates()[0].qfmt));
-    for field_name in cloze_replacements {
-        let fields = model.fields();
-        let mut field_index_iter = fields
-            .iter()
-            .enumerate()
-            .filter(|(_, field)| field.name == field_name)
-            .map(|(i, _)| i);
-        let field_value = if let Some(field_index) = field_index_iter.next() {
-            self_fields[field_index].clone()
-        } else {
-            "".to_string()
-        };
-        let updates_str = re_findall(r"(?s){{c(\d+)::.+?}}", &field_value);
-        let updates = updates_str
-            .iter()
-            .map(|m| i64::from_str(m).expect("parsed from regex") - 1)
-            .filter(|&m| m >= 0);
-        card_ords.extend(updates);
-    }
-    if card_ords.is_empty() {
-        card_ords.insert(0);
+/// The (de)serialized layout of a `Note`
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct NoteDef {
+    model: Model,
+    fields: Vec<String>,
+    #[serde(default)]
+    sort_field: bool,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    guid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    guid_fields: Option<Vec<usize>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due: Option<i64>,
+    #[serde(default = "default_strict")]
+    strict: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cards: Vec<CardDef>,
+}
+
+#[cfg(feature = "serde")]
+fn default_strict() -> bool {
+    true
+}
+
+/// The (de)serialized settings of a `Card`, only written for cards that have any
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CardDef {
+    ord: i64,
+    #[serde(default, skip_serializing_if = "crate::db_entries::is_false")]
+    suspended: bool,
+    #[serde(default, skip_serializing_if = "is_zero_flag")]
+    flag: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    schedule: Option<CardSchedule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deck_id: Option<i64>,
+}
+
+#[cfg(feature = "serde")]
+fn is_zero_flag(flag: &u8) -> bool {
+    *flag == 0
+}
+
+#[cfg(feature = "serde")]
+impl From<Note> for NoteDef {
+    fn from(note: Note) -> Self {
+        NoteDef {
+            model: Arc::try_unwrap(note.model).unwrap_or_else(|model| (*model).clone()),
+            fields: note.fields,
+            sort_field: note.sort_field,
+            tags: note.tags,
+            guid: note.guid,
+            guid_fields: note.guid_fields,
+            due: note.due,
+            strict: note.strict,
+            cards: note
+                .cards
+                .iter()
+                .filter(|card| **card != Card::new(card.ord, false))
+                .map(|card| CardDef {
+                    ord: card.ord,
+                    suspended: card.suspend,
+                    flag: card.flag,
+                    schedule: card.schedule,
+                    deck_id: card.deck_id,
+                })
+                .collect(),
+        }
     }
-    card_ords
-        .iter()
-        .map(|&card_ord| Card::new(card_ord, false))
-        .collect()
 }
 
-fn front_back_cards(model: &Model, self_fields: &Vec<String>) -> Result<Vec<Card>, Error> {
-    let mut rv = vec![];
-    for (card_ord, any_or_all, required_field_ords) in model.req()?.drain(..) {
-        let mut iter = required_field_ords.iter().map(|&ord| &self_fields[ord]);
-        let condition = match any_or_all.as_str() {
-            "any" => iter.any(|field| field.len() > 0),
-            "all" => iter.all(|field| field.len() > 0),
-            _ => panic!("only any or all"),
-        };
-        if condition {
-            rv.push(Card::new(card_ord as i64, false));
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<NoteDef> for Note {
+    type Error = Error;
+
+    fn try_from(def: NoteDef) -> Result<Self, Error> {
+        let fields = def.fields.iter().map(String::as_str).collect();
+        let mut note = Note::new_checked(def.model, fields, def.strict)?
+            .sort_field(def.sort_field)
+            .tags(def.tags)?;
+        note.guid = def.guid;
+        note.due = def.due;
+        // Like after `Note::set_field`, settings of cards that aren't generated are dropped
+        for card_def in def.cards {
+            if let Some(card) = note.cards.iter_mut().find(|card| card.ord == card_def.ord) {
+                card.set_suspended(card_def.suspended);
+                card.set_flag(card_def.flag)?;
+                card.set_schedule(card_def.schedule)?;
+                card.set_deck_id(card_def.deck_id);
+            }
+        }
+        match def.guid_fields {
+            Some(indices) => note.guid_from_fields(&indices),
+            None => Ok(note),
         }
     }
-    Ok(rv)
 }
 
-fn re_findall(regex_str: &'static str, to_match: &str) -> Vec<String> {
-    let regex = Regex::new(regex_str).expect("static regex");
-    regex
-        .captures_iter(to_match)
-        .filter_map(|m| m.ok())
-        .map(|cap| {
-            cap.iter()
-                .skip(1)
-                .filter_map(|m| m)
-                .map(|m| m.as_str().to_string())
-                .collect::<Vec<String>>()
+/// Orders the values of `fields` like the fields of `model`
+fn fields_from_map<K: AsRef<str>, V: AsRef<str>>(
+    model: &Model,
+    fields: impl IntoIterator<Item = (K, V)>,
+    strict: bool,
+) -> Result<Vec<String>, Error> {
+    let field_names = model.field_names();
+    let known_fields = || field_names.iter().map(|name| name.to_string()).collect();
+    let mut values = vec![None; field_names.len()];
+    for (key, value) in fields {
+        let key = key.as_ref();
+        match field_names.iter().position(|&name| name == key) {
+            Some(index) => values[index] = Some(value.as_ref().to_string()),
+            None => {
+                return Err(Error::UnknownNoteField {
+                    key: key.to_string(),
+                    known_fields: known_fields(),
+                })
+            }
+        }
+    }
+    values
+        .into_iter()
+        .zip(&field_names)
+        .map(|(value, name)| match value {
+            Some(value) => Ok(value),
+            None if strict => Err(Error::MissingNoteField {
+                name: name.to_string(),
+            }),
+            None => Ok(String::new()),
         })
-        .flatten()
         .collect()
 }
 
-fn validate_tags(tags: &Vec<String>) -> Result<(), Error> {
-    if tags.iter().any(|tag| tag.contains(' ')) {
-        Err(Error::TagContainsWhitespace)
-    } else {
-        Ok(())
+/// How much of the first field value is included in `Error::FieldCountMismatch`
+const MISMATCH_FIELD_PREVIEW_LEN: usize = 40;
+
+fn check_field_count(model: &Model, fields: &[String]) -> Result<(), Error> {
+    let expected = model.field_names().len();
+    if expected == fields.len() {
+        return Ok(());
+    }
+    Err(Error::FieldCountMismatch {
+        model_name: model.name().to_string(),
+        model_id: model.id,
+        expected,
+        got: fields.len(),
+        first_field: fields
+            .first()
+            .map(|field| truncate(field, MISMATCH_FIELD_PREVIEW_LEN))
+            .unwrap_or_default(),
+    })
+}
+
+fn generate_cards(model: &Model, fields: &[String], strict: bool) -> Result<Vec<Card>, Error> {
+    check_field_count(model, fields)?;
+    match model.get_model_type() {
+        ModelType::FrontBack => {
+            if strict {
+                check_no_cloze_deletions(fields)?;
+            }
+            Ok(model.card_gen_plan()?.cards(fields))
+        }
+        ModelType::Cloze => match model.card_gen_plan()?.cards(fields) {
+            // Like Anki, cloze numbers that are skipped don't get cards
+            cards if !cards.is_empty() => Ok(cards),
+            _ if strict => Err(Error::NoClozeDeletionsFound),
+            _ => Ok(vec![Card::new(0, false)]),
+        },
+    }
+}
+
+/// Cloze deletions are shown literally on the cards of a `FrontBack` model, which is almost
+/// never intended. Like Anki, only lowercase `c` starts a cloze and `\{{` is not a tag.
+fn check_no_cloze_deletions(fields: &[String]) -> Result<(), Error> {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    let regex = REGEX.get_or_init(|| Regex::new(r"(?<!\\)\{\{c\d+::").expect("static regex"));
+    match fields
+        .iter()
+        .position(|field| regex.is_match(field).unwrap_or(false))
+    {
+        Some(field_index) => Err(Error::ClozeInNonClozeModel { field_index }),
+        None => Ok(()),
+    }
+}
+
+/// Returns the numbers of all cloze deletions in `text`, the way Anki finds them.
+///
+/// A deletion starts with `{{cN::`, where `N` can have any number of digits, and ends at the
+/// matching `}}`. Deletions can be nested, and everything after a second `::` is a hint which
+/// does not change the number. Deletions that are never closed are ignored.
+pub(crate) fn cloze_numbers(text: &str) -> BTreeSet<u32> {
+    let mut numbers = BTreeSet::new();
+    let mut open = vec![];
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some((number, after)) = cloze_open(rest) {
+            open.push(number);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            if let Some(number) = open.pop() {
+                numbers.insert(number);
+            }
+            rest = after;
+        } else {
+            let next = rest.chars().next().map_or(1, char::len_utf8);
+            rest = &rest[next..];
+        }
     }
+    numbers
+}
+
+/// Parses a `{{cN::` at the start of `text`, returning `N` and the text after it
+pub(crate) fn cloze_open(text: &str) -> Option<(u32, &str)> {
+    let after_c = text.strip_prefix("{{c")?;
+    let digits = after_c
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(after_c.len());
+    let number = after_c[..digits].parse().ok()?;
+    let after = after_c[digits..].strip_prefix("::")?;
+    Some((number, after))
+}
+
+/// Anki splits tags at whitespace and compares them case-insensitively
+pub(crate) fn normalize_tags(
+    tags: impl IntoIterator<Item = impl ToString>,
+) -> Result<Vec<String>, Error> {
+    let mut seen = HashSet::new();
+    let mut normalized = vec![];
+    for tag in tags {
+        let tag = tag.to_string();
+        if tag.chars().any(char::is_whitespace) {
+            return Err(Error::TagContainsWhitespace);
+        }
+        if seen.insert(tag.to_lowercase()) {
+            normalized.push(tag);
+        }
+    }
+    Ok(normalized)
 }
 
 fn find_invalid_html_tags_in_field(field: &str) -> Vec<String> {
-    let regex = Regex::new(r"<(?!/?[a-z0-9]+(?: .*|/?)>)(?:.|\n)*?>").unwrap();
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    let regex = REGEX.get_or_init(|| {
+        Regex::new(r"<(?!/?[a-z0-9]+(?: .*|/?)>)(?:.|\n)*?>").expect("static regex")
+    });
     regex
         .find_iter(field)
-        .map(|m| m.unwrap().as_str().to_string())
+        // Only a warning, so the rest of a field that exceeds the backtrack limit isn't checked
+        .map_while(Result::ok)
+        .map(|m| m.as_str().to_string())
         .collect()
 }
 
@@ -282,18 +1046,15 @@ mod tests {
     use crate::apkg_schema::APKG_SCHEMA;
     use crate::{Field, Model, Note, Template};
     use rusqlite::Connection;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::collections::HashMap;
     use tempfile::{NamedTempFile, TempPath};
 
-    fn write_to_db_setup(db_file: &TempPath) -> (Connection, f64, i64, RangeFrom<usize>) {
-        let conn = Connection::open(&db_file).unwrap();
+    fn write_to_db_setup(db_file: &TempPath) -> (Connection, Timestamp, i64, RangeFrom<usize>) {
+        let conn = Connection::open(db_file).unwrap();
         conn.execute_batch(APKG_SCHEMA).unwrap();
         conn.execute_batch(APKG_COL).unwrap();
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs_f64();
-        (conn, timestamp, 0, ((timestamp * 1000.0) as usize..))
+        let timestamp = Timestamp::now().unwrap();
+        (conn, timestamp, 0, timestamp.ids())
     }
 
     #[test]
@@ -311,9 +1072,397 @@ mod tests {
         let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
         let transaction = conn.transaction().unwrap();
         my_note
-            .write_to_db(&transaction, timestamp, deck_id, &mut id_gen)
+            .write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
+            .unwrap();
+        transaction.commit().unwrap();
+    }
+
+    #[test]
+    fn template_deck_override() {
+        let model = Model::new(
+            1376484377,
+            "Listening Model",
+            vec![Field::new("Word"), Field::new("Audio")],
+            vec![
+                Template::new("Reading")
+                    .qfmt("{{Word}}")
+                    .afmt("{{FrontSide}}<hr id=answer>{{Audio}}"),
+                Template::new("Listening")
+                    .qfmt("{{Audio}}")
+                    .afmt("{{FrontSide}}<hr id=answer>{{Word}}")
+                    .deck_override(1234),
+            ],
+        );
+        let model_json: serde_json::Value =
+            serde_json::from_str(&model.to_json(0.0, 42).unwrap()).unwrap();
+        assert_eq!(model_json["tmpls"][0]["did"], serde_json::Value::Null);
+        assert_eq!(model_json["tmpls"][1]["did"], 1234);
+
+        let note = Note::new(model, vec!["Hund", "[sound:hund.mp3]"]).unwrap();
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let (mut conn, timestamp, _, mut id_gen) = write_to_db_setup(&db_file);
+        let transaction = conn.transaction().unwrap();
+        note.write_to_db(&transaction, timestamp, 42, &[], 0, &mut id_gen)
+            .unwrap();
+        transaction.commit().unwrap();
+        let dids: Vec<(i64, i64)> = conn
+            .prepare("SELECT ord, did FROM cards ORDER BY ord")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(dids, vec![(0, 42), (1, 1234)]);
+    }
+
+    #[test]
+    fn cloze_in_front_back_model() {
+        let model = || {
+            Model::new(
+                1376484378,
+                "Simple Model",
+                vec![Field::new("Question"), Field::new("Answer")],
+                vec![Template::new("Card 1")
+                    .qfmt("{{Question}}")
+                    .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
+            )
+        };
+        match Note::new(model(), vec!["Capital of Germany", "{{c1::Berlin}}"]) {
+            Err(Error::ClozeInNonClozeModel { field_index }) => assert_eq!(field_index, 1),
+            _ => panic!("expected ClozeInNonClozeModel"),
+        }
+        assert!(matches!(
+            Note::new_with_options(model(), vec!["{{c12::x}}", ""], None, None, None),
+            Err(Error::ClozeInNonClozeModel { field_index: 0 })
+        ));
+        assert!(Note::new(model(), vec!["{{C1::x}}", "b"]).is_ok());
+        assert!(Note::new(model(), vec!["{{Field}}", "{{c::x}}"]).is_ok());
+        assert!(Note::new(model(), vec![r"\{{c1::x}}", "b"]).is_ok());
+        assert!(Note::new_lenient(model(), vec!["{{c1::x}}", "b"]).is_ok());
+    }
+
+    #[test]
+    fn cloze_numbers_like_anki() {
+        let numbers = |text: &str| cloze_numbers(text).into_iter().collect::<Vec<_>>();
+        assert_eq!(numbers("{{c10::a}} {{c2::b}} {{c15::c}}"), vec![2, 10, 15]);
+        assert_eq!(
+            numbers("{{c1::Berlin::capital of Germany}} and {{c2::Paris::capital}}"),
+            vec![1, 2]
+        );
+        assert_eq!(numbers("{{c1::Berlin::city}}}"), vec![1]);
+        assert_eq!(numbers("{{c1::a}} {{c1::b}} {{c3::c}}"), vec![1, 3]);
+        assert_eq!(
+            numbers("<b>{{c1::Ber</b>lin}} {{c2::<i>x</i>}}"),
+            vec![1, 2]
+        );
+        assert_eq!(numbers("{{c1::outer {{c2::inner}} a::b}}"), vec![1, 2]);
+        assert_eq!(numbers("{{c1::never closed {{c2::x}}"), vec![2]);
+        assert_eq!(numbers("}} {{c::x}} {{C1::y}} {{c1:z}}"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn cloze_card_ords() {
+        let model = Model::new(
+            1376484379,
+            "Cloze Model",
+            vec![Field::new("Text"), Field::new("Back Extra")],
+            vec![Template::new("Cloze")
+                .qfmt("{{cloze:Text}}")
+                .afmt("{{cloze:Text}}<br>{{Back Extra}}")],
+        )
+        .model_type(ModelType::Cloze);
+        let note = Note::new(
+            model,
+            vec![
+                "{{c1::a}} {{c12::b::hint}} {{c1::c}} {{c10::d}}",
+                "{{c2::not referenced}}",
+            ],
+        )
+        .unwrap();
+        let ords: Vec<i64> = note.cards().iter().map(Card::ord).collect();
+        assert_eq!(ords, vec![0, 9, 11]);
+    }
+
+    #[test]
+    fn cloze_notes_need_cloze_deletions() {
+        let model = Model::new(
+            1376484380,
+            "Cloze Model",
+            vec![Field::new("Text"), Field::new("Back Extra")],
+            vec![Template::new("Cloze")
+                .qfmt("{{cloze:Text}}")
+                .afmt("{{cloze:Text}}<br>{{Back Extra}}")],
+        )
+        .model_type(ModelType::Cloze);
+        let ords = |text: &str| {
+            Note::new(model.clone(), vec![text, ""])
+                .map(|note| note.cards().iter().map(Card::ord).collect::<Vec<_>>())
+        };
+        assert_eq!(ords("{{c1::a}} {{c3::b}}").unwrap(), vec![0, 2]);
+        assert_eq!(ords("{{c2::a}} {{c2::b}} {{c5::c}}").unwrap(), vec![1, 4]);
+        assert!(matches!(
+            ords("no clozes"),
+            Err(Error::NoClozeDeletionsFound)
+        ));
+        assert!(matches!(ords(""), Err(Error::NoClozeDeletionsFound)));
+        assert!(matches!(
+            Note::new(model.clone(), vec!["", "{{c1::only in Back Extra}}"]),
+            Err(Error::NoClozeDeletionsFound)
+        ));
+        let lenient = Note::new_lenient(model, vec!["no clozes", ""]).unwrap();
+        assert_eq!(
+            lenient.cards().iter().map(Card::ord).collect::<Vec<_>>(),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn field_map() {
+        let model = Model::new(
+            1376484381,
+            "Whitespace Model",
+            vec![
+                Field::new("Front"),
+                Field::new("Front "),
+                Field::new("Back"),
+            ],
+            vec![Template::new("Card 1")
+                .qfmt("{{Front}}")
+                .afmt("{{Front }}<hr id=answer>{{Back}}")],
+        );
+        let note =
+            Note::new_with_field_map(model.clone(), vec![("Back", "b"), ("Front", "a")]).unwrap();
+        assert_eq!(note.fields, vec!["a", "", "b"]);
+
+        let mut map = HashMap::new();
+        map.insert("Front ".to_string(), "a2");
+        map.insert("Front".to_string(), "a");
+        let note = Note::new_with_field_map(model.clone(), map).unwrap();
+        assert_eq!(note.fields, vec!["a", "a2", ""]);
+
+        match Note::new_with_field_map_strict(model.clone(), vec![("Front", "a"), ("Back", "b")]) {
+            Err(Error::MissingNoteField { name }) => assert_eq!(name, "Front "),
+            _ => panic!("expected MissingNoteField"),
+        }
+        match Note::new_with_field_map(model, vec![("Front", "a"), ("Answer", "b")]) {
+            Err(Error::UnknownNoteField { key, known_fields }) => {
+                assert_eq!(key, "Answer");
+                assert_eq!(known_fields, vec!["Front", "Front ", "Back"]);
+            }
+            _ => panic!("expected UnknownNoteField"),
+        }
+    }
+
+    #[test]
+    fn field_count_mismatch() {
+        let model = Model::new(
+            1894808899,
+            "Test Model",
+            vec![
+                Field::new("Question"),
+                Field::new("Answer"),
+                Field::new("Extra"),
+            ],
+            vec![Template::new("Card 1")
+                .qfmt("{{Question}}")
+                .afmt(r#"{{FrontSide}}<hr id="answer">{{Answer}}"#)],
+        );
+        let long = "What is the capital of Germany, the largest country in Europe?";
+        match Note::new(model.clone(), vec![long, "Berlin"]) {
+            Err(error @ Error::FieldCountMismatch { .. }) => assert_eq!(
+                error.to_string(),
+                "model \"Test Model\" (1894808899) has 3 fields, but the note has 2; first field of \
+                 the note: \"What is the capital of Germany, the larg…\""
+            ),
+            _ => panic!("expected FieldCountMismatch"),
+        }
+        assert!(matches!(
+            Note::new(model.clone(), vec!["a", "b", "c", "d"]),
+            Err(Error::FieldCountMismatch {
+                expected: 3,
+                got: 4,
+                ..
+            })
+        ));
+
+        let note = Note::new_padded(model.clone(), vec!["Capital of Germany", "Berlin"]).unwrap();
+        assert_eq!(note.fields, vec!["Capital of Germany", "Berlin", ""]);
+        assert!(Note::new_padded(model, vec!["a", "b", "c", "d"]).is_err());
+    }
+
+    #[test]
+    fn tags_column() {
+        let model = Model::new(
+            1376484382,
+            "Simple Model",
+            vec![Field::new("Question"), Field::new("Answer")],
+            vec![Template::new("Card 1").qfmt("{{Question}}")],
+        );
+        let tagged = Note::new(model.clone(), vec!["der Hund", "the dog"])
+            .unwrap()
+            .tags(vec!["lang::de::nouns", "animals", "Animals"])
+            .unwrap()
+            .with_tag("lang::de::nouns")
+            .unwrap()
+            .with_tag("a1")
+            .unwrap();
+        assert_eq!(tagged.tags, vec!["lang::de::nouns", "animals", "a1"]);
+        let untagged = Note::new(model.clone(), vec!["die Katze", "the cat"]).unwrap();
+        assert!(matches!(
+            untagged.clone().with_tag("two\twords"),
+            Err(Error::TagContainsWhitespace)
+        ));
+
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
+        let transaction = conn.transaction().unwrap();
+        for note in &[tagged, untagged] {
+            note.write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
+                .unwrap();
+        }
+        transaction.commit().unwrap();
+        let tags: Vec<String> = conn
+            .prepare("SELECT tags FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        // This is how Anki stores the same tags
+        assert_eq!(tags, vec![" lang::de::nouns animals a1 ", ""]);
+    }
+
+    #[test]
+    fn sort_field_and_checksum_columns() {
+        let model = Model::new(
+            1376484382,
+            "Numbered Model",
+            vec![Field::new("Question"), Field::new("Number")],
+            vec![Template::new("Card 1").qfmt("{{Question}}")],
+        )
+        .sort_field(1);
+        let notes = vec![
+            Note::new(model.clone(), vec!["<b>Tom</b> &amp; Jerry", "<i>12</i>"]).unwrap(),
+            Note::new(model.clone(), vec!["dog[sound:x.mp3]", "[sound:x.mp3]dog"]).unwrap(),
+            Note::new(model.sort_field(0), vec!["<div>42</div>", "a"]).unwrap(),
+        ];
+
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
+        let transaction = conn.transaction().unwrap();
+        for note in &notes {
+            note.write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
+                .unwrap();
+        }
+        transaction.commit().unwrap();
+        let rows: Vec<(String, String, i64)> = conn
+            .prepare("SELECT CAST(sfld AS TEXT), typeof(sfld), csum FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        // The checksums are int(sha1(stripped_first_field).hexdigest()[:8], 16)
+        assert_eq!(
+            rows,
+            vec![
+                ("12".to_string(), "integer".to_string(), 0x1d862a07),
+                ("dog".to_string(), "text".to_string(), 0xe4951252),
+                ("42".to_string(), "integer".to_string(), 0x92cfceb3),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_field_updates_sort_field() {
+        let model = Model::new(
+            1376484382,
+            "Simple Model",
+            vec![Field::new("Question"), Field::new("Answer")],
+            vec![Template::new("Card 1").qfmt("{{Question}}")],
+        )
+        .sort_field(1);
+        let mut note = Note::new(model, vec!["der Hund", "dog"]).unwrap();
+        let guid = note.get_guid();
+        note.set_field_by_name("Answer", "<b>the</b> dog").unwrap();
+        assert_eq!(note.fields(), ["der Hund", "<b>the</b> dog"]);
+        assert_eq!(note.field_by_name("Answer"), Some("<b>the</b> dog"));
+        assert_ne!(note.get_guid(), guid);
+
+        assert!(matches!(
+            note.set_field(2, "x"),
+            Err(Error::IndexOutOfRange { index: 2, len: 2 })
+        ));
+        assert!(matches!(
+            note.set_field_by_name("Back", "x"),
+            Err(Error::UnknownNoteField { .. })
+        ));
+        assert!(matches!(
+            note.set_field(0, "{{c1::der}} Hund"),
+            Err(Error::ClozeInNonClozeModel { field_index: 0 })
+        ));
+        assert_eq!(note.field(0), Some("der Hund"));
+
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
+        let transaction = conn.transaction().unwrap();
+        note.write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
+            .unwrap();
+        transaction.commit().unwrap();
+        let sort_field: String = conn
+            .query_row("SELECT sfld FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(sort_field, "the dog");
+    }
+
+    #[test]
+    fn set_field_generates_cards() {
+        let mut note = Note::new(
+            crate::basic_optional_reversed_card_model(),
+            vec!["der Hund", "the dog", ""],
+        )
+        .unwrap();
+        assert_eq!(note.cards().len(), 1);
+        note.set_field(2, "y").unwrap();
+        assert_eq!(note.cards().len(), 2);
+    }
+
+    #[test]
+    fn suspended_and_flagged_cards() {
+        let mut note = Note::new(
+            crate::basic_optional_reversed_card_model(),
+            vec!["der Hund", "the dog", ""],
+        )
+        .unwrap()
+        .suspend_cards(true)
+        .configure_cards(|card| card.set_flag(3).unwrap());
+        let mut card = note.cards()[0].clone();
+        assert!(matches!(card.set_flag(8), Err(Error::InvalidCardFlag(8))));
+        assert_eq!(card.flag(), 3);
+        note.set_field(2, "y").unwrap();
+        let note = note.configure_cards(|card| {
+            if card.ord() == 1 {
+                card.set_flag(7).unwrap();
+            }
+        });
+
+        let db_file = NamedTempFile::new().unwrap().into_temp_path();
+        let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
+        let transaction = conn.transaction().unwrap();
+        note.write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
             .unwrap();
         transaction.commit().unwrap();
+        let cards: Vec<(i64, i64, i64, i64)> = conn
+            .prepare("SELECT ord, type, queue, flags FROM cards ORDER BY ord")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        // The card generated by set_field isn't suspended
+        assert_eq!(cards, vec![(0, 0, -1, 3), (1, 0, 0, 7)]);
     }
 
     #[test]
@@ -368,7 +1517,7 @@ mod tests {
         let db_file = NamedTempFile::new().unwrap().into_temp_path();
         let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
         let transaction = conn.transaction().unwrap();
-        note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen)
+        note.write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
             .unwrap();
         transaction.commit().unwrap();
     }
@@ -393,7 +1542,7 @@ mod tests {
         let db_file = NamedTempFile::new().unwrap().into_temp_path();
         let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
         let transaction = conn.transaction().unwrap();
-        note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen)
+        note.write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
             .unwrap();
         transaction.commit().unwrap();
     }
@@ -422,11 +1571,96 @@ mod tests {
         let db_file = NamedTempFile::new().unwrap().into_temp_path();
         let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
         let transaction = conn.transaction().unwrap();
-        note.write_to_db(&transaction, timestamp, deck_id, &mut id_gen)
+        note.write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
             .unwrap();
         transaction.commit().unwrap();
     }
 
+    #[test]
+    #[cfg(feature = "markdown")]
+    fn new_markdown() {
+        let front = "Plurals:\n\n- der Hund, *die Hunde*\n- die Katze, *die Katzen*";
+        let back = "Use `der` for masculine nouns:\n\n```rust\nlet a = 1 < 2;\n```\n\n![a dog](<my dog.jpg>)";
+        let note = Note::new_markdown(crate::basic_model(), vec![front, back]).unwrap();
+        assert_eq!(
+            note.fields(),
+            &[
+                "<p>Plurals:</p>\n<ul>\n<li>der Hund, <em>die Hunde</em></li>\n\
+                 <li>die Katze, <em>die Katzen</em></li>\n</ul>",
+                "<p>Use <code>der</code> for masculine nouns:</p>\n\
+                 <pre><code class=\"language-rust\">let a = 1 &lt; 2;\n</code></pre>\n\
+                 <p><img src=\"my%20dog.jpg\" alt=\"a dog\" /></p>",
+            ]
+        );
+        assert_eq!(
+            note.media_references(),
+            vec![MediaRef {
+                kind: crate::MediaKind::Image,
+                filename: "my dog.jpg".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "markdown")]
+    fn new_markdown_escapes_text() {
+        let note = Note::new_markdown(
+            crate::basic_model(),
+            vec!["Tom & Jerry <div>", "<b>bold</b> and {{Front}}"],
+        )
+        .unwrap();
+        assert_eq!(
+            note.fields(),
+            &[
+                "Tom &amp; Jerry &lt;div&gt;",
+                "&lt;b&gt;bold&lt;/b&gt; and {{Front}}"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sanitize")]
+    fn sanitize_html() {
+        let front = r#"<img src="dog.jpg" onerror="alert(document.cookie)"><b>der</b> <u>Hund</u>[sound:hund.mp3]"#;
+        let back = r#"<span style="color: red; background: url(evil.png)" onclick="x()">the dog</span><script>alert(1)</script><style>body { display: none }</style><iframe src="https://example.com">frame</iframe><a href="javascript:alert(1)">link</a><img src="javascript:alert(1)">"#;
+        let note = Note::new(crate::basic_model(), vec![front, back])
+            .unwrap()
+            .sanitize_html(&crate::SanitizePolicy::new())
+            .unwrap();
+        assert_eq!(
+            note.fields(),
+            &[
+                r#"<img src="dog.jpg"><b>der</b> <u>Hund</u>[sound:hund.mp3]"#,
+                r#"<span style="color: red">the dog</span>link<img>"#,
+            ]
+        );
+        assert_eq!(
+            note.media_references(),
+            vec![
+                MediaRef {
+                    kind: crate::MediaKind::Image,
+                    filename: "dog.jpg".to_string()
+                },
+                MediaRef {
+                    kind: crate::MediaKind::Sound,
+                    filename: "hund.mp3".to_string()
+                }
+            ]
+        );
+
+        let policy = crate::SanitizePolicy::new().allow_tags(["a", "script"]);
+        let links = r#"<a href="https://example.com">x</a><a href="javascript:x()">y</a>"#;
+        let note = Note::new(crate::basic_model(), vec![links, "<script>x()</script>"])
+            .unwrap()
+            .sanitize_html(&policy)
+            .unwrap();
+        // `<script>` is removed even if it is allowed
+        assert_eq!(
+            note.fields(),
+            &[r#"<a href="https://example.com">x</a><a>y</a>"#, ""]
+        );
+    }
+
     #[test]
     fn find_invalid_html_tags_in_field_ok() {
         assert_eq!(
@@ -517,14 +1751,200 @@ mod tests {
             1234,
             "model",
             vec![Field::new("a"), Field::new("b")],
-            vec![Template::new("template")],
+            vec![Template::new("template").qfmt("{{a}}")],
         );
         let _note = Note::new(model, vec!["a", "b"])?
             .guid("1234")
-            .tags(["tag_a"])
-            .with_tag("tag_b")
+            .tags(["tag_a"])?
+            .with_tag("tag_b")?
             .sort_field(true);
 
         Ok(())
     }
+
+    #[test]
+    fn guid_from_key_field() -> anyhow::Result<()> {
+        let model = Model::new(
+            1234,
+            "model",
+            vec![Field::new("ID"), Field::new("Word"), Field::new("Meaning")],
+            vec![Template::new("template").qfmt("{{Word}}")],
+        );
+        let keyed = |fields| -> Result<Note, Error> {
+            Note::new(model.clone(), fields)?.guid_from_field_names(&["ID"])
+        };
+
+        let original = keyed(vec!["42", "der Hund", "dog"])?;
+        assert_eq!(original.get_guid(), guid_for(&["42"]));
+        let edited = keyed(vec!["42", "der Hund", "the dog"])?;
+        assert_eq!(original.get_guid(), edited.get_guid());
+        let other_key = keyed(vec!["43", "der Hund", "the dog"])?;
+        assert_ne!(original.get_guid(), other_key.get_guid());
+        assert_eq!(
+            Note::new(model.clone(), vec!["42", "der Hund", "dog"])?.get_guid(),
+            guid_for(&["42", "der Hund", "dog"])
+        );
+
+        assert_eq!(original.clone().guid("1234").get_guid(), "1234");
+        assert_eq!(
+            Note::new(model.clone(), vec!["42", "der Hund", "dog"])?
+                .guid("1234")
+                .guid_from_fields(&[0])?
+                .get_guid(),
+            "1234"
+        );
+
+        assert!(matches!(
+            original.clone().guid_from_fields(&[0, 3]),
+            Err(Error::IndexOutOfRange { index: 3, len: 3 })
+        ));
+        assert!(matches!(
+            original.guid_from_field_names(&["Id"]),
+            Err(Error::UnknownNoteField { key, .. }) if key == "Id"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn field_transforms() -> Result<(), Error> {
+        let raw = Note::new(
+            crate::basic_model(),
+            vec!["<img src=\"dog.jpg\">", " <b>der</b>\nHund "],
+        )?;
+        let escaped = raw
+            .clone()
+            .with_field_transforms("Front", &[FieldTransform::HtmlEscape])?;
+        assert_eq!(
+            escaped.fields(),
+            ["&lt;img src=&quot;dog.jpg&quot;&gt;", " <b>der</b>\nHund "]
+        );
+        assert!(escaped.media_references().is_empty());
+        assert_eq!(raw.media_references().len(), 1);
+
+        let trimmed_first = raw.clone().with_transforms(&[
+            FieldTransform::Trim,
+            FieldTransform::NewlinesToBr,
+            FieldTransform::Custom(Box::new(|field| field.to_uppercase())),
+        ])?;
+        assert_eq!(trimmed_first.field(1), Some("<B>DER</B><BR>HUND"));
+        let escaped_last = raw
+            .clone()
+            .with_transforms(&[FieldTransform::NewlinesToBr, FieldTransform::HtmlEscape])?;
+        assert_eq!(
+            escaped_last.field(1),
+            Some(" &lt;b&gt;der&lt;/b&gt;&lt;br&gt;Hund ")
+        );
+        assert!(matches!(
+            raw.with_field_transforms("Back ", &[]),
+            Err(Error::UnknownNoteField { .. })
+        ));
+
+        let sort_field_and_checksum = |note: &Note| {
+            let db_file = NamedTempFile::new().unwrap().into_temp_path();
+            let (mut conn, timestamp, deck_id, mut id_gen) = write_to_db_setup(&db_file);
+            let transaction = conn.transaction().unwrap();
+            note.write_to_db(&transaction, timestamp, deck_id, &[], 0, &mut id_gen)
+                .unwrap();
+            transaction.commit().unwrap();
+            conn.query_row("SELECT sfld, csum FROM notes", [], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })
+            .unwrap()
+        };
+        let transformed = Note::new(crate::basic_model(), vec![" a < b ", ""])?
+            .with_transforms(&[FieldTransform::Trim, FieldTransform::HtmlEscape])?;
+        let written = Note::new(crate::basic_model(), vec!["a &lt; b", ""])?;
+        assert_eq!(
+            sort_field_and_checksum(&transformed),
+            sort_field_and_checksum(&written)
+        );
+        assert_eq!(sort_field_and_checksum(&transformed).0, "a < b");
+        Ok(())
+    }
+
+    fn vocabulary_model(id: i64, fields: &[&str]) -> Model {
+        let mut builder = Model::builder(id, format!("Vocabulary {}", id));
+        for name in fields {
+            builder = builder.field(Field::new(name));
+        }
+        builder
+            .template(Template::new("Card 1").qfmt("{{Word}}"))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn remap_model() {
+        let old_model = vocabulary_model(1, &["Word", "Meaning", "Note"]);
+        let note = Note::new(old_model, vec!["der Hund", "the dog", "masculine"])
+            .unwrap()
+            .tags(["nouns"])
+            .unwrap()
+            .suspend_cards(true);
+        let guid = note.get_guid();
+
+        // Renamed field
+        let renamed = vocabulary_model(2, &["Word", "Definition", "Note"]);
+        let mapping = FieldMapping::by_name().map("Meaning", "Definition");
+        let remapped = note.remap_model(renamed.clone(), &mapping).unwrap();
+        assert_eq!(remapped.fields(), ["der Hund", "the dog", "masculine"]);
+        assert_eq!(remapped.model_id(), 2);
+        assert_eq!(remapped.get_guid(), guid);
+        assert_eq!(remapped.get_tags(), ["nouns"]);
+        assert!(remapped.cards()[0].suspend);
+        let by_index = FieldMapping::new()
+            .map_index(0, 0)
+            .map_index(1, 1)
+            .map_index(2, 2);
+        assert_eq!(note.remap_model(renamed, &by_index).unwrap(), remapped);
+
+        // Added field, which stays empty
+        let added = vocabulary_model(3, &["Example", "Word", "Meaning", "Note"]);
+        let remapped = note.remap_model(added, &FieldMapping::by_name()).unwrap();
+        assert_eq!(remapped.fields(), ["", "der Hund", "the dog", "masculine"]);
+        assert_eq!(remapped.get_guid(), guid);
+
+        // Dropped fields, which are lost unless they go to the overflow field
+        let dropped = vocabulary_model(4, &["Word", "Extra"]);
+        let remapped = note.remap_model(dropped.clone(), &FieldMapping::by_name());
+        assert_eq!(remapped.unwrap().fields(), ["der Hund", ""]);
+        let mapping = FieldMapping::by_name().overflow("Extra");
+        let remapped = note.remap_model(dropped.clone(), &mapping).unwrap();
+        assert_eq!(remapped.fields(), ["der Hund", "the dog<br>masculine"]);
+        assert_eq!(remapped.get_guid(), guid);
+        let mapping = FieldMapping::new().map("Word", "Word").map("Note", "Extra");
+        let remapped = note.remap_model(dropped.clone(), &mapping.overflow("Extra"));
+        assert_eq!(
+            remapped.unwrap().fields(),
+            ["der Hund", "masculine<br>the dog"]
+        );
+
+        // A field of both models can go to several fields, and the last mapping counts
+        let mapping = FieldMapping::new()
+            .map("Word", "Word")
+            .map("Word", "Extra")
+            .map("Meaning", "Extra");
+        let remapped = note.remap_model(dropped.clone(), &mapping).unwrap();
+        assert_eq!(remapped.fields(), ["der Hund", "the dog"]);
+        let guid_from_word = note.clone().guid_from_fields(&[0]).unwrap();
+        let remapped = guid_from_word.remap_model(dropped.clone(), &mapping);
+        assert_eq!(remapped.unwrap().get_guid(), guid_for(&["der Hund"]));
+
+        let mapping = FieldMapping::by_name().map("Meaning", "Definition");
+        assert!(matches!(
+            note.remap_model(dropped.clone(), &mapping),
+            Err(Error::UnknownNoteField { key, .. }) if key == "Definition"
+        ));
+        let mapping = FieldMapping::new().map_index(3, 0);
+        assert!(matches!(
+            note.remap_model(dropped, &mapping),
+            Err(Error::IndexOutOfRange { index: 3, len: 3 })
+        ));
+        // The new fields are checked like those of a new note
+        let mapping = FieldMapping::new().map("Word", "Text");
+        assert!(matches!(
+            note.remap_model(crate::cloze_model(), &mapping),
+            Err(Error::NoClozeDeletionsFound)
+        ));
+    }
 }
@@ -1,26 +1,42 @@
-use rusqlite::{Connection, Transaction};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tempfile::NamedTempFile;
-use zip::{write::FileOptions, ZipWriter};
-
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::Path;
 
-use crate::apkg_col::APKG_COL;
-use crate::apkg_schema::APKG_SCHEMA;
-use crate::deck::Deck;
-use crate::error::{database_error, json_error, zip_error};
+use crate::builders::{CollectionConf, CompressionOptions};
+use crate::deck::{Deck, DedupBy};
+use crate::latex::{prerender_note_latex, LatexRenderer};
+use crate::media::{
+    check_media_content, check_media_filename, is_always_used, MediaCheck, MediaFile, MediaFiles,
+    MediaIssue, MediaReport, Strictness,
+};
+#[cfg(feature = "modern-format")]
+use crate::modern_format::PackageFormat;
+use crate::note::Note;
+use crate::package_appender::append_to_package;
+use crate::package_reader::{read_package, LoadedPackage};
+use crate::package_writer::{
+    check_deck_ids, CollectionDump, PackageWriter, Progress, ProgressReporter, SchedulerVersion,
+    WriteSummary, BATCH_SIZE,
+};
+#[cfg(feature = "spec")]
+use crate::spec::{deck_from_spec, DeckSpec, Record};
+use crate::timestamp::Timestamp;
+use crate::tts::{TtsField, TtsProvider};
+use crate::util::{strip_html, temp_file_next_to};
+use crate::validation::{validate_decks, ValidationReport, DEFAULT_MAX_FIELD_BYTES};
+use crate::verification::verify_package;
 use crate::Error;
-use std::str::FromStr;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 /// `Package` to pack `Deck`s and `media_files` and write them to a `.apkg` file
 ///
 /// Example:
-/// ```rust
+/// ```rust,no_run
 /// use genanki_rs::{Package, Deck, Note, Model, Field, Template};
 ///
+/// # fn main() -> Result<(), genanki_rs::Error> {
 /// let model = Model::new(
 ///     1607392319,
 ///     "Simple Model",
@@ -38,123 +54,2794 @@ use std::str::FromStr;
 /// deck.add_note(Note::new(model.clone(), vec!["What is the capital of France?", "Paris", "[sound:sound.mp3]"])?);
 /// deck.add_note(Note::new(model.clone(), vec!["What is the capital of France?", "Paris", r#"<img src="image.jpg">"#])?);
 ///
-/// let mut package = Package::new(vec![my_deck], vec!["sound.mp3", "images/image.jpg"])?;
+/// let mut package = Package::new(vec![deck], vec!["sound.mp3", "images/image.jpg"])?;
 /// package.write_to_file("output.apkg")?;
+/// # Ok(())
+/// # }
 /// ```
+#[derive(Clone)]
 pub struct Package {
     decks: Vec<Deck>,
-    media_files: Vec<PathBuf>,
+    media: MediaFiles,
+    auto_rename_media: bool,
+    compression: Option<CompressionOptions>,
+    use_tempfile: bool,
+    atomic_write: bool,
+    #[cfg(feature = "nfc")]
+    normalize_unicode: bool,
+    #[cfg(feature = "modern-format")]
+    format: PackageFormat,
+    scheduler_version: SchedulerVersion,
+    max_field_bytes: usize,
+    unchecked: bool,
+    collection_conf: Option<CollectionConf>,
+    selected_deck: Option<i64>,
+    timestamp: Option<Timestamp>,
 }
 
 impl Package {
     /// Create a new package with `decks` and `media_files`
     ///
-    /// Returns `Err` if `media_files` are invalid
+    /// Decks above the `decks` in the deck hierarchy, like `Japanese` for `Japanese::Vocab`, are
+    /// added to the package without notes if they are missing, with ids from
+    /// [`Deck::id_from_name`].
+    ///
+    /// The `media_files` are paths, which are named after their last component in the package.
+    /// Media files with the same file name and the same content are only added once. Use
+    /// [`Package::with_media_paths`] for paths that aren't valid UTF-8.
+    ///
+    /// Returns `Error::InvalidMediaPath`, `Error::NonUtf8MediaFilename` or
+    /// `Error::InvalidMediaFilename` if one of the `media_files` can't be added with
+    /// [`Package::add_media_file`], `Error::MediaNameCollision` if two of them have the same file
+    /// name but different content, `Error::InvalidDeckName` if the name of a deck starts or ends
+    /// with `::` or contains `::::`, `Error::IdOutOfRange` if the id of a deck isn't in
+    /// `1..=MAX_ID`, see [`MAX_ID`](crate::MAX_ID), or `Error::DuplicateDeckId` if decks with
+    /// different names have the same id. The id `1` is the id of Anki's default deck, so a deck
+    /// with that id has to be named "Default" and stands for the default deck.
     pub fn new(decks: Vec<Deck>, media_files: Vec<&str>) -> Result<Self, Error> {
-        let media_files = media_files
+        Self::with_media_paths(decks, media_files)
+    }
+
+    /// Create a new package with `decks` and the `media_files` at paths like `&Path` or `PathBuf`,
+    /// which don't have to be valid UTF-8
+    ///
+    /// Fails like [`Package::new`].
+    pub fn with_media_paths<P: AsRef<Path>>(
+        decks: Vec<Deck>,
+        media_files: Vec<P>,
+    ) -> Result<Self, Error> {
+        for deck in &decks {
+            deck.validate_name()?;
+        }
+        check_deck_ids(&decks.iter().collect::<Vec<_>>())?;
+        let mut package = Self {
+            decks,
+            media: MediaFiles::default(),
+            auto_rename_media: false,
+            compression: None,
+            use_tempfile: false,
+            atomic_write: true,
+            #[cfg(feature = "nfc")]
+            normalize_unicode: false,
+            #[cfg(feature = "modern-format")]
+            format: PackageFormat::default(),
+            scheduler_version: SchedulerVersion::default(),
+            max_field_bytes: DEFAULT_MAX_FIELD_BYTES,
+            unchecked: false,
+            collection_conf: None,
+            selected_deck: None,
+            timestamp: None,
+        };
+        for media_file in media_files {
+            package.push_media(MediaFile::from_path(media_file.as_ref())?)?;
+        }
+        Ok(package)
+    }
+
+    /// Reads the decks, models, notes and media files of the `.apkg` file at `path`, e.g. to change
+    /// some notes of a deck made in Anki and write it again with [`LoadedPackage::into_package`]
+    ///
+    /// Only the content is read: the notes get new cards that aren't suspended, flagged or
+    /// scheduled, and the decks have the default options. Notes are added to the deck of their
+    /// first card, filtered decks and the empty default deck are left out.
+    ///
+    /// Packages in the format of Anki 2.1.50 and later, which stores the collection in
+    /// `collection.anki21b`, can only be read with the `modern-format` feature.
+    ///
+    /// Returns `Error::InvalidPackage` if the file doesn't contain a collection that can be read,
+    /// and `Err` if the collection can't be read or the notes don't match their models
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<LoadedPackage, Error> {
+        read_package(path.as_ref())
+    }
+
+    /// Creates a package with the deck described by `spec` and a note for each record of `data`
+    ///
+    /// The fields of a note are taken from the columns of its record as the spec maps them, see
+    /// [`DeckSpec`] for the format of a spec.
+    ///
+    /// Returns `Error::InvalidSpec` with the path of the offending key of the spec if e.g. a field
+    /// that isn't in the model is mapped, the model has no templates or a template refers to an
+    /// unknown field, or if a record doesn't have a column that is mapped explicitly
+    #[cfg(feature = "spec")]
+    pub fn from_spec(
+        spec: &DeckSpec,
+        data: impl IntoIterator<Item = Record>,
+    ) -> Result<Package, Error> {
+        Package::new(vec![deck_from_spec(spec, data)?], vec![])
+    }
+
+    #[cfg(feature = "ankiconnect")]
+    pub(crate) fn decks(&self) -> &[Deck] {
+        &self.decks
+    }
+
+    #[cfg(feature = "ankiconnect")]
+    pub(crate) fn media_files(&self) -> impl Iterator<Item = &MediaFile> {
+        self.media.iter()
+    }
+
+    /// Adds `new_notes` to the decks with the given ids and the media files at the paths
+    /// `new_media` to the `.apkg` file at `path`, without reading and writing the whole package
+    ///
+    /// The notes are inserted into the collection of the package, and the media files that are
+    /// already in the package are copied without compressing them again. A note is skipped if the
+    /// package or one of the notes before it already has a duplicate of it according to `dedup`,
+    /// like [`Deck::add_note_dedup_by`], and so is a media file if the package already has the same
+    /// file. Models of the notes that the package doesn't have yet are added to it.
+    ///
+    /// Returns the number of notes that were added, `Error::UnknownDeckId` if the package doesn't
+    /// have one of the decks, `Error::ModelIdConflict` if it has a different model with the id of
+    /// the model of a note, `Error::MediaNameCollision` if it has a different media file with the
+    /// same name as a new one, `Error::InvalidPackage` if the package is in the format of Anki
+    /// 2.1.50 and later, or fails like [`Package::open`]. The package is only replaced once
+    /// the new one is written.
+    pub fn append<P: AsRef<Path>>(
+        path: P,
+        new_notes: Vec<(i64, Note)>,
+        new_media: &[&Path],
+        dedup: DedupBy,
+    ) -> Result<usize, Error> {
+        append_to_package(path.as_ref(), new_notes, new_media, dedup)
+    }
+
+    /// Sets whether media files added with [`Package::add_media_file`] are renamed if the package
+    /// already contains a different file with the same name, default is `false`
+    ///
+    /// The hash of the content is appended to the name of a renamed file, e.g.
+    /// `lesson2/audio.mp3` becomes `audio-1a2b3c4d.mp3`, and references to a file by its path,
+    /// like `[sound:lesson2/audio.mp3]`, are replaced with references to its name in the package.
+    /// Files added from bytes or readers are never renamed.
+    pub fn auto_rename_media(mut self, auto_rename_media: bool) -> Self {
+        self.auto_rename_media = auto_rename_media;
+        self
+    }
+
+    /// Adds the media file at `path`
+    ///
+    /// The file is named after the last component of `path`, so `lesson2/audio.mp3` is named
+    /// `audio.mp3` in the package.
+    ///
+    /// Returns `Error::InvalidMediaPath` if `path` doesn't end in a file name,
+    /// `Error::NonUtf8MediaFilename` if the file name isn't valid UTF-8,
+    /// `Error::InvalidMediaFilename` if it contains `\` or NUL or `Error::MediaNameCollision` if
+    /// the package already contains a file with the same name but different content, unless
+    /// [`Package::auto_rename_media`] is set
+    pub fn add_media_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let media_file = MediaFile::from_path(path)?;
+        let original_name = media_file.name().to_string();
+        let name = self.push_media(media_file)?;
+        if self.auto_rename_media {
+            if let Some(path) = path.to_str().filter(|&p| p != original_name) {
+                for deck in &mut self.decks {
+                    deck.rename_media(path, &name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a media file named `filename` with the content `bytes`, e.g. an image generated in
+    /// memory
+    ///
+    /// Returns `Error::InvalidMediaFilename` if `filename` contains `/`, `\` or NUL or
+    /// `Error::MediaNameCollision` if the package already contains a media file with this name
+    /// but different content
+    pub fn add_media_bytes(
+        &mut self,
+        filename: &str,
+        bytes: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        check_media_filename(filename)?;
+        self.push_media(MediaFile::Bytes {
+            name: filename.to_string(),
+            data: bytes.into(),
+        })?;
+        Ok(())
+    }
+
+    /// Adds a media file named `filename` with the content read from `reader`
+    ///
+    /// Returns `Error::MediaNameCollision` if the package already contains a media file with
+    /// this name but different content or `Err` if reading fails
+    pub fn add_media_reader(&mut self, filename: &str, mut reader: impl Read) -> Result<(), Error> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        self.add_media_bytes(filename, data)
+    }
+
+    /// Adds the media files referenced by the notes of all decks, see
+    /// [`Note::media_references`], from the directory `base`
+    ///
+    /// Files that are referenced several times are added once and references to files already in
+    /// the package are skipped. References that aren't plain file names, like `images/dog.jpg`,
+    /// can't be resolved by Anki and are reported as missing.
+    ///
+    /// Example:
+    /// ```rust,no_run
+    /// use genanki_rs::{Deck, Note, Package, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(basic_model(), vec![r#"<img src="dog.jpg">"#, "Dog"])?);
+    ///
+    /// let mut package = Package::new(vec![deck], vec![])?;
+    /// let report = package.collect_media_from_dir("media")?;
+    /// for filename in &report.missing {
+    ///     eprintln!("missing media file: {}", filename);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Note::media_references`]: crate::Note::media_references
+    pub fn collect_media_from_dir<P: AsRef<Path>>(
+        &mut self,
+        base: P,
+    ) -> Result<MediaReport, Error> {
+        let base = base.as_ref();
+        let mut report = MediaReport::default();
+        for name in self.check_media().missing {
+            let path = base.join(&name);
+            let is_file_name = Path::new(&name).file_name() == Some(name.as_ref());
+            if is_file_name && path.is_file() {
+                self.push_media(MediaFile::Path {
+                    name: name.clone(),
+                    path,
+                })?;
+                report.found.push(name);
+            } else {
+                report.missing.push(name);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Like [`Package::collect_media_from_dir`], but returns `Error::MissingMediaFiles` without
+    /// adding any files if a referenced file is missing
+    pub fn collect_media_from_dir_strict<P: AsRef<Path>>(
+        &mut self,
+        base: P,
+    ) -> Result<MediaReport, Error> {
+        let added = self.media.len();
+        let report = self.collect_media_from_dir(base)?;
+        if report.missing.is_empty() {
+            Ok(report)
+        } else {
+            self.media.truncate(added);
+            Err(Error::MissingMediaFiles {
+                filenames: report.missing,
+            })
+        }
+    }
+
+    /// Replaces the `[latex]...[/latex]`, `[$]...[/$]` and `[$$]...[/$$]` blocks in the notes of
+    /// all decks with images rendered by `renderer`, so that they are shown without a LaTeX
+    /// installation, e.g. on phones
+    ///
+    /// The images are added to the media files with the names Anki gives them, like
+    /// `latex-<sha1>.png`, and the fields get the same `<img>` tags Anki shows instead of the
+    /// LaTeX. Each LaTeX is rendered once, with the `latex_pre` and `latex_post` of the model of
+    /// its note.
+    ///
+    /// Returns `Error::LatexRenderFailed` with the deck, the note and the field of the first
+    /// LaTeX that can't be rendered and the output of the renderer. The notes before it are
+    /// rendered already.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, LatexRenderer, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Math", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["[$]e^{i \\pi}[/$]", "-1"])?);
+    /// let mut package = Package::new(vec![deck], vec![])?;
+    /// // Renders with `latex` and `dvipng` like Anki, or e.g. with a library
+    /// let renderer = LatexRenderer::custom(|document| Ok(render_png(document)));
+    /// package.prerender_latex(&renderer)?;
+    /// # Ok(())
+    /// # }
+    /// # fn render_png(_document: &str) -> Vec<u8> { vec![] }
+    /// ```
+    pub fn prerender_latex(&mut self, renderer: &LatexRenderer) -> Result<(), Error> {
+        for deck in &mut self.decks {
+            let deck_id = deck.id();
+            for (index, note) in deck.notes_mut().enumerate() {
+                let media = &self.media;
+                let images = prerender_note_latex(
+                    note,
+                    renderer,
+                    |name| media.contains(name),
+                    deck_id,
+                    index,
+                )?;
+                for (name, data) in images {
+                    self.media.push(MediaFile::Bytes { name, data }, false)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds audio of the text in the field `field.source` of the notes of all decks, synthesized
+    /// by `tts`, as `[sound:...]` to the field `field.target`
+    ///
+    /// The HTML and media references are removed from the text before it is synthesized, the
+    /// same text is only synthesized once and the audio files are added to the media files. Notes
+    /// whose model doesn't have both fields and notes with an empty text are skipped, and so are
+    /// notes that already have the sound, so generating it again doesn't add it twice.
+    ///
+    /// Returns the first error of `tts`, the notes before it have their sound already.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, Error, Field, Model, Note, Package, Template, TtsField, TtsProvider};
+    ///
+    /// struct Silence;
+    ///
+    /// impl TtsProvider for Silence {
+    ///     fn synthesize(&self, text: &str, lang: &str) -> Result<(String, Vec<u8>), Error> {
+    ///         Ok((format!("{}-{}.mp3", lang, text), vec![]))
+    ///     }
+    /// }
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// let model = Model::new(
+    ///     1234,
+    ///     "Vocabulary",
+    ///     vec![Field::new("German"), Field::new("English"), Field::new("Audio")],
+    ///     vec![Template::new("Card 1")
+    ///         .qfmt("{{German}}")
+    ///         .afmt("{{FrontSide}}<hr id=answer>{{English}} {{Audio}}")],
+    /// );
+    /// let mut deck = Deck::new(1234, "German", "");
+    /// deck.add_note(Note::new(model, vec!["Hund", "dog", ""])?);
+    /// let mut package = Package::new(vec![deck], vec![])?;
+    /// package.generate_tts(&TtsField::new("German", "Audio", "de_DE"), &Silence)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn generate_tts(&mut self, field: &TtsField, tts: &impl TtsProvider) -> Result<(), Error> {
+        let mut synthesized: HashMap<String, String> = HashMap::new();
+        for note in self.decks.iter_mut().flat_map(Deck::notes_mut) {
+            let (text, target) = match (
+                note.field_by_name(&field.source),
+                note.field_by_name(&field.target),
+            ) {
+                (Some(source), Some(target)) => (strip_html(source), target),
+                _ => continue,
+            };
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+            let filename = match synthesized.get(text) {
+                Some(filename) => filename.clone(),
+                None => {
+                    let (filename, data) = tts.synthesize(text, &field.lang)?;
+                    let name = filename.clone();
+                    self.media.push(MediaFile::Bytes { name, data }, false)?;
+                    synthesized.insert(text.to_string(), filename.clone());
+                    filename
+                }
+            };
+            let sound = format!("[sound:{}]", filename);
+            if !target.contains(&sound) {
+                let target = format!("{}{}", target, sound);
+                note.set_field_by_name(&field.target, target)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the media files of the package with the files referenced by the notes of all
+    /// decks, see [`Note::media_references`]
+    ///
+    /// Files starting with `_`, like `_background.png`, are meant to be referenced by templates
+    /// and styling and never count as unreferenced. The names are compared as they are written,
+    /// i.e. in NFC with [`Package::normalize_unicode`].
+    ///
+    /// [`Note::media_references`]: crate::Note::media_references
+    pub fn check_media(&self) -> MediaCheck {
+        let referenced = self.referenced_media();
+        let written: Vec<String> = referenced.iter().map(|r| self.written_name(r)).collect();
+        let unreferenced = self
+            .media
+            .iter()
+            .map(MediaFile::name)
+            .filter(|name| !is_always_used(name) && !written.contains(&self.written_name(name)))
+            .map(str::to_string)
+            .collect();
+        let media: HashSet<String> = self
+            .media
             .iter()
-            .map(|&s| PathBuf::from_str(s))
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(Self { decks, media_files })
+            .map(|file| self.written_name(file.name()))
+            .collect();
+        let missing = referenced
+            .into_iter()
+            .zip(written)
+            .filter(|(_, written)| !media.contains(written))
+            .map(|(name, _)| name)
+            .collect();
+        MediaCheck {
+            unreferenced,
+            missing,
+        }
+    }
+
+    /// Removes the media files that no note references, see [`Package::check_media`], and
+    /// returns their names
+    pub fn prune_unreferenced_media(&mut self) -> Vec<String> {
+        let unreferenced = self.check_media().unreferenced;
+        self.media
+            .retain(|f| !unreferenced.iter().any(|name| name == f.name()));
+        unreferenced
+    }
+
+    /// Checks that the media files aren't empty, have an extension Anki supports and that the
+    /// first bytes of images, sounds and videos match their extension
+    ///
+    /// With `Strictness::Lenient` the problems are returned, with `Strictness::Strict` they are
+    /// returned as `Error::InvalidMedia`. Returns `Err` if a media file cannot be read.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{MediaIssue, MediaIssueKind, Package, Strictness};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut package = Package::new(vec![], vec![])?;
+    /// package.add_media_bytes("dog.mp3", "<!DOCTYPE html><p>Not Found</p>")?;
+    /// assert_eq!(
+    ///     package.validate_media(Strictness::Lenient)?,
+    ///     vec![MediaIssue {
+    ///         filename: "dog.mp3".to_string(),
+    ///         kind: MediaIssueKind::ContentMismatch { detected: Some("html") },
+    ///     }]
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate_media(&self, strictness: Strictness) -> Result<Vec<MediaIssue>, Error> {
+        let mut issues = vec![];
+        for media_file in self.media.iter() {
+            let (len, head) = media_file.head()?;
+            if let Some(kind) = check_media_content(media_file.name(), len, &head) {
+                let filename = media_file.name().to_string();
+                issues.push(MediaIssue { filename, kind });
+            }
+        }
+        match strictness {
+            Strictness::Strict if !issues.is_empty() => Err(Error::InvalidMedia { issues }),
+            _ => Ok(issues),
+        }
+    }
+
+    /// `name` as it is written to the package, converted to NFC with
+    /// [`Package::normalize_unicode`]
+    fn written_name(&self, name: &str) -> String {
+        #[cfg(feature = "nfc")]
+        if self.normalize_unicode {
+            use unicode_normalization::UnicodeNormalization;
+            return name.nfc().collect();
+        }
+        name.to_string()
+    }
+
+    /// The file names referenced by the notes of all decks, without duplicates
+    fn referenced_media(&self) -> Vec<String> {
+        let mut referenced: Vec<String> = vec![];
+        for media in self.decks.iter().flat_map(Deck::media_references) {
+            if !referenced.contains(&media.filename) {
+                referenced.push(media.filename);
+            }
+        }
+        referenced
+    }
+
+    fn push_media(&mut self, media_file: MediaFile) -> Result<String, Error> {
+        self.media.push(media_file, self.auto_rename_media)
+    }
+
+    /// Sets how the files in the package are compressed
+    ///
+    /// By default, all files are compressed with the default level of Deflate.
+    pub fn compression(mut self, options: CompressionOptions) -> Self {
+        self.compression = Some(options);
+        self
+    }
+
+    /// Sets the format of the package, default is `PackageFormat::Legacy`
+    ///
+    /// `PackageFormat::Latest` is the format that Anki 2.1.50 and later export. The collection in
+    /// it has the same schema as in the legacy format, which Anki upgrades when importing it.
+    /// Everything is compressed with zstd, so the [`Package::compression`] options don't apply.
+    #[cfg(feature = "modern-format")]
+    pub fn format(mut self, format: PackageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the scheduler version in the configuration of the collection, default is
+    /// `SchedulerVersion::V1`
+    ///
+    /// With `SchedulerVersion::V2`, Anki doesn't ask to upgrade the scheduler when the package
+    /// is imported into a new profile. The cards are the same for both versions: new cards are
+    /// due by their position, and cards with a [`CardSchedule`](crate::CardSchedule) are review
+    /// cards that are due on a day, which both schedulers store the same way. The `ver` of the
+    /// collection stays `11`, as it is the version of the schema of the database, which is the
+    /// same for both schedulers.
+    pub fn scheduler_version(mut self, version: SchedulerVersion) -> Self {
+        self.scheduler_version = version;
+        self
+    }
+
+    /// Sets options of the collection, like the column the card browser is sorted by
+    ///
+    /// Options that aren't set in `conf` keep their values. Anki uses the options of a collection
+    /// package, see [`Package::write_colpkg`], but keeps the options of the profile when a `.apkg`
+    /// is imported. Returns `Error::UnknownDeckId` when writing if a deck of `conf` isn't a deck of
+    /// the package or the default deck.
+    pub fn collection_conf(mut self, conf: CollectionConf) -> Self {
+        self.collection_conf = Some(conf);
+        self
+    }
+
+    /// Selects the deck with `deck_id` when Anki imports the package as a collection package,
+    /// written with [`Package::write_colpkg`], by setting `curDeck` and `activeDecks` of the
+    /// collection options, see [`CollectionConf::cur_deck`]
+    ///
+    /// This replaces those two options of [`Package::collection_conf`], no matter which is set
+    /// first, and keeps the others. Like them, it has no effect on importing a `.apkg`, which
+    /// keeps the deck that is selected in the profile. Returns `Error::UnknownDeckId` when writing
+    /// if the deck isn't a deck of the package or the default deck.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// use genanki_rs::{Deck, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let deck = Deck::new(1234, "Example deck", "").collapsed(true);
+    /// let package = Package::new(vec![deck], vec![])?.select_deck_after_import(1234);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn select_deck_after_import(mut self, deck_id: i64) -> Self {
+        self.selected_deck = Some(deck_id);
+        self
+    }
+
+    /// Sets the size in bytes above which [`Package::validate`] rejects a field, default is 128 KiB
+    ///
+    /// Very large fields, e.g. with images embedded as data URLs, make Anki slow to show and
+    /// search notes, and can exceed the limits of its tools.
+    pub fn max_field_bytes(mut self, max_field_bytes: usize) -> Self {
+        self.max_field_bytes = max_field_bytes;
+        self
+    }
+
+    /// Sets the time the package is written at, instead of the current time when it is written
+    ///
+    /// Everything that would otherwise depend on the current time, like the ids of notes and
+    /// cards and the modification times in the zip file, is derived from `timestamp`, so writing
+    /// the same package with the same timestamp gives the same bytes, e.g. in tests or to build a
+    /// deck reproducibly. [`Package::write_to_file_timestamp`] overrides it.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note, Package, Timestamp};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["Hund", "dog"])?);
+    /// let mut package = Package::new(vec![deck], vec![])?
+    ///     .with_timestamp(Timestamp::from_secs(1700000000));
+    /// assert_eq!(package.write_to_bytes()?, package.write_to_bytes()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_timestamp(mut self, timestamp: impl Into<Timestamp>) -> Self {
+        self.timestamp = Some(timestamp.into());
+        self
+    }
+
+    /// Sets whether the package is written without calling [`Package::validate`] first, default
+    /// is `false`
+    pub fn unchecked(mut self, unchecked: bool) -> Self {
+        self.unchecked = unchecked;
+        self
+    }
+
+    /// Checks the package for problems that Anki doesn't report, which is done before the
+    /// package is written unless [`Package::unchecked`] is set
+    ///
+    /// Returns `Error::NoDecks` if the package has no decks, `Error::FieldTooLarge` if a field of
+    /// a note is larger than [`Package::max_field_bytes`], `Error::IdOutOfRange` if the id of a
+    /// model isn't in `1..=MAX_ID`, see [`MAX_ID`](crate::MAX_ID), `Error::UnknownDeckId` if a
+    /// card is routed to a deck that isn't in the package, see [`Card::set_deck_id`], and
+    /// `Error::ModelIdConflict` if notes use different models with the same id, of which Anki
+    /// would only import one. Decks without notes are reported as warnings.
+    ///
+    /// [`Card::set_deck_id`]: crate::Card::set_deck_id
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{Deck, Package, ValidationWarning};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let package = Package::new(vec![Deck::new(1234, "Empty deck", "")], vec![])?;
+    /// for warning in package.validate()?.warnings {
+    ///     match warning {
+    ///         ValidationWarning::EmptyDeck { name, .. } => println!("{} has no notes", name),
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate(&self) -> Result<ValidationReport, Error> {
+        validate_decks(&self.decks, self.max_field_bytes)
+    }
+
+    /// Sets whether the collection database is built in a temporary file instead of in memory
+    /// while the package is written, default is `false`
+    ///
+    /// The output is the same either way, but the temporary file needs less memory for large
+    /// decks.
+    pub fn use_tempfile(mut self, use_tempfile: bool) -> Self {
+        self.use_tempfile = use_tempfile;
+        self
+    }
+
+    /// Sets whether files are replaced only once the package is completely written, default is
+    /// `true`
+    ///
+    /// The package is written to a temporary file in the directory of the file, which is renamed
+    /// to the file if writing succeeds and removed otherwise, so that the file never contains a
+    /// partial package, e.g. when the process is killed or the disk is full. An existing file is
+    /// replaced, also on Windows. With `false`, the file is created and written directly, for
+    /// filesystems that don't support renaming files atomically.
+    pub fn atomic_write(mut self, atomic_write: bool) -> Self {
+        self.atomic_write = atomic_write;
+        self
+    }
+
+    /// Sets whether the note fields and media file names are converted to Unicode NFC when the
+    /// package is written, default is `false`
+    ///
+    /// Anki converts text that is pasted into it to NFC, so text in NFD, e.g. from macOS file
+    /// names, doesn't match what users type when searching or looking for duplicates. The file
+    /// names in the package are converted too, so that the references in the fields still match.
+    #[cfg(feature = "nfc")]
+    pub fn normalize_unicode(mut self, normalize_unicode: bool) -> Self {
+        self.normalize_unicode = normalize_unicode;
+        self
+    }
+
+    /// Writes the package to a file and returns what was written
+    ///
+    /// The file is only replaced once the package is complete, see [`Package::atomic_write`].
+    ///
+    /// Returns `Err` if the `file` cannot be created
+    pub fn write_to_file<P: AsRef<Path>>(&mut self, file: P) -> Result<WriteSummary, Error> {
+        self.write_file(file.as_ref(), |package, writer| {
+            package.write_maybe_timestamp(writer, None, &mut |_| {})
+        })
+    }
+
+    /// Writes the package to a file like [`Package::write_to_file`] and then checks that it can be
+    /// read again
+    ///
+    /// After writing, the CRC of every entry of the archive is checked, the integrity of the
+    /// collection is checked with SQLite and the numbers of notes, cards, models and media files
+    /// in the package are compared with the returned [`WriteSummary`]. This reads the whole
+    /// package again, so it is only done by this method and not by [`Package::write_to_file`].
+    ///
+    /// Returns `Error::VerificationFailed` if the written package is invalid
+    pub fn write_to_file_verified<P: AsRef<Path>>(
+        &mut self,
+        file: P,
+    ) -> Result<WriteSummary, Error> {
+        let summary = self.write_to_file(file.as_ref())?;
+        verify_package(file.as_ref(), &summary)?;
+        Ok(summary)
+    }
+
+    /// Writes the package to a file like [`Package::write_to_file`] and calls `callback` with
+    /// the progress, e.g. to show a progress bar
+    ///
+    /// `callback` is called every 1000 notes and before each media file. If it panics, it isn't
+    /// called again and the panic continues after the package is completely written.
+    ///
+    /// Example:
+    /// ```rust,no_run
+    /// use genanki_rs::{Deck, Package, Progress};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut package = Package::new(vec![Deck::new(1234, "Example deck", "")], vec!["sound.mp3"])?;
+    /// package.write_to_file_with_progress("output.apkg", |progress| match progress {
+    ///     Progress::WritingNotes { done, total } => println!("{}/{} notes", done, total),
+    ///     Progress::CompressingMedia { file, .. } => println!("{}", file),
+    ///     Progress::Finalizing => println!("finishing"),
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_to_file_with_progress<P: AsRef<Path>>(
+        &mut self,
+        file: P,
+        mut callback: impl FnMut(Progress),
+    ) -> Result<WriteSummary, Error> {
+        // A panic of `callback` continues only after the file is replaced
+        let mut progress = ProgressReporter::new(&mut callback);
+        let result = self.write_file(file.as_ref(), |package, writer| {
+            package.write_with_progress(writer, None, false, &mut progress)
+        });
+        progress.finish();
+        result
+    }
+
+    /// Writes the package to `writer`, e.g. to send it without saving it to a file first, and
+    /// returns what was written
+    ///
+    /// Returns `Err` if writing to `writer` fails
+    pub fn write<W: Write + Seek>(&mut self, writer: W) -> Result<WriteSummary, Error> {
+        self.write_maybe_timestamp(writer, None, &mut |_| {})
+    }
+
+    /// Writes the package to a new `Vec<u8>`
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{Deck, Note, Package, basic_model};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut my_deck = Deck::new(1234, "Example deck", "This is an example deck");
+    /// my_deck.add_note(Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?);
+    ///
+    /// let bytes = Package::new(vec![my_deck], vec![])?.write_to_bytes()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_to_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let mut cursor = Cursor::new(Vec::new());
+        self.write(&mut cursor)?;
+        Ok(cursor.into_inner())
     }
 
-    /// Writes the package to a file
+    /// Writes the package as a collection package (`.colpkg`) to a file and returns what was
+    /// written
+    ///
+    /// Anki replaces the whole collection of a profile with a `.colpkg` when it is imported with
+    /// "Import collection", so the collection contains the decks, models and notes of the package
+    /// and the default deck. The files are the same as in a `.apkg` in the same
+    /// [format](Package::format), but the configuration of the collection, which Anki keeps
+    /// instead of merging, selects the first model and gives new notes added in Anki the positions
+    /// after the notes of the package.
     ///
     /// Returns `Err` if the `file` cannot be created
-    pub fn write_to_file<P: AsRef<Path>>(&mut self, file: P) -> Result<(), Error> {
-        self.write_to_file_maybe_timestamp(file, None)
+    pub fn write_colpkg<P: AsRef<Path>>(&mut self, file: P) -> Result<WriteSummary, Error> {
+        self.write_file(file.as_ref(), |package, writer| {
+            let mut callback = |_| {};
+            let mut progress = ProgressReporter::new(&mut callback);
+            let result = package.write_with_progress(writer, None, true, &mut progress);
+            progress.finish();
+            result
+        })
+    }
+
+    /// Writes only the collection database of the package and returns it with its decks, models
+    /// and configuration, e.g. to check in a test what Anki will import
+    ///
+    /// The collection is the same as in the `.apkg` written by [`Package::write_to_file`], in the
+    /// format of Anki's schema 11, but it isn't zipped and the media files aren't read.
+    ///
+    /// Example:
+    /// ```rust
+    /// use genanki_rs::{basic_model, Deck, Note, Package};
+    ///
+    /// # fn main() -> Result<(), genanki_rs::Error> {
+    /// let mut deck = Deck::new(1234, "Example deck", "");
+    /// deck.add_note(Note::new(basic_model(), vec!["What is the capital of France?", "Paris"])?);
+    /// let collection = Package::new(vec![deck], vec![])?.build_collection()?;
+    ///
+    /// let model = &collection.models[basic_model().id.to_string()];
+    /// assert_eq!(model["flds"][0]["name"], "Front");
+    /// assert_eq!((collection.notes, collection.cards), (1, 1));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Returns `Err` if the package is invalid, like [`Package::write_to_file`]
+    pub fn build_collection(&mut self) -> Result<CollectionDump, Error> {
+        let mut callback = |_| {};
+        let mut progress = ProgressReporter::new(&mut callback);
+        let result = self
+            .write_notes(Cursor::new(vec![]), None, false, &mut progress)
+            .and_then(PackageWriter::finish_to_collection);
+        progress.finish();
+        result
     }
 
     /// Writes the package to a file using a timestamp
     ///
+    /// Everything that would otherwise depend on the current time, like the ids of notes and
+    /// cards and the modification times in the zip file, is derived from `timestamp`, so writing
+    /// the same package with the same timestamp gives the same bytes. `timestamp` is a
+    /// [`Timestamp`] or unix seconds as `f64`, see [`Package::with_timestamp`] to set it for all
+    /// writes.
+    ///
     /// Returns `Err` if the `file` cannot be created
     pub fn write_to_file_timestamp<P: AsRef<Path>>(
         &mut self,
         file: P,
-        timestamp: f64,
-    ) -> Result<(), Error> {
-        self.write_to_file_maybe_timestamp(file, Some(timestamp))
+        timestamp: impl Into<Timestamp>,
+    ) -> Result<WriteSummary, Error> {
+        let timestamp = timestamp.into();
+        self.write_file(file.as_ref(), |package, writer| {
+            package.write_maybe_timestamp(writer, Some(timestamp), &mut |_| {})
+        })
+    }
+
+    /// Writes the package to `writer` without blocking the async runtime and returns what was
+    /// written
+    ///
+    /// The package is written to memory on a thread for blocking tasks of tokio, and then to
+    /// `writer`, so large packages are better written with [`Package::write_to_file_async`].
+    /// The thread writes a copy of the package, so if the returned future is dropped before it
+    /// completes, e.g. by a timeout, the package stays as it was and can be written again.
+    ///
+    /// Example:
+    /// ```rust,no_run
+    /// use genanki_rs::{basic_model, Deck, Error, Note, Package};
+    ///
+    /// // e.g. an axum handler that returns the package as the body of the response
+    /// async fn download_deck(word: String) -> Result<Vec<u8>, Error> {
+    ///     let mut deck = Deck::new(1234, "Example deck", "");
+    ///     deck.add_note(Note::new(basic_model(), vec![&word, "a word"])?);
+    ///     let mut package = Package::new(vec![deck], vec![])?;
+    ///     let mut body = vec![];
+    ///     package.write_async(&mut body).await?;
+    ///     Ok(body)
+    /// }
+    ///
+    /// #[tokio::main(flavor = "current_thread")]
+    /// async fn main() -> Result<(), Error> {
+    ///     let body = download_deck("Hund".to_string()).await?;
+    ///     println!("{} bytes", body.len());
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// Returns `Err` if writing to `writer` fails
+    #[cfg(feature = "async")]
+    pub async fn write_async<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: W,
+    ) -> Result<WriteSummary, Error> {
+        self.write_async_maybe_timestamp(writer, None).await
     }
 
-    fn write_to_file_maybe_timestamp<P: AsRef<Path>>(
+    /// Writes the package to a file without blocking the async runtime and returns what was
+    /// written
+    ///
+    /// The package is written on a thread for blocking tasks of tokio like
+    /// [`Package::write_to_file`]. If the returned future is dropped, the package is still written
+    /// to `file` in the background, but with [`Package::atomic_write`] `file` never contains only
+    /// a part of the package. Like with [`Package::write_async`], the package stays as it was.
+    ///
+    /// Returns `Err` if the `file` cannot be created
+    #[cfg(feature = "async")]
+    pub async fn write_to_file_async<P: AsRef<Path>>(
         &mut self,
         file: P,
-        timestamp: Option<f64>,
-    ) -> Result<(), Error> {
-        let file = File::create(file)?;
-        let db_file = NamedTempFile::new()?.into_temp_path();
+    ) -> Result<WriteSummary, Error> {
+        self.write_to_file_async_maybe_timestamp(file.as_ref(), None)
+            .await
+    }
 
-        let mut conn = Connection::open(&db_file).map_err(database_error)?;
-        let transaction = conn.transaction().map_err(database_error)?;
+    #[cfg(feature = "async")]
+    async fn write_async_maybe_timestamp<W: AsyncWrite + Unpin>(
+        &mut self,
+        mut writer: W,
+        timestamp: Option<Timestamp>,
+    ) -> Result<WriteSummary, Error> {
+        let (bytes, summary) = self
+            .spawn_blocking(move |package| {
+                let mut cursor = Cursor::new(Vec::new());
+                let summary = package.write_maybe_timestamp(&mut cursor, timestamp, &mut |_| {})?;
+                Ok((cursor.into_inner(), summary))
+            })
+            .await?;
+        writer.write_all(&bytes).await?;
+        writer.flush().await?;
+        Ok(summary)
+    }
 
-        let timestamp = if let Some(timestamp) = timestamp {
-            timestamp
-        } else {
-            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64()
+    #[cfg(feature = "async")]
+    async fn write_to_file_async_maybe_timestamp(
+        &mut self,
+        file: &Path,
+        timestamp: Option<Timestamp>,
+    ) -> Result<WriteSummary, Error> {
+        let file = file.to_path_buf();
+        self.spawn_blocking(move |package| {
+            package.write_file(&file, |package, writer| {
+                package.write_maybe_timestamp(writer, timestamp, &mut |_| {})
+            })
+        })
+        .await
+    }
+
+    /// Writes the package to `file` with `write`, atomically unless
+    /// [`Package::atomic_write`] is `false`
+    fn write_file<T>(
+        &mut self,
+        file: &Path,
+        write: impl FnOnce(&mut Self, &mut File) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if !self.atomic_write {
+            return write(self, &mut File::create(file)?);
+        }
+        // The temporary file is removed when it is dropped before it is persisted
+        let mut temp_file = temp_file_next_to(file)?;
+        let result = write(self, temp_file.as_file_mut())?;
+        temp_file.persist(file).map_err(|e| e.error)?;
+        Ok(result)
+    }
+
+    /// Runs `f` with a copy of the package on a thread for blocking tasks and replaces the
+    /// package with the copy once `f` returns, so the package is unchanged if the task is
+    /// cancelled
+    #[cfg(feature = "async")]
+    async fn spawn_blocking<T: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut Package) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let mut package = self.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let result = f(&mut package);
+            (package, result)
+        });
+        let (package, result) = match task.await {
+            Ok(done) => done,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => return Err(std::io::Error::other(e).into()),
         };
+        *self = package;
+        result
+    }
 
-        self.write_to_db(&transaction, timestamp)?;
-        transaction.commit().map_err(database_error)?;
-        conn.close().expect("Should always close");
+    fn write_maybe_timestamp<W: Write + Seek>(
+        &mut self,
+        writer: W,
+        timestamp: Option<Timestamp>,
+        callback: &mut dyn FnMut(Progress),
+    ) -> Result<WriteSummary, Error> {
+        let mut progress = ProgressReporter::new(callback);
+        let result = self.write_with_progress(writer, timestamp, false, &mut progress);
+        progress.finish();
+        result
+    }
 
-        let mut outzip = ZipWriter::new(file);
-        outzip
-            .start_file("collection.anki2", FileOptions::default())
-            .map_err(zip_error)?;
-        outzip.write_all(&read_file_bytes(db_file)?)?;
+    fn write_with_progress<W: Write + Seek>(
+        &mut self,
+        writer: W,
+        timestamp: Option<Timestamp>,
+        collection_package: bool,
+        progress: &mut ProgressReporter,
+    ) -> Result<WriteSummary, Error> {
+        let package_writer = self.write_notes(writer, timestamp, collection_package, progress)?;
+        let (_, summary) = package_writer.finish_with_media(&self.media, progress)?;
+        Ok(summary)
+    }
 
-        let media_file_idx_to_path = self
-            .media_files
-            .iter()
-            .enumerate()
-            .collect::<HashMap<usize, &PathBuf>>();
-        let media_map = media_file_idx_to_path
-            .clone()
-            .into_iter()
-            .map(|(id, path)| {
-                (
-                    id.to_string(),
-                    path.file_name()
-                        .expect("Should always have a filename")
-                        .to_str()
-                        .expect("should always have string"),
+    /// Validates the package and writes its decks and notes to a new [`PackageWriter`]
+    fn write_notes<W: Write + Seek>(
+        &mut self,
+        writer: W,
+        timestamp: Option<Timestamp>,
+        collection_package: bool,
+        progress: &mut ProgressReporter,
+    ) -> Result<PackageWriter<W>, Error> {
+        if !self.unchecked {
+            self.validate()?;
+        }
+        let package_writer = PackageWriter::new_maybe_timestamp(
+            writer,
+            vec![],
+            &self.decks,
+            timestamp.or(self.timestamp),
+            self.use_tempfile,
+        )?
+        .batch_size(0);
+        let package_writer = match &self.compression {
+            Some(options) => package_writer.compression(options.clone()),
+            None => package_writer,
+        };
+        let mut collection_conf = self.collection_conf.clone();
+        if let Some(deck_id) = self.selected_deck {
+            let conf = collection_conf.unwrap_or_default();
+            collection_conf = Some(conf.cur_deck(deck_id).active_decks(&[deck_id]));
+        }
+        let package_writer = match collection_conf {
+            Some(conf) => package_writer.collection_conf(conf),
+            None => package_writer,
+        };
+        #[cfg(feature = "nfc")]
+        let package_writer = package_writer.normalize_unicode(self.normalize_unicode);
+        #[cfg(feature = "modern-format")]
+        let package_writer = package_writer.format(self.format);
+        let package_writer = package_writer
+            .scheduler_version(self.scheduler_version)
+            .collection_package(collection_package);
+        let mut package_writer = package_writer;
+        let total = self.decks.iter().map(Deck::note_count).sum();
+        progress.report(Progress::WritingNotes { done: 0, total });
+        let mut done = 0;
+        for deck in &self.decks {
+            for note in deck.notes() {
+                // Like `PackageWriter::add_note`, only the written copy of a note is normalised
+                #[cfg(feature = "nfc")]
+                if self.normalize_unicode {
+                    package_writer.add_note(deck.id(), note.clone())?;
+                } else {
+                    package_writer.write_note(deck.id(), note)?;
+                }
+                #[cfg(not(feature = "nfc"))]
+                package_writer.write_note(deck.id(), note)?;
+                done += 1;
+                if done % BATCH_SIZE == 0 || done == total {
+                    progress.report(Progress::WritingNotes { done, total });
+                }
+            }
+        }
+        Ok(package_writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        basic_and_reversed_card_model, basic_model, CardSchedule, Field, MediaIssueKind, Model,
+        NewSpread, Note, SortType, Template,
+    };
+    use rusqlite::Connection;
+    use sha2::{Digest, Sha256};
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+    use zip::CompressionMethod;
+
+    #[cfg(feature = "nfc")]
+    const NFD: &str = "u\u{308}ber";
+    #[cfg(feature = "nfc")]
+    const NFC: &str = "\u{fc}ber";
+
+    fn open_collection(archive: &mut zip::ZipArchive<File>, dir: &TempDir) -> Connection {
+        let db_path = dir.path().join("collection.anki2");
+        std::io::copy(
+            &mut archive.by_name("collection.anki2").unwrap(),
+            &mut File::create(&db_path).unwrap(),
+        )
+        .unwrap();
+        Connection::open(&db_path).unwrap()
+    }
+
+    #[test]
+    fn reproducible_output() {
+        let dir = TempDir::new().unwrap();
+        let media_paths: Vec<PathBuf> = (0..12)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.mp3", i));
+                File::create(&path).unwrap().write_all(&[i]).unwrap();
+                path
+            })
+            .collect();
+        let write = || {
+            let models = [basic_and_reversed_card_model(), crate::basic_model()];
+            let decks: Vec<Deck> = (0..5)
+                .map(|i| {
+                    let mut deck = Deck::new(1234 + i, format!("Deck {}", i), "");
+                    for model in &models {
+                        deck.add_note(Note::new(model.clone(), vec!["a", "b"]).unwrap());
+                    }
+                    deck
+                })
+                .collect();
+            let media = media_paths.iter().map(|p| p.to_str().unwrap()).collect();
+            let mut package = Package::new(decks, media).unwrap();
+            let mut cursor = Cursor::new(Vec::new());
+            package
+                .write_maybe_timestamp(
+                    &mut cursor,
+                    Some(Timestamp::from_millis(1700000000500)),
+                    &mut |_| {},
+                )
+                .unwrap();
+            cursor.into_inner()
+        };
+        assert!(write() == write());
+    }
+
+    #[test]
+    fn with_timestamp() {
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        let mut deck = Deck::new(1234, "Deck", "");
+        for front in ["a", "b", "c"] {
+            deck.add_note(Note::new(basic_and_reversed_card_model(), vec![front, "b"]).unwrap());
+        }
+        // All notes of the package are written in the same millisecond
+        let timestamp = Timestamp::from_millis(1700000000123);
+        let mut package = Package::new(vec![deck], vec![])
+            .unwrap()
+            .with_timestamp(timestamp);
+        package.write_to_file(&out_path).unwrap();
+        let first = std::fs::read(&out_path).unwrap();
+        package.write_to_file(&out_path).unwrap();
+        assert_eq!(std::fs::read(&out_path).unwrap(), first);
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let conn = open_collection(&mut archive, &dir);
+        let ids = |query: &str| -> Vec<i64> {
+            let mut statement = conn.prepare(query).unwrap();
+            let rows = statement.query_map([], |row| row.get(0)).unwrap();
+            rows.map(Result::unwrap).collect()
+        };
+        let note_ids = ids("SELECT id FROM notes ORDER BY id");
+        let card_ids = ids("SELECT id FROM cards ORDER BY id");
+        assert_eq!(note_ids[0], 1700000000123);
+        assert_eq!(note_ids.len(), 3);
+        assert_eq!(card_ids.len(), 6);
+        let mut all: Vec<i64> = note_ids.iter().chain(&card_ids).copied().collect();
+        all.sort_unstable();
+        all.dedup();
+        assert_eq!(all.len(), 9);
+        let modified = ids("SELECT DISTINCT mod FROM notes");
+        assert_eq!(modified, vec![1700000000]);
+    }
+
+    #[test]
+    fn build_collection() {
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        let mut deck = Deck::new(1234, "Deck", "");
+        deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["a", "b"]).unwrap());
+        for use_tempfile in [false, true] {
+            let mut package = Package::new(vec![deck.clone()], vec![])
+                .unwrap()
+                .with_timestamp(Timestamp::from_secs(1700000000))
+                .scheduler_version(SchedulerVersion::V2)
+                .use_tempfile(use_tempfile);
+            let collection = package.build_collection().unwrap();
+            assert_eq!((collection.notes, collection.cards), (1, 2));
+            assert_eq!(collection.decks["1234"]["name"], "Deck");
+            assert_eq!(collection.conf["schedVer"], 2);
+            let model_id = basic_and_reversed_card_model().id.to_string();
+            assert_eq!(collection.models[&model_id]["tmpls"][1]["name"], "Card 2");
+
+            package.write_to_file(&out_path).unwrap();
+            let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+            let mut written = vec![];
+            archive
+                .by_name("collection.anki2")
+                .unwrap()
+                .read_to_end(&mut written)
+                .unwrap();
+            assert_eq!(collection.bytes, written);
+        }
+
+        let mut missing_media =
+            Package::with_media_paths(vec![deck], vec![dir.path().join("missing.mp3")])
+                .unwrap()
+                .unchecked(true);
+        assert_eq!(missing_media.build_collection().unwrap().notes, 1);
+        assert!(missing_media.write_to_file(&out_path).is_err());
+    }
+
+    #[test]
+    fn default_tags() {
+        let note = Note::new(basic_model(), vec!["der Hund", "the dog"])
+            .unwrap()
+            .tags(["Vocab", "animals"])
+            .unwrap();
+        let mut n3 = Deck::new(1234, "JLPT N3", "")
+            .default_tags(["jlpt::n3", "vocab"])
+            .unwrap();
+        n3.add_note(note.clone());
+        let mut other = Deck::new(5678, "Other", "");
+        other.add_note(note.guid("other"));
+        assert_eq!(n3.notes().next().unwrap().get_tags(), &["Vocab", "animals"]);
+        assert!(matches!(
+            Deck::new(1, "A", "").default_tags(["jlpt n3"]),
+            Err(Error::TagContainsWhitespace)
+        ));
+
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        let mut package = Package::new(vec![n3.clone(), other], vec![]).unwrap();
+        package.write_to_file(&out_path).unwrap();
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let conn = open_collection(&mut archive, &dir);
+        let mut statement = conn
+            .prepare("SELECT DISTINCT tags FROM notes JOIN cards ON nid = notes.id ORDER BY did")
+            .unwrap();
+        let tags: Vec<String> = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(tags, vec![" Vocab animals jlpt::n3 ", " Vocab animals "]);
+
+        let mut merged = Deck::new(9012, "Merged", "");
+        merged.extend(n3, crate::MergePolicy::Error).unwrap();
+        assert_eq!(
+            merged.notes().next().unwrap().get_tags(),
+            &["Vocab", "animals", "jlpt::n3"]
+        );
+    }
+
+    #[test]
+    fn unique_ids() {
+        const NOTES: i64 = 50_000;
+        let mut german = Deck::new(1234, "German", "");
+        let mut french = Deck::new(5678, "French", "");
+        for i in 0..NOTES {
+            let deck = if i % 2 == 0 { &mut german } else { &mut french };
+            let note = Note::new(basic_and_reversed_card_model(), vec![&i.to_string(), "a"]);
+            deck.add_note(note.unwrap());
+        }
+        let dir = TempDir::new().unwrap();
+        let mut package = Package::new(vec![german, french], vec![]).unwrap();
+        for (millis, name) in [
+            (1700000000000, "first.apkg"),
+            (1800000000000, "second.apkg"),
+        ] {
+            let out_path = dir.path().join(name);
+            package
+                .write_to_file_timestamp(&out_path, Timestamp::from_millis(millis))
+                .unwrap();
+            let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+            let conn = open_collection(&mut archive, &dir);
+            let count =
+                |query: &str| -> i64 { conn.query_row(query, [], |row| row.get(0)).unwrap() };
+            assert_eq!(count("SELECT COUNT(DISTINCT id) FROM notes"), NOTES);
+            assert_eq!(count("SELECT COUNT(*) FROM cards"), 2 * NOTES);
+            assert_eq!(
+                count("SELECT COUNT(*) FROM cards WHERE id IN (SELECT id FROM notes)"),
+                0
+            );
+            // The ids of each package start at its timestamp
+            assert_eq!(count("SELECT MIN(id) FROM notes"), millis);
+        }
+    }
+
+    #[test]
+    fn same_database_as_before_streaming() {
+        let mut nested = Deck::new(1234, "A::B", "Description");
+        for i in 0..1200 {
+            nested.add_note(Note::new(crate::basic_model(), vec![&i.to_string(), "a"]).unwrap());
+        }
+        let mut other = Deck::new(99, "Other", "");
+        for i in 0..5 {
+            let note = Note::new(basic_and_reversed_card_model(), vec![&i.to_string(), "b"]);
+            other.add_note(note.unwrap());
+        }
+        for use_tempfile in [false, true] {
+            let mut package = Package::new(vec![nested.clone(), other.clone()], vec![])
+                .unwrap()
+                .use_tempfile(use_tempfile);
+            let mut cursor = Cursor::new(Vec::new());
+            package
+                .write_maybe_timestamp(
+                    &mut cursor,
+                    Some(Timestamp::from_secs(1700000000)),
+                    &mut |_| {},
                 )
+                .unwrap();
+
+            let mut archive = zip::ZipArchive::new(cursor).unwrap();
+            let mut db = vec![];
+            archive
+                .by_name("collection.anki2")
+                .unwrap()
+                .read_to_end(&mut db)
+                .unwrap();
+            // The SHA-256 of the database written before packages were written with a
+            // `PackageWriter`, with the SQLite version that rusqlite bundles and the id of the
+            // parent deck `A` from the 53-bit `Deck::id_from_name`
+            assert_eq!(
+                format!("{:x}", Sha256::digest(&db)),
+                "608b1c943c153ccd06631fba745d6265c30c1f863b8db5bb1fc8f722136f5760"
+            );
+        }
+    }
+
+    #[test]
+    fn without_temp_dir() {
+        const MISSING_DIR: &str = "/nonexistent/genanki-rs";
+        if std::env::var_os("TMPDIR").is_some_and(|dir| dir == MISSING_DIR) {
+            let mut deck = Deck::new(1234, "Example deck", "");
+            deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["a", "b"]).unwrap());
+            let mut package = Package::new(vec![deck], vec![]).unwrap();
+            package.write_to_bytes().unwrap();
+            assert!(package.use_tempfile(true).write_to_bytes().is_err());
+            return;
+        }
+        // The temporary directory is read from the environment, so the test runs in a new process
+        // to not affect the other tests
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "package::tests::without_temp_dir"])
+            .env("TMPDIR", MISSING_DIR)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+    }
+
+    fn progress_package() -> Package {
+        let mut first = Deck::new(1234, "First", "");
+        first.add_note(Note::new(basic_and_reversed_card_model(), vec!["a", "b"]).unwrap());
+        let mut second = Deck::new(5678, "Second", "");
+        second.add_note(Note::new(crate::basic_model(), vec!["c", "d"]).unwrap());
+        second.add_note(Note::new(crate::basic_model(), vec!["e", "f"]).unwrap());
+        let mut package = Package::new(vec![first, second], vec![]).unwrap();
+        package.add_media_bytes("a.txt", "a").unwrap();
+        package.add_media_bytes("b.txt", "b").unwrap();
+        package
+    }
+
+    #[test]
+    fn write_progress() {
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        let mut events = vec![];
+        let summary = progress_package()
+            .write_to_file_with_progress(&out_path, |progress| events.push(progress))
+            .unwrap();
+
+        let media = |file: &str, index| Progress::CompressingMedia {
+            file: file.to_string(),
+            index,
+            total: 2,
+        };
+        assert_eq!(
+            events,
+            vec![
+                Progress::WritingNotes { done: 0, total: 3 },
+                Progress::WritingNotes { done: 3, total: 3 },
+                media("a.txt", 0),
+                media("b.txt", 1),
+                Progress::Finalizing,
+            ]
+        );
+        let expected = WriteSummary {
+            bytes: std::fs::metadata(&out_path).unwrap().len(),
+            notes: 3,
+            cards: 4,
+            models: 2,
+            media_files: 2,
+        };
+        assert_eq!(summary, expected);
+    }
+
+    #[test]
+    fn panicking_progress_callback() {
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        let mut calls = 0;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            progress_package().write_to_file_with_progress(&out_path, |_| {
+                calls += 1;
+                panic!("progress bar closed");
             })
-            .collect::<HashMap<String, &str>>();
-        let media_json = serde_json::to_string(&media_map).map_err(json_error)?;
-        outzip
-            .start_file("media", FileOptions::default())
-            .map_err(zip_error)?;
-        outzip.write_all(media_json.as_bytes())?;
-
-        for (idx, &path) in &media_file_idx_to_path {
-            outzip
-                .start_file(idx.to_string(), FileOptions::default())
-                .map_err(zip_error)?;
-            outzip.write_all(&read_file_bytes(path)?)?;
-        }
-        outzip.finish().map_err(zip_error)?;
-        Ok(())
+        }));
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        assert_eq!(archive.len(), 4);
+        let conn = open_collection(&mut archive, &dir);
+        let notes: usize = conn
+            .query_row("SELECT COUNT(*) FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(notes, 3);
     }
 
-    fn write_to_db(&mut self, transaction: &Transaction, timestamp: f64) -> Result<(), Error> {
-        let mut id_gen = ((timestamp * 1000.0) as usize)..;
-        transaction
-            .execute_batch(APKG_SCHEMA)
-            .map_err(database_error)?;
-        transaction
-            .execute_batch(APKG_COL)
-            .map_err(database_error)?;
-        for deck in &mut self.decks {
-            deck.write_to_db(transaction, timestamp, &mut id_gen)?;
+    #[test]
+    fn write_to_cursor() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["a", "b"]).unwrap());
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        let mut cursor = Cursor::new(Vec::new());
+        package.write(&mut cursor).unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(cursor.into_inner())).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["collection.anki2", "media"]);
+        let mut media = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media)
+            .unwrap();
+        assert_eq!(media, "{}");
+
+        let bytes = package.write_to_bytes().unwrap();
+        assert!(zip::ZipArchive::new(Cursor::new(bytes))
+            .unwrap()
+            .by_name("collection.anki2")
+            .is_ok());
+    }
+
+    #[test]
+    fn media_from_paths_and_bytes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sound.mp3");
+        File::create(&path).unwrap().write_all(b"sound").unwrap();
+        let mut package = Package::new(vec![], vec![path.to_str().unwrap()])
+            .unwrap()
+            .unchecked(true);
+        package
+            .add_media_bytes("image.png", b"image".to_vec())
+            .unwrap();
+        package
+            .add_media_reader("other.png", &b"other"[..])
+            .unwrap();
+        package.add_media_bytes("sound.mp3", "sound").unwrap();
+        match package.add_media_bytes("sound.mp3", "again") {
+            Err(Error::MediaNameCollision {
+                name,
+                first_source,
+                second_source,
+            }) => {
+                assert_eq!(name, "sound.mp3");
+                assert_eq!(first_source, Some(path.clone()));
+                assert_eq!(second_source, None);
+            }
+            _ => panic!("expected a media name collision"),
         }
-        Ok(())
+
+        let bytes = package.write_to_bytes().unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut read = |name: &str| {
+            let mut content = String::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        };
+        assert_eq!(
+            read("media"),
+            r#"{"0":"sound.mp3","1":"image.png","2":"other.png"}"#
+        );
+        assert_eq!(read("0"), "sound");
+        assert_eq!(read("1"), "image");
+        assert_eq!(read("2"), "other");
+    }
+
+    #[test]
+    fn collect_media() {
+        let dir = TempDir::new().unwrap();
+        for name in ["my dog.jpg", "bark.mp3"] {
+            File::create(dir.path().join(name))
+                .unwrap()
+                .write_all(name.as_bytes())
+                .unwrap();
+        }
+        let mut deck = Deck::new(1234, "Example deck", "");
+        for fields in [
+            vec![r#"<img src="my%20dog.jpg">"#, "[sound:bark.mp3]"],
+            vec![r#"<img src="cat.jpg">"#, "<img src='my dog.jpg'>"],
+        ] {
+            deck.add_note(Note::new(crate::basic_model(), fields).unwrap());
+        }
+
+        let mut package = Package::new(vec![deck.clone()], vec![]).unwrap();
+        match package.collect_media_from_dir_strict(dir.path()) {
+            Err(Error::MissingMediaFiles { filenames }) => assert_eq!(filenames, vec!["cat.jpg"]),
+            _ => panic!("expected missing media files"),
+        }
+        assert!(package.media.len() == 0);
+
+        let report = package.collect_media_from_dir(dir.path()).unwrap();
+        assert_eq!(
+            report,
+            MediaReport {
+                found: vec!["my dog.jpg".to_string(), "bark.mp3".to_string()],
+                missing: vec!["cat.jpg".to_string()],
+            }
+        );
+        let mut archive =
+            zip::ZipArchive::new(Cursor::new(package.write_to_bytes().unwrap())).unwrap();
+        let mut media = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media)
+            .unwrap();
+        assert_eq!(media, r#"{"0":"my dog.jpg","1":"bark.mp3"}"#);
+
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        package.add_media_bytes("cat.jpg", "cat").unwrap();
+        let report = package.collect_media_from_dir_strict(dir.path()).unwrap();
+        assert_eq!(report.found, vec!["my dog.jpg", "bark.mp3"]);
+        assert!(report.missing.is_empty());
+    }
+
+    #[test]
+    fn check_and_prune_media() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(
+            Note::new(
+                crate::basic_model(),
+                vec![r#"<img src="dog.jpg">"#, "[sound:bark.mp3][sound:meow.mp3]"],
+            )
+            .unwrap(),
+        );
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        for name in [
+            "unused.png",
+            "dog.jpg",
+            "_background.png",
+            "bark.mp3",
+            "_font.ttf",
+        ] {
+            package.add_media_bytes(name, name).unwrap();
+        }
+        assert_eq!(
+            package.check_media(),
+            MediaCheck {
+                unreferenced: vec!["unused.png".to_string()],
+                missing: vec!["meow.mp3".to_string()],
+            }
+        );
+
+        assert_eq!(package.prune_unreferenced_media(), vec!["unused.png"]);
+        let names: Vec<&str> = package.media.iter().map(MediaFile::name).collect();
+        assert_eq!(
+            names,
+            vec!["dog.jpg", "_background.png", "bark.mp3", "_font.ttf"]
+        );
+        assert!(package.check_media().unreferenced.is_empty());
+    }
+
+    #[test]
+    fn validate_media() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("empty.ogg");
+        File::create(&path).unwrap();
+        let mut package = Package::new(vec![], vec![path.to_str().unwrap()]).unwrap();
+        package
+            .add_media_bytes("dog.gif", b"GIF89a\x01\0\x01\0".to_vec())
+            .unwrap();
+        package.add_media_bytes("dog.exe", "MZ").unwrap();
+        let issues = vec![
+            MediaIssue {
+                filename: "empty.ogg".to_string(),
+                kind: MediaIssueKind::Empty,
+            },
+            MediaIssue {
+                filename: "dog.exe".to_string(),
+                kind: MediaIssueKind::UnsupportedExtension,
+            },
+        ];
+        assert_eq!(package.validate_media(Strictness::Lenient).unwrap(), issues);
+        match package.validate_media(Strictness::Strict) {
+            Err(Error::InvalidMedia { issues: errors }) => assert_eq!(errors, issues),
+            _ => panic!("expected invalid media"),
+        }
+
+        let mut package = Package::new(vec![], vec![]).unwrap();
+        package.add_media_bytes("dog.gif", "GIF87a").unwrap();
+        assert!(package
+            .validate_media(Strictness::Strict)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn compression() {
+        let write = |package: &mut Package| {
+            package.add_media_bytes("sound.MP3", vec![0; 1000]).unwrap();
+            package.add_media_bytes("notes.txt", vec![0; 1000]).unwrap();
+            let bytes = package.write_to_bytes().unwrap();
+            let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+            (0..archive.len())
+                .map(|i| {
+                    let file = archive.by_index(i).unwrap();
+                    (file.name().to_string(), file.compression())
+                })
+                .collect::<Vec<_>>()
+        };
+        let deflated = CompressionMethod::Deflated;
+        assert!(
+            write(&mut Package::new(vec![], vec![]).unwrap().unchecked(true))
+                .iter()
+                .all(|(_, method)| *method == deflated)
+        );
+
+        let options = CompressionOptions::new().database_level(9);
+        let mut package = Package::new(vec![], vec![])
+            .unwrap()
+            .compression(options)
+            .unchecked(true);
+        let names = |n: &str| n.to_string();
+        assert_eq!(
+            write(&mut package),
+            vec![
+                (names("0"), CompressionMethod::Stored),
+                (names("1"), deflated),
+                (names("collection.anki2"), deflated),
+                (names("media"), deflated),
+            ]
+        );
+    }
+
+    #[cfg(feature = "modern-format")]
+    #[test]
+    fn latest_format() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sound.mp3");
+        File::create(&path).unwrap().write_all(b"sound").unwrap();
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(Note::new(basic_and_reversed_card_model(), vec!["a", "b"]).unwrap());
+        let mut package = Package::new(vec![deck], vec![path.to_str().unwrap()])
+            .unwrap()
+            .format(PackageFormat::Latest);
+        package.add_media_bytes("image.svg", "<svg/>").unwrap();
+        let mut cursor = Cursor::new(Vec::new());
+        package
+            .write_maybe_timestamp(
+                &mut cursor,
+                Some(Timestamp::from_secs(1700000000)),
+                &mut |_| {},
+            )
+            .unwrap();
+
+        let mut archive = zip::ZipArchive::new(cursor).unwrap();
+        let mut read = |name: &str, zstd_compressed: bool| {
+            let mut file = archive.by_name(name).unwrap();
+            let method = file.compression();
+            assert_eq!(
+                method == CompressionMethod::Stored,
+                zstd_compressed,
+                "{}",
+                name
+            );
+            let mut bytes = vec![];
+            file.read_to_end(&mut bytes).unwrap();
+            if zstd_compressed {
+                zstd::decode_all(&bytes[..]).unwrap()
+            } else {
+                bytes
+            }
+        };
+        assert_eq!(read("0", true), b"sound");
+        assert_eq!(read("1", true), b"<svg/>");
+        let media = crate::modern_format::media_entries(
+            package.media.iter().map(|f| (f.name().to_string(), f)),
+        );
+        assert_eq!(read("media", true), media.unwrap());
+        let collection = read("collection.anki21b", true);
+        let stub = read("collection.anki2", false);
+        let mut meta = vec![];
+        archive
+            .by_name("meta")
+            .unwrap()
+            .read_to_end(&mut meta)
+            .unwrap();
+        assert_eq!(meta, vec![0x08, 0x03]);
+
+        let fields = |db: &[u8]| -> Vec<String> {
+            let db_path = dir.path().join("collection");
+            std::fs::write(&db_path, db).unwrap();
+            let conn = Connection::open(&db_path).unwrap();
+            let mut statement = conn.prepare("SELECT flds FROM notes").unwrap();
+            let fields = statement.query_map([], |row| row.get(0)).unwrap();
+            fields.collect::<Result<_, _>>().unwrap()
+        };
+        assert_eq!(fields(&collection), vec!["a\u{1f}b"]);
+        let stub_fields = fields(&stub);
+        assert_eq!(stub_fields.len(), 1);
+        assert!(stub_fields[0].starts_with("Please update to the latest Anki version"));
+    }
+
+    #[test]
+    fn many_media_files() {
+        let mut package = Package::new(vec![], vec![]).unwrap().unchecked(true);
+        for i in 0..70_000 {
+            package
+                .add_media_bytes(&format!("{}.txt", i), i.to_string())
+                .unwrap();
+        }
+        let bytes = package.write_to_bytes().unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 70_002);
+        let mut media = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media)
+            .unwrap();
+        let media: HashMap<String, String> = serde_json::from_str(&media).unwrap();
+        assert_eq!(media.len(), 70_000);
+        assert_eq!(media["69999"], "69999.txt");
+        let mut content = String::new();
+        archive
+            .by_name("69999")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "69999");
+    }
+
+    #[test]
+    fn duplicate_media_paths() {
+        let dir = TempDir::new().unwrap();
+        let write = |lesson: &str, content: &str| {
+            std::fs::create_dir_all(dir.path().join(lesson)).unwrap();
+            let path = dir.path().join(lesson).join("audio.mp3");
+            File::create(&path)
+                .unwrap()
+                .write_all(content.as_bytes())
+                .unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let (first, second, third) = (write("1", "bark"), write("2", "bark"), write("3", "meow"));
+
+        let package = Package::new(vec![], vec![&first, &second]).unwrap();
+        assert_eq!(package.media.len(), 1);
+
+        match Package::new(vec![], vec![&first, &second, &third]) {
+            Err(Error::MediaNameCollision {
+                name,
+                first_source,
+                second_source,
+            }) => {
+                assert_eq!(name, "audio.mp3");
+                assert_eq!(first_source, Some(PathBuf::from(&first)));
+                assert_eq!(second_source, Some(PathBuf::from(&third)));
+            }
+            _ => panic!("expected a media name collision"),
+        }
+        assert!(matches!(
+            Package::new(vec![], vec!["images/.."]),
+            Err(Error::InvalidMediaPath { .. })
+        ));
+    }
+
+    #[test]
+    fn media_paths() {
+        let dir = TempDir::new().unwrap();
+        let nested = dir.path().join("lesson1").join("audio");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(nested.join("bark.mp3"), "bark").unwrap();
+        let mut deck = Deck::new(1234, "Deck", "");
+        deck.add_note(Note::new(basic_model(), vec!["[sound:bark.mp3]", "bark"]).unwrap());
+        let mut package =
+            Package::with_media_paths(vec![deck], vec![nested.join("bark.mp3")]).unwrap();
+        let mut archive =
+            zip::ZipArchive::new(Cursor::new(package.write_to_bytes().unwrap())).unwrap();
+        let mut media = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media)
+            .unwrap();
+        assert_eq!(media, r#"{"0":"bark.mp3"}"#);
+
+        assert!(matches!(
+            package.add_media_bytes("audio/bark.mp3", "bark"),
+            Err(Error::InvalidMediaFilename { .. })
+        ));
+        assert!(matches!(
+            package.add_media_bytes("bark\0.mp3", "bark"),
+            Err(Error::InvalidMediaFilename { .. })
+        ));
+        #[cfg(unix)]
+        {
+            assert!(matches!(
+                package.add_media_file(r"audio\bark.mp3"),
+                Err(Error::InvalidMediaFilename { .. })
+            ));
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+            let path = dir.path().join(OsStr::from_bytes(b"bark\xff.mp3"));
+            assert!(matches!(
+                package.add_media_file(&path),
+                Err(Error::NonUtf8MediaFilename { path: p }) if p == path
+            ));
+        }
+    }
+
+    #[test]
+    fn auto_rename_media() {
+        let dir = TempDir::new().unwrap();
+        let lessons = ["lesson1", "lesson2"];
+        let mut deck = Deck::new(1234, "Example deck", "");
+        for (lesson, content) in lessons.iter().zip(["bark", "meow"]) {
+            std::fs::create_dir(dir.path().join(lesson)).unwrap();
+            let path = dir.path().join(lesson).join("audio.mp3");
+            File::create(&path)
+                .unwrap()
+                .write_all(content.as_bytes())
+                .unwrap();
+            let sound = format!("[sound:{}]", path.to_str().unwrap());
+            deck.add_note(Note::new(crate::basic_model(), vec![lesson, &sound]).unwrap());
+        }
+
+        let mut package = Package::new(vec![deck], vec![])
+            .unwrap()
+            .auto_rename_media(true);
+        for lesson in &lessons {
+            package
+                .add_media_file(dir.path().join(lesson).join("audio.mp3"))
+                .unwrap();
+        }
+        // The first 4 bytes of sha256("meow")
+        let renamed = "audio-404cdd7b.mp3";
+        let names: Vec<&str> = package.media.iter().map(MediaFile::name).collect();
+        assert_eq!(names, vec!["audio.mp3", renamed]);
+        let fields: Vec<String> = package.decks[0]
+            .notes()
+            .map(|note| note.fields()[1].clone())
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                "[sound:audio.mp3]".to_string(),
+                format!("[sound:{}]", renamed)
+            ]
+        );
+        assert!(package.check_media().missing.is_empty());
     }
-}
 
-fn read_file_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
-    let mut handle = File::open(path)?;
-    let mut data = Vec::new();
-    handle.read_to_end(&mut data)?;
-    Ok(data)
+    #[test]
+    fn parent_decks_are_added() {
+        let deck = Deck::new(1234, "A", "")
+            .subdeck("B", 1235)
+            .subdeck("C", 1236);
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        Package::new(vec![deck], vec![])
+            .unwrap()
+            .write_to_file(&out_path)
+            .unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let decks: String = open_collection(&mut archive, &dir)
+            .query_row("SELECT decks FROM col", [], |row| row.get(0))
+            .unwrap();
+        let decks: HashMap<i64, serde_json::Value> = serde_json::from_str(&decks).unwrap();
+        let mut names: Vec<(i64, &str)> = decks
+            .iter()
+            .map(|(&id, deck)| (id, deck["name"].as_str().unwrap()))
+            .collect();
+        names.sort_unstable_by_key(|&(_, name)| name);
+        assert_eq!(
+            names,
+            vec![
+                (Deck::id_from_name("A"), "A"),
+                (Deck::id_from_name("A::B"), "A::B"),
+                (1236, "A::B::C"),
+                (1, "Default"),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_deck_ids() {
+        let decks = vec![
+            Deck::new(1234, "A", ""),
+            Deck::new(1235, "B", ""),
+            Deck::new(1234, "C", ""),
+        ];
+        assert!(matches!(
+            Package::new(vec![Deck::new(1, "A", "")], vec![]),
+            Err(Error::DuplicateDeckId { id: 1, .. })
+        ));
+        assert!(matches!(
+            Package::new(decks, vec![]),
+            Err(Error::DuplicateDeckId { id: 1234, names }) if names == ("A".to_string(), "C".to_string())
+        ));
+        assert!(Package::new(
+            vec![Deck::new(1234, "A", ""), Deck::new(1234, "A", "")],
+            vec![]
+        )
+        .is_ok());
+
+        let parent_id = Deck::id_from_name("A");
+        let mut package = Package::new(
+            vec![Deck::new(parent_id, "B", ""), Deck::new(1235, "A::C", "")],
+            vec![],
+        )
+        .unwrap();
+        let dir = TempDir::new().unwrap();
+        assert!(matches!(
+            package.write_to_file(dir.path().join("output.apkg")),
+            Err(Error::DuplicateDeckId { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_deck_names() {
+        for name in &["::A", "A::", "A::::B", ""] {
+            assert!(
+                matches!(
+                    Package::new(vec![Deck::new(1234, name, "")], vec![]),
+                    Err(Error::InvalidDeckName { .. })
+                ),
+                "{}",
+                name
+            );
+        }
+        assert!(Package::new(vec![Deck::new(1234, "A::B", "")], vec![]).is_ok());
+    }
+
+    #[test]
+    fn scheduled_cards() {
+        let schedule = CardSchedule {
+            interval_days: 12,
+            ease_factor: 2350,
+            due_days_from_today: 3,
+            reps: 5,
+            lapses: 1,
+        };
+        let note = Note::new(basic_and_reversed_card_model(), vec!["der Hund", "the dog"])
+            .unwrap()
+            .configure_cards(|card| {
+                if card.ord() == 0 {
+                    card.set_schedule(Some(schedule)).unwrap();
+                }
+            });
+        let mut card = note.cards()[1].clone();
+        let low_ease = CardSchedule {
+            ease_factor: 1299,
+            ..schedule
+        };
+        assert!(matches!(
+            card.set_schedule(Some(low_ease)),
+            Err(Error::InvalidEaseFactor(1299))
+        ));
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(note);
+
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        // 100 days and one hour after the creation of the collection
+        let timestamp = (1411124400 + 100 * 86400 + 3600) as f64;
+        Package::new(vec![deck], vec![])
+            .unwrap()
+            .write_to_file_timestamp(&out_path, timestamp)
+            .unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let conn = open_collection(&mut archive, &dir);
+        let cards: Vec<[i64; 8]> = conn
+            .prepare(
+                "SELECT ord, type, queue, due, ivl, factor, reps, lapses FROM cards ORDER BY ord",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                let mut values = [0; 8];
+                for (i, value) in values.iter_mut().enumerate() {
+                    *value = row.get(i)?;
+                }
+                Ok(values)
+            })
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            cards,
+            vec![[0, 2, 2, 103, 12, 2350, 5, 1], [1, 0, 0, 0, 0, 0, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn shared_model() {
+        let model = basic_and_reversed_card_model();
+        let shared = std::sync::Arc::new(model.clone());
+        let rows = [["der Hund", "the dog"], ["die Katze", "the cat"]];
+        let write = |notes: Vec<Note>| {
+            let mut deck = Deck::new(1234, "German", "");
+            notes.into_iter().for_each(|note| deck.add_note(note));
+            Package::new(vec![deck], vec![])
+                .unwrap()
+                .with_timestamp(Timestamp::from_secs(1700000000))
+                .write_to_bytes()
+                .unwrap()
+        };
+        let shared_notes: Vec<Note> = rows
+            .iter()
+            .map(|row| Note::new(shared.clone(), row.to_vec()).unwrap())
+            .collect();
+        assert!(std::sync::Arc::ptr_eq(
+            shared_notes[0].model(),
+            shared_notes[1].model()
+        ));
+        let cloned_notes: Vec<Note> = rows
+            .iter()
+            .map(|row| Note::new(model.clone(), row.to_vec()).unwrap())
+            .collect();
+        assert_eq!(shared_notes, cloned_notes);
+        assert_eq!(write(shared_notes), write(cloned_notes));
+    }
+
+    #[test]
+    fn card_routing() {
+        let mut recognition = Deck::new(1234, "German::Recognition", "");
+        let recall = Deck::new(5678, "German::Recall", "");
+        let note = || Note::new(basic_and_reversed_card_model(), vec!["der Hund", "the dog"]);
+        recognition
+            .add_note_with_card_routing(note().unwrap(), &[1234, 5678])
+            .unwrap();
+        assert!(matches!(
+            recognition.add_note_with_card_routing(note().unwrap(), &[1234]),
+            Err(Error::CardRoutingMismatch {
+                cards: 2,
+                deck_ids: 1
+            })
+        ));
+
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        Package::new(vec![recognition.clone(), recall], vec![])
+            .unwrap()
+            .write_to_file(&out_path)
+            .unwrap();
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let conn = open_collection(&mut archive, &dir);
+        let notes: i64 = conn
+            .query_row("SELECT COUNT(*) FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(notes, 1);
+        let cards: Vec<(i64, i64)> = conn
+            .prepare("SELECT ord, did FROM cards ORDER BY ord")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(cards, vec![(0, 1234), (1, 5678)]);
+
+        // The routed decks and the deck overrides of templates have to be in the package
+        assert!(matches!(
+            Package::new(vec![recognition], vec![])
+                .unwrap()
+                .write_to_bytes(),
+            Err(Error::UnknownDeckId(5678))
+        ));
+        let model =
+            basic_and_reversed_card_model().map_templates(|template| template.deck_override(9012));
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(Note::new(model, vec!["der Hund", "the dog"]).unwrap());
+        assert!(matches!(
+            Package::new(vec![deck], vec![]).unwrap().write_to_bytes(),
+            Err(Error::UnknownDeckId(9012))
+        ));
+    }
+
+    #[test]
+    fn collection_conf() {
+        let read_conf = |package: &mut Package| -> serde_json::Map<String, serde_json::Value> {
+            let bytes = package.write_to_bytes().unwrap();
+            let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+            let dir = TempDir::new().unwrap();
+            let db_path = dir.path().join("collection.anki2");
+            std::io::copy(
+                &mut archive.by_name("collection.anki2").unwrap(),
+                &mut File::create(&db_path).unwrap(),
+            )
+            .unwrap();
+            let conf: String = Connection::open(&db_path)
+                .unwrap()
+                .query_row("SELECT conf FROM col", [], |row| row.get(0))
+                .unwrap();
+            serde_json::from_str(&conf).unwrap()
+        };
+        let decks = || vec![Deck::new(1234, "German", ""), Deck::new(5678, "French", "")];
+        let mut package = Package::new(decks(), vec![]).unwrap();
+        let default_conf = read_conf(&mut package);
+
+        let conf = CollectionConf::new()
+            .sort_type(SortType::Due)
+            .sort_backwards(true)
+            .cur_deck(1234)
+            .active_decks(&[1234, 5678])
+            .day_learn_first(true)
+            .new_spread(NewSpread::First)
+            .collapse_time(600)
+            .est_times(false)
+            .due_counts(false);
+        let mut package = Package::new(decks(), vec![]).unwrap().collection_conf(conf);
+        let mut conf = read_conf(&mut package);
+        let expected = serde_json::json!({
+            "sortType": "cardDue",
+            "sortBackwards": true,
+            "curDeck": 1234,
+            "activeDecks": [1234, 5678],
+            "dayLearnFirst": true,
+            "newSpread": 2,
+            "collapseTime": 600,
+            "estTimes": false,
+            "dueCounts": false,
+        });
+        for (key, value) in expected.as_object().unwrap() {
+            assert_eq!(conf.remove(key).as_ref(), Some(value), "{}", key);
+        }
+        for (key, value) in &conf {
+            assert_eq!(default_conf.get(key), Some(value), "{}", key);
+        }
+        assert_eq!(
+            conf.len(),
+            default_conf.len() - expected.as_object().unwrap().len() + 1
+        );
+
+        let conf = CollectionConf::new().sort_type(SortType::SortField);
+        let mut package = Package::new(decks(), vec![]).unwrap().collection_conf(conf);
+        let conf = read_conf(&mut package);
+        assert_eq!(conf, default_conf);
+
+        let mut package = Package::new(decks(), vec![])
+            .unwrap()
+            .collection_conf(CollectionConf::new().active_decks(&[1, 9012]));
+        assert!(matches!(
+            package.write_to_bytes(),
+            Err(Error::UnknownDeckId(9012))
+        ));
+    }
+
+    #[test]
+    fn select_deck_after_import() {
+        let read_col = |package: &mut Package| -> (serde_json::Value, serde_json::Value) {
+            let bytes = package.write_to_bytes().unwrap();
+            let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+            let dir = TempDir::new().unwrap();
+            let db_path = dir.path().join("collection.anki2");
+            std::io::copy(
+                &mut archive.by_name("collection.anki2").unwrap(),
+                &mut File::create(&db_path).unwrap(),
+            )
+            .unwrap();
+            let (conf, decks): (String, String) = Connection::open(&db_path)
+                .unwrap()
+                .query_row("SELECT conf, decks FROM col", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .unwrap();
+            (
+                serde_json::from_str(&conf).unwrap(),
+                serde_json::from_str(&decks).unwrap(),
+            )
+        };
+        let decks = || {
+            vec![
+                Deck::new(1234, "German", "")
+                    .collapsed(true)
+                    .browser_collapsed(true)
+                    .extend_new(20)
+                    .extend_rev(100),
+                Deck::new(5678, "French", ""),
+            ]
+        };
+        let mut package = Package::new(decks(), vec![])
+            .unwrap()
+            .select_deck_after_import(1234)
+            .collection_conf(CollectionConf::new().cur_deck(5678).due_counts(false));
+        let (conf, decks_json) = read_col(&mut package);
+        assert_eq!(conf["curDeck"], 1234);
+        assert_eq!(conf["activeDecks"], serde_json::json!([1234]));
+        assert_eq!(conf["dueCounts"], false);
+        let german = &decks_json["1234"];
+        assert_eq!(german["collapsed"], true);
+        assert_eq!(german["browserCollapsed"], true);
+        assert_eq!(german["extendNew"], 20);
+        assert_eq!(german["extendRev"], 100);
+        let french = &decks_json["5678"];
+        assert_eq!(french["collapsed"], false);
+        // Anki treats the missing key like `false`, which keeps the database as before
+        assert_eq!(french.get("browserCollapsed"), None);
+        assert_eq!(french["extendNew"], 0);
+        assert_eq!(french["extendRev"], 50);
+
+        let mut package = Package::new(decks(), vec![])
+            .unwrap()
+            .select_deck_after_import(9012);
+        assert!(matches!(
+            package.write_to_bytes(),
+            Err(Error::UnknownDeckId(9012))
+        ));
+    }
+
+    #[test]
+    fn collection_package() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        for word in ["Hund", "Katze", "Maus"] {
+            deck.add_note(Note::new(basic_and_reversed_card_model(), vec![word, word]).unwrap());
+        }
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("collection.colpkg");
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        package.add_media_bytes("a.mp3", b"abc".to_vec()).unwrap();
+        let summary = package.write_colpkg(&out_path).unwrap();
+        assert_eq!((summary.notes, summary.cards), (3, 6));
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(names, ["0", "collection.anki2", "media"]);
+        let conn = open_collection(&mut archive, &dir);
+        let integrity: String = conn
+            .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(integrity, "ok");
+        let (ver, conf, decks): (i64, String, String) = conn
+            .query_row("SELECT ver, conf, decks FROM col", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(ver, 11);
+        let conf: serde_json::Value = serde_json::from_str(&conf).unwrap();
+        assert_eq!(
+            conf["curModel"],
+            basic_and_reversed_card_model().id.to_string()
+        );
+        assert_eq!(conf["nextPos"], 3);
+        let decks: HashMap<String, serde_json::Value> = serde_json::from_str(&decks).unwrap();
+        let mut deck_names: Vec<&str> = decks
+            .values()
+            .map(|d| d["name"].as_str().unwrap())
+            .collect();
+        deck_names.sort_unstable();
+        assert_eq!(deck_names, ["Default", "Example deck"]);
+    }
+
+    #[test]
+    fn scheduler_v2() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        for word in ["Hund", "Katze", "Maus"] {
+            deck.add_note(Note::new(crate::basic_model(), vec![word, ""]).unwrap());
+        }
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        Package::new(vec![deck], vec![])
+            .unwrap()
+            .scheduler_version(SchedulerVersion::V2)
+            .write_to_file(&out_path)
+            .unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let conn = open_collection(&mut archive, &dir);
+        let conf: serde_json::Value = serde_json::from_str(
+            &conn
+                .query_row("SELECT conf FROM col", [], |row| row.get::<_, String>(0))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(conf["schedVer"], 2);
+        assert_eq!(conf["nextPos"], 1);
+        let ver: i64 = conn
+            .query_row("SELECT ver FROM col", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(ver, 11);
+        let dues: Vec<(i64, i64)> = conn
+            .prepare("SELECT queue, due FROM cards ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(dues, vec![(0, 0), (0, 1), (0, 2)]);
+    }
+
+    #[test]
+    #[cfg(feature = "nfc")]
+    fn normalize_unicode_fields_and_media() {
+        let dir = TempDir::new().unwrap();
+        let media_path = dir.path().join(format!("{}.mp3", NFD));
+        File::create(&media_path).unwrap();
+        let sound = format!("[sound:{}.mp3]", NFD);
+        let mut deck = Deck::new(1234, "Example deck", "");
+        deck.add_note(Note::new(crate::basic_model(), vec![NFD, &sound]).unwrap());
+        let out_path = dir.path().join("output.apkg");
+        let mut package = Package::new(vec![deck], vec![media_path.to_str().unwrap()])
+            .unwrap()
+            .normalize_unicode(true);
+        package.write_to_file(&out_path).unwrap();
+        // The notes of the package are left as they are
+        assert_eq!(package.decks[0].notes().next().unwrap().field(0), Some(NFD));
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let mut media_json = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media_json)
+            .unwrap();
+        let media: HashMap<String, String> = serde_json::from_str(&media_json).unwrap();
+        assert_eq!(media["0"], format!("{}.mp3", NFC));
+
+        let fields: String = open_collection(&mut archive, &dir)
+            .query_row("SELECT flds FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(fields, format!("{}\x1f[sound:{}.mp3]", NFC, NFC));
+    }
+
+    #[test]
+    #[cfg(feature = "nfc")]
+    fn normalized_media_names() {
+        let mut deck = Deck::new(1234, "Example deck", "");
+        let sound = format!("[sound:{}.mp3]", NFC);
+        deck.add_note(Note::new(crate::basic_model(), vec![NFC, &sound]).unwrap());
+        let mut package = Package::new(vec![deck], vec![])
+            .unwrap()
+            .normalize_unicode(true);
+        package
+            .add_media_bytes(&format!("{}.mp3", NFD), "sound")
+            .unwrap();
+        assert_eq!(package.check_media(), MediaCheck::default());
+        assert!(package.write_to_bytes().is_ok());
+
+        // Both files would be written with the same name
+        package
+            .add_media_bytes(&format!("{}.mp3", NFC), "other sound")
+            .unwrap();
+        assert!(matches!(
+            package.write_to_bytes(),
+            Err(Error::MediaNameCollision { name, .. }) if name == format!("{}.mp3", NFC)
+        ));
+    }
+
+    #[test]
+    fn special_media_filenames() {
+        let names = ["für_elise.mp3", "日本語.png", "it's.jpg", "track #2.ogg"];
+        let mut deck = Deck::new(1234, "Example deck", "");
+        let references = format!(
+            "[sound:{}]<img src=\"{}\"><img src=\"it&#39;s.jpg\">[sound:{}]",
+            names[0], names[1], names[3]
+        );
+        deck.add_note(Note::new(crate::basic_model(), vec!["Media", &references]).unwrap());
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        for name in names.iter() {
+            package.add_media_bytes(name, name.as_bytes()).unwrap();
+        }
+        assert_eq!(package.check_media(), MediaCheck::default());
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        package.write_to_file(&out_path).unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        let mut media_json = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media_json)
+            .unwrap();
+        // The names are written as UTF-8, not escaped
+        assert!(media_json.contains("日本語.png"));
+        let media: BTreeMap<String, String> = serde_json::from_str(&media_json).unwrap();
+        let mut entry_names: Vec<&str> = archive.file_names().collect();
+        entry_names.sort_unstable();
+        assert_eq!(
+            entry_names,
+            vec!["0", "1", "2", "3", "collection.anki2", "media"]
+        );
+        for (index, name) in &media {
+            let mut content = vec![];
+            archive
+                .by_name(index)
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, name.as_bytes());
+        }
+        let written: Vec<&str> = media.values().map(String::as_str).collect();
+        assert_eq!(written, names);
+
+        let fields: String = open_collection(&mut archive, &dir)
+            .query_row("SELECT flds FROM notes", [], |row| row.get(0))
+            .unwrap();
+        let referenced: Vec<String> = crate::media::media_references(&fields)
+            .into_iter()
+            .map(|media| media.filename)
+            .collect();
+        assert_eq!(referenced, names);
+    }
+
+    #[test]
+    fn atomic_write() {
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        let media_path = dir.path().join("sound.mp3");
+        File::create(&media_path)
+            .unwrap()
+            .write_all(b"sound")
+            .unwrap();
+        let package = || {
+            let mut deck = Deck::new(1234, "German", "");
+            deck.add_note(Note::new(crate::basic_model(), vec!["der Hund", "the dog"]).unwrap());
+            Package::new(vec![deck], vec![media_path.to_str().unwrap()]).unwrap()
+        };
+        let mut failing = package();
+        let mut failing_colpkg = package();
+        let mut direct = package().atomic_write(false);
+        package().write_to_file(&out_path).unwrap();
+        let written = std::fs::read(&out_path).unwrap();
+
+        // The media file is only read while the package is written, after the notes
+        std::fs::remove_file(&media_path).unwrap();
+        assert!(failing.write_to_file(&out_path).is_err());
+        assert!(failing_colpkg.write_colpkg(&out_path).is_err());
+        assert!(std::fs::read(&out_path).unwrap() == written);
+        std::fs::remove_file(&out_path).unwrap();
+        assert!(failing.write_to_file(&out_path).is_err());
+        assert!(!out_path.exists());
+        // The temporary files are removed too
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        assert!(direct.write_to_file(&out_path).is_err());
+        assert!(out_path.exists());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn write_async() {
+        let mut cursor = Cursor::new(Vec::new());
+        let sync_summary = progress_package()
+            .write_maybe_timestamp(
+                &mut cursor,
+                Some(Timestamp::from_secs(1700000000)),
+                &mut |_| {},
+            )
+            .unwrap();
+        let sync_bytes = cursor.into_inner();
+
+        let mut package = progress_package();
+        let mut bytes = vec![];
+        let summary = package
+            .write_async_maybe_timestamp(&mut bytes, Some(Timestamp::from_secs(1700000000)))
+            .await
+            .unwrap();
+        assert_eq!(summary, sync_summary);
+        assert!(bytes == sync_bytes);
+        // The package is put back after writing it
+        assert_eq!(package.decks.len(), 2);
+        assert!(package.write_async(&mut vec![]).await.is_ok());
+
+        // Dropping the future, like a timeout does, leaves the package as it was
+        let mut bytes = vec![];
+        let mut write = Box::pin(package.write_async(&mut bytes));
+        tokio::select! {
+            biased;
+            _ = &mut write => {}
+            _ = std::future::ready(()) => {}
+        }
+        drop(write);
+        assert_eq!(package.decks.len(), 2);
+        assert_eq!(package.media.len(), progress_package().media.len());
+        assert!(package.write_async(&mut vec![]).await.is_ok());
+
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        let summary = progress_package()
+            .write_to_file_async_maybe_timestamp(&out_path, Some(Timestamp::from_secs(1700000000)))
+            .await
+            .unwrap();
+        assert_eq!(summary, sync_summary);
+        assert!(tokio::fs::read(&out_path).await.unwrap() == sync_bytes);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn write_to_file_async_errors() {
+        let dir = TempDir::new().unwrap();
+        let out_path = dir.path().join("output.apkg");
+        let mut package = Package::new(vec![], vec![]).unwrap();
+        assert!(matches!(
+            package.write_to_file_async(&out_path).await,
+            Err(Error::NoDecks)
+        ));
+        // Neither the package nor the temporary file are left in the directory
+        assert!(!out_path.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    fn latex_package(fields: Vec<&str>) -> Package {
+        let mut deck = Deck::new(1234, "Math", "");
+        deck.add_note(Note::new(basic_model(), vec!["1 + 1", "2"]).unwrap());
+        deck.add_note(Note::new(basic_model(), fields).unwrap());
+        Package::new(vec![deck], vec![]).unwrap()
+    }
+
+    #[test]
+    fn prerender_latex() {
+        let mut package = latex_package(vec![
+            "[$]a &amp; b[/$] and [latex]x^2[/latex]",
+            "[$$]\\sum_i<br>i[/$$], [$]a &amp; b[/$]",
+        ]);
+        let documents = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let recorded = documents.clone();
+        let renderer = LatexRenderer::custom(move |document| {
+            recorded.borrow_mut().push(document.to_string());
+            Ok(b"image".to_vec())
+        });
+        package.prerender_latex(&renderer).unwrap();
+
+        let expression = "latex-3bef23675044dca35582f15e12f5a8febbdea8fa.png";
+        let latex = "latex-7046d961a8144b7b2c2da6066849a9f889ff2ac9.png";
+        let math = "latex-a950d0bd1f4be844d174add0b1267e657a717d3c.png";
+        let alt = "&#x24;a&#x20;&#x26;&#x20;b&#x24;";
+        let note = package.decks[0].notes().nth(1).unwrap();
+        assert_eq!(
+            note.fields(),
+            &[
+                format!(
+                    r#"<img class=latex alt="{}" src="{}"> and <img class=latex alt="x&#x5E;2" src="{}">"#,
+                    alt, expression, latex
+                ),
+                format!(
+                    "<img class=latex alt=\"&#x5C;begin&#x7B;displaymath&#x7D;&#x5C;sum&#x5F;i&#x0A;i\
+                     &#x5C;end&#x7B;displaymath&#x7D;\" src=\"{}\">, <img class=latex alt=\"{}\" src=\"{}\">",
+                    math, alt, expression
+                ),
+            ]
+        );
+        let model = basic_model();
+        assert_eq!(
+            documents.borrow()[0],
+            format!(
+                "{}\n$a & b$\n{}",
+                model.get_latex_pre(),
+                model.get_latex_post()
+            )
+        );
+        // Each LaTeX is only rendered once
+        assert_eq!(documents.borrow().len(), 3);
+        let media: Vec<&str> = package.media.iter().map(MediaFile::name).collect();
+        assert_eq!(media, vec![expression, latex, math]);
+        assert!(package.check_media().missing.is_empty());
+    }
+
+    #[test]
+    fn prerender_latex_to_svg() {
+        let mut deck = Deck::new(1234, "Math", "");
+        let model = basic_model().latex_svg(true);
+        deck.add_note(Note::new(model, vec!["[$]x[/$]", ""]).unwrap());
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        package
+            .prerender_latex(&LatexRenderer::custom(|_| Ok(b"<svg/>".to_vec())))
+            .unwrap();
+        let field = package.decks[0].notes().next().unwrap().fields()[0].clone();
+        assert!(field.ends_with(".svg\">"), "{}", field);
+    }
+
+    #[test]
+    fn prerender_latex_errors() {
+        let mut package = latex_package(vec!["x", "[latex]\\undefined[/latex]"]);
+        let renderer = LatexRenderer::custom(|_| {
+            Err(Error::Io(std::io::Error::other(
+                "Undefined control sequence",
+            )))
+        });
+        match package.prerender_latex(&renderer) {
+            Err(Error::LatexRenderFailed {
+                deck_id,
+                index,
+                field,
+                output,
+            }) => {
+                assert_eq!((deck_id, index, field.as_str()), (1234, 1, "Back"));
+                assert!(output.contains("Undefined control sequence"), "{}", output);
+            }
+            other => panic!("expected a render error, got {:?}", other),
+        }
+
+        let mut package = latex_package(vec!["[$]x[/$]", ""]);
+        let renderer = LatexRenderer::commands().latex_command("/nonexistent/latex");
+        match package.prerender_latex(&renderer) {
+            Err(Error::LatexRenderFailed { output, .. }) => {
+                assert!(
+                    output.starts_with("/nonexistent/latex couldn't be run"),
+                    "{}",
+                    output
+                );
+            }
+            other => panic!("expected a render error, got {:?}", other),
+        }
+    }
+
+    /// Names the files after the text and records what it synthesized
+    struct MockTts {
+        texts: std::cell::RefCell<Vec<(String, String)>>,
+    }
+
+    impl TtsProvider for MockTts {
+        fn synthesize(&self, text: &str, lang: &str) -> Result<(String, Vec<u8>), Error> {
+            if text == "fail" {
+                return Err(Error::Io(std::io::Error::other("engine failed")));
+            }
+            self.texts
+                .borrow_mut()
+                .push((text.to_string(), lang.to_string()));
+            let filename = format!("tts-{}.mp3", text.replace(' ', "_"));
+            Ok((filename, text.as_bytes().to_vec()))
+        }
+    }
+
+    fn vocabulary_model() -> Model {
+        Model::new(
+            4321,
+            "Vocabulary",
+            vec![
+                Field::new("German"),
+                Field::new("English"),
+                Field::new("Audio"),
+            ],
+            vec![Template::new("Card 1")
+                .qfmt("{{German}}")
+                .afmt("{{FrontSide}}<hr id=answer>{{English}} {{Audio}}")],
+        )
+    }
+
+    #[test]
+    fn generate_tts() {
+        let mut deck = Deck::new(1234, "German", "");
+        for fields in [
+            vec!["der <b>Hund</b>", "the dog", ""],
+            vec![
+                "<img src=\"cat.jpg\">die Katze",
+                "the cat",
+                "[sound:katze.mp3]",
+            ],
+            vec!["der Hund", "the dog, again", ""],
+            vec!["", "empty", ""],
+        ] {
+            deck.add_note(Note::new(vocabulary_model(), fields).unwrap());
+        }
+        deck.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"]).unwrap());
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        let tts = MockTts {
+            texts: Default::default(),
+        };
+        let field = TtsField::new("German", "Audio", "de_DE");
+        package.generate_tts(&field, &tts).unwrap();
+        // Generating the sounds again doesn't add them twice
+        package.generate_tts(&field, &tts).unwrap();
+
+        assert_eq!(
+            &tts.texts.borrow()[..2],
+            &[
+                ("der Hund".to_string(), "de_DE".to_string()),
+                ("die Katze".to_string(), "de_DE".to_string())
+            ]
+        );
+        let audio: Vec<&str> = package.decks[0]
+            .notes()
+            .filter_map(|note| note.field_by_name("Audio"))
+            .collect();
+        assert_eq!(
+            audio,
+            vec![
+                "[sound:tts-der_Hund.mp3]",
+                "[sound:katze.mp3][sound:tts-die_Katze.mp3]",
+                "[sound:tts-der_Hund.mp3]",
+                ""
+            ]
+        );
+        let media: Vec<&str> = package.media.iter().map(MediaFile::name).collect();
+        assert_eq!(media, vec!["tts-der_Hund.mp3", "tts-die_Katze.mp3"]);
+        assert_eq!(
+            package.decks[0].notes().last().unwrap().fields(),
+            &["der Hund", "the dog"]
+        );
+
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(Note::new(vocabulary_model(), vec!["fail", "", ""]).unwrap());
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        assert!(matches!(
+            package.generate_tts(&field, &tts),
+            Err(Error::Io(_))
+        ));
+    }
+
+    /// Text with the characters that mean something in fields, templates, tags and file names
+    const USER_TEXT: &str = "[{}<>\\[\\]:/&;#%!^\"' ac0-9\\\\\u{0}\u{fc}\n-]{0,30}";
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(500))]
+
+        #[test]
+        fn user_data_never_panics(
+            qfmt in USER_TEXT,
+            afmt in USER_TEXT,
+            field in USER_TEXT,
+            deck_name in USER_TEXT,
+            text in USER_TEXT,
+            cloze in proptest::prelude::any::<bool>(),
+        ) {
+            let model = Model::new(
+                1,
+                "model",
+                vec![Field::new("Front"), Field::new("Back")],
+                vec![Template::new("Card")
+                    .qfmt(format!("{{{{Front}}}}{}", qfmt))
+                    .afmt(&afmt)],
+            );
+            let model = if cloze {
+                model.model_type(crate::ModelType::Cloze)
+            } else {
+                model
+            };
+            let _ = model.validate();
+            if let Ok(note) = Note::new(model, vec![&field, &afmt]) {
+                let note = note.tags([text.clone()]).unwrap_or_else(|_| {
+                    Note::new(basic_model(), vec!["front", "back"]).unwrap()
+                });
+                let _ = note.media_references();
+                let mut deck = Deck::new(1234, &deck_name, &text);
+                deck.add_note(note.guid(&text));
+                if let Ok(mut package) = Package::new(vec![deck], vec![]) {
+                    let _ = package.add_media_bytes(&text, "content");
+                    let _ = package.add_media_file(&text);
+                    let _ = package.check_media();
+                    let _ = package.validate();
+                    let _ = package.write(&mut Cursor::new(vec![]));
+                }
+            }
+        }
+
+        #[test]
+        fn open_never_panics(
+            changes in proptest::collection::vec((0..1usize << 16, proptest::prelude::any::<u8>()), 0..8),
+            length in 0..1usize << 16,
+        ) {
+            let mut deck = Deck::new(1234, "Deck", "");
+            deck.add_note(Note::new(basic_model(), vec!["front", "back"]).unwrap());
+            let mut package = Package::new(vec![deck], vec![]).unwrap();
+            package.add_media_bytes("media.txt", "content").unwrap();
+            let mut bytes = vec![];
+            package.write(&mut Cursor::new(&mut bytes)).unwrap();
+            for (position, byte) in changes {
+                let position = position % bytes.len();
+                bytes[position] = byte;
+            }
+            bytes.truncate(length);
+
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().join("corrupted.apkg");
+            std::fs::write(&path, &bytes).unwrap();
+            let _ = Package::open(&path);
+        }
+    }
 }
@@ -0,0 +1,465 @@
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::RangeFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::db_entries::ModelDbEntry;
+use crate::deck::DedupBy;
+use crate::error::{database_error, json_error, zip_error};
+use crate::media::{MediaFile, MediaFiles};
+use crate::model::{same_model, Model};
+use crate::note::Note;
+use crate::package_reader::{collection_name, extract_collection, read_media_map};
+use crate::package_writer::{is_large, write_media_entry, zip_time};
+use crate::timestamp::Timestamp;
+use crate::util::{strip_html_media, temp_file_next_to};
+use crate::Error;
+
+/// The ways in which the notes of a collection can be duplicates of a new note
+struct ExistingNotes {
+    guids: HashSet<String>,
+    first_fields: HashSet<(i64, String)>,
+}
+
+impl ExistingNotes {
+    fn read(conn: &Connection) -> Result<Self, Error> {
+        let mut existing = Self {
+            guids: HashSet::new(),
+            first_fields: HashSet::new(),
+        };
+        let mut statement = conn
+            .prepare("SELECT guid, mid, flds FROM notes")
+            .map_err(database_error)?;
+        let mut rows = statement.query([]).map_err(database_error)?;
+        while let Some(row) = rows.next().map_err(database_error)? {
+            let fields: String = row.get(2).map_err(database_error)?;
+            let first_field = fields.split('\x1f').next().unwrap_or_default();
+            existing.guids.insert(row.get(0).map_err(database_error)?);
+            existing.first_fields.insert((
+                row.get(1).map_err(database_error)?,
+                strip_html_media(first_field),
+            ));
+        }
+        Ok(existing)
+    }
+
+    /// Adds `note`, unless there already is a duplicate of it according to `by`
+    ///
+    /// Returns whether the note was added.
+    fn insert(&mut self, note: &Note, by: DedupBy) -> bool {
+        let guid = note.get_guid();
+        let first_field = (note.model_id(), note.stripped_first_field());
+        let duplicate = match by {
+            DedupBy::Guid => self.guids.contains(&guid),
+            DedupBy::FirstField => self.first_fields.contains(&first_field),
+        };
+        if !duplicate {
+            self.guids.insert(guid);
+            self.first_fields.insert(first_field);
+        }
+        !duplicate
+    }
+}
+
+pub(crate) fn append_to_package(
+    path: &Path,
+    new_notes: Vec<(i64, Note)>,
+    new_media: &[&Path],
+    dedup: DedupBy,
+) -> Result<usize, Error> {
+    let timestamp = Timestamp::now()?;
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+    let collection = collection_name(&archive)?;
+    if collection == "collection.anki21b" {
+        return Err(Error::InvalidPackage(
+            "notes can't be appended to packages in the format of Anki 2.1.50 and later"
+                .to_string(),
+        ));
+    }
+    let db_file = extract_collection(&mut archive)?;
+    let conn = Connection::open(db_file.path()).map_err(database_error)?;
+    conn.execute_batch("BEGIN").map_err(database_error)?;
+    let appended = append_notes(&conn, new_notes, dedup, timestamp)?;
+    conn.execute_batch("COMMIT").map_err(database_error)?;
+    conn.close().map_err(|(_, error)| database_error(error))?;
+
+    let mut media_map = read_media_map(&mut archive)?;
+    let mut media = MediaFiles::default();
+    for media_path in new_media {
+        media.push(MediaFile::from_path(media_path)?, false)?;
+    }
+    let names: HashMap<String, String> = media_map
+        .iter()
+        .map(|(index, name)| (name.clone(), index.clone()))
+        .collect();
+    let mut added: Vec<&MediaFile> = vec![];
+    for media_file in media.iter() {
+        match names.get(media_file.name()) {
+            Some(index) => {
+                let mut data = vec![];
+                archive
+                    .by_name(index)
+                    .map_err(zip_error)?
+                    .read_to_end(&mut data)?;
+                let existing = MediaFile::Bytes {
+                    name: media_file.name().to_string(),
+                    data,
+                };
+                if existing.content_hash()? != media_file.content_hash()? {
+                    return Err(Error::MediaNameCollision {
+                        name: media_file.name().to_string(),
+                        first_source: None,
+                        second_source: media_file.source(),
+                    });
+                }
+            }
+            None => added.push(media_file),
+        }
+    }
+
+    // The package is written next to the old one, which it replaces once it is complete
+    let mut new_file = temp_file_next_to(path)?;
+    let options = FileOptions::default()
+        .last_modified_time(zip_time(timestamp))
+        .unix_permissions(0o644);
+    let mut outzip = ZipWriter::new(new_file.as_file_mut());
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i).map_err(zip_error)?;
+        if entry.name() != collection && entry.name() != "media" {
+            outzip.raw_copy_file(entry).map_err(zip_error)?;
+        }
+    }
+    let first_index = media_map
+        .keys()
+        .filter_map(|index| index.parse::<usize>().ok())
+        .max()
+        .map_or(0, |index| index + 1);
+    for (index, media_file) in (first_index..).zip(added) {
+        write_media_entry(&mut outzip, index, media_file, options, None, false)?;
+        media_map.insert(index.to_string(), media_file.name().to_string());
+    }
+    let db_options = options.large_file(is_large(db_file.as_file().metadata()?.len()));
+    outzip
+        .start_file(collection, db_options)
+        .map_err(zip_error)?;
+    std::io::copy(&mut File::open(db_file.path())?, &mut outzip)?;
+    outzip.start_file("media", options).map_err(zip_error)?;
+    outzip.write_all(
+        serde_json::to_string(&media_map)
+            .map_err(json_error)?
+            .as_bytes(),
+    )?;
+    outzip.finish().map_err(zip_error)?;
+    drop(outzip);
+    new_file.persist(path).map_err(|e| e.error)?;
+    Ok(appended)
+}
+
+/// Writes the notes that aren't duplicates of the notes in `conn` and returns how many there are
+fn append_notes(
+    conn: &Connection,
+    new_notes: Vec<(i64, Note)>,
+    dedup: DedupBy,
+    timestamp: Timestamp,
+) -> Result<usize, Error> {
+    let (models_json, decks_json): (String, String) = conn
+        .query_row("SELECT models, decks FROM col", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .map_err(database_error)?;
+    // The JSON of the models and decks of the collection is kept as it is, Anki may have keys that
+    // this crate doesn't know
+    let mut models: BTreeMap<String, serde_json::Value> =
+        serde_json::from_str(&models_json).map_err(json_error)?;
+    let decks: BTreeMap<String, serde_json::Value> =
+        serde_json::from_str(&decks_json).map_err(json_error)?;
+    let (max_id, mut position): (Option<i64>, i64) = conn
+        .query_row(
+            "SELECT (SELECT MAX(id) FROM (SELECT id FROM notes UNION ALL SELECT id FROM cards)),
+                (SELECT COALESCE(MAX(due) + 1, 0) FROM cards WHERE type = 0)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(database_error)?;
+    let first_id = timestamp
+        .ids()
+        .start
+        .max(max_id.map_or(0, |id| id as usize + 1));
+    let mut id_gen: RangeFrom<usize> = first_id..;
+
+    let mut existing = ExistingNotes::read(conn)?;
+    let model_count = models.len();
+    // The models of the notes that match the model with their id in the collection
+    let mut matching_models: Vec<Arc<Model>> = vec![];
+    let mut appended = 0;
+    for (deck_id, note) in new_notes {
+        if let Some(id) = std::iter::once(deck_id)
+            .chain(note.card_deck_ids(deck_id))
+            .find(|id| !decks.contains_key(&id.to_string()))
+        {
+            return Err(Error::UnknownDeckId(id));
+        }
+        let model = note.model();
+        match models.entry(note.model_id().to_string()) {
+            Entry::Vacant(entry) => {
+                let db_entry = model.to_db_entry(timestamp, deck_id)?;
+                entry.insert(serde_json::to_value(db_entry).map_err(json_error)?);
+            }
+            Entry::Occupied(entry) => {
+                if !matching_models.iter().any(|known| same_model(known, model)) {
+                    check_same_model(entry.get(), model)?;
+                }
+            }
+        }
+        if !matching_models
+            .iter()
+            .any(|known| Arc::ptr_eq(known, model))
+        {
+            matching_models.push(Arc::clone(model));
+        }
+        if !existing.insert(&note, dedup) {
+            continue;
+        }
+        note.write_to_db(conn, timestamp, deck_id, &[], position, &mut id_gen)?;
+        position += 1;
+        appended += 1;
+    }
+    if models.len() > model_count {
+        conn.execute(
+            "UPDATE col SET models = ?",
+            params![serde_json::to_string(&models).map_err(json_error)?],
+        )
+        .map_err(database_error)?;
+    }
+    Ok(appended)
+}
+
+/// Returns `Error::ModelIdConflict` if the model `entry` of the collection isn't `model`, which
+/// Anki would keep for the notes of `model` on import
+fn check_same_model(entry: &serde_json::Value, model: &Model) -> Result<(), Error> {
+    let db_entry = ModelDbEntry::deserialize(entry).map_err(json_error)?;
+    let existing = Model::try_from(db_entry)?;
+    // The time the model was modified and its deck don't matter
+    if existing.to_json(0.0, 1)? != model.to_json(0.0, 1)? {
+        return Err(Error::ModelIdConflict {
+            id: model.id,
+            name: existing.name().to_string(),
+            other_name: model.name().to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_and_reversed_card_model, basic_model, Deck, Field, Package};
+    use tempfile::TempDir;
+
+    /// The raw (compressed) content of every entry of the archive at `path`
+    fn raw_entries(path: &Path) -> BTreeMap<String, Vec<u8>> {
+        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        (0..archive.len())
+            .map(|i| {
+                let mut entry = archive.by_index_raw(i).unwrap();
+                let mut data = vec![];
+                entry.read_to_end(&mut data).unwrap();
+                (entry.name().to_string(), data)
+            })
+            .collect()
+    }
+
+    fn query_collection<T>(path: &Path, f: impl FnOnce(&Connection) -> T) -> T {
+        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let db_file = extract_collection(&mut archive).unwrap();
+        f(&Connection::open(db_file.path()).unwrap())
+    }
+
+    fn rows(conn: &Connection, sql: &str) -> Vec<Vec<rusqlite::types::Value>> {
+        let mut statement = conn.prepare(sql).unwrap();
+        let columns = statement.column_count();
+        statement
+            .query_map([], |row| (0..columns).map(|i| row.get(i)).collect())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn models(conn: &Connection) -> BTreeMap<String, serde_json::Value> {
+        let json: String = conn
+            .query_row("SELECT models FROM col", [], |row| row.get(0))
+            .unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn append_notes_and_media() {
+        let dir = TempDir::new().unwrap();
+        let audio = dir.path().join("hund.mp3");
+        let image = dir.path().join("katze.jpg");
+        File::create(&audio).unwrap().write_all(b"woof").unwrap();
+        File::create(&image).unwrap().write_all(b"meow").unwrap();
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(
+            Note::new(basic_model(), vec!["der Hund", "the dog [sound:hund.mp3]"])
+                .unwrap()
+                .guid("hund"),
+        );
+        deck.add_note(Note::new(basic_model(), vec!["die Maus", "the mouse"]).unwrap());
+        let path = dir.path().join("german.apkg");
+        Package::new(vec![deck], vec![audio.to_str().unwrap()])
+            .unwrap()
+            .write_to_file_timestamp(&path, 1600000000.0)
+            .unwrap();
+        let old_entries = raw_entries(&path);
+        let (old_notes, old_cards, old_models) = query_collection(&path, |conn| {
+            (
+                rows(conn, "SELECT * FROM notes ORDER BY id"),
+                rows(conn, "SELECT * FROM cards ORDER BY id"),
+                models(conn),
+            )
+        });
+
+        let new_notes = vec![
+            (
+                1234,
+                Note::new(
+                    basic_and_reversed_card_model(),
+                    vec!["die Katze", "the cat"],
+                )
+                .unwrap(),
+            ),
+            (
+                1234,
+                Note::new(basic_model(), vec!["der Hund", "a dog"])
+                    .unwrap()
+                    .guid("hund"),
+            ),
+        ];
+        let media = [audio.as_path(), image.as_path()];
+        let appended = Package::append(&path, new_notes, &media, DedupBy::Guid).unwrap();
+        assert_eq!(appended, 1);
+
+        let new_entries = raw_entries(&path);
+        assert_eq!(new_entries["0"], old_entries["0"]);
+        let mut archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(
+            read_media_map(&mut archive).unwrap(),
+            BTreeMap::from([
+                ("0".to_string(), "hund.mp3".to_string()),
+                ("1".to_string(), "katze.jpg".to_string())
+            ])
+        );
+        query_collection(&path, |conn| {
+            let notes = rows(conn, "SELECT * FROM notes ORDER BY id");
+            let cards = rows(conn, "SELECT * FROM cards ORDER BY id");
+            assert_eq!(notes[..2], old_notes[..]);
+            assert_eq!(cards[..2], old_cards[..]);
+            assert_eq!((notes.len(), cards.len()), (3, 4));
+            let new_cards = rows(
+                conn,
+                "SELECT did, due FROM cards WHERE nid = (SELECT MAX(id) FROM notes)",
+            );
+            assert_eq!(new_cards, vec![vec![1234.into(), 2.into()]; 2]);
+            let models = models(conn);
+            let basic_id = basic_model().id.to_string();
+            assert_eq!(models[&basic_id], old_models[&basic_id]);
+            assert!(models.contains_key(&basic_and_reversed_card_model().id.to_string()));
+        });
+
+        let loaded = Package::open(&path).unwrap();
+        assert_eq!(loaded.decks[0].note_count(), 3);
+        assert_eq!(
+            loaded.media[&1],
+            ("katze.jpg".to_string(), b"meow".to_vec())
+        );
+    }
+
+    #[test]
+    fn append_dedup_and_errors() {
+        let dir = TempDir::new().unwrap();
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(Note::new(basic_model(), vec!["der <b>Hund</b>", "the dog"]).unwrap());
+        let path = dir.path().join("german.apkg");
+        Package::new(vec![deck], vec![])
+            .unwrap()
+            .write_to_file(&path)
+            .unwrap();
+        let note = || Note::new(basic_model(), vec!["der Hund", "a dog"]).unwrap();
+        let appended = Package::append(
+            &path,
+            vec![(1234, note()), (1234, note())],
+            &[],
+            DedupBy::FirstField,
+        )
+        .unwrap();
+        assert_eq!(appended, 0);
+        let appended = Package::append(
+            &path,
+            vec![(1234, note()), (1234, note())],
+            &[],
+            DedupBy::Guid,
+        )
+        .unwrap();
+        assert_eq!(appended, 1);
+
+        let before = std::fs::read(&path).unwrap();
+        assert!(matches!(
+            Package::append(&path, vec![(5678, note())], &[], DedupBy::Guid),
+            Err(Error::UnknownDeckId(5678))
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        // A model with the id of a model of the collection but other fields
+        let extended = basic_model().with_field(Field::new("Notes"));
+        let note = Note::new(extended, vec!["die Katze", "the cat", ""]).unwrap();
+        assert!(matches!(
+            Package::append(&path, vec![(1234, note)], &[], DedupBy::Guid),
+            Err(Error::ModelIdConflict { id, .. }) if id == basic_model().id
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        let other = dir.path().join("hund.mp3");
+        File::create(&other).unwrap().write_all(b"woof").unwrap();
+        Package::append(&path, vec![], &[&other], DedupBy::Guid).unwrap();
+        File::create(&other).unwrap().write_all(b"bark").unwrap();
+        assert!(matches!(
+            Package::append(&path, vec![], &[&other], DedupBy::Guid),
+            Err(Error::MediaNameCollision { .. })
+        ));
+        assert_eq!(Package::open(&path).unwrap().media.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn append_media_from_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("german.apkg");
+        Package::new(vec![Deck::new(1234, "German", "")], vec![])
+            .unwrap()
+            .write_to_file(&path)
+            .unwrap();
+        // Only the file name ends up in the package, the directory doesn't need to be UTF-8
+        let lesson = dir.path().join(OsStr::from_bytes(b"lektion\xff"));
+        std::fs::create_dir(&lesson).unwrap();
+        let audio = lesson.join("hund.mp3");
+        File::create(&audio).unwrap().write_all(b"woof").unwrap();
+        Package::append(&path, vec![], &[&audio], DedupBy::Guid).unwrap();
+        let loaded = Package::open(&path).unwrap();
+        assert_eq!(loaded.media[&0], ("hund.mp3".to_string(), b"woof".to_vec()));
+        let bark = lesson.join(OsStr::from_bytes(b"bark\xff.mp3"));
+        File::create(&bark).unwrap().write_all(b"bark").unwrap();
+        assert!(matches!(
+            Package::append(&path, vec![], &[&bark], DedupBy::Guid),
+            Err(Error::NonUtf8MediaFilename { path: p }) if p == bark
+        ));
+    }
+}
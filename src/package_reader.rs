@@ -0,0 +1,657 @@
+use rusqlite::{Connection, OpenFlags};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+use zip::ZipArchive;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::db_entries::ModelDbEntry;
+use crate::deck::Deck;
+use crate::error::{database_error, json_error, zip_error};
+use crate::model::Model;
+#[cfg(feature = "modern-format")]
+use crate::modern_format;
+use crate::note::Note;
+use crate::{Error, Package};
+
+/// The id of the deck that every Anki collection contains
+const DEFAULT_DECK_ID: i64 = 1;
+
+/// The contents of an `.apkg` file, see [`Package::open`]
+#[derive(Clone, Debug)]
+pub struct LoadedPackage {
+    /// The decks of the package that contain notes, and all other decks except the default deck
+    /// and filtered decks
+    pub decks: Vec<Deck>,
+    /// The models of the package ordered by id, including models without notes
+    pub models: Vec<Model>,
+    /// The media files of the package by their index in the archive, with their names and contents
+    pub media: BTreeMap<usize, (String, Vec<u8>)>,
+}
+
+impl LoadedPackage {
+    /// Creates a `Package` with the decks and media files, e.g. to write them again after changing
+    /// some notes
+    ///
+    /// Like for any `Package`, only the models of the notes are written. Returns
+    /// `Error::MediaNameCollision` if two media files have the same name
+    pub fn into_package(self) -> Result<Package, Error> {
+        let mut package = Package::new(self.decks, vec![])?;
+        for (name, data) in self.media.into_values() {
+            package.add_media_bytes(&name, data)?;
+        }
+        Ok(package)
+    }
+}
+
+/// The parts of a deck in the `decks` column of an Anki collection that are read
+#[derive(Deserialize)]
+struct DeckJson {
+    id: i64,
+    name: String,
+    #[serde(default)]
+    desc: String,
+    #[serde(rename = "dyn", default)]
+    filtered: i64,
+}
+
+pub(crate) fn read_package(path: &Path) -> Result<LoadedPackage, Error> {
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+    let db_file = extract_collection(&mut archive)?;
+    let conn = Connection::open_with_flags(db_file.path(), OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(database_error)?;
+    let (models, decks) = read_collection(&conn)?;
+    let media = read_media(&mut archive)?;
+    Ok(LoadedPackage {
+        decks,
+        models,
+        media,
+    })
+}
+
+/// The name of the entry of `archive` that contains the collection
+///
+/// Anki 2.1 writes `collection.anki21` and a `collection.anki2` for older versions that only
+/// contains a note asking to update Anki, later versions do the same with `collection.anki21b`,
+/// which is compressed with zstd and can only be read with the `modern-format` feature.
+pub(crate) fn collection_name<R: Read + Seek>(
+    archive: &ZipArchive<R>,
+) -> Result<&'static str, Error> {
+    let names: Vec<&str> = archive.file_names().collect();
+    if names.contains(&"collection.anki21b") {
+        if cfg!(feature = "modern-format") {
+            Ok("collection.anki21b")
+        } else {
+            Err(Error::InvalidPackage(
+                "packages in the format of Anki 2.1.50 and later can only be read with the \
+                 `modern-format` feature"
+                    .to_string(),
+            ))
+        }
+    } else if names.contains(&"collection.anki21") {
+        Ok("collection.anki21")
+    } else if names.contains(&"collection.anki2") {
+        Ok("collection.anki2")
+    } else {
+        Err(Error::InvalidPackage(
+            "the package doesn't contain a collection".to_string(),
+        ))
+    }
+}
+
+/// Copies the collection of `archive` to a temporary file, so that SQLite can open it
+pub(crate) fn extract_collection<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<NamedTempFile, Error> {
+    let name = collection_name(archive)?;
+    let mut db_file = NamedTempFile::new()?;
+    let mut entry = archive.by_name(name).map_err(zip_error)?;
+    #[cfg(feature = "modern-format")]
+    if name == "collection.anki21b" {
+        zstd::stream::copy_decode(entry, &mut db_file)?;
+        return Ok(db_file);
+    }
+    std::io::copy(&mut entry, &mut db_file)?;
+    Ok(db_file)
+}
+
+/// Reads the models, and the decks with their notes from the collection in `conn`
+///
+/// Collections of Anki 2.1.50 and later store the models and decks in tables of their own, the
+/// ones of this crate and older versions in the `models` and `decks` columns of `col`.
+fn read_collection(conn: &Connection) -> Result<(Vec<Model>, Vec<Deck>), Error> {
+    #[cfg(feature = "modern-format")]
+    let (mut models, decks) = if modern_format::has_notetypes(conn)? {
+        (
+            modern_format::read_notetypes(conn)?,
+            modern_format::read_decks(conn)?,
+        )
+    } else {
+        read_col_json(conn)?
+    };
+    #[cfg(not(feature = "modern-format"))]
+    let (mut models, decks) = read_col_json(conn)?;
+    models.sort_by_key(|model| model.id);
+    // The notes of a model share it
+    let models_by_id: HashMap<i64, Arc<Model>> = models
+        .iter()
+        .map(|model| (model.id, Arc::new(model.clone())))
+        .collect();
+
+    let mut decks: BTreeMap<i64, Deck> = decks.into_iter().map(|deck| (deck.id(), deck)).collect();
+
+    // A note is added to the deck of its first card, or the original deck of the card if it is
+    // in a filtered deck
+    let mut statement = conn
+        .prepare(
+            "SELECT guid, mid, tags, flds, (
+                SELECT CASE WHEN odid != 0 THEN odid ELSE did END FROM cards
+                WHERE nid = notes.id ORDER BY ord LIMIT 1
+            ) FROM notes ORDER BY id",
+        )
+        .map_err(database_error)?;
+    let mut rows = statement.query([]).map_err(database_error)?;
+    while let Some(row) = rows.next().map_err(database_error)? {
+        let guid: String = row.get(0).map_err(database_error)?;
+        let model_id: i64 = row.get(1).map_err(database_error)?;
+        let tags: String = row.get(2).map_err(database_error)?;
+        let fields: String = row.get(3).map_err(database_error)?;
+        let deck_id: Option<i64> = row.get(4).map_err(database_error)?;
+        let model = models_by_id.get(&model_id).ok_or_else(|| {
+            Error::InvalidPackage(format!(
+                "the note {:?} has the unknown model id {}",
+                guid, model_id
+            ))
+        })?;
+        let note = Note::new_lenient(Arc::clone(model), fields.split('\x1f').collect())?
+            .tags(tags.split_whitespace())?
+            .guid(guid);
+        let deck_id = deck_id
+            .filter(|id| decks.contains_key(id))
+            .unwrap_or(DEFAULT_DECK_ID);
+        decks
+            .entry(deck_id)
+            .or_insert_with(|| Deck::new(DEFAULT_DECK_ID, "Default", ""))
+            .add_note(note);
+    }
+    if decks
+        .get(&DEFAULT_DECK_ID)
+        .is_some_and(|deck| deck.note_count() == 0)
+    {
+        decks.remove(&DEFAULT_DECK_ID);
+    }
+    Ok((models, decks.into_values().collect()))
+}
+
+/// Reads the models and the decks except filtered decks from the `models` and `decks` columns of
+/// the collection in `conn`
+fn read_col_json(conn: &Connection) -> Result<(Vec<Model>, Vec<Deck>), Error> {
+    let (models_json, decks_json): (String, String) = conn
+        .query_row("SELECT models, decks FROM col", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .map_err(database_error)?;
+    let model_entries: HashMap<String, ModelDbEntry> =
+        serde_json::from_str(&models_json).map_err(json_error)?;
+    let models = model_entries
+        .into_values()
+        .map(Model::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    let deck_entries: HashMap<String, DeckJson> =
+        serde_json::from_str(&decks_json).map_err(json_error)?;
+    let decks = deck_entries
+        .into_values()
+        .filter(|deck| deck.filtered == 0)
+        .map(|deck| Deck::new(deck.id, deck.name, deck.desc))
+        .collect();
+    Ok((models, decks))
+}
+
+/// Reads the `media` file of `archive`, which maps the entries of the media files to their names
+pub(crate) fn read_media_map<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<BTreeMap<String, String>, Error> {
+    match archive.by_name("media") {
+        Ok(media_file) => serde_json::from_reader(media_file).map_err(json_error),
+        Err(zip::result::ZipError::FileNotFound) => Ok(BTreeMap::new()),
+        Err(e) => Err(zip_error(e)),
+    }
+}
+
+/// Reads the media files listed in the `media` file of `archive`
+fn read_media<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<BTreeMap<usize, (String, Vec<u8>)>, Error> {
+    #[cfg(feature = "modern-format")]
+    if collection_name(archive)? == "collection.anki21b" {
+        return read_latest_media(archive);
+    }
+    let media_map = read_media_map(archive)?;
+    let mut media = BTreeMap::new();
+    for (index, name) in media_map {
+        let mut data = vec![];
+        archive
+            .by_name(&index)
+            .map_err(zip_error)?
+            .read_to_end(&mut data)?;
+        let index = index.parse().map_err(|_| {
+            Error::InvalidPackage(format!(
+                "the media file {:?} has the index {:?}",
+                name, index
+            ))
+        })?;
+        media.insert(index, (name, data));
+    }
+    Ok(media)
+}
+
+/// Reads the media files of `archive` in the latest format, whose `media` file and media files
+/// are compressed with zstd
+#[cfg(feature = "modern-format")]
+fn read_latest_media<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<BTreeMap<usize, (String, Vec<u8>)>, Error> {
+    let entries = match archive.by_name("media") {
+        Ok(media_file) => zstd::decode_all(media_file)?,
+        Err(zip::result::ZipError::FileNotFound) => vec![],
+        Err(e) => return Err(zip_error(e)),
+    };
+    let mut media = BTreeMap::new();
+    for (index, name) in modern_format::media_names(&entries)?
+        .into_iter()
+        .enumerate()
+    {
+        let entry = archive.by_name(&index.to_string()).map_err(zip_error)?;
+        media.insert(index, (name, zstd::decode_all(entry)?));
+    }
+    Ok(media)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apkg_col::APKG_COL;
+    use crate::apkg_schema::APKG_SCHEMA;
+    use crate::{basic_and_reversed_card_model, basic_model, cloze_model};
+    #[cfg(feature = "modern-format")]
+    use crate::{ModelType, PackageFormat};
+    use std::io::Write;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn guids(package: &LoadedPackage) -> Vec<(i64, Vec<String>)> {
+        package
+            .decks
+            .iter()
+            .map(|deck| {
+                (
+                    deck.id(),
+                    deck.notes().map(|note| note.get_guid()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut deck = Deck::new(1234, "Languages", "German <b>words</b>");
+        deck.add_note(
+            Note::new(basic_model(), vec!["der Hund", "the dog [sound:hund.mp3]"])
+                .unwrap()
+                .tags(["german", "animals"])
+                .unwrap()
+                .guid("custom guid"),
+        );
+        deck.add_note(
+            Note::new(
+                basic_and_reversed_card_model(),
+                vec!["die Katze", "the cat"],
+            )
+            .unwrap(),
+        );
+        let mut subdeck = deck.subdeck("Cloze", 5678);
+        subdeck.add_note(
+            Note::new(cloze_model(), vec!["{{c1::Berlin}} is a {{c2::city}}", ""]).unwrap(),
+        );
+        let empty = Deck::new(9012, "Empty", "");
+        let mut package = Package::new(vec![deck, subdeck, empty], vec![]).unwrap();
+        package
+            .add_media_bytes("hund.mp3", b"woof".to_vec())
+            .unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("first.apkg");
+        let timestamp = 1600000000.0;
+        package.write_to_file_timestamp(&path, timestamp).unwrap();
+        let loaded = Package::open(&path).unwrap();
+        assert_eq!(
+            loaded
+                .decks
+                .iter()
+                .map(|deck| (deck.id(), deck.name(), deck.note_count()))
+                .collect::<Vec<_>>(),
+            vec![
+                (1234, "Languages", 2),
+                (5678, "Languages::Cloze", 1),
+                (9012, "Empty", 0)
+            ]
+        );
+        let notes: Vec<&Note> = loaded.decks.iter().flat_map(Deck::notes).collect();
+        assert_eq!(notes[0].fields(), ["der Hund", "the dog [sound:hund.mp3]"]);
+        assert_eq!(notes[0].get_guid(), "custom guid");
+        assert_eq!(notes[1].card_count(), 2);
+        assert_eq!(notes[2].card_count(), 2);
+        assert_eq!(
+            loaded.media,
+            BTreeMap::from([(0, ("hund.mp3".to_string(), b"woof".to_vec()))])
+        );
+        let model_json = |models: &[Model]| -> Vec<String> {
+            models
+                .iter()
+                .map(|model| model.to_json(timestamp, 1234).unwrap())
+                .collect()
+        };
+        let mut expected_models = vec![
+            basic_model(),
+            basic_and_reversed_card_model(),
+            cloze_model(),
+        ];
+        expected_models.sort_by_key(|model| model.id);
+        assert_eq!(model_json(&loaded.models), model_json(&expected_models));
+
+        let again_path = dir.path().join("again.apkg");
+        let first_guids = guids(&loaded);
+        let first_models = model_json(&loaded.models);
+        loaded
+            .into_package()
+            .unwrap()
+            .write_to_file_timestamp(&again_path, timestamp)
+            .unwrap();
+        let again = Package::open(&again_path).unwrap();
+        assert_eq!(guids(&again), first_guids);
+        assert_eq!(model_json(&again.models), first_models);
+        assert_eq!(again.media.len(), 1);
+    }
+
+    #[test]
+    fn loaded_notes_share_models() {
+        let mut deck = Deck::new(1234, "German", "");
+        for word in ["der Hund", "die Katze", "die Maus"] {
+            deck.add_note(Note::new(basic_model(), vec![word, ""]).unwrap());
+        }
+        deck.add_note(Note::new(cloze_model(), vec!["{{c1::Berlin}}", ""]).unwrap());
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("german.apkg");
+        Package::new(vec![deck], vec![])
+            .unwrap()
+            .write_to_file(&path)
+            .unwrap();
+        let loaded = Package::open(&path).unwrap();
+        let notes: Vec<&Note> = loaded.decks[0].notes().collect();
+        assert!(Arc::ptr_eq(notes[0].model(), notes[1].model()));
+        assert!(Arc::ptr_eq(notes[0].model(), notes[2].model()));
+        assert!(!Arc::ptr_eq(notes[0].model(), notes[3].model()));
+    }
+
+    /// A package like Anki 2.1 exports it, with the collection in `collection.anki21`, ids as
+    /// numbers, keys that this crate doesn't know and a note in a filtered deck
+    #[test]
+    fn package_exported_by_anki() {
+        let dir = TempDir::new().unwrap();
+        let db_path = dir.path().join("collection.anki21");
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(APKG_SCHEMA).unwrap();
+        conn.execute_batch(APKG_COL).unwrap();
+        let models = r#"{"1342697561419": {
+            "id": 1342697561419, "name": "Basic", "type": 0, "mod": 1600000000, "usn": -1,
+            "sortf": 0, "did": null, "originalStockKind": 1,
+            "tmpls": [{"name": "Card 1", "ord": 0, "qfmt": "{{Front}}",
+                "afmt": "{{FrontSide}}<hr id=answer>{{Back}}", "bqfmt": "", "bafmt": "",
+                "did": null, "bfont": "", "bsize": 0, "id": 8593373151901766000}],
+            "flds": [{"name": "Back", "ord": 1, "sticky": false, "rtl": false, "font": "Arial",
+                    "size": 20, "description": "", "plainText": false, "collapsed": false,
+                    "excludeFromSearch": false, "id": 2, "tag": null, "preventDeletion": false},
+                {"name": "Front", "ord": 0, "sticky": false, "rtl": false, "font": "Arial",
+                    "size": 20, "media": []}],
+            "css": ".card {}", "latexPre": "", "latexPost": "", "latexsvg": false,
+            "req": [[0, "any", [0]]]
+        }}"#;
+        let decks = r#"{
+            "1": {"id": 1, "name": "Default", "desc": "", "dyn": 0, "conf": 1},
+            "1600000000001": {"id": 1600000000001, "name": "Vocabulary", "desc": "Words",
+                "dyn": 0, "conf": 1, "browserCollapsed": false},
+            "1600000000002": {"id": 1600000000002, "name": "Filtered Deck 1", "dyn": 1,
+                "terms": [["deck:Vocabulary", 100, 0]]}
+        }"#;
+        conn.execute("UPDATE col SET models = ?, decks = ?", [models, decks])
+            .unwrap();
+        for (id, guid, fields, did, odid) in [
+            (1, "a", "Hund\x1fdog", 1600000000001i64, 0i64),
+            (2, "b", "Katze\x1fcat", 1600000000002, 1600000000001),
+        ] {
+            conn.execute(
+                "INSERT INTO notes VALUES(?, ?, 1342697561419, 0, -1, ' leech ', ?, '', 0, 0, '')",
+                rusqlite::params![id, guid, fields],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO cards VALUES(?, ?, ?, 0, 0, -1, 2, -1, 10, 5, 2500, 3, 1, 0, 0, ?, 0, '')",
+                rusqlite::params![id, id, did, odid],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        let path = dir.path().join("anki.apkg");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        zip.start_file("collection.anki2", FileOptions::default())
+            .unwrap();
+        zip.write_all(b"not the collection").unwrap();
+        zip.start_file("collection.anki21", FileOptions::default())
+            .unwrap();
+        std::io::copy(&mut File::open(&db_path).unwrap(), &mut zip).unwrap();
+        zip.start_file("media", FileOptions::default()).unwrap();
+        zip.write_all(br#"{"0": "a.jpg", "1": "b.mp3"}"#).unwrap();
+        zip.start_file("1", FileOptions::default()).unwrap();
+        zip.write_all(b"mp3").unwrap();
+        zip.start_file("0", FileOptions::default()).unwrap();
+        zip.write_all(b"jpg").unwrap();
+        zip.finish().unwrap();
+
+        let loaded = Package::open(&path).unwrap();
+        assert_eq!(loaded.models.len(), 1);
+        assert_eq!(loaded.models[0].field_names(), ["Front", "Back"]);
+        assert_eq!(loaded.decks.len(), 1);
+        assert_eq!(loaded.decks[0].name(), "Vocabulary");
+        assert_eq!(
+            guids(&loaded),
+            vec![(1600000000001, vec!["a".to_string(), "b".to_string()])]
+        );
+        let note = loaded.decks[0].notes().nth(1).unwrap();
+        assert_eq!(note.fields(), ["Katze", "cat"]);
+        assert_eq!(note.cards()[0].schedule(), None);
+        assert_eq!(loaded.media[&1], ("b.mp3".to_string(), b"mp3".to_vec()));
+
+        let mut package = loaded.into_package().unwrap();
+        package
+            .write_to_file(dir.path().join("again.apkg"))
+            .unwrap();
+    }
+
+    #[test]
+    fn unreadable_packages() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("empty.apkg");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        zip.start_file("media", FileOptions::default()).unwrap();
+        zip.write_all(b"{}").unwrap();
+        zip.finish().unwrap();
+        assert!(matches!(
+            Package::open(&path),
+            Err(Error::InvalidPackage(_))
+        ));
+
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for name in ["collection.anki2", "collection.anki21b"] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+        }
+        zip.finish().unwrap();
+        #[cfg(not(feature = "modern-format"))]
+        assert!(matches!(
+            Package::open(&path),
+            Err(Error::InvalidPackage(_))
+        ));
+        // The empty collection.anki21b isn't compressed with zstd
+        #[cfg(feature = "modern-format")]
+        assert!(matches!(Package::open(&path), Err(Error::Io(_))));
+    }
+
+    #[cfg(feature = "modern-format")]
+    #[test]
+    fn latest_format_round_trip() {
+        let mut deck = Deck::new(1234, "Languages", "German <b>words</b>");
+        deck.add_note(
+            Note::new(basic_model(), vec!["der Hund", "the dog [sound:hund.mp3]"])
+                .unwrap()
+                .guid("custom guid"),
+        );
+        let mut subdeck = deck.subdeck("Cloze", 5678);
+        subdeck.add_note(
+            Note::new(cloze_model(), vec!["{{c1::Berlin}} is a {{c2::city}}", ""]).unwrap(),
+        );
+        let mut package = Package::new(vec![deck, subdeck], vec![]).unwrap();
+        package
+            .add_media_bytes("hund.mp3", b"woof".to_vec())
+            .unwrap();
+        package.add_media_bytes("empty.txt", vec![]).unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let timestamp = 1600000000.0;
+        let legacy_path = dir.path().join("legacy.apkg");
+        package
+            .clone()
+            .write_to_file_timestamp(&legacy_path, timestamp)
+            .unwrap();
+        let latest_path = dir.path().join("latest.apkg");
+        let mut package = package.format(PackageFormat::Latest);
+        package
+            .write_to_file_timestamp(&latest_path, timestamp)
+            .unwrap();
+
+        let legacy = Package::open(&legacy_path).unwrap();
+        let latest = Package::open(&latest_path).unwrap();
+        let model_json = |models: &[Model]| -> Vec<String> {
+            models
+                .iter()
+                .map(|model| model.to_json(timestamp, 1234).unwrap())
+                .collect()
+        };
+        assert_eq!(guids(&latest), guids(&legacy));
+        assert_eq!(model_json(&latest.models), model_json(&legacy.models));
+        assert_eq!(latest.media, legacy.media);
+        assert_eq!(latest.media[&0], ("hund.mp3".to_string(), b"woof".to_vec()));
+
+        let again_path = dir.path().join("again.apkg");
+        let first_guids = guids(&latest);
+        let first_models = model_json(&latest.models);
+        let mut package = latest.into_package().unwrap().format(PackageFormat::Latest);
+        package
+            .write_to_file_timestamp(&again_path, timestamp)
+            .unwrap();
+        let again = Package::open(&again_path).unwrap();
+        assert_eq!(guids(&again), first_guids);
+        assert_eq!(model_json(&again.models), first_models);
+        assert_eq!(again.media.len(), 2);
+    }
+
+    /// A package like Anki 2.1.50 and later exports it in the latest format, with the models and
+    /// decks in the tables of schema 18, a filtered deck and media files compressed with zstd
+    #[cfg(feature = "modern-format")]
+    #[test]
+    fn latest_package_exported_by_anki() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/anki_latest_export.apkg");
+        let loaded = Package::open(&path).unwrap();
+        assert_eq!(
+            loaded
+                .models
+                .iter()
+                .map(|model| (model.id, model.name(), model.field_names()))
+                .collect::<Vec<_>>(),
+            vec![
+                (1678901234567, "Basic", vec!["Front", "Back"]),
+                (1678901234568, "Cloze", vec!["Text", "Back Extra"])
+            ]
+        );
+        let cloze = &loaded.models[1];
+        assert_eq!(cloze.get_model_type(), ModelType::Cloze);
+        let json: serde_json::Value =
+            serde_json::from_str(&loaded.models[0].to_json(0.0, 1).unwrap()).unwrap();
+        assert_eq!(json["tmpls"][0]["qfmt"], "{{Front}}");
+        assert_eq!(
+            json["tmpls"][0]["afmt"],
+            "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}"
+        );
+        assert_eq!(json["flds"][1]["font"], "Arial");
+        assert_eq!(json["latexPost"], "\\end{document}");
+        let json: serde_json::Value =
+            serde_json::from_str(&cloze.to_json(0.0, 1).unwrap()).unwrap();
+        assert_eq!(json["flds"][1]["sticky"], true);
+        assert_eq!(json["flds"][1]["description"], "Shown on the back");
+
+        assert_eq!(
+            loaded
+                .decks
+                .iter()
+                .map(|deck| (deck.id(), deck.name(), deck.note_count()))
+                .collect::<Vec<_>>(),
+            vec![
+                (1678901300001, "Languages", 0),
+                (1678901300002, "Languages::German", 3)
+            ]
+        );
+        assert_eq!(
+            loaded.decks[1].to_deck_db_entry().desc,
+            "German <b>words</b>"
+        );
+        assert_eq!(
+            guids(&loaded)[1].1,
+            vec!["f2Lt!xK#7P", "Bx9@w+3Qe_", "q<5Rz^8mNa"]
+        );
+        let notes: Vec<&Note> = loaded.decks[1].notes().collect();
+        assert_eq!(notes[0].fields(), ["der Hund", "the dog [sound:hund.mp3]"]);
+        assert_eq!(notes[0].get_tags(), ["animals", "german"]);
+        assert_eq!(notes[2].card_count(), 2);
+        assert_eq!(
+            loaded
+                .media
+                .values()
+                .map(|(name, data)| (name.as_str(), data.len()))
+                .collect::<Vec<_>>(),
+            vec![("hund.mp3", 14), ("katze.jpg", 17)]
+        );
+
+        let dir = TempDir::new().unwrap();
+        let again_path = dir.path().join("again.apkg");
+        let first_guids = guids(&loaded);
+        let model_json = |models: &[Model]| -> Vec<String> {
+            models
+                .iter()
+                .map(|model| model.to_json(0.0, 1).unwrap())
+                .collect()
+        };
+        let first_models = model_json(&loaded.models);
+        let mut package = loaded.into_package().unwrap().format(PackageFormat::Latest);
+        package.write_to_file(&again_path).unwrap();
+        let again = Package::open(&again_path).unwrap();
+        assert_eq!(guids(&again), first_guids);
+        assert_eq!(model_json(&again.models), first_models);
+        assert_eq!(again.media.len(), 2);
+    }
+}
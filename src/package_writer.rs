@@ -0,0 +1,1091 @@
+use rusqlite::{ffi, Connection};
+use tempfile::{NamedTempFile, TempPath};
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipWriter};
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::ops::RangeFrom;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use crate::apkg_col::APKG_COL;
+use crate::apkg_schema::APKG_SCHEMA;
+use crate::builders::{CollectionConf, CompressionOptions};
+use crate::db_entries::ModelDbEntry;
+use crate::deck::Deck;
+use crate::error::{database_error, json_error, zip_error};
+use crate::media::{check_media_filename, open_media, MediaFile, MediaFiles};
+use crate::model::Model;
+#[cfg(feature = "modern-format")]
+use crate::modern_format::{self, PackageFormat};
+use crate::note::Note;
+use crate::timestamp::Timestamp;
+use crate::validation::{check_id, IdKind};
+use crate::Error;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::convert::TryFrom;
+#[cfg(feature = "parallel")]
+use std::io::Cursor;
+#[cfg(feature = "parallel")]
+use zip::ZipArchive;
+
+/// The default number of notes that are written in one SQLite transaction, see
+/// [`PackageWriter::batch_size`]
+pub(crate) const BATCH_SIZE: usize = 1000;
+
+/// The size of the largest media file that is compressed in parallel with others
+#[cfg(feature = "parallel")]
+const PARALLEL_MAX_LEN: u64 = 8 << 20;
+
+/// The maximum size of the media files that are compressed in parallel at once
+#[cfg(feature = "parallel")]
+const PARALLEL_GROUP_LEN: u64 = 128 << 20;
+
+/// A phase of writing a package, see
+/// [`Package::write_to_file_with_progress`](crate::Package::write_to_file_with_progress)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// `done` of the `total` notes are written to the collection database
+    WritingNotes { done: usize, total: usize },
+    /// The media file `file`, the one at `index` of the `total` files, is compressed into the
+    /// archive
+    CompressingMedia {
+        file: String,
+        index: usize,
+        total: usize,
+    },
+    /// The collection database is compressed into the archive, which is the last phase
+    Finalizing,
+}
+
+/// The version of Anki's scheduler a collection is set up for, see
+/// [`Package::scheduler_version`](crate::Package::scheduler_version)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SchedulerVersion {
+    /// The original scheduler, which Anki assumes for collections without a version
+    #[default]
+    V1,
+    /// The scheduler of Anki 2.1, which newer versions require
+    V2,
+}
+
+/// What was written to a package
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteSummary {
+    /// The size of the `.apkg` file
+    pub bytes: u64,
+    pub notes: usize,
+    pub cards: usize,
+    pub models: usize,
+    pub media_files: usize,
+}
+
+/// The collection database of a package, written by
+/// [`Package::build_collection`](crate::Package::build_collection) without zipping it
+///
+/// [`CollectionDump::bytes`] is the SQLite database of the `collection.anki2` file, in the
+/// format of Anki's schema 11, in both [formats](crate::PackageFormat) of the package. The other
+/// fields are parsed from its `col` row and tables to inspect the package, e.g. in tests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollectionDump {
+    /// The content of the database file
+    pub bytes: Vec<u8>,
+    /// The `decks` column of the `col` table, an object with the decks by id
+    pub decks: serde_json::Value,
+    /// The `models` column of the `col` table, an object with the models by id
+    pub models: serde_json::Value,
+    /// The `conf` column of the `col` table, the configuration of the collection
+    pub conf: serde_json::Value,
+    pub notes: usize,
+    pub cards: usize,
+}
+
+/// Calls a progress callback, without letting a panic in it interrupt the write
+pub(crate) struct ProgressReporter<'a> {
+    callback: &'a mut dyn FnMut(Progress),
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<'a> ProgressReporter<'a> {
+    pub(crate) fn new(callback: &'a mut dyn FnMut(Progress)) -> Self {
+        Self {
+            callback,
+            panic: None,
+        }
+    }
+
+    /// Calls the callback with `progress`, unless it panicked before
+    pub(crate) fn report(&mut self, progress: Progress) {
+        if self.panic.is_none() {
+            let callback = &mut self.callback;
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| callback(progress))) {
+                self.panic = Some(panic);
+            }
+        }
+    }
+
+    /// Continues a panic of the callback after the write is finished
+    pub(crate) fn finish(self) {
+        if let Some(panic) = self.panic {
+            panic::resume_unwind(panic);
+        }
+    }
+}
+
+/// `PackageWriter` writes notes to a `.apkg` file one at a time, without keeping them in memory
+///
+/// This is meant for decks that are too large to build in memory before writing them with a
+/// [`Package`](crate::Package), which is implemented with a `PackageWriter`. The collection
+/// database is kept in memory, unless [`PackageWriter::use_tempfile`] is used.
+///
+/// Example:
+/// ```rust,no_run
+/// use genanki_rs::{Deck, Note, PackageWriter, basic_model};
+/// use std::fs::File;
+///
+/// # fn main() -> Result<(), genanki_rs::Error> {
+/// let deck = Deck::new(1234, "Numbers", "");
+/// let file = File::create("output.apkg")?;
+/// let mut writer = PackageWriter::new(file, vec![basic_model()], &[deck])?.use_tempfile()?;
+/// for i in 0..300_000 {
+///     let note = Note::new(basic_model(), vec![&i.to_string(), &format!("{:x}", i)])?;
+///     writer.add_note(1234, note)?;
+/// }
+/// writer.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PackageWriter<W: Write + Seek> {
+    writer: W,
+    /// The temporary file of the database, or `None` if the database is in memory
+    db_file: Option<TempPath>,
+    conn: Connection,
+    timestamp: Timestamp,
+    /// The ids of the notes, cards and deck options of all decks, which start at the
+    /// milliseconds of `timestamp` and increase by one for each id, so that they never collide
+    /// no matter how many notes are written per millisecond
+    id_gen: RangeFrom<usize>,
+    positions: RangeFrom<i64>,
+    deck_ids: HashSet<i64>,
+    /// The default tags of the decks that have them
+    default_tags: HashMap<i64, Vec<String>>,
+    model_ids: HashSet<i64>,
+    batch_size: usize,
+    /// The number of notes written in the current transaction
+    batched: usize,
+    /// The number of transactions that changed the database
+    commits: u32,
+    media: MediaFiles,
+    compression: Option<CompressionOptions>,
+    #[cfg(feature = "nfc")]
+    normalize_unicode: bool,
+    #[cfg(feature = "parallel")]
+    parallel_max_len: u64,
+    #[cfg(feature = "modern-format")]
+    format: PackageFormat,
+    scheduler_version: SchedulerVersion,
+    collection_package: bool,
+    collection_conf: Option<CollectionConf>,
+}
+
+impl<W: Write + Seek> PackageWriter<W> {
+    /// Starts a package that is written to `writer` when [`PackageWriter::finish`] is called
+    ///
+    /// `decks` are the decks the notes can be added to; their notes aren't written, add them with
+    /// [`PackageWriter::add_note`]. Missing parent decks are added like in
+    /// [`Package::new`](crate::Package::new). The `models` and the models of the notes are added
+    /// to the collection.
+    ///
+    /// Returns `Error::InvalidDeckName` or `Error::DuplicateDeckId` like `Package::new`, or `Err`
+    /// if a model is invalid
+    pub fn new(writer: W, models: Vec<Model>, decks: &[Deck]) -> Result<Self, Error> {
+        Self::new_maybe_timestamp(writer, models, decks, None, false)
+    }
+
+    /// Like [`PackageWriter::new`], but everything that would otherwise depend on the current
+    /// time is derived from `timestamp`, see
+    /// [`Package::write_to_file_timestamp`](crate::Package::write_to_file_timestamp)
+    pub fn new_with_timestamp(
+        writer: W,
+        models: Vec<Model>,
+        decks: &[Deck],
+        timestamp: impl Into<Timestamp>,
+    ) -> Result<Self, Error> {
+        Self::new_maybe_timestamp(writer, models, decks, Some(timestamp.into()), false)
+    }
+
+    pub(crate) fn new_maybe_timestamp(
+        writer: W,
+        models: Vec<Model>,
+        decks: &[Deck],
+        timestamp: Option<Timestamp>,
+        use_tempfile: bool,
+    ) -> Result<Self, Error> {
+        for deck in decks {
+            deck.validate_name()?;
+        }
+        let parents = missing_parent_decks(decks);
+        let all_decks: Vec<&Deck> = parents.iter().chain(decks).collect();
+        check_deck_ids(&all_decks)?;
+
+        let timestamp = if let Some(timestamp) = timestamp {
+            timestamp
+        } else {
+            Timestamp::now()?
+        };
+        let (conn, db_file) = if use_tempfile {
+            let db_file = NamedTempFile::new()?.into_temp_path();
+            (open_temp_database(&db_file)?, Some(db_file))
+        } else {
+            let conn = Connection::open_in_memory().map_err(database_error)?;
+            conn.execute_batch("BEGIN").map_err(database_error)?;
+            (conn, None)
+        };
+        conn.execute_batch(APKG_SCHEMA).map_err(database_error)?;
+        conn.execute_batch(APKG_COL).map_err(database_error)?;
+        let mut package_writer = Self {
+            writer,
+            db_file,
+            conn,
+            timestamp,
+            id_gen: timestamp.ids(),
+            positions: 0..,
+            deck_ids: HashSet::new(),
+            default_tags: HashMap::new(),
+            model_ids: HashSet::new(),
+            batch_size: BATCH_SIZE,
+            batched: 0,
+            commits: 0,
+            media: MediaFiles::default(),
+            compression: None,
+            #[cfg(feature = "nfc")]
+            normalize_unicode: false,
+            #[cfg(feature = "parallel")]
+            parallel_max_len: PARALLEL_MAX_LEN,
+            #[cfg(feature = "modern-format")]
+            format: PackageFormat::default(),
+            scheduler_version: SchedulerVersion::default(),
+            collection_package: false,
+            collection_conf: None,
+        };
+        for deck in all_decks {
+            deck.write_to_db(&package_writer.conn, &mut package_writer.id_gen)?;
+            package_writer.deck_ids.insert(deck.id());
+            if !deck.get_default_tags().is_empty() {
+                let tags = deck.get_default_tags().to_vec();
+                package_writer.default_tags.insert(deck.id(), tags);
+            }
+        }
+        let default_deck_id = decks.first().map_or(1, Deck::id);
+        for model in &models {
+            package_writer.write_model(model, default_deck_id)?;
+        }
+        Ok(package_writer)
+    }
+
+    /// Sets how the files in the package are compressed, see
+    /// [`Package::compression`](crate::Package::compression)
+    pub fn compression(mut self, options: CompressionOptions) -> Self {
+        self.compression = Some(options);
+        self
+    }
+
+    /// Sets the format of the package, see [`Package::format`](crate::Package::format)
+    #[cfg(feature = "modern-format")]
+    pub fn format(mut self, format: PackageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the scheduler version of the collection, see
+    /// [`Package::scheduler_version`](crate::Package::scheduler_version)
+    pub fn scheduler_version(mut self, version: SchedulerVersion) -> Self {
+        self.scheduler_version = version;
+        self
+    }
+
+    /// Sets whether a collection package (`.colpkg`) is written instead of a `.apkg`, see
+    /// [`Package::write_colpkg`](crate::Package::write_colpkg)
+    pub fn collection_package(mut self, collection_package: bool) -> Self {
+        self.collection_package = collection_package;
+        self
+    }
+
+    /// Sets options of the collection, see
+    /// [`Package::collection_conf`](crate::Package::collection_conf)
+    pub fn collection_conf(mut self, conf: CollectionConf) -> Self {
+        self.collection_conf = Some(conf);
+        self
+    }
+
+    /// Sets how many notes are written in one SQLite transaction, or `0` to write all of them in a
+    /// single transaction
+    ///
+    /// The default is `1000`. Larger transactions are a little faster, smaller ones keep less of
+    /// the database in memory.
+    pub fn batch_size(mut self, notes: usize) -> Self {
+        self.batch_size = notes;
+        self
+    }
+
+    /// Moves the collection database from memory to a temporary file, for decks that are too large
+    /// to keep in memory
+    ///
+    /// Returns `Err` if the temporary file can't be created
+    pub fn use_tempfile(mut self) -> Result<Self, Error> {
+        if self.db_file.is_some() {
+            return Ok(self);
+        }
+        self.commit()?;
+        let mut file = NamedTempFile::new()?;
+        with_serialized(&self.conn, self.commits, |db| Ok(file.write_all(db)?))?;
+        let db_file = file.into_temp_path();
+        self.conn = open_temp_database(&db_file)?;
+        self.db_file = Some(db_file);
+        Ok(self)
+    }
+
+    /// Sets whether the fields of the notes added after this and the media file names are
+    /// converted to Unicode NFC, see
+    /// [`Package::normalize_unicode`](crate::Package::normalize_unicode)
+    #[cfg(feature = "nfc")]
+    pub fn normalize_unicode(mut self, normalize_unicode: bool) -> Self {
+        self.normalize_unicode = normalize_unicode;
+        self
+    }
+
+    /// Writes `note` to the deck with the id `deck_id`
+    ///
+    /// Returns `Error::UnknownDeckId` if the deck, or a deck a card of the note is written to
+    /// (see [`Card::set_deck_id`](crate::Card::set_deck_id)), wasn't passed to
+    /// [`PackageWriter::new`] and isn't a parent of one of those decks, or `Err` if the note is
+    /// invalid
+    pub fn add_note(&mut self, deck_id: i64, note: Note) -> Result<(), Error> {
+        #[cfg(feature = "nfc")]
+        if self.normalize_unicode {
+            let mut note = note;
+            note.normalize_unicode();
+            return self.write_note(deck_id, &note);
+        }
+        self.write_note(deck_id, &note)
+    }
+
+    /// Adds the media file at `path`, see
+    /// [`Package::add_media_file`](crate::Package::add_media_file)
+    ///
+    /// Returns `Error::InvalidMediaPath` if `path` doesn't end in a file name,
+    /// `Error::NonUtf8MediaFilename` or `Error::InvalidMediaFilename` if the file name can't be
+    /// the name of a media file, or `Error::MediaNameCollision` if there already is a file with
+    /// the same name but different content
+    pub fn add_media_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.media
+            .push(MediaFile::from_path(path.as_ref())?, false)?;
+        Ok(())
+    }
+
+    /// Adds a media file named `filename` with the content `bytes`
+    ///
+    /// Returns `Error::InvalidMediaFilename` if `filename` contains `/`, `\` or NUL or
+    /// `Error::MediaNameCollision` if there already is a file with this name but different content
+    pub fn add_media_bytes(
+        &mut self,
+        filename: &str,
+        bytes: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        check_media_filename(filename)?;
+        let name = filename.to_string();
+        let data = bytes.into();
+        self.media.push(MediaFile::Bytes { name, data }, false)?;
+        Ok(())
+    }
+
+    /// Writes the package with the added notes and media files and returns the writer
+    ///
+    /// Returns `Err` if writing fails
+    pub fn finish(mut self) -> Result<W, Error> {
+        let media = std::mem::take(&mut self.media);
+        let (writer, _) =
+            self.finish_with_media(&media, &mut ProgressReporter::new(&mut |_| {}))?;
+        Ok(writer)
+    }
+
+    pub(crate) fn write_note(&mut self, deck_id: i64, note: &Note) -> Result<(), Error> {
+        if let Some(id) = std::iter::once(deck_id)
+            .chain(note.card_deck_ids(deck_id))
+            .find(|id| !self.deck_ids.contains(id))
+        {
+            return Err(Error::UnknownDeckId(id));
+        }
+        if !self.model_ids.contains(&note.model_id()) {
+            self.write_model(note.model(), deck_id)?;
+        }
+        // Invariant: `positions` is an unbounded range
+        let position = self
+            .positions
+            .next()
+            .expect("Should always have a next position");
+        let default_tags = self
+            .default_tags
+            .get(&deck_id)
+            .map_or(&[][..], Vec::as_slice);
+        note.write_to_db(
+            &self.conn,
+            self.timestamp,
+            deck_id,
+            default_tags,
+            position,
+            &mut self.id_gen,
+        )?;
+        self.batched += 1;
+        if self.batched == self.batch_size {
+            self.commit()?;
+            self.conn.execute_batch("BEGIN").map_err(database_error)?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        self.conn.execute_batch("COMMIT").map_err(database_error)?;
+        // The first transaction creates the database, the others only change it if they contain
+        // notes
+        if self.commits == 0 || self.batched > 0 {
+            self.commits += 1;
+        }
+        self.batched = 0;
+        Ok(())
+    }
+
+    fn write_model(&mut self, model: &Model, deck_id: i64) -> Result<(), Error> {
+        if !self.model_ids.insert(model.id) {
+            return Ok(());
+        }
+        write_model_to_col(&self.conn, model, self.timestamp, deck_id)
+    }
+
+    /// Writes the package like [`PackageWriter::finish`], but with the media files `media`
+    /// instead of the added ones, and reports the media files to `progress`
+    pub(crate) fn finish_with_media(
+        mut self,
+        media: &MediaFiles,
+        progress: &mut ProgressReporter,
+    ) -> Result<(W, WriteSummary), Error> {
+        let notes = count_rows(&self.conn, "notes")?;
+        let cards = count_rows(&self.conn, "cards")?;
+        let models = self.model_ids.len();
+        self.finish_collection()?;
+
+        #[cfg(feature = "nfc")]
+        let normalize_unicode = self.normalize_unicode;
+        let media_name = |name: &str| -> String {
+            #[cfg(feature = "nfc")]
+            if normalize_unicode {
+                use unicode_normalization::UnicodeNormalization;
+                return name.nfc().collect();
+            }
+            name.to_string()
+        };
+        // The zip entries of the media files are named by their index, so the manifest is the
+        // only place their names are written to, as UTF-8 like the references in the notes
+        let names: Vec<String> = media.iter().map(|file| media_name(file.name())).collect();
+        check_written_names(media, &names)?;
+
+        #[cfg(feature = "modern-format")]
+        let zstd_compressed = self.format == PackageFormat::Latest;
+        #[cfg(not(feature = "modern-format"))]
+        let zstd_compressed = false;
+
+        let start = self.writer.stream_position()?;
+        let options = FileOptions::default()
+            .last_modified_time(zip_time(self.timestamp))
+            .unix_permissions(0o644);
+        let mut outzip = ZipWriter::new(self.writer);
+        let files: Vec<&MediaFile> = media.iter().collect();
+        let compression = self.compression.as_ref();
+        let media_progress = |idx: usize| Progress::CompressingMedia {
+            file: media_name(files[idx].name()),
+            index: idx,
+            total: files.len(),
+        };
+        let mut idx = 0;
+        while idx < files.len() {
+            #[cfg(feature = "parallel")]
+            {
+                let end = parallel_group_end(&files, idx, self.parallel_max_len)?;
+                if end > idx + 1 {
+                    // Each file is compressed into a zip of its own, whose entry is copied as is
+                    let compressed: Vec<Result<Vec<u8>, Error>> = (idx..end)
+                        .into_par_iter()
+                        .map(|idx| {
+                            let mut zip = ZipWriter::new(Cursor::new(vec![]));
+                            let file = files[idx];
+                            write_media_entry(
+                                &mut zip,
+                                idx,
+                                file,
+                                options,
+                                compression,
+                                zstd_compressed,
+                            )?;
+                            let mut zip = zip.finish().map_err(zip_error)?.into_inner();
+                            clear_external_attributes(&mut zip);
+                            Ok(zip)
+                        })
+                        .collect();
+                    for (idx, zip) in (idx..end).zip(compressed) {
+                        progress.report(media_progress(idx));
+                        let mut zip = ZipArchive::new(Cursor::new(zip?)).map_err(zip_error)?;
+                        let entry = zip.by_index_raw(0).map_err(zip_error)?;
+                        outzip.raw_copy_file(entry).map_err(zip_error)?;
+                    }
+                    idx = end;
+                    continue;
+                }
+            }
+            progress.report(media_progress(idx));
+            let file = files[idx];
+            write_media_entry(
+                &mut outzip,
+                idx,
+                file,
+                options,
+                compression,
+                zstd_compressed,
+            )?;
+            idx += 1;
+        }
+
+        progress.report(Progress::Finalizing);
+        let (db_name, db_options) = if zstd_compressed {
+            let stored = options.compression_method(CompressionMethod::Stored);
+            ("collection.anki21b", stored)
+        } else {
+            let level = compression.and_then(|c| c.get_database_level());
+            ("collection.anki2", options.compression_level(level))
+        };
+        match &self.db_file {
+            Some(db_file) => {
+                let db_options = db_options.large_file(is_large(std::fs::metadata(db_file)?.len()));
+                outzip.start_file(db_name, db_options).map_err(zip_error)?;
+                copy_content(File::open(db_file)?, &mut outzip, zstd_compressed)?;
+            }
+            None => with_serialized(&self.conn, self.commits, |db| {
+                let db_options = db_options.large_file(is_large(db.len() as u64));
+                outzip.start_file(db_name, db_options).map_err(zip_error)?;
+                copy_content(db, &mut outzip, zstd_compressed)
+            })?,
+        }
+        self.conn
+            .close()
+            .map_err(|(_, error)| database_error(error))?;
+
+        let names = names.into_iter();
+        if zstd_compressed {
+            #[cfg(feature = "modern-format")]
+            {
+                let stored = options.compression_method(CompressionMethod::Stored);
+                outzip
+                    .start_file("collection.anki2", options)
+                    .map_err(zip_error)?;
+                outzip.write_all(&modern_format::update_collection(self.timestamp)?)?;
+                outzip.start_file("meta", stored).map_err(zip_error)?;
+                outzip.write_all(&modern_format::meta())?;
+                let entries = modern_format::media_entries(names.zip(media.iter()))?;
+                outzip.start_file("media", stored).map_err(zip_error)?;
+                outzip.write_all(&zstd::bulk::compress(&entries, 0)?)?;
+            }
+        } else {
+            let media_map = names
+                .enumerate()
+                .map(|(id, name)| (id.to_string(), name))
+                .collect::<BTreeMap<String, String>>();
+            let media_json = serde_json::to_string(&media_map).map_err(json_error)?;
+            outzip.start_file("media", options).map_err(zip_error)?;
+            outzip.write_all(media_json.as_bytes())?;
+        }
+        let mut writer = outzip.finish().map_err(zip_error)?;
+
+        let summary = WriteSummary {
+            bytes: writer.stream_position()? - start,
+            notes,
+            cards,
+            models,
+            media_files: media.len(),
+        };
+        Ok((writer, summary))
+    }
+
+    /// Finishes the collection like [`PackageWriter::finish`] and returns it instead of writing
+    /// the package
+    pub(crate) fn finish_to_collection(mut self) -> Result<CollectionDump, Error> {
+        let notes = count_rows(&self.conn, "notes")?;
+        let cards = count_rows(&self.conn, "cards")?;
+        self.finish_collection()?;
+        let (decks, models, conf): (String, String, String) = self
+            .conn
+            .query_row("SELECT decks, models, conf FROM col", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .map_err(database_error)?;
+        let bytes = match &self.db_file {
+            Some(db_file) => std::fs::read(db_file)?,
+            None => with_serialized(&self.conn, self.commits, |db| Ok(db.to_vec()))?,
+        };
+        self.conn
+            .close()
+            .map_err(|(_, error)| database_error(error))?;
+        Ok(CollectionDump {
+            bytes,
+            decks: serde_json::from_str(&decks).map_err(json_error)?,
+            models: serde_json::from_str(&models).map_err(json_error)?,
+            conf: serde_json::from_str(&conf).map_err(json_error)?,
+            notes,
+            cards,
+        })
+    }
+
+    /// Checks the configuration of the collection, adds it to the database and commits the last
+    /// transaction
+    fn finish_collection(&mut self) -> Result<(), Error> {
+        if let Some(collection_conf) = &self.collection_conf {
+            if let Some(id) = collection_conf
+                .deck_ids()
+                .find(|id| *id != 1 && !self.deck_ids.contains(id))
+            {
+                return Err(Error::UnknownDeckId(id));
+            }
+        }
+        if self.scheduler_version == SchedulerVersion::V2
+            || self.collection_package
+            || self.collection_conf.is_some()
+        {
+            update_conf_in_col(&self.conn, |conf| {
+                if self.scheduler_version == SchedulerVersion::V2 {
+                    conf.insert("schedVer".to_string(), 2.into());
+                }
+                if self.collection_package {
+                    // Anki ignores the configuration when importing a `.apkg`, but uses it as it is
+                    // after importing a `.colpkg`
+                    if let Some(model_id) = self.model_ids.iter().min() {
+                        conf.insert("curModel".to_string(), model_id.to_string().into());
+                    }
+                    conf.insert("nextPos".to_string(), self.positions.start.into());
+                }
+                if let Some(collection_conf) = &self.collection_conf {
+                    collection_conf.apply(conf);
+                }
+            })?;
+        }
+        self.commit()
+    }
+}
+
+/// The zip modification time for a unix `timestamp` in UTC, or 1980-01-01 if zip can't represent
+/// it
+pub(crate) fn zip_time(timestamp: Timestamp) -> DateTime {
+    let seconds = timestamp.as_secs();
+    let (days, time) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    // The civil date of a day since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    u16::try_from(year)
+        .ok()
+        .and_then(|year| {
+            DateTime::from_date_and_time(
+                year,
+                month as u8,
+                day as u8,
+                (time / 3600) as u8,
+                (time / 60 % 60) as u8,
+                (time % 60) as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the decks above `decks` in the deck hierarchy that aren't in `decks`, without notes and
+/// with ids from [`Deck::id_from_name`]
+pub(crate) fn missing_parent_decks(decks: &[Deck]) -> Vec<Deck> {
+    let names: Vec<&str> = decks.iter().map(Deck::name).collect();
+    let mut parents: Vec<Deck> = vec![];
+    for name in decks.iter().flat_map(Deck::ancestor_names) {
+        if !names.contains(&name.as_str()) && parents.iter().all(|deck| deck.name() != name) {
+            parents.push(Deck::new(Deck::id_from_name(&name), name, ""));
+        }
+    }
+    parents
+}
+
+/// Returns `Error::IdOutOfRange` if the id of a deck isn't in `1..=MAX_ID`, or
+/// `Error::DuplicateDeckId` if decks with different names have the same id
+///
+/// The id `1` belongs to Anki's default deck, which every collection contains, so a deck with that
+/// id is only accepted if it's named "Default" and then stands for the default deck. Packages read
+/// with [`Package::open`](crate::Package::open) use it for notes without a deck.
+pub(crate) fn check_deck_ids(decks: &[&Deck]) -> Result<(), Error> {
+    let mut names: HashMap<i64, &str> = HashMap::new();
+    names.insert(1, "Default");
+    for deck in decks {
+        check_id(IdKind::Deck, deck.id())?;
+        match names.insert(deck.id(), deck.name()) {
+            Some(other) if other != deck.name() => {
+                return Err(Error::DuplicateDeckId {
+                    id: deck.id(),
+                    names: (other.to_string(), deck.name().to_string()),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Whether a file of `len` bytes needs the zip64 format, i.e. doesn't fit in 4 GiB
+///
+/// The zip writer switches to the zip64 format for the whole archive by itself if it has more
+/// than 65535 files or is larger than 4 GiB, but needs to know about large files beforehand.
+pub(crate) fn is_large(len: u64) -> bool {
+    len >= u64::from(u32::MAX)
+}
+
+/// Checks that two media files with different content aren't written with the same name, which
+/// can happen when their names are converted to Unicode NFC
+///
+/// Returns `Error::MediaNameCollision` for the first two files with the same name
+fn check_written_names(media: &MediaFiles, names: &[String]) -> Result<(), Error> {
+    let mut first_with_name: HashMap<&str, &MediaFile> = HashMap::new();
+    for (media_file, name) in media.iter().zip(names) {
+        if let Some(first) = first_with_name.insert(name, media_file) {
+            if first.content_hash()? != media_file.content_hash()? {
+                return Err(Error::MediaNameCollision {
+                    name: name.clone(),
+                    first_source: first.source(),
+                    second_source: media_file.source(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `media_file` to `outzip` as the entry named `idx`, compressed with zstd instead of
+/// Deflate if `zstd_compressed`
+pub(crate) fn write_media_entry<Z: Write + Seek>(
+    outzip: &mut ZipWriter<Z>,
+    idx: usize,
+    media_file: &MediaFile,
+    options: FileOptions,
+    compression: Option<&CompressionOptions>,
+    zstd_compressed: bool,
+) -> Result<(), Error> {
+    let stored = matches!(compression, Some(c) if c.is_stored(media_file.name()));
+    let method = if stored || zstd_compressed {
+        CompressionMethod::Stored
+    } else {
+        CompressionMethod::Deflated
+    };
+    let media_options = options
+        .compression_method(method)
+        .large_file(is_large(media_file.len()?));
+    outzip
+        .start_file(idx.to_string(), media_options)
+        .map_err(zip_error)?;
+    match media_file {
+        MediaFile::Path { path, .. } => copy_content(open_media(path)?, outzip, zstd_compressed),
+        MediaFile::Bytes { data, .. } => copy_content(&data[..], outzip, zstd_compressed),
+    }
+}
+
+/// Copies `content` to `outzip`, compressed with zstd if `zstd_compressed`
+fn copy_content(
+    mut content: impl Read,
+    outzip: &mut impl Write,
+    zstd_compressed: bool,
+) -> Result<(), Error> {
+    if zstd_compressed {
+        #[cfg(feature = "modern-format")]
+        zstd::stream::copy_encode(content, outzip, 0)?;
+    } else {
+        std::io::copy(&mut content, outzip)?;
+    }
+    Ok(())
+}
+
+/// The end of the media files from `start` that are compressed in parallel, which are at most
+/// `max_len` bytes each and [`PARALLEL_GROUP_LEN`] together, because they are compressed into
+/// memory
+#[cfg(feature = "parallel")]
+fn parallel_group_end(files: &[&MediaFile], start: usize, max_len: u64) -> Result<usize, Error> {
+    let mut group_len = 0;
+    let mut end = start;
+    while let Some(file) = files.get(end) {
+        let len = file.len()?;
+        if len > max_len || group_len + len > PARALLEL_GROUP_LEN {
+            break;
+        }
+        group_len += len;
+        end += 1;
+    }
+    Ok(end)
+}
+
+/// Clears the external attributes of the only entry of the archive `zip`
+///
+/// `ZipWriter::raw_copy_file` keeps only the permissions of an entry with attributes, but gives
+/// an entry without them the same attributes as `ZipWriter::start_file`, i.e. those of a regular
+/// file.
+#[cfg(feature = "parallel")]
+fn clear_external_attributes(zip: &mut [u8]) {
+    // The archive has no comment, so the end of central directory record is its last 22 bytes
+    let end = zip.len() - 22;
+    // Invariant: the archive was just written with a single entry, so the offsets are in bounds
+    let offset = <[u8; 4]>::try_from(&zip[end + 16..end + 20]).expect("4 bytes");
+    let header = u32::from_le_bytes(offset) as usize;
+    zip[header + 38..header + 42].fill(0);
+}
+
+/// Adds `model` to the models of the collection in `conn`
+pub(crate) fn write_model_to_col(
+    conn: &Connection,
+    model: &Model,
+    timestamp: Timestamp,
+    deck_id: i64,
+) -> Result<(), Error> {
+    check_id(IdKind::Model, model.id)?;
+    let models_json_str: String = conn
+        .query_row("SELECT models FROM col", [], |row| row.get(0))
+        .map_err(database_error)?;
+    let mut models: BTreeMap<i64, ModelDbEntry> =
+        serde_json::from_str(&models_json_str).map_err(json_error)?;
+    models.insert(model.id, model.to_model_db_entry(timestamp, deck_id)?);
+    conn.execute(
+        "UPDATE col SET models = ?",
+        [serde_json::to_string(&models).map_err(json_error)?],
+    )
+    .map_err(database_error)?;
+    Ok(())
+}
+
+/// Changes the configuration of the collection in `conn` with `f`
+fn update_conf_in_col(
+    conn: &Connection,
+    f: impl FnOnce(&mut serde_json::Map<String, serde_json::Value>),
+) -> Result<(), Error> {
+    let conf_json_str: String = conn
+        .query_row("SELECT conf FROM col", [], |row| row.get(0))
+        .map_err(database_error)?;
+    let mut conf: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&conf_json_str).map_err(json_error)?;
+    f(&mut conf);
+    conn.execute(
+        "UPDATE col SET conf = ?",
+        [serde_json::to_string(&conf).map_err(json_error)?],
+    )
+    .map_err(database_error)?;
+    Ok(())
+}
+
+fn count_rows(conn: &Connection, table: &str) -> Result<usize, Error> {
+    conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+        row.get(0)
+    })
+    .map_err(database_error)
+}
+
+/// Opens the database in the temporary file `path` and starts a transaction
+fn open_temp_database(path: &Path) -> Result<Connection, Error> {
+    let conn = Connection::open(path).map_err(database_error)?;
+    // The database is thrown away if writing fails, so it doesn't need to survive crashes
+    conn.execute_batch("PRAGMA synchronous = OFF; PRAGMA journal_mode = MEMORY; BEGIN")
+        .map_err(database_error)?;
+    Ok(conn)
+}
+
+/// Calls `f` with the bytes of the in-memory database of `conn`, i.e. the content its file would
+/// have after `commits` transactions
+pub(crate) fn with_serialized<T>(
+    conn: &Connection,
+    commits: u32,
+    f: impl FnOnce(&[u8]) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut size: ffi::sqlite3_int64 = 0;
+    // SAFETY: The handle is valid while `conn` is borrowed, and `sqlite3_serialize` returns a copy
+    // of the database that is owned by the caller, or null if it can't allocate it
+    let data =
+        unsafe { ffi::sqlite3_serialize(conn.handle(), b"main\0".as_ptr().cast(), &mut size, 0) };
+    if data.is_null() {
+        let error = ffi::Error::new(ffi::SQLITE_NOMEM);
+        return Err(database_error(rusqlite::Error::SqliteFailure(error, None)));
+    }
+    // SAFETY: `data` points to `size` bytes that are only freed below
+    let db = unsafe { std::slice::from_raw_parts_mut(data, size as usize) };
+    // SQLite only updates the change counter and the version in the header of database files,
+    // see https://www.sqlite.org/fileformat.html#the_database_header
+    db[24..28].copy_from_slice(&commits.to_be_bytes());
+    db[92..96].copy_from_slice(&commits.to_be_bytes());
+    db[96..100].copy_from_slice(&ffi::SQLITE_VERSION_NUMBER.to_be_bytes());
+    let result = f(db);
+    // SAFETY: `data` was allocated by SQLite and isn't used after this
+    unsafe { ffi::sqlite3_free(data.cast()) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_model;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn open_collection(bytes: Vec<u8>, dir: &TempDir) -> Connection {
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let db_path = dir.path().join("collection.anki2");
+        std::io::copy(
+            &mut archive.by_name("collection.anki2").unwrap(),
+            &mut File::create(&db_path).unwrap(),
+        )
+        .unwrap();
+        Connection::open(&db_path).unwrap()
+    }
+
+    #[test]
+    fn zip_times() {
+        let parts = |time: DateTime| {
+            let date = (time.year(), time.month(), time.day());
+            (date, time.hour(), time.minute(), time.second())
+        };
+        assert_eq!(
+            parts(zip_time(Timestamp::from_millis(1700000000500))),
+            ((2023, 11, 14), 22, 13, 20)
+        );
+        assert_eq!(
+            parts(zip_time(Timestamp::from_secs(951782400))),
+            ((2000, 2, 29), 0, 0, 0)
+        );
+        assert_eq!(
+            parts(zip_time(Timestamp::from_secs(0))),
+            ((1980, 1, 1), 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn move_to_tempfile() {
+        let write = |use_tempfile: bool, move_after: Option<usize>| {
+            let decks = [Deck::new(1234, "Numbers", "")];
+            let cursor = Cursor::new(vec![]);
+            let mut writer = PackageWriter::new_maybe_timestamp(
+                cursor,
+                vec![],
+                &decks,
+                Some(Timestamp::from_secs(1700000000)),
+                use_tempfile,
+            )
+            .unwrap()
+            .batch_size(2);
+            for i in 0..9 {
+                if move_after == Some(i) {
+                    writer = writer.use_tempfile().unwrap();
+                    assert!(writer.db_file.as_ref().unwrap().exists());
+                }
+                let note = Note::new(basic_model(), vec![&i.to_string(), "a"]).unwrap();
+                writer.add_note(1234, note).unwrap();
+            }
+            writer.finish().unwrap().into_inner()
+        };
+        let bytes = write(false, None);
+        assert!(bytes == write(true, None));
+        assert!(bytes == write(false, Some(4)));
+        assert!(bytes == write(false, Some(5)));
+
+        let dir = TempDir::new().unwrap();
+        let conn = open_collection(bytes, &dir);
+        let count: usize = conn
+            .query_row("SELECT COUNT(*) FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 9);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_media_compression() {
+        use std::io::Read;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("large.txt");
+        std::fs::write(&path, "large".repeat(1000)).unwrap();
+        let write = |parallel_max_len: u64| {
+            let decks = [Deck::new(1234, "Media", "")];
+            let mut writer =
+                PackageWriter::new_with_timestamp(Cursor::new(vec![]), vec![], &decks, 1.7e9)
+                    .unwrap()
+                    .compression(CompressionOptions::new());
+            writer.parallel_max_len = parallel_max_len;
+            for i in 0..40 {
+                let extension = if i % 3 == 0 { "mp3" } else { "txt" };
+                let data = format!("{} ", i).repeat(i * 50);
+                writer
+                    .add_media_bytes(&format!("{}.{}", i, extension), data)
+                    .unwrap();
+                if i == 20 {
+                    writer.add_media_file(&path).unwrap();
+                }
+            }
+            writer.finish().unwrap().into_inner()
+        };
+        let serial = write(0);
+        assert!(serial == write(4000));
+        assert!(serial == write(PARALLEL_MAX_LEN));
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(serial)).unwrap();
+        let mut large = String::new();
+        archive
+            .by_name("21")
+            .unwrap()
+            .read_to_string(&mut large)
+            .unwrap();
+        assert_eq!(large, "large".repeat(1000));
+    }
+
+    #[test]
+    fn unknown_deck_and_new_models() {
+        let decks = [Deck::new(1234, "A::B", "")];
+        let mut writer =
+            PackageWriter::new_with_timestamp(Cursor::new(vec![]), vec![], &decks, 1.0).unwrap();
+        let note = || Note::new(basic_model(), vec!["a", "b"]).unwrap();
+        assert!(matches!(
+            writer.add_note(42, note()),
+            Err(Error::UnknownDeckId(42))
+        ));
+        writer.add_note(1234, note()).unwrap();
+        writer.add_note(Deck::id_from_name("A"), note()).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let dir = TempDir::new().unwrap();
+        let conn = open_collection(bytes, &dir);
+        let (decks, models): (String, String) = conn
+            .query_row("SELECT decks, models FROM col", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        let decks: HashMap<String, serde_json::Value> = serde_json::from_str(&decks).unwrap();
+        let mut names: Vec<&str> = decks
+            .values()
+            .map(|d| d["name"].as_str().unwrap())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["A", "A::B", "Default"]);
+        let models: HashMap<String, serde_json::Value> = serde_json::from_str(&models).unwrap();
+        assert_eq!(models[&basic_model().id.to_string()]["did"], 1234);
+    }
+}
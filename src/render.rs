@@ -0,0 +1,214 @@
+//! Rendering the templates of a model for a note, see
+//! [`Model::render_preview`](crate::Model::render_preview)
+
+use fancy_regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::furigana;
+use crate::note::cloze_open;
+use crate::template_parser::Node;
+use crate::util::{field_checksum, strip_html};
+
+/// The HTML of both sides of a card, see [`Model::render_preview`](crate::Model::render_preview)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenderedCard {
+    pub question_html: String,
+    pub answer_html: String,
+    /// The CSS of the model, which Anki puts around both sides
+    pub css: String,
+}
+
+/// What the replacements of a template are rendered with
+pub(crate) struct Context<'a> {
+    /// The fields of the note and the special fields, like `Tags`
+    pub(crate) fields: HashMap<&'a str, &'a str>,
+    /// The cloze number of the card, which is its ord plus one
+    pub(crate) cloze_number: u32,
+    pub(crate) question: bool,
+}
+
+/// Renders `nodes` like Anki does, with the field `FrontSide` only set on the answer side
+pub(crate) fn render(nodes: &[Node], context: &Context) -> String {
+    let mut html = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(text) => html.push_str(text),
+            Node::Replacement { key, filters } => {
+                let value = context.fields.get(key).copied().unwrap_or_default();
+                // The filter next to the field is applied first
+                let value = filters
+                    .iter()
+                    .rev()
+                    .fold(value.to_string(), |value, filter| {
+                        apply_filter(filter, &value, key, context)
+                    });
+                html.push_str(&value);
+            }
+            Node::Conditional { key, children } | Node::NegatedConditional { key, children } => {
+                let value = context.fields.get(key).copied().unwrap_or_default();
+                let negated = matches!(node, Node::NegatedConditional { .. });
+                if field_is_empty(value) == negated {
+                    html.push_str(&render(children, context));
+                }
+            }
+        }
+    }
+    html
+}
+
+/// Whether a field counts as empty for `{{#Field}}` sections, which is also the case if it only
+/// contains whitespace, `<br>` and `<div>` tags
+pub(crate) fn field_is_empty(field: &str) -> bool {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    let regex = REGEX
+        .get_or_init(|| Regex::new(r"(?si)^(?:\s|</?(?:br|div)\s*/?>)*$").expect("static regex"));
+    regex.is_match(field).unwrap_or(false)
+}
+
+fn apply_filter(filter: &str, value: &str, field_name: &str, context: &Context) -> String {
+    match filter {
+        "cloze" => render_clozes(value, context.cloze_number, context.question),
+        "hint" if value.trim().is_empty() => String::new(),
+        "hint" => {
+            let id = field_checksum(value);
+            format!(
+                "<a class=hint href=\"#\" onclick=\"this.style.display='none';\
+                 document.getElementById('hint{id}').style.display='block';return false;\" \
+                 draggable=false>{}</a><div id=\"hint{id}\" class=hint style=\"display: none\">{}</div>",
+                field_name,
+                value,
+                id = id
+            )
+        }
+        "text" => strip_html(value),
+        // The input box and the comparison with the typed answer only exist in Anki
+        "type" => String::new(),
+        "furigana" => furigana::to_ruby(value),
+        "kana" => furigana::readings_only(value),
+        "kanji" => furigana::strip_readings(value),
+        // Like Anki, filters it doesn't know, e.g. of add-ons, leave the field unchanged
+        _ => value.to_string(),
+    }
+}
+
+/// A part of a field with cloze deletions
+#[derive(Debug, PartialEq, Eq)]
+enum ClozeNode<'a> {
+    Text(&'a str),
+    Cloze {
+        number: u32,
+        children: Vec<ClozeNode<'a>>,
+        hint: Option<&'a str>,
+    },
+}
+
+/// A cloze deletion that is still open while parsing
+struct OpenCloze<'a> {
+    number: u32,
+    /// The offset of the `{{c`, where the deletion starts if it is never closed
+    start: usize,
+    children: Vec<ClozeNode<'a>>,
+    /// The offset after the `::` that starts the hint
+    hint_start: Option<usize>,
+}
+
+/// Adds `node` to the innermost open deletion, or to `root`
+fn push_node<'a>(open: &mut [OpenCloze<'a>], root: &mut Vec<ClozeNode<'a>>, node: ClozeNode<'a>) {
+    match open.last_mut() {
+        Some(cloze) => cloze.children.push(node),
+        None => root.push(node),
+    }
+}
+
+/// Parses the cloze deletions of `text` the way cards are generated from them, see
+/// [`Note::cards`](crate::Note::cards); deletions that are never closed stay text
+fn parse_clozes(text: &str) -> Vec<ClozeNode<'_>> {
+    let mut root = vec![];
+    let mut open: Vec<OpenCloze> = vec![];
+    let mut text_start = 0;
+    let mut offset = 0;
+    while offset < text.len() {
+        let rest = &text[offset..];
+        let in_hint = open.last().is_some_and(|cloze| cloze.hint_start.is_some());
+        let text_before = (offset > text_start).then(|| ClozeNode::Text(&text[text_start..offset]));
+        if let (false, Some((number, after))) = (in_hint, cloze_open(rest)) {
+            if let Some(node) = text_before {
+                push_node(&mut open, &mut root, node);
+            }
+            open.push(OpenCloze {
+                number,
+                start: offset,
+                children: vec![],
+                hint_start: None,
+            });
+            offset = text.len() - after.len();
+            text_start = offset;
+        } else if !open.is_empty() && !in_hint && rest.starts_with("::") {
+            offset += 2;
+            // Invariant: `open` isn't empty
+            let cloze = open.last_mut().expect("Should have an open cloze");
+            cloze.children.extend(text_before);
+            cloze.hint_start = Some(offset);
+            text_start = offset;
+        } else if !open.is_empty() && rest.starts_with("}}") {
+            // Invariant: `open` isn't empty
+            let mut cloze = open.pop().expect("Should have an open cloze");
+            let hint = match cloze.hint_start {
+                Some(start) => Some(&text[start..offset]),
+                None => {
+                    cloze.children.extend(text_before);
+                    None
+                }
+            };
+            let node = ClozeNode::Cloze {
+                number: cloze.number,
+                children: cloze.children,
+                hint,
+            };
+            push_node(&mut open, &mut root, node);
+            offset += 2;
+            text_start = offset;
+        } else {
+            offset += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    match open.first() {
+        // The deletions inside a deletion that is never closed are text as well
+        Some(outermost) => root.push(ClozeNode::Text(&text[outermost.start..])),
+        None if text.len() > text_start => root.push(ClozeNode::Text(&text[text_start..])),
+        None => {}
+    }
+    root
+}
+
+/// Renders the deletions with `number` as `[...]` or their hint on the question side and
+/// highlighted on the answer side, and the other deletions as their text
+fn render_clozes(field: &str, number: u32, question: bool) -> String {
+    fn render_nodes(nodes: &[ClozeNode], number: u32, question: bool, html: &mut String) {
+        for node in nodes {
+            match node {
+                ClozeNode::Text(text) => html.push_str(text),
+                ClozeNode::Cloze {
+                    number: n,
+                    children,
+                    hint,
+                } if *n == number => {
+                    html.push_str("<span class=cloze>");
+                    if question {
+                        html.push('[');
+                        html.push_str(hint.unwrap_or("..."));
+                        html.push(']');
+                    } else {
+                        render_nodes(children, number, question, html);
+                    }
+                    html.push_str("</span>");
+                }
+                ClozeNode::Cloze { children, .. } => render_nodes(children, number, question, html),
+            }
+        }
+    }
+    let mut html = String::new();
+    render_nodes(&parse_clozes(field), number, question, &mut html);
+    html
+}
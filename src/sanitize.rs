@@ -0,0 +1,75 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::builders::SanitizePolicy;
+
+/// The tags that are removed with their content, since it isn't text
+const REMOVED_WITH_CONTENT: [&str; 3] = ["script", "style", "iframe"];
+
+/// Removes the HTML that `policy` doesn't allow from `field`, see
+/// [`Note::sanitize_html`](crate::Note::sanitize_html)
+pub(crate) fn sanitize_html(field: &str, policy: &SanitizePolicy) -> String {
+    let tags: HashSet<&str> = policy
+        .get_tags()
+        .iter()
+        .map(String::as_str)
+        .filter(|tag| !REMOVED_WITH_CONTENT.contains(tag))
+        .collect();
+    let tag_attributes: HashMap<&str, HashSet<&str>> = tags
+        .iter()
+        .map(|&tag| {
+            let attributes = match tag {
+                "img" => ["src", "alt"].iter().copied().collect(),
+                "a" => ["href", "style"].iter().copied().collect(),
+                _ => ["style"].iter().copied().collect(),
+            };
+            (tag, attributes)
+        })
+        .collect();
+    let style_properties = policy.get_style_properties().clone();
+    ammonia::Builder::empty()
+        .tags(tags)
+        .clean_content_tags(REMOVED_WITH_CONTENT.iter().copied().collect())
+        .generic_attributes(["class", "title", "lang"].iter().copied().collect())
+        .tag_attributes(tag_attributes)
+        .link_rel(None)
+        .url_schemes(
+            policy
+                .get_url_schemes()
+                .iter()
+                .map(String::as_str)
+                .collect(),
+        )
+        .attribute_filter(move |_, attribute, value| {
+            if attribute == "style" {
+                filter_style(value, &style_properties).map(Cow::Owned)
+            } else {
+                Some(value.into())
+            }
+        })
+        .clean(field)
+        .to_string()
+}
+
+/// Keeps the declarations of `style` with allowed properties, without `url(...)` and other
+/// functions that load or run something
+fn filter_style(style: &str, properties: &BTreeSet<String>) -> Option<String> {
+    let declarations: Vec<String> = style
+        .split(';')
+        .filter_map(|declaration| {
+            let (property, value) = declaration.split_once(':')?;
+            let property = property.trim().to_lowercase();
+            let value = value.trim();
+            let lowercase = value.to_lowercase();
+            let loads = ["url(", "expression(", "image(", "image-set("]
+                .iter()
+                .any(|function| lowercase.contains(function));
+            (properties.contains(&property) && !loads).then(|| format!("{}: {}", property, value))
+        })
+        .collect();
+    if declarations.is_empty() {
+        None
+    } else {
+        Some(declarations.join("; "))
+    }
+}
@@ -0,0 +1,399 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::{Deck, Error, Field, Model, ModelType, Note, Template};
+
+/// A row of the data a package is built from with
+/// [`Package::from_spec`](crate::Package::from_spec), which maps the names of the columns to
+/// their values
+pub type Record = BTreeMap<String, String>;
+
+/// A declarative description of a deck, its model and how the columns of the data are mapped to
+/// the fields of the notes, see [`Package::from_spec`](crate::Package::from_spec)
+///
+/// A spec is usually deserialized from a configuration file. All keys of a spec in TOML:
+///
+/// ```toml
+/// # The id and name of the deck, the description is optional
+/// id = 2059400110
+/// name = "German::Vocabulary"
+/// description = "Words from the course"
+/// # Tags that all notes get, optional
+/// tags = ["german"]
+/// # The column with additional tags of each note, separated by spaces, optional
+/// tags_column = "tags"
+/// # The column with the guid of each note, optional. By default, the guid is derived from the
+/// # fields of the note
+/// guid_column = "id"
+///
+/// # The columns of the fields by field name. Fields that aren't listed are read from the column
+/// # with the same name as the field, or are left empty if a record doesn't have that column
+/// [fields]
+/// Word = "word"
+/// Meaning = "translation"
+///
+/// [model]
+/// id = 1607392319
+/// name = "Vocabulary"
+/// # The names of the fields in the order Anki shows them
+/// fields = ["Word", "Meaning", "Example"]
+/// # Optional, the default is the first field
+/// sort_field = "Word"
+/// # Optional, `front_back` or `cloze`, the default is `front_back`
+/// type = "front_back"
+/// # Optional
+/// css = ".card { font-family: arial; }"
+///
+/// [[model.templates]]
+/// name = "Recognition"
+/// qfmt = "{{Word}}"
+/// afmt = "{{FrontSide}}<hr id=answer>{{Meaning}}<br>{{Example}}"
+/// ```
+///
+/// The same spec in JSON, built into a package:
+///
+/// ```rust
+/// use genanki_rs::{DeckSpec, Package, Record};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let spec: DeckSpec = serde_json::from_str(
+///     r#"{
+///         "id": 2059400110,
+///         "name": "German::Vocabulary",
+///         "description": "Words from the course",
+///         "tags": ["german"],
+///         "tags_column": "tags",
+///         "guid_column": "id",
+///         "fields": { "Word": "word", "Meaning": "translation" },
+///         "model": {
+///             "id": 1607392319,
+///             "name": "Vocabulary",
+///             "fields": ["Word", "Meaning", "Example"],
+///             "sort_field": "Word",
+///             "type": "front_back",
+///             "css": ".card { font-family: arial; }",
+///             "templates": [{
+///                 "name": "Recognition",
+///                 "qfmt": "{{Word}}",
+///                 "afmt": "{{FrontSide}}<hr id=answer>{{Meaning}}<br>{{Example}}"
+///             }]
+///         }
+///     }"#,
+/// )?;
+/// let record: Record = [
+///     ("id", "1"),
+///     ("word", "der Hund"),
+///     ("translation", "the dog"),
+///     ("Example", "Der Hund bellt."),
+///     ("tags", "nouns animals"),
+/// ]
+/// .iter()
+/// .map(|(column, value)| (column.to_string(), value.to_string()))
+/// .collect();
+/// let mut package = Package::from_spec(&spec, vec![record])?;
+/// let bytes = package.write_to_bytes()?;
+/// assert!(!bytes.is_empty());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeckSpec {
+    pub id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub model: ModelSpec,
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub tags_column: Option<String>,
+    #[serde(default)]
+    pub guid_column: Option<String>,
+}
+
+/// The model of a [`DeckSpec`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelSpec {
+    pub id: i64,
+    pub name: String,
+    pub fields: Vec<String>,
+    pub templates: Vec<TemplateSpec>,
+    #[serde(default)]
+    pub sort_field: Option<String>,
+    #[serde(rename = "type", default = "front_back")]
+    pub model_type: ModelType,
+    #[serde(default)]
+    pub css: String,
+}
+
+/// A card template of a [`ModelSpec`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateSpec {
+    pub name: String,
+    pub qfmt: String,
+    pub afmt: String,
+}
+
+fn front_back() -> ModelType {
+    ModelType::FrontBack
+}
+
+/// Builds the deck of [`Package::from_spec`](crate::Package::from_spec) with a note for each
+/// record of `data`
+pub(crate) fn deck_from_spec(
+    spec: &DeckSpec,
+    data: impl IntoIterator<Item = Record>,
+) -> Result<Deck, Error> {
+    let model = Arc::new(spec.model.to_model()?);
+    for field in spec.fields.keys() {
+        if !spec.model.fields.contains(field) {
+            return Err(invalid(
+                format!("fields.{}", field),
+                format!("the model has no field {:?}", field),
+            ));
+        }
+    }
+    let columns: Vec<(&str, bool)> = spec
+        .model
+        .fields
+        .iter()
+        .map(|field| match spec.fields.get(field) {
+            Some(column) => (column.as_str(), true),
+            None => (field.as_str(), false),
+        })
+        .collect();
+
+    let mut deck = Deck::new(spec.id, &spec.name, &spec.description);
+    for (index, record) in (1..).zip(data) {
+        let mut fields = vec![];
+        for (field, (column, required)) in spec.model.fields.iter().zip(&columns) {
+            match record.get(*column) {
+                Some(value) => fields.push(value.as_str()),
+                None if *required => {
+                    return Err(invalid(
+                        format!("fields.{}", field),
+                        format!("record {} has no column {:?}", index, column),
+                    ))
+                }
+                None => fields.push(""),
+            }
+        }
+        let record_tags = match &spec.tags_column {
+            Some(column) => record.get(column).map_or("", String::as_str),
+            None => "",
+        };
+        let tags = spec
+            .tags
+            .iter()
+            .map(String::as_str)
+            .chain(record_tags.split_whitespace());
+        let mut note = Note::new(model.clone(), fields)?.tags(tags)?;
+        if let Some(column) = &spec.guid_column {
+            let guid = record.get(column).ok_or_else(|| {
+                invalid(
+                    "guid_column",
+                    format!("record {} has no column {:?}", index, column),
+                )
+            })?;
+            note = note.guid(guid);
+        }
+        deck.add_note(note);
+    }
+    Ok(deck)
+}
+
+impl ModelSpec {
+    /// Creates the model, with errors that point at the part of the spec that is invalid
+    fn to_model(&self) -> Result<Model, Error> {
+        if self.fields.is_empty() {
+            return Err(invalid("model.fields", "the model has no fields"));
+        }
+        if self.templates.is_empty() {
+            return Err(invalid("model.templates", "the model has no templates"));
+        }
+        let sort_field = match &self.sort_field {
+            Some(name) => self
+                .fields
+                .iter()
+                .position(|field| field == name)
+                .ok_or_else(|| {
+                    invalid(
+                        "model.sort_field",
+                        format!("the model has no field {:?}", name),
+                    )
+                })?,
+            None => 0,
+        };
+        let model = Model::new(
+            self.id,
+            &self.name,
+            self.fields.iter().map(Field::new).collect(),
+            self.templates
+                .iter()
+                .map(|template| {
+                    Template::new(&template.name)
+                        .qfmt(&template.qfmt)
+                        .afmt(&template.afmt)
+                })
+                .collect(),
+        )
+        .model_type(self.model_type)
+        .css(&self.css)
+        .sort_field(sort_field);
+        model.validate().map_err(|e| {
+            let path = match &e {
+                Error::DuplicateFieldName { index, .. } => format!("model.fields[{}]", index),
+                Error::DuplicateTemplateName { index, .. } => format!("model.templates[{}]", index),
+                Error::TemplateSyntax { template_name, .. }
+                | Error::UnknownTemplateField { template_name, .. }
+                | Error::ClozeTemplateWithoutCloze { template_name } => {
+                    match self.templates.iter().position(|t| t.name == *template_name) {
+                        Some(index) => format!("model.templates[{}]", index),
+                        None => "model.templates".to_string(),
+                    }
+                }
+                Error::ClozeTemplateCount(_) => "model.templates".to_string(),
+                _ => "model".to_string(),
+            };
+            invalid(path, e.to_string())
+        })?;
+        Ok(model)
+    }
+}
+
+fn invalid(path: impl ToString, message: impl ToString) -> Error {
+    Error::InvalidSpec {
+        path: path.to_string(),
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Package;
+
+    const SPEC: &str = include_str!("../tests/fixtures/vocabulary.toml");
+
+    fn record(values: &[(&str, &str)]) -> Record {
+        values
+            .iter()
+            .map(|(column, value)| (column.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn spec_error(spec: &DeckSpec, data: Vec<Record>) -> (String, String) {
+        match deck_from_spec(spec, data) {
+            Err(Error::InvalidSpec { path, message }) => (path, message),
+            Err(e) => panic!("expected an invalid spec, got {:?}", e),
+            Ok(_) => panic!("expected an invalid spec"),
+        }
+    }
+
+    #[test]
+    fn package_from_toml_spec() {
+        let spec: DeckSpec = toml::from_str(SPEC).unwrap();
+        let data = vec![
+            record(&[
+                ("word", "der Hund"),
+                ("translation", "the dog"),
+                ("Example", "Der Hund bellt."),
+                ("tags", "nouns animals"),
+            ]),
+            record(&[("word", "die Katze"), ("translation", "the cat")]),
+            record(&[
+                ("word", "laufen"),
+                ("translation", "to run"),
+                ("tags", "verbs"),
+                ("unused", "ignored"),
+            ]),
+        ];
+        let deck = deck_from_spec(&spec, data.clone()).unwrap();
+        assert_eq!((deck.id(), deck.name()), (2059400110, "German::Vocabulary"));
+        let notes: Vec<_> = deck.notes().collect();
+        assert_eq!(notes.len(), 3);
+        assert_eq!(
+            notes[0].fields(),
+            &["der Hund", "the dog", "Der Hund bellt."]
+        );
+        assert_eq!(notes[1].fields(), &["die Katze", "the cat", ""]);
+        assert_eq!(notes[0].get_tags(), &["german", "nouns", "animals"]);
+        assert_eq!(notes[1].get_tags(), &["german"]);
+        assert_eq!(notes[2].get_tags(), &["german", "verbs"]);
+
+        let model = notes[0].model();
+        assert_eq!(model.name(), "Vocabulary");
+        assert_eq!(model.field_names(), vec!["Word", "Meaning", "Example"]);
+        assert_eq!(model.template_names(), vec!["Recognition", "Recall"]);
+        assert_eq!(model.get_sort_field_index(), 0);
+        assert_eq!(model.get_css(), ".card { font-family: arial; }\n");
+
+        let package = Package::from_spec(&spec, data).unwrap();
+        assert_eq!(package.validate().unwrap().warnings, vec![]);
+    }
+
+    #[test]
+    fn invalid_specs() {
+        let spec: DeckSpec = toml::from_str(SPEC).unwrap();
+        let data = vec![record(&[("word", "der Hund"), ("translation", "the dog")])];
+
+        let mut unknown_field = spec.clone();
+        unknown_field
+            .fields
+            .insert("Meening".to_string(), "translation".to_string());
+        assert_eq!(
+            spec_error(&unknown_field, data.clone()),
+            (
+                "fields.Meening".to_string(),
+                "the model has no field \"Meening\"".to_string()
+            )
+        );
+
+        let mut no_templates = spec.clone();
+        no_templates.model.templates.clear();
+        assert_eq!(spec_error(&no_templates, data.clone()).0, "model.templates");
+
+        let mut unknown_sort_field = spec.clone();
+        unknown_sort_field.model.sort_field = Some("Wort".to_string());
+        assert_eq!(
+            spec_error(&unknown_sort_field, data.clone()).0,
+            "model.sort_field"
+        );
+
+        let mut bad_template = spec.clone();
+        bad_template.model.templates[1].qfmt = "{{Wort}}".to_string();
+        assert_eq!(
+            spec_error(&bad_template, data.clone()).0,
+            "model.templates[1]"
+        );
+
+        let mut duplicate_field = spec.clone();
+        duplicate_field.model.fields.push("Word".to_string());
+        assert_eq!(
+            spec_error(&duplicate_field, data.clone()).0,
+            "model.fields[3]"
+        );
+
+        let missing_column = vec![data[0].clone(), record(&[("word", "die Katze")])];
+        assert_eq!(
+            spec_error(&spec, missing_column),
+            (
+                "fields.Meaning".to_string(),
+                "record 2 has no column \"translation\"".to_string()
+            )
+        );
+
+        let mut guid_column = spec;
+        guid_column.guid_column = Some("id".to_string());
+        assert_eq!(spec_error(&guid_column, data).0, "guid_column");
+
+        let unknown_key = SPEC.replace("tags_column", "tag_column");
+        assert!(toml::from_str::<DeckSpec>(&unknown_key).is_err());
+    }
+}
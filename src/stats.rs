@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::model::ModelType;
+use crate::note::Note;
+
+/// The numbers of notes, cards, empty fields and tags of a deck, see [`Deck::stats`]
+///
+/// The `Display` implementation prints them as a table.
+///
+/// [`Deck::stats`]: crate::Deck::stats
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeckStats {
+    pub notes: usize,
+    pub cards: usize,
+    /// The numbers of each model, in the order the models are first used
+    pub models: Vec<ModelStats>,
+    /// The number of notes with each tag, including the default tags of the deck
+    pub tags: BTreeMap<String, usize>,
+}
+
+/// The numbers of the notes of one model in a deck
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModelStats {
+    pub id: i64,
+    pub name: String,
+    pub notes: usize,
+    pub cards: usize,
+    /// The cards of each template by ord, with all templates of a `FrontBack` model and the
+    /// cloze numbers of a `Cloze` model that have cards, named like `c1`
+    pub templates: BTreeMap<i64, TemplateStats>,
+    /// The names of the fields and the number of notes that leave them empty or only contain
+    /// whitespace, in the order of the fields
+    pub empty_fields: Vec<(String, usize)>,
+}
+
+/// The cards of one template of a model, see [`ModelStats::templates`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateStats {
+    pub name: String,
+    pub cards: usize,
+}
+
+impl DeckStats {
+    pub(crate) fn new(notes: &[Note], default_tags: &[String]) -> Self {
+        let mut stats = Self::default();
+        for note in notes {
+            stats.notes += 1;
+            stats.cards += note.card_count();
+            for tag in note.tags_with(default_tags) {
+                *stats.tags.entry(tag).or_default() += 1;
+            }
+            let model = match stats.models.iter_mut().find(|m| m.id == note.model_id()) {
+                Some(model) => model,
+                None => {
+                    stats.models.push(ModelStats::new(note));
+                    // Invariant: a model was just pushed
+                    stats.models.last_mut().expect("Should have a model")
+                }
+            };
+            model.add(note);
+        }
+        stats
+    }
+}
+
+impl ModelStats {
+    /// Starts the numbers of the model of `note` at zero
+    fn new(note: &Note) -> Self {
+        let model = note.model();
+        let templates = match model.get_model_type() {
+            ModelType::FrontBack => model
+                .templates()
+                .iter()
+                .map(|template| {
+                    let name = template.name.clone();
+                    (template.ord, TemplateStats { name, cards: 0 })
+                })
+                .collect(),
+            ModelType::Cloze => BTreeMap::new(),
+        };
+        Self {
+            id: model.id,
+            name: model.name().to_string(),
+            notes: 0,
+            cards: 0,
+            templates,
+            empty_fields: model
+                .field_names()
+                .into_iter()
+                .map(|name| (name.to_string(), 0))
+                .collect(),
+        }
+    }
+
+    fn add(&mut self, note: &Note) {
+        self.notes += 1;
+        self.cards += note.card_count();
+        for card in note.cards() {
+            let template = self.templates.entry(card.ord()).or_insert_with(|| {
+                let name = format!("c{}", card.ord() + 1);
+                TemplateStats { name, cards: 0 }
+            });
+            template.cards += 1;
+        }
+        for ((_, empty), field) in self.empty_fields.iter_mut().zip(note.fields()) {
+            if field.trim().is_empty() {
+                *empty += 1;
+            }
+        }
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+/// Writes `rows` as a table with the columns `headings`, indented by two spaces
+fn write_rows<'a>(
+    f: &mut fmt::Formatter<'_>,
+    headings: (&str, &str),
+    rows: impl Iterator<Item = (&'a str, usize)> + Clone,
+) -> fmt::Result {
+    let width = rows
+        .clone()
+        .map(|(name, _)| name.chars().count())
+        .chain(std::iter::once(headings.0.len()))
+        .max()
+        .unwrap_or_default();
+    let count_width = headings.1.len();
+    writeln!(f, "  {:<width$}  {}", headings.0, headings.1, width = width)?;
+    for (name, count) in rows {
+        writeln!(
+            f,
+            "  {:<width$}  {:>count_width$}",
+            name,
+            count,
+            width = width,
+            count_width = count_width
+        )?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for DeckStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}, {}",
+            plural(self.notes, "note"),
+            plural(self.cards, "card")
+        )?;
+        for model in &self.models {
+            writeln!(
+                f,
+                "\n{} ({}): {}, {}",
+                model.name,
+                model.id,
+                plural(model.notes, "note"),
+                plural(model.cards, "card")
+            )?;
+            let templates = model.templates.values();
+            let templates = templates.map(|t| (t.name.as_str(), t.cards));
+            write_rows(f, ("template", "cards"), templates)?;
+            let fields = model.empty_fields.iter();
+            let fields = fields.map(|(name, empty)| (name.as_str(), *empty));
+            write_rows(f, ("field", "empty"), fields)?;
+        }
+        if !self.tags.is_empty() {
+            writeln!(f)?;
+            let tags = self.tags.iter().map(|(tag, notes)| (tag.as_str(), *notes));
+            write_rows(f, ("tag", "notes"), tags)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{basic_and_reversed_card_model, basic_optional_reversed_card_model, cloze_model};
+    use crate::{Deck, Note};
+
+    fn mixed_deck() -> Deck {
+        let mut deck = Deck::new(1234, "German", "")
+            .default_tags(["german"])
+            .unwrap();
+        let notes = vec![
+            Note::new(
+                basic_optional_reversed_card_model(),
+                vec!["der Hund", "dog", "y"],
+            ),
+            Note::new(
+                basic_optional_reversed_card_model(),
+                vec!["die Katze", " ", ""],
+            ),
+            Note::new(
+                cloze_model(),
+                vec!["{{c1::Berlin}} is in {{c3::Germany}}", ""],
+            ),
+            Note::new(
+                cloze_model(),
+                vec!["{{c1::Bern}} is in Switzerland", "Extra"],
+            ),
+            Note::new(basic_and_reversed_card_model(), vec!["laufen", "to run"]),
+        ];
+        let tags = [
+            vec!["nouns", "animals"],
+            vec!["Nouns"],
+            vec![],
+            vec!["geo"],
+            vec![],
+        ];
+        for (note, tags) in notes.into_iter().zip(tags.iter()) {
+            deck.add_note(note.unwrap().tags(tags).unwrap());
+        }
+        deck
+    }
+
+    #[test]
+    fn numbers() {
+        let stats = mixed_deck().stats();
+        assert_eq!((stats.notes, stats.cards), (5, 8));
+        let names: Vec<&str> = stats.models.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Basic (optional reversed card) (genanki)",
+                "Cloze (genanki)",
+                "Basic (and reversed card) (genanki)"
+            ]
+        );
+
+        let optional = &stats.models[0];
+        assert_eq!((optional.notes, optional.cards), (2, 3));
+        let templates: Vec<(i64, &str, usize)> = optional
+            .templates
+            .iter()
+            .map(|(ord, t)| (*ord, t.name.as_str(), t.cards))
+            .collect();
+        assert_eq!(templates, vec![(0, "Card 1", 2), (1, "Card 2", 1)]);
+        let empty_fields: Vec<(&str, usize)> = optional
+            .empty_fields
+            .iter()
+            .map(|(name, empty)| (name.as_str(), *empty))
+            .collect();
+        assert_eq!(
+            empty_fields,
+            vec![("Front", 0), ("Back", 1), ("Add Reverse", 1)]
+        );
+
+        let cloze = &stats.models[1];
+        let templates: Vec<(i64, &str, usize)> = cloze
+            .templates
+            .iter()
+            .map(|(ord, t)| (*ord, t.name.as_str(), t.cards))
+            .collect();
+        assert_eq!(templates, vec![(0, "c1", 2), (2, "c3", 1)]);
+        assert_eq!(cloze.empty_fields[1].1, 1);
+
+        let tags: Vec<(&str, usize)> = stats.tags.iter().map(|(t, n)| (t.as_str(), *n)).collect();
+        assert_eq!(
+            tags,
+            vec![
+                ("Nouns", 1),
+                ("animals", 1),
+                ("geo", 1),
+                ("german", 5),
+                ("nouns", 1)
+            ]
+        );
+        assert_eq!(Deck::new(1, "Empty", "").stats(), Default::default());
+    }
+
+    #[test]
+    fn display() {
+        let mut deck = Deck::new(1234, "German", "");
+        for note in mixed_deck().notes().skip(2).take(2) {
+            deck.add_note(note.clone());
+        }
+        assert_eq!(
+            deck.stats().to_string(),
+            "2 notes, 3 cards\n\
+             \n\
+             Cloze (genanki) (1550428389): 2 notes, 3 cards\n\
+             \x20 template  cards\n\
+             \x20 c1            2\n\
+             \x20 c3            1\n\
+             \x20 field       empty\n\
+             \x20 Text            0\n\
+             \x20 Back Extra      1\n\
+             \n\
+             \x20 tag  notes\n\
+             \x20 geo      1\n"
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! A small parser for the mustache-like template language used by Anki's `qfmt`/`afmt`.
+//!
+//! Only the parts of the language that influence card generation are modelled: text,
+//! `{{Field}}` replacements (optionally with filters like `{{cloze:Text}}`), and
+//! `{{#Field}}…{{/Field}}` / `{{^Field}}…{{/Field}}` sections.
+
+use std::collections::HashSet;
+use std::fmt;
+
+/// Replacements that are filled in by Anki itself instead of coming from a note field
+pub(crate) const SPECIAL_FIELDS: &[&str] = &[
+    "FrontSide",
+    "Tags",
+    "Type",
+    "Deck",
+    "Subdeck",
+    "Card",
+    "CardFlag",
+    "CardID",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Node<'a> {
+    Text(&'a str),
+    Replacement {
+        key: &'a str,
+        filters: Vec<&'a str>,
+    },
+    Conditional {
+        key: &'a str,
+        children: Vec<Node<'a>>,
+    },
+    NegatedConditional {
+        key: &'a str,
+        children: Vec<Node<'a>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ParseError {
+    /// A `{{` without a matching `}}`
+    UnclosedTag { offset: usize },
+    /// A `{{#X}}` or `{{^X}}` without a matching `{{/X}}`
+    UnclosedSection { key: String, offset: usize },
+    /// A `{{/X}}` that does not close the innermost open section
+    UnexpectedClose { key: String, offset: usize },
+    /// A `{{#X}}` or `{{^X}}` where `X` is neither a field nor a special field
+    UnknownSection { key: String, offset: usize },
+}
+
+impl ParseError {
+    /// The approximate byte offset of the offending tag in the template
+    pub(crate) fn offset(&self) -> usize {
+        match self {
+            ParseError::UnclosedTag { offset }
+            | ParseError::UnclosedSection { offset, .. }
+            | ParseError::UnexpectedClose { offset, .. }
+            | ParseError::UnknownSection { offset, .. } => *offset,
+        }
+    }
+
+    /// The offending tag, as written in `template`
+    pub(crate) fn tag<'a>(&self, template: &'a str) -> &'a str {
+        let tag = &template[self.offset()..];
+        match tag.find("}}") {
+            Some(end) => &tag[..end + 2],
+            None => tag,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnclosedTag { .. } => write!(f, "tag is never closed with \"}}}}\""),
+            ParseError::UnclosedSection { key, .. } => {
+                write!(f, "section \"{}\" is never closed", key)
+            }
+            ParseError::UnexpectedClose { key, .. } => {
+                write!(f, "\"{}\" does not close the innermost open section", key)
+            }
+            ParseError::UnknownSection { key, .. } => {
+                write!(f, "section \"{}\" is not a field", key)
+            }
+        }
+    }
+}
+
+enum Tag<'a> {
+    Open(&'a str),
+    NegatedOpen(&'a str),
+    Close(&'a str),
+    Replacement(&'a str),
+}
+
+struct Token<'a> {
+    offset: usize,
+    kind: TokenKind<'a>,
+}
+
+enum TokenKind<'a> {
+    Text(&'a str),
+    Tag(Tag<'a>),
+}
+
+fn tokenize(template: &str) -> Result<Vec<Token<'_>>, ParseError> {
+    let mut tokens = vec![];
+    let mut rest = template;
+    let mut offset = 0;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            tokens.push(Token {
+                offset,
+                kind: TokenKind::Text(&rest[..start]),
+            });
+        }
+        let after_open = &rest[start + 2..];
+        let end = after_open.find("}}").ok_or(ParseError::UnclosedTag {
+            offset: offset + start,
+        })?;
+        let mut inner = &after_open[..end];
+        let mut consumed = start + 2 + end + 2;
+        // Triple mustaches `{{{Field}}}` are equivalent to `{{Field}}`
+        if inner.starts_with('{') && after_open[end + 2..].starts_with('}') {
+            inner = &inner[1..];
+            consumed += 1;
+        }
+        tokens.push(Token {
+            offset: offset + start,
+            kind: TokenKind::Tag(classify_tag(inner.trim())),
+        });
+        rest = &rest[consumed..];
+        offset += consumed;
+    }
+    if !rest.is_empty() {
+        tokens.push(Token {
+            offset,
+            kind: TokenKind::Text(rest),
+        });
+    }
+    Ok(tokens)
+}
+
+fn classify_tag(inner: &str) -> Tag<'_> {
+    if let Some(key) = inner.strip_prefix('#') {
+        Tag::Open(key.trim())
+    } else if let Some(key) = inner.strip_prefix('^') {
+        Tag::NegatedOpen(key.trim())
+    } else if let Some(key) = inner.strip_prefix('/') {
+        Tag::Close(key.trim())
+    } else if let Some(key) = inner.strip_prefix('&') {
+        Tag::Replacement(key.trim())
+    } else {
+        Tag::Replacement(inner)
+    }
+}
+
+/// Parses a template into a tree of `Node`s
+pub(crate) fn parse(template: &str) -> Result<Vec<Node<'_>>, ParseError> {
+    let mut tokens = tokenize(template)?.into_iter();
+    let nodes = parse_inner(&mut tokens, None)?;
+    Ok(nodes)
+}
+
+/// Checks that the sections of a template are balanced and properly nested, and that every
+/// section refers to one of `field_names` or a special field.
+pub(crate) fn check(template: &str, field_names: &[&str]) -> Result<(), ParseError> {
+    parse(template)?;
+    for token in tokenize(template)? {
+        if let TokenKind::Tag(Tag::Open(key)) | TokenKind::Tag(Tag::NegatedOpen(key)) = token.kind {
+            if !field_names.contains(&key) && !SPECIAL_FIELDS.contains(&key) {
+                return Err(ParseError::UnknownSection {
+                    key: key.to_string(),
+                    offset: token.offset,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_inner<'a>(
+    tokens: &mut impl Iterator<Item = Token<'a>>,
+    open_section: Option<(&'a str, usize)>,
+) -> Result<Vec<Node<'a>>, ParseError> {
+    let mut nodes = vec![];
+    while let Some(token) = tokens.next() {
+        let tag = match token.kind {
+            TokenKind::Text(text) => {
+                nodes.push(Node::Text(text));
+                continue;
+            }
+            TokenKind::Tag(tag) => tag,
+        };
+        match tag {
+            Tag::Replacement(inner) => {
+                let mut parts: Vec<&str> = inner.split(':').map(str::trim).collect();
+                let key = parts.pop().unwrap_or_default();
+                nodes.push(Node::Replacement {
+                    key,
+                    filters: parts,
+                });
+            }
+            Tag::Open(key) => nodes.push(Node::Conditional {
+                key,
+                children: parse_inner(tokens, Some((key, token.offset)))?,
+            }),
+            Tag::NegatedOpen(key) => nodes.push(Node::NegatedConditional {
+                key,
+                children: parse_inner(tokens, Some((key, token.offset)))?,
+            }),
+            Tag::Close(key) => {
+                return match open_section {
+                    Some((open_key, _)) if open_key == key => Ok(nodes),
+                    _ => Err(ParseError::UnexpectedClose {
+                        key: key.to_string(),
+                        offset: token.offset,
+                    }),
+                }
+            }
+        }
+    }
+    match open_section {
+        Some((key, offset)) => Err(ParseError::UnclosedSection {
+            key: key.to_string(),
+            offset,
+        }),
+        None => Ok(nodes),
+    }
+}
+
+/// Returns whether rendering `nodes` with only the fields in `nonempty_fields` filled in
+/// would output the content of any field.
+///
+/// Type-in-the-answer boxes like `{{type:Back}}` or `{{type:cloze:Text}}` don't count, since
+/// they show an empty input box instead of the field's content.
+pub(crate) fn renders_with_fields(nodes: &[Node], nonempty_fields: &HashSet<&str>) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Text(_) => false,
+        Node::Replacement { key, filters } => {
+            filters.first() != Some(&"type") && nonempty_fields.contains(key)
+        }
+        Node::Conditional { key, children } => {
+            nonempty_fields.contains(key) && renders_with_fields(children, nonempty_fields)
+        }
+        Node::NegatedConditional { key, children } => {
+            !nonempty_fields.contains(key) && renders_with_fields(children, nonempty_fields)
+        }
+    })
+}
+
+/// Returns the names of all fields referenced by `{{…}}` replacements, excluding the
+/// special fields Anki fills in itself.
+pub(crate) fn referenced_fields<'a>(nodes: &[Node<'a>]) -> Vec<&'a str> {
+    let mut fields = vec![];
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            Node::Replacement { key, .. } => {
+                if !SPECIAL_FIELDS.contains(key) {
+                    fields.push(*key);
+                }
+            }
+            Node::Conditional { children, .. } | Node::NegatedConditional { children, .. } => {
+                fields.extend(referenced_fields(children))
+            }
+        }
+    }
+    fields
+}
+
+/// Returns whether any replacement uses the `cloze` filter, like `{{cloze:Text}}`
+pub(crate) fn has_cloze(nodes: &[Node]) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Text(_) => false,
+        Node::Replacement { filters, .. } => filters.contains(&"cloze"),
+        Node::Conditional { children, .. } | Node::NegatedConditional { children, .. } => {
+            has_cloze(children)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_replacements_and_sections() {
+        let nodes =
+            parse("{{Front}}<br>{{#Hint}}{{hint:Hint}}{{/Hint}}{{^Back}}none{{/Back}}").unwrap();
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Replacement {
+                    key: "Front",
+                    filters: vec![]
+                },
+                Node::Text("<br>"),
+                Node::Conditional {
+                    key: "Hint",
+                    children: vec![Node::Replacement {
+                        key: "Hint",
+                        filters: vec!["hint"]
+                    }]
+                },
+                Node::NegatedConditional {
+                    key: "Back",
+                    children: vec![Node::Text("none")]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_unbalanced_sections() {
+        assert_eq!(
+            parse("{{#Add Reverse}}{{Back}}"),
+            Err(ParseError::UnclosedSection {
+                key: "Add Reverse".to_string(),
+                offset: 0
+            })
+        );
+        assert_eq!(
+            parse("{{Front}}{{/Front}}"),
+            Err(ParseError::UnexpectedClose {
+                key: "Front".to_string(),
+                offset: 9
+            })
+        );
+        assert_eq!(
+            parse("text {{Front"),
+            Err(ParseError::UnclosedTag { offset: 5 })
+        );
+    }
+
+    #[test]
+    fn check_sections() {
+        let fields = &["Front", "Add Reverse"];
+        assert_eq!(
+            check(
+                "{{#Add Reverse}}{{Front}}{{/Add Reverse}}{{^Tags}}-{{/Tags}}",
+                fields
+            ),
+            Ok(())
+        );
+        let template = "{{Front}} {{#Add Reverse}}{{^Front}}x{{/Add Reverse}}{{/Front}}";
+        let error = check(template, fields).unwrap_err();
+        assert_eq!(
+            error,
+            ParseError::UnexpectedClose {
+                key: "Add Reverse".to_string(),
+                offset: 37
+            }
+        );
+        assert_eq!(error.tag(template), "{{/Add Reverse}}");
+
+        let template = "{{#AddReverse}}{{Front}}{{/AddReverse}}";
+        let error = check(template, fields).unwrap_err();
+        assert_eq!(
+            error,
+            ParseError::UnknownSection {
+                key: "AddReverse".to_string(),
+                offset: 0
+            }
+        );
+        assert_eq!(error.tag(template), "{{#AddReverse}}");
+        assert_eq!(error.to_string(), "section \"AddReverse\" is not a field");
+
+        let error = check("text {{Front", fields).unwrap_err();
+        assert_eq!(error.tag("text {{Front"), "{{Front");
+    }
+
+    #[test]
+    fn referenced_fields_skip_special_fields() {
+        let nodes = parse("{{FrontSide}}<hr>{{#Hint}}{{hint:Hint}}{{/Hint}}{{cloze:Text}}{{Tags}}")
+            .unwrap();
+        assert_eq!(referenced_fields(&nodes), vec!["Hint", "Text"]);
+    }
+
+    #[test]
+    fn type_in_the_answer() {
+        let nodes = parse("{{type:cloze:Text}}").unwrap();
+        assert_eq!(
+            nodes,
+            vec![Node::Replacement {
+                key: "Text",
+                filters: vec!["type", "cloze"]
+            }]
+        );
+        assert_eq!(referenced_fields(&nodes), vec!["Text"]);
+
+        let nodes = parse("{{Front}}{{type:Back}}").unwrap();
+        let fields = |names: &[&'static str]| names.iter().copied().collect::<HashSet<_>>();
+        assert!(!renders_with_fields(&nodes, &fields(&["Back"])));
+        assert!(renders_with_fields(&nodes, &fields(&["Front"])));
+    }
+
+    #[test]
+    fn renders_with_fields_respects_sections() {
+        let nodes = parse("{{#Add Reverse}}{{Back}}{{/Add Reverse}}").unwrap();
+        let fields = |names: &[&'static str]| names.iter().copied().collect::<HashSet<_>>();
+        assert!(!renders_with_fields(&nodes, &fields(&["Back"])));
+        assert!(!renders_with_fields(&nodes, &fields(&["Add Reverse"])));
+        assert!(renders_with_fields(
+            &nodes,
+            &fields(&["Back", "Add Reverse"])
+        ));
+    }
+}
@@ -0,0 +1,98 @@
+use std::ops::RangeFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Error;
+
+/// A point in time in milliseconds since the unix epoch, which a package is written at
+///
+/// The ids of notes and cards start at the milliseconds of the timestamp, and the modification
+/// times in the collection and the zip file are its seconds. `f64` seconds convert into a
+/// `Timestamp`, so `1700000000.5` is `Timestamp::from_millis(1700000000500)`.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::Timestamp;
+///
+/// let timestamp = Timestamp::from_secs(1700000000);
+/// assert_eq!(timestamp.as_millis(), 1700000000000);
+/// assert_eq!(Timestamp::from(1700000000.5).as_millis(), 1700000000500);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    millis: i64,
+}
+
+impl Timestamp {
+    /// The current time
+    ///
+    /// Returns `Error::SystemTime` if the system clock is set before 1970
+    pub fn now() -> Result<Self, Error> {
+        let elapsed = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        Ok(Self::from_millis(elapsed.as_millis() as i64))
+    }
+
+    pub fn from_millis(millis: i64) -> Self {
+        Self { millis }
+    }
+
+    /// Seconds too far from the epoch for milliseconds in an `i64` saturate, like `f64` seconds
+    pub fn from_secs(secs: i64) -> Self {
+        Self::from_millis(secs.saturating_mul(1000))
+    }
+
+    /// Rounds `secs` to milliseconds
+    pub fn from_secs_f64(secs: f64) -> Self {
+        Self::from_millis((secs * 1000.0).round() as i64)
+    }
+
+    pub fn as_millis(&self) -> i64 {
+        self.millis
+    }
+
+    /// The whole seconds, rounded down
+    pub fn as_secs(&self) -> i64 {
+        self.millis.div_euclid(1000)
+    }
+
+    pub fn as_secs_f64(&self) -> f64 {
+        self.millis as f64 / 1000.0
+    }
+
+    /// The ids of the notes and cards written at this time, from its milliseconds on
+    pub(crate) fn ids(&self) -> RangeFrom<usize> {
+        (self.millis.max(0) as usize)..
+    }
+}
+
+impl From<f64> for Timestamp {
+    /// Converts unix seconds, like [`Timestamp::from_secs_f64`]
+    fn from(secs: f64) -> Self {
+        Self::from_secs_f64(secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        let timestamp = Timestamp::from(1700000000.1234);
+        assert_eq!(timestamp.as_millis(), 1700000000123);
+        assert_eq!(timestamp.as_secs(), 1700000000);
+        assert_eq!(Timestamp::from_millis(-1).as_secs(), -1);
+        assert_eq!(Timestamp::from_secs(2).as_secs_f64(), 2.0);
+        // Rounding keeps the milliseconds of f64 seconds, which often aren't exact
+        let late = Timestamp::from_millis(253402300799999);
+        assert_eq!(Timestamp::from(late.as_secs_f64()), late);
+        assert_eq!(Timestamp::from_millis(-5).ids().start, 0);
+        assert!(Timestamp::now().unwrap() > Timestamp::from_secs(1700000000));
+        assert_eq!(Timestamp::from_secs(i64::MAX).as_millis(), i64::MAX);
+        assert_eq!(Timestamp::from_secs(i64::MIN).as_millis(), i64::MIN);
+        assert_eq!(
+            Timestamp::from_secs(i64::MAX),
+            Timestamp::from(i64::MAX as f64)
+        );
+    }
+}
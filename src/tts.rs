@@ -0,0 +1,49 @@
+use crate::Error;
+
+/// A text-to-speech engine for [`Package::generate_tts`](crate::Package::generate_tts)
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::{Error, TtsProvider};
+///
+/// struct Espeak;
+///
+/// impl TtsProvider for Espeak {
+///     fn synthesize(&self, text: &str, lang: &str) -> Result<(String, Vec<u8>), Error> {
+///         let output = std::process::Command::new("espeak-ng")
+///             .args(["-v", lang, "--stdout", text])
+///             .output()?;
+///         let filename = format!("tts-{}-{}.wav", lang, genanki_rs::guid_for(&[text]));
+///         Ok((filename, output.stdout))
+///     }
+/// }
+/// ```
+pub trait TtsProvider {
+    /// Returns the name and the content of an audio file of `text` spoken in the language `lang`
+    ///
+    /// The name has to be a valid media file name, and different texts have to get different
+    /// names, e.g. by using a hash of the text.
+    fn synthesize(&self, text: &str, lang: &str) -> Result<(String, Vec<u8>), Error>;
+}
+
+/// Which field of the notes [`Package::generate_tts`](crate::Package::generate_tts) reads and
+/// which field it adds the audio to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TtsField {
+    pub(crate) source: String,
+    pub(crate) target: String,
+    pub(crate) lang: String,
+}
+
+impl TtsField {
+    /// Speaks the text of the field `source` in the language `lang`, e.g. `"de_DE"`, and appends
+    /// `[sound:...]` to the field `target`, which can be the same field
+    pub fn new(source: impl ToString, target: impl ToString, lang: impl ToString) -> Self {
+        Self {
+            source: source.to_string(),
+            target: target.to_string(),
+            lang: lang.to_string(),
+        }
+    }
+}
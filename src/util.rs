@@ -1,15 +1,216 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use fancy_regex::{Captures, Regex};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::OnceLock;
+use tempfile::NamedTempFile;
 
-pub fn guid_for(fields: &Vec<String>) -> String {
-    fields
+const BASE91_TABLE: &[u8; 91] =
+    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!#$%&()*+,-./:;<=>?@[]^_`{|}~";
+
+/// Generates a note guid from `fields`, the same way Python genanki's `guid_for` does.
+///
+/// The values are joined with `__`, hashed with SHA-256 and the first 8 bytes of the hash are
+/// written in the base91 alphabet Anki uses for guids. `Note` uses this with all of its fields
+/// when no guid is set, so regenerating a deck from the same source updates the existing notes on
+/// import instead of duplicating them. The derivation will not change between releases.
+///
+/// Example:
+///
+/// ```rust
+/// use genanki_rs::guid_for;
+///
+/// assert_eq!(guid_for(&["foo", "bar"]), "BY#j^qU/}3");
+/// ```
+pub fn guid_for(fields: &[impl AsRef<str>]) -> String {
+    let joined = fields
         .iter()
-        .map(|f| u64::to_string(&hash_str(f)))
-        .collect()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join("__");
+    let digest = Sha256::digest(joined.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    let mut hash = u64::from_be_bytes(bytes);
+
+    let mut reversed = vec![];
+    while hash > 0 {
+        reversed.push(BASE91_TABLE[(hash % 91) as usize] as char);
+        hash /= 91;
+    }
+    reversed.iter().rev().collect()
+}
+
+/// Cuts `s` off after `max_chars` characters, marking the cut with `…`
+pub fn truncate(s: &str, max_chars: usize) -> String {
+    match s.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &s[..end]),
+        None => s.to_string(),
+    }
+}
+
+/// Strips the HTML of a field like Anki does before it sorts or compares fields
+///
+/// Comments, `<style>` and `<script>` blocks, tags and `[sound:...]` references are removed and
+/// entities such as `&amp;` are replaced with the characters they stand for. Images are replaced
+/// with their file name, so fields that only contain an image can still be told apart.
+pub fn strip_html_media(field: &str) -> String {
+    // Compiled once, since this runs for every note that is written
+    static MEDIA: OnceLock<Regex> = OnceLock::new();
+    let media = MEDIA.get_or_init(|| {
+        Regex::new(r#"(?i)<img[^>]+src=["']?([^"'>]+)["']?[^>]*>"#).expect("static regex")
+    });
+    strip_html(&media.replace_all(field, " $1 "))
 }
 
-fn hash_str(to_hash: &str) -> u64 {
-    let mut s = DefaultHasher::new();
-    to_hash.hash(&mut s);
-    s.finish()
+/// Strips the HTML of a field like [`strip_html_media`], but removes images like other tags
+pub fn strip_html(field: &str) -> String {
+    static REGEXES: OnceLock<(Regex, Regex)> = OnceLock::new();
+    let (markup, sound) = REGEXES.get_or_init(|| {
+        let markup =
+            Regex::new(r"(?si)<!--.*?-->|<style.*?>.*?</style>|<script.*?>.*?</script>|<.*?>")
+                .expect("static regex");
+        let sound = Regex::new(r"\[sound:[^\]]+\]").expect("static regex");
+        (markup, sound)
+    });
+
+    let field = markup.replace_all(field, "");
+    let field = sound.replace_all(&field, "");
+    decode_entities(&field)
+}
+
+/// Replaces HTML entities like `&amp;` with the characters they stand for
+pub fn decode_entities(text: &str) -> String {
+    static ENTITY: OnceLock<Regex> = OnceLock::new();
+    let entity = ENTITY.get_or_init(|| {
+        Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").expect("static regex")
+    });
+    entity
+        .replace_all(text, |captures: &Captures| {
+            let name = &captures[1];
+            let decoded = match name {
+                // Anki uses a normal space instead of U+00A0
+                "nbsp" => Some(' '),
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => {
+                    let code = if let Some(hex) =
+                        name.strip_prefix("#x").or_else(|| name.strip_prefix("#X"))
+                    {
+                        u32::from_str_radix(hex, 16).ok()
+                    } else {
+                        name.strip_prefix('#').and_then(|dec| dec.parse().ok())
+                    };
+                    code.and_then(char::from_u32)
+                }
+            };
+            decoded.map_or_else(|| captures[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+/// Escapes `&`, `<`, `>` and `"`, so that text shows up literally in a field
+pub(crate) fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The checksum Anki uses to find duplicate notes: the first 8 hex digits of the SHA-1 hash of
+/// the stripped first field, as a number
+pub fn field_checksum(first_field: &str) -> u32 {
+    let digest = Sha1::digest(strip_html_media(first_field).as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// Renders the Markdown of a field as HTML for [`Note::new_markdown`](crate::Note::new_markdown)
+///
+/// Raw HTML in the Markdown is escaped like text, so that `<` and `&` in the content show up
+/// literally instead of breaking the card. A field that is a single paragraph isn't wrapped in
+/// `<p>`, so that it stays inline like a field written by hand.
+#[cfg(feature = "markdown")]
+pub(crate) fn markdown_to_html(markdown: &str) -> String {
+    use pulldown_cmark::{Event, Parser, Tag};
+
+    let events: Vec<Event> = Parser::new(markdown)
+        .map(|event| match event {
+            Event::Html(html) => Event::Text(html),
+            event => event,
+        })
+        .collect();
+    let single_paragraph = matches!(events.first(), Some(Event::Start(Tag::Paragraph)))
+        && matches!(events.last(), Some(Event::End(Tag::Paragraph)))
+        && events
+            .iter()
+            .filter(|event| matches!(event, Event::End(Tag::Paragraph)))
+            .count()
+            == 1;
+    let events = if single_paragraph {
+        &events[1..events.len() - 1]
+    } else {
+        &events[..]
+    };
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.iter().cloned());
+    html.truncate(html.trim_end_matches('\n').len());
+    html
+}
+
+/// Creates a temporary file in the directory of `path`, so that it can replace `path` with an
+/// atomic rename once it is complete
+///
+/// The file gets the permissions of a file created with `File::create` instead of the
+/// permissions only for the owner of other temporary files.
+pub(crate) fn temp_file_next_to(path: &Path) -> std::io::Result<NamedTempFile> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut builder = tempfile::Builder::new();
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+    builder.tempfile_in(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guid_for_matches_genanki() {
+        assert_eq!(guid_for(&["foo", "bar"]), "BY#j^qU/}3");
+        assert_eq!(guid_for(&["a"]), "IkF(BOZ;]l");
+        assert_eq!(
+            guid_for(&["der Hund".to_string(), "the dog".to_string()]),
+            "k~J)wUJLND"
+        );
+        assert_eq!(guid_for(&[] as &[&str]), "ME_YHw2?15");
+    }
+
+    #[test]
+    fn strip_html_media_like_anki() {
+        assert_eq!(strip_html_media("<b>bold</b> <i>text</i>"), "bold text");
+        assert_eq!(strip_html_media("dog[sound:dog.mp3]"), "dog");
+        assert_eq!(
+            strip_html_media("Tom &amp; Jerry&nbsp;&lt;3 &#65;&#x42; &unknown;"),
+            "Tom & Jerry <3 AB &unknown;"
+        );
+        assert_eq!(
+            strip_html_media("<!-- note --><style>b { color: red; }</style><img src=\"dog.jpg\">"),
+            " dog.jpg "
+        );
+        assert_eq!(strip_html("<img src=\"dog.jpg\">der Hund"), "der Hund");
+    }
+
+    #[test]
+    fn field_checksum_like_anki() {
+        // int(sha1("hello".encode()).hexdigest()[:8], 16)
+        assert_eq!(field_checksum("hello"), 0xaaf4c61d);
+        assert_eq!(field_checksum("<b>hello</b>[sound:x.mp3]"), 0xaaf4c61d);
+    }
 }
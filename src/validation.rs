@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::deck::Deck;
+use crate::model::{same_model, Model};
+use crate::package_writer::missing_parent_decks;
+use crate::Error;
+
+/// The default of [`Package::max_field_bytes`](crate::Package::max_field_bytes), 128 KiB
+pub(crate) const DEFAULT_MAX_FIELD_BYTES: usize = 128 << 10;
+
+/// The largest id of a model or deck, `2^53 - 1`
+///
+/// Anki's web and mobile clients store ids as JavaScript numbers, which can't represent all
+/// larger integers, and AnkiWeb doesn't sync them.
+pub const MAX_ID: i64 = (1 << 53) - 1;
+
+/// What an id in [`Error::IdOutOfRange`] belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IdKind {
+    Model,
+    Deck,
+}
+
+impl std::fmt::Display for IdKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IdKind::Model => "model",
+            IdKind::Deck => "deck",
+        })
+    }
+}
+
+/// Returns `Error::IdOutOfRange` unless `id` is in `1..=MAX_ID`
+pub(crate) fn check_id(kind: IdKind, id: i64) -> Result<(), Error> {
+    if (1..=MAX_ID).contains(&id) {
+        Ok(())
+    } else {
+        Err(Error::IdOutOfRange { kind, id })
+    }
+}
+
+/// The problems [`Package::validate`](crate::Package::validate) found that don't prevent writing
+/// the package
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// The warnings in the order of the decks and notes they are about
+    pub warnings: Vec<ValidationWarning>,
+}
+
+/// A problem with a package that doesn't prevent writing it, see [`ValidationReport`]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ValidationWarning {
+    /// The deck has no notes, so importing it only adds an empty deck
+    EmptyDeck { id: i64, name: String },
+}
+
+/// Checks the decks of a package, see [`Package::validate`](crate::Package::validate)
+pub(crate) fn validate_decks(
+    decks: &[Deck],
+    max_field_bytes: usize,
+) -> Result<ValidationReport, Error> {
+    if decks.is_empty() {
+        return Err(Error::NoDecks);
+    }
+    let mut report = ValidationReport::default();
+    let mut models: HashMap<i64, &Arc<Model>> = HashMap::new();
+    let deck_ids: HashSet<i64> = decks
+        .iter()
+        .chain(&missing_parent_decks(decks))
+        .map(Deck::id)
+        .collect();
+    for deck in decks {
+        if deck.note_count() == 0 {
+            report.warnings.push(ValidationWarning::EmptyDeck {
+                id: deck.id(),
+                name: deck.name().to_string(),
+            });
+        }
+        for note in deck.notes() {
+            if let Some((field_index, field)) = note
+                .fields()
+                .iter()
+                .enumerate()
+                .find(|(_, field)| field.len() > max_field_bytes)
+            {
+                return Err(Error::FieldTooLarge {
+                    guid: note.get_guid(),
+                    field_index,
+                    len: field.len(),
+                    limit: max_field_bytes,
+                });
+            }
+            if let Some(id) = note
+                .card_deck_ids(deck.id())
+                .into_iter()
+                .find(|id| !deck_ids.contains(id))
+            {
+                return Err(Error::UnknownDeckId(id));
+            }
+            let model = note.model();
+            check_id(IdKind::Model, model.id)?;
+            match models.get(&model.id) {
+                Some(known) if !same_model(known, model) => {
+                    return Err(Error::ModelIdConflict {
+                        id: model.id,
+                        name: known.name().to_string(),
+                        other_name: model.name().to_string(),
+                    })
+                }
+                Some(_) => {}
+                None => {
+                    models.insert(model.id, model);
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_model, Field, Note, Package, Template};
+
+    #[test]
+    fn no_decks() {
+        let mut package = Package::new(vec![], vec![]).unwrap();
+        assert!(matches!(package.validate(), Err(Error::NoDecks)));
+        assert!(matches!(package.write_to_bytes(), Err(Error::NoDecks)));
+        let mut package = package.unchecked(true);
+        assert!(package.write_to_bytes().is_ok());
+    }
+
+    #[test]
+    fn empty_decks() {
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"]).unwrap());
+        let decks = vec![
+            deck,
+            Deck::new(5678, "French", ""),
+            Deck::new(9012, "Dutch", ""),
+        ];
+        let mut package = Package::new(decks, vec![]).unwrap();
+        assert_eq!(
+            package.validate().unwrap().warnings,
+            vec![
+                ValidationWarning::EmptyDeck {
+                    id: 5678,
+                    name: "French".to_string()
+                },
+                ValidationWarning::EmptyDeck {
+                    id: 9012,
+                    name: "Dutch".to_string()
+                },
+            ]
+        );
+        assert!(package.write_to_bytes().is_ok());
+    }
+
+    #[test]
+    fn oversized_fields() {
+        let large = "a".repeat(DEFAULT_MAX_FIELD_BYTES + 1);
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(
+            Note::new(basic_model(), vec!["der Hund", &large])
+                .unwrap()
+                .guid("hund"),
+        );
+        let mut package = Package::new(vec![deck.clone()], vec![]).unwrap();
+        match package.validate() {
+            Err(Error::FieldTooLarge {
+                guid,
+                field_index,
+                len,
+                limit,
+            }) => {
+                assert_eq!(guid, "hund");
+                assert_eq!(field_index, 1);
+                assert_eq!(
+                    (len, limit),
+                    (DEFAULT_MAX_FIELD_BYTES + 1, DEFAULT_MAX_FIELD_BYTES)
+                );
+            }
+            other => panic!("expected a field that is too large, got {:?}", other),
+        }
+        assert!(matches!(
+            package.write_to_bytes(),
+            Err(Error::FieldTooLarge { .. })
+        ));
+
+        let package = Package::new(vec![deck.clone()], vec![])
+            .unwrap()
+            .max_field_bytes(DEFAULT_MAX_FIELD_BYTES + 1);
+        assert!(package.validate().is_ok());
+        let package = Package::new(vec![deck], vec![]).unwrap().max_field_bytes(3);
+        assert!(matches!(
+            package.validate(),
+            Err(Error::FieldTooLarge {
+                field_index: 0,
+                len: 8,
+                limit: 3,
+                ..
+            })
+        ));
+    }
+
+    fn id_model(id: i64) -> Model {
+        Model::new(
+            id,
+            "Basic",
+            vec![Field::new("Front"), Field::new("Back")],
+            vec![Template::new("Card 1").qfmt("{{Front}}").afmt("{{Back}}")],
+        )
+    }
+
+    #[test]
+    fn id_ranges() {
+        for id in [0, -1, MAX_ID + 1] {
+            assert!(matches!(
+                Package::new(vec![Deck::new(id, "German", "")], vec![]),
+                Err(Error::IdOutOfRange { kind: IdKind::Deck, id: i }) if i == id
+            ));
+
+            let model = id_model(id);
+            let mut deck = Deck::new(1234, "German", "");
+            deck.add_note(Note::new(model, vec!["der Hund", "the dog"]).unwrap());
+            let package = Package::new(vec![deck], vec![]).unwrap();
+            assert!(matches!(
+                package.validate(),
+                Err(Error::IdOutOfRange { kind: IdKind::Model, id: i }) if i == id
+            ));
+            let mut package = package.unchecked(true);
+            assert!(matches!(
+                package.write_to_bytes(),
+                Err(Error::IdOutOfRange {
+                    kind: IdKind::Model,
+                    ..
+                })
+            ));
+        }
+
+        assert!(Package::new(vec![Deck::new(1, "Default", "")], vec![]).is_ok());
+        assert!(matches!(
+            Package::new(vec![Deck::new(1, "German", "")], vec![]),
+            Err(Error::DuplicateDeckId { id: 1, names }) if names == ("Default".to_string(), "German".to_string())
+        ));
+
+        let model = id_model(MAX_ID);
+        let mut deck = Deck::new(MAX_ID - 1, "German", "");
+        deck.add_note(Note::new(model, vec!["der Hund", "the dog"]).unwrap());
+        let mut package = Package::new(vec![deck], vec![]).unwrap();
+        assert!(package.validate().is_ok());
+        assert!(package.write_to_bytes().is_ok());
+        assert!(Model::id_from_name("Basic") <= MAX_ID);
+        assert_eq!(
+            Error::IdOutOfRange {
+                kind: IdKind::Deck,
+                id: 0
+            }
+            .to_string(),
+            "the deck id 0 isn't between 1 and 2^53 - 1"
+        );
+    }
+
+    #[test]
+    fn conflicting_models() {
+        let other_basic = Model::new(
+            basic_model().id,
+            "Other basic",
+            vec![Field::new("Question"), Field::new("Answer")],
+            vec![Template::new("Card 1")
+                .qfmt("{{Question}}")
+                .afmt("{{Answer}}")],
+        );
+        let mut german = Deck::new(1234, "German", "");
+        german.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"]).unwrap());
+        let mut french = Deck::new(5678, "French", "");
+        french.add_note(Note::new(basic_model(), vec!["le chien", "the dog"]).unwrap());
+        assert!(Package::new(vec![german.clone(), french.clone()], vec![])
+            .unwrap()
+            .validate()
+            .is_ok());
+
+        french.add_note(Note::new(other_basic, vec!["le chat", "the cat"]).unwrap());
+        match Package::new(vec![german, french], vec![])
+            .unwrap()
+            .validate()
+        {
+            Err(Error::ModelIdConflict {
+                id,
+                name,
+                other_name,
+            }) => {
+                assert_eq!(id, basic_model().id);
+                assert_eq!(name, basic_model().name());
+                assert_eq!(other_name, "Other basic");
+            }
+            other => panic!("expected a model id conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn routed_deck_ids() {
+        let routed = |deck_id: i64| {
+            let mut deck = Deck::new(1234, "German::Nouns", "");
+            let note = Note::new(crate::basic_and_reversed_card_model(), vec!["Hund", "dog"]);
+            deck.add_note_with_card_routing(note.unwrap(), &[1234, deck_id])
+                .unwrap();
+            Package::new(vec![deck, Deck::new(5678, "French", "")], vec![]).unwrap()
+        };
+        assert!(routed(5678).validate().is_ok());
+        // The parent deck is added to the package
+        assert!(routed(Deck::id_from_name("German")).validate().is_ok());
+        assert!(matches!(
+            routed(9012).validate(),
+            Err(Error::UnknownDeckId(9012))
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+use rusqlite::{Connection, OpenFlags};
+use serde_json::{Map, Value};
+use zip::ZipArchive;
+
+use std::fs::File;
+use std::path::Path;
+
+use crate::error::{database_error, json_error, zip_error};
+use crate::package_reader::extract_collection;
+use crate::{Error, WriteSummary};
+
+/// Checks that the package at `path` can be read again and contains what `summary` says was
+/// written, see [`Package::write_to_file_verified`](crate::Package::write_to_file_verified)
+///
+/// Returns `Error::VerificationFailed` if an entry of the archive doesn't match its CRC, the
+/// collection is corrupted, or the number of notes, cards, models or media files is different.
+pub(crate) fn verify_package(path: &Path, summary: &WriteSummary) -> Result<(), Error> {
+    check_package(path, summary).map_err(|e| match e {
+        Error::VerificationFailed { .. } => e,
+        e => Error::VerificationFailed {
+            detail: e.to_string(),
+        },
+    })
+}
+
+fn check_package(path: &Path, summary: &WriteSummary) -> Result<(), Error> {
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+    let mut media_files = 0;
+    for index in 0..archive.len() {
+        // The CRC of an entry is only checked once it is read to the end
+        let mut entry = archive.by_index(index).map_err(zip_error)?;
+        std::io::copy(&mut entry, &mut std::io::sink())?;
+        if entry.name().parse::<usize>().is_ok() {
+            media_files += 1;
+        }
+    }
+    let db_file = extract_collection(&mut archive)?;
+    let conn = Connection::open_with_flags(db_file.path(), OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(database_error)?;
+    let integrity: String = conn
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .map_err(database_error)?;
+    if integrity != "ok" {
+        return Err(failed(format!(
+            "the collection is corrupted: {}",
+            integrity
+        )));
+    }
+
+    let count = |table: &str| -> Result<usize, Error> {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+            row.get::<_, i64>(0)
+        })
+        .map(|count| count as usize)
+        .map_err(database_error)
+    };
+    let models_json: String = conn
+        .query_row("SELECT models FROM col", [], |row| row.get(0))
+        .map_err(database_error)?;
+    let models = serde_json::from_str::<Map<String, Value>>(&models_json)
+        .map_err(json_error)?
+        .len();
+    let counts = [
+        ("notes", count("notes")?, summary.notes),
+        ("cards", count("cards")?, summary.cards),
+        ("models", models, summary.models),
+        ("media files", media_files, summary.media_files),
+    ];
+    for (what, found, written) in counts {
+        if found != written {
+            return Err(failed(format!(
+                "{} {} were written, but the package contains {}",
+                written, what, found
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn failed(detail: String) -> Error {
+    Error::VerificationFailed { detail }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic_model, CompressionOptions, Deck, Note, Package};
+    use tempfile::TempDir;
+
+    const CONTENT: &[u8] = b"the content of a media file that is stored without compression";
+
+    fn write_package(path: &Path) -> WriteSummary {
+        let mut deck = Deck::new(1234, "German", "");
+        deck.add_note(Note::new(basic_model(), vec!["der Hund", "the dog"]).unwrap());
+        deck.add_note(Note::new(basic_model(), vec!["die Katze", "the cat"]).unwrap());
+        let mut package = Package::new(vec![deck], vec![])
+            .unwrap()
+            .compression(CompressionOptions::new().stored_extensions(&["txt"]));
+        package.add_media_bytes("content.txt", CONTENT).unwrap();
+        package.write_to_file(path).unwrap()
+    }
+
+    #[test]
+    fn written_package_is_verified() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("output.apkg");
+        let summary = write_package(&path);
+        assert_eq!((summary.notes, summary.cards, summary.models), (2, 2, 1));
+        verify_package(&path, &summary).unwrap();
+
+        let mut package = Package::new(vec![Deck::new(1234, "German", "")], vec![]).unwrap();
+        let summary = package.write_to_file_verified(&path).unwrap();
+        assert_eq!(summary.notes, 0);
+    }
+
+    #[test]
+    fn corrupted_package_fails_verification() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("output.apkg");
+        let summary = write_package(&path);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let offset = bytes
+            .windows(CONTENT.len())
+            .position(|window| window == CONTENT)
+            .unwrap();
+        bytes[offset + CONTENT.len() / 2] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        match verify_package(&path, &summary) {
+            Err(Error::VerificationFailed { detail }) => assert!(detail.contains("checksum")),
+            other => panic!("expected the verification to fail, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn different_counts_fail_verification() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("output.apkg");
+        let summary = write_package(&path);
+        let wrong = WriteSummary {
+            cards: summary.cards + 1,
+            ..summary
+        };
+        match verify_package(&path, &wrong) {
+            Err(Error::VerificationFailed { detail }) => {
+                assert_eq!(detail, "3 cards were written, but the package contains 2")
+            }
+            other => panic!("expected the verification to fail, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Checks that `PackageWriter` writes notes without keeping them in memory
+//!
+//! The test counts allocations with its own global allocator, so it is a separate test binary.
+
+use genanki_rs::{basic_model, CompressionOptions, Deck, Note, PackageWriter};
+use rusqlite::Connection;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::Cursor;
+use tempfile::TempDir;
+
+/// Counts the bytes that are currently allocated by each thread
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocated() -> isize {
+    ALLOCATED.with(Cell::get)
+}
+
+#[test]
+fn many_notes() {
+    const NOTES: usize = 100_000;
+    let decks = [Deck::new(1, "Default", ""), Deck::new(1234, "Numbers", "")];
+    let mut writer = PackageWriter::new(Cursor::new(vec![]), vec![basic_model()], &decks)
+        .unwrap()
+        .compression(CompressionOptions::new())
+        .use_tempfile()
+        .unwrap();
+    let start = allocated();
+    let mut peak = 0;
+    for i in 0..NOTES {
+        let fields = [i.to_string(), format!("{:x}", i)];
+        let note = Note::new(basic_model(), fields.iter().map(String::as_str).collect());
+        writer.add_note(1234, note.unwrap()).unwrap();
+        peak = peak.max(allocated() - start);
+    }
+    // A single note with its model takes about 2 KiB
+    assert!(peak < 1 << 20, "{} bytes", peak);
+    writer.add_media_bytes("notes.txt", "notes").unwrap();
+    let bytes = writer.finish().unwrap().into_inner();
+
+    let dir = TempDir::new().unwrap();
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+    let db_path = dir.path().join("collection.anki2");
+    std::io::copy(
+        &mut archive.by_name("collection.anki2").unwrap(),
+        &mut File::create(&db_path).unwrap(),
+    )
+    .unwrap();
+    let conn = Connection::open(&db_path).unwrap();
+    let count = |table: &str| -> usize {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    };
+    assert_eq!((count("notes"), count("cards")), (NOTES, NOTES));
+    let (max_due, last_id): (i64, i64) = conn
+        .query_row("SELECT MAX(due), MAX(did) FROM cards", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .unwrap();
+    assert_eq!((max_due, last_id), (NOTES as i64 - 1, 1234));
+}